@@ -0,0 +1,295 @@
+use std::fmt::{self, Display, Formatter};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+        Method,
+    },
+    middleware::Next,
+    web::Data,
+    HttpMessage, ResponseError,
+};
+use chrono::{DateTime, Utc};
+use mongodb::{bson::oid::ObjectId, Database};
+use pasetors::claims::Claims;
+use serde::{Deserialize, Serialize};
+use tracing::{error, instrument, warn};
+
+use crate::{
+    auth::{keys::KeyRing, policy::is_api_path},
+    endpoints::error::AppError,
+    models::{
+        api_token::ApiTokenRepo,
+        mongo::{User, UserRepo},
+        repository::RepoError,
+    },
+};
+
+/// Lifetimes, in days, a token can be made with
+pub const LIFETIME_DAYS: [i64; 4] = [7, 30, 90, 365];
+
+/// Marks a PASETO as an API token rather than an emailed link
+const PURPOSE: &str = "api";
+
+/// What an API token may do through `/v1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    /// `GET` and `HEAD` requests
+    Read,
+    /// Every other request
+    Write,
+}
+
+impl ApiScope {
+    pub const ALL: [Self; 2] = [Self::Read, Self::Write];
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+
+    /// # Result
+    ///   - The scope a request with `method` needs
+    #[must_use]
+    pub fn for_method(method: &Method) -> Self {
+        if method == Method::GET || method == Method::HEAD {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+}
+
+impl Display for ApiScope {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// # Result
+///   - The bearer token for the stored token with `token_id`
+/// # Errors
+///   - ``String``: The token cannot be built
+pub fn issue_api_token(
+    key_ring: &KeyRing,
+    token_id: ObjectId,
+    user_id: ObjectId,
+    expires_at: DateTime<Utc>,
+) -> Result<String, String> {
+    let mut claims = Claims::new().map_err(|err| format!("Cannot create claims: {err:?}"))?;
+    claims
+        .expiration(&expires_at.to_rfc3339())
+        .map_err(|err| format!("Cannot set expiration time: {err:?}"))?;
+    for (name, value) in [
+        ("purpose", PURPOSE.to_string()),
+        ("token_id", token_id.to_hex()),
+        ("user_id", user_id.to_hex()),
+    ] {
+        claims
+            .add_additional(name, value)
+            .map_err(|err| format!("Cannot add {name} claim: {err:?}"))?;
+    }
+
+    key_ring.encrypt(&claims)
+}
+
+/// # Result
+///   - The stored token ID and user ID a bearer token names
+/// # Errors
+///   - ``String``: The token is malformed, forged, expired or not an API token
+pub fn decode_api_token(key_ring: &KeyRing, token: &str) -> Result<(ObjectId, ObjectId), String> {
+    let trusted_token = key_ring.decrypt(token)?;
+
+    let claims = trusted_token
+        .payload_claims()
+        .ok_or_else(|| String::from("Token has no payload claims"))?;
+    let claim = |name: &str| -> Result<&str, String> {
+        claims
+            .get_claim(name)
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| format!("Token has no {name} claim"))
+    };
+
+    if claim("purpose")? != PURPOSE {
+        return Err(String::from("Not an API token"));
+    }
+    let id = |name: &str| {
+        ObjectId::parse_str(claim(name)?).map_err(|err| format!("Cannot parse {name}: {err}"))
+    };
+    Ok((id("token_id")?, id("user_id")?))
+}
+
+/// # Result
+///   - The token in an `Authorization: Bearer` header, if there is one
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// # Result
+///   - The owner of `token`, if it may make a request needing `scope`
+/// # Errors
+///   - `AppError::Unauthorized` for a bad, revoked or expired token
+///   - `AppError::Forbidden` if the token lacks the scope
+async fn authenticate(
+    db: &Database,
+    key_ring: &KeyRing,
+    token: &str,
+    scope: ApiScope,
+) -> Result<User, AppError> {
+    let (token_id, user_id) = decode_api_token(key_ring, token).map_err(|err| {
+        warn!("Rejected API token: {err}");
+        AppError::Unauthorized(String::from("Invalid API token"))
+    })?;
+
+    let tokens = ApiTokenRepo::new(db);
+    let stored = match tokens.find_live(token_id, user_id).await {
+        Ok(stored) => stored,
+        Err(RepoError::NotFound(_)) => {
+            return Err(AppError::Unauthorized(String::from(
+                "This API token has been revoked or has expired",
+            )))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if !stored.allows(scope) {
+        return Err(AppError::Forbidden(format!(
+            "This API token lacks the {scope} scope"
+        )));
+    }
+
+    let user = match UserRepo::new(db).get_user(Some(user_id), None).await {
+        Ok(user) => user,
+        Err(RepoError::NotFound(_)) => {
+            return Err(AppError::Unauthorized(String::from(
+                "This API token's account no longer exists",
+            )))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Err(err) = tokens.touch(token_id).await {
+        error!("API token use not recorded: {err}");
+    }
+    Ok(user)
+}
+
+/// Middleware that signs `/v1` requests carrying an `Authorization: Bearer`
+/// header in as the token's owner, so scripts need no cookies.
+///
+/// The `User` is
+/// put in the request extensions, in place of any from the session, for
+/// `enforce_policy` to check. Requests without the header are left alone.
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Authenticate API token",
+    level = "debug",
+    target = "kid_data",
+    skip(req, next),
+    fields(path = %req.path())
+)]
+pub async fn authenticate_api_tokens(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let token = match bearer_token(req.headers()) {
+        Some(token) if is_api_path(req.path()) => token.to_string(),
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+
+    let Some(db) = req.app_data::<Data<Database>>().cloned() else {
+        error!("No database to check the API token against");
+        let response = AppError::Backend(String::from("Database not configured")).error_response();
+        return Ok(req.into_response(response));
+    };
+    let Some(key_ring) = req.app_data::<Data<KeyRing>>().cloned() else {
+        error!("No key ring to decrypt the API token with");
+        let response = AppError::Backend(String::from("Key ring not configured")).error_response();
+        return Ok(req.into_response(response));
+    };
+
+    match authenticate(
+        db.as_ref(),
+        &key_ring,
+        &token,
+        ApiScope::for_method(req.method()),
+    )
+    .await
+    {
+        Ok(user) => {
+            req.extensions_mut().insert(user);
+            Ok(next.call(req).await?.map_into_boxed_body())
+        }
+        Err(err) => {
+            let mut response = err.error_response();
+            if matches!(err, AppError::Unauthorized(_)) {
+                response
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            Ok(req.into_response(response))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_api_tokens {
+    use actix_web::test::TestRequest;
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_tokens_name_their_record_and_owner() {
+        let key_ring = KeyRing::for_tests();
+        let (token_id, user_id) = (ObjectId::new(), ObjectId::new());
+        let token = issue_api_token(&key_ring, token_id, user_id, Utc::now() + Duration::days(7))
+            .expect("token is issued");
+        assert_eq!(decode_api_token(&key_ring, &token), Ok((token_id, user_id)));
+    }
+
+    #[test]
+    fn test_expired_and_foreign_tokens_are_refused() {
+        let key_ring = KeyRing::for_tests();
+        let expired = issue_api_token(
+            &key_ring,
+            ObjectId::new(),
+            ObjectId::new(),
+            Utc::now() - Duration::minutes(1),
+        )
+        .expect("token is issued");
+        assert!(decode_api_token(&key_ring, &expired).is_err());
+        assert!(decode_api_token(&key_ring, "v4.local.garbage").is_err());
+    }
+
+    #[test]
+    fn test_bearer_header_is_read() {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "bearer  v4.local.abc "))
+            .to_http_request();
+        assert_eq!(bearer_token(req.headers()), Some("v4.local.abc"));
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Basic dXNlcjpwdw=="))
+            .to_http_request();
+        assert_eq!(bearer_token(req.headers()), None);
+    }
+
+    #[test]
+    fn test_writes_need_the_write_scope() {
+        assert_eq!(ApiScope::for_method(&Method::HEAD), ApiScope::Read);
+        assert_eq!(ApiScope::for_method(&Method::DELETE), ApiScope::Write);
+    }
+}
@@ -0,0 +1,221 @@
+use actix_session::{Session, SessionExt, SessionInsertError};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    cookie::{Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web::Data,
+    ResponseError,
+};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use tracing::{debug, error, instrument, warn};
+
+use crate::{
+    auth::{api_tokens::bearer_token, keys::KeyRing, policy::is_api_path},
+    endpoints::error::{render_forgery_error, AppError},
+    types::Types,
+};
+
+/// Cookie holding the browser's CSRF token. Scripts must be able to read it,
+/// so `base.html` can copy it into a header.
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// Header every state-changing request must echo the token in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// # Result
+///   - A new token: a random nonce and its HMAC under `secret` over the
+///     session's `binding` and the nonce, so tokens another site plants in
+///     the cookie, or that another session was given, are refused
+#[must_use]
+pub fn issue_csrf_token(secret: &[u8], binding: &str) -> String {
+    let nonce = random_hex();
+    format!("{nonce}.{}", hex::encode(sign(secret, binding, &nonce)))
+}
+
+/// # Result
+///   - `true` if this server issued `token` to the session with `binding`
+#[must_use]
+pub fn is_genuine(secret: &[u8], binding: &str, token: &str) -> bool {
+    let Some((nonce, tag)) = token.split_once('.') else {
+        return false;
+    };
+    let Ok(tag) = hex::decode(tag) else {
+        return false;
+    };
+    keyed_mac(secret, binding, nonce).is_some_and(|mac| mac.verify_slice(&tag).is_ok())
+}
+
+/// Gives a signed in session its own value to bind CSRF tokens to, so the
+/// token the browser held before signing in stops working
+/// # Errors
+///   - ``SessionInsertError`` if the value cannot be stored in the session
+pub fn bind_csrf_tokens(session: &Session) -> Result<(), SessionInsertError> {
+    session.insert(Types::CsrfKey, random_hex())
+}
+
+/// # Result
+///   - The value the session's tokens are bound to. Sessions that never
+///     signed in have none, and share the empty binding.
+fn session_binding(session: &Session) -> String {
+    session
+        .get::<String>(&Types::CsrfKey.to_string())
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn keyed_mac(secret: &[u8], binding: &str, nonce: &str) -> Option<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .inspect_err(|err| error!("Cannot key the CSRF HMAC: {err}"))
+        .ok()?;
+    // Both are hex, so the separator keeps the pair unambiguous
+    mac.update(binding.as_bytes());
+    mac.update(b".");
+    mac.update(nonce.as_bytes());
+    Some(mac)
+}
+
+fn sign(secret: &[u8], binding: &str, nonce: &str) -> Vec<u8> {
+    keyed_mac(secret, binding, nonce)
+        .map_or_else(Vec::new, |mac| mac.finalize().into_bytes().to_vec())
+}
+
+/// # Result
+///   - `true` if the two tokens match, taking as long whichever byte differs
+fn same_token(left: &str, right: &str) -> bool {
+    left.len() == right.len()
+        && left
+            .bytes()
+            .zip(right.bytes())
+            .fold(0, |diff, (l, r)| diff | (l ^ r))
+            == 0
+}
+
+/// # Result
+///   - `true` if the request could change something on the user's behalf.
+///     Bearer requests to `/v1` carry no ambient credentials, so a forged
+///     one has nothing to ride on.
+fn needs_token(req: &ServiceRequest) -> bool {
+    let safe = [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE];
+    if safe.contains(req.method()) {
+        return false;
+    }
+    !is_api_path(req.path()) || bearer_token(req.headers()).is_none()
+}
+
+/// Middleware that refuses state-changing requests whose `X-CSRF-Token`
+/// header does not match the signed token in the `csrf_token` cookie, or
+/// whose token was not issued to the current session.
+///
+/// Browsers without a token for their session are given one with their next
+/// response, including the response that signs them in or out; it lasts as
+/// long as the browser session.
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Check CSRF token",
+    level = "debug",
+    target = "kid_data",
+    skip(req, next),
+    fields(path = %req.path())
+)]
+pub async fn reject_forged_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(key_ring) = req.app_data::<Data<KeyRing>>().cloned() else {
+        error!("No key ring to check CSRF tokens with");
+        let response = AppError::Backend(String::from("Key ring not configured")).error_response();
+        return Ok(req.into_response(response));
+    };
+
+    let session = req.get_session();
+    let binding = session_binding(&session);
+    let cookie = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| is_genuine(key_ring.hmac_secret(), &binding, token));
+
+    let mut res = if needs_token(&req) {
+        let header = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+        match (&cookie, header) {
+            (Some(cookie), Some(header)) if same_token(cookie, header) => {
+                next.call(req).await?.map_into_boxed_body()
+            }
+            _ => {
+                warn!("Refused a request without a valid CSRF token");
+                req.into_response(render_forgery_error())
+            }
+        }
+    } else {
+        next.call(req).await?.map_into_boxed_body()
+    };
+
+    // Signing in or out gives the session another binding
+    let rebound = session_binding(&session);
+    if cookie.is_none() || rebound != binding {
+        debug!("Issuing a CSRF token");
+        let cookie = Cookie::build(
+            CSRF_COOKIE,
+            issue_csrf_token(key_ring.hmac_secret(), &rebound),
+        )
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .finish();
+        if let Err(err) = res.response_mut().add_cookie(&cookie) {
+            error!("CSRF token not set: {err}");
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test_csrf {
+    use super::*;
+
+    const SECRET: &[u8] = b"a secret long enough for the test";
+
+    #[test]
+    fn test_only_tokens_signed_here_are_genuine() {
+        let token = issue_csrf_token(SECRET, "");
+        assert!(is_genuine(SECRET, "", &token));
+        assert!(!is_genuine(b"another secret entirely", "", &token));
+        assert!(!is_genuine(SECRET, "", &token.replace('.', "")));
+
+        let (nonce, tag) = token.split_once('.').expect("token has a tag");
+        assert!(!is_genuine(SECRET, "", &format!("{nonce}0.{tag}")));
+    }
+
+    #[test]
+    fn test_tokens_only_work_for_their_session() {
+        let (mine, theirs) = (random_hex(), random_hex());
+        let token = issue_csrf_token(SECRET, &mine);
+        assert!(is_genuine(SECRET, &mine, &token));
+        assert!(!is_genuine(SECRET, &theirs, &token));
+        assert!(!is_genuine(SECRET, "", &token));
+        assert!(!is_genuine(SECRET, &mine, &issue_csrf_token(SECRET, "")));
+    }
+
+    #[test]
+    fn test_tokens_differ_and_compare_exactly() {
+        let (one, two) = (issue_csrf_token(SECRET, ""), issue_csrf_token(SECRET, ""));
+        assert!(!same_token(&one, &two));
+        assert!(same_token(&one, &one.clone()));
+        assert!(!same_token(&one, &one[1..]));
+    }
+}
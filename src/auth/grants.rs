@@ -0,0 +1,232 @@
+use std::fmt::{self, Display, Formatter};
+
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    auth::policy::Role,
+    endpoints::error::AppError,
+    models::{child::Child, mongo::User},
+};
+
+/// The kinds of a child's records a grant can open up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Records {
+    Medical,
+    Dental,
+    School,
+    Photos,
+}
+
+impl Records {
+    pub const ALL: [Self; 4] = [Self::Medical, Self::Dental, Self::School, Self::Photos];
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Medical => "medical",
+            Self::Dental => "dental",
+            Self::School => "school",
+            Self::Photos => "photos",
+        }
+    }
+
+    /// # Result
+    ///   - What the records are called on the page
+    #[must_use]
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Medical => "Doctor visits",
+            Self::Dental => "Dental visits",
+            Self::School => "School",
+            Self::Photos => "Photos",
+        }
+    }
+
+    /// # Result
+    ///   - The kind named `name`, as `as_str` spells it
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|records| records.as_str() == name)
+    }
+}
+
+impl Display for Records {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// What a request does with a child's records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    View,
+    Edit,
+}
+
+/// Access to some of one child's records, given to someone who is not the
+/// child's guardian, such as a grandparent or babysitter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildGrant {
+    pub child_id: ObjectId,
+    pub records: Vec<Records>,
+    /// Whether the records may be changed as well as seen
+    pub can_edit: bool,
+}
+
+/// # Result
+///   - `true` if `user` may do `permission` to the child's `records`, or to
+///     the child's profile when `records` is `None`. Admins and the child's
+///     guardians may do anything. Anyone else needs a grant, and may never
+///     change the profile.
+#[must_use]
+pub fn permits(
+    user: &User,
+    child: &Child,
+    records: Option<Records>,
+    permission: Permission,
+) -> bool {
+    if user.role == Role::Admin || user.id.is_some_and(|id| child.guardians.contains(&id)) {
+        return true;
+    }
+
+    user.grants
+        .iter()
+        .filter(|grant| Some(grant.child_id) == child.id)
+        .any(|grant| match (records, permission) {
+            (None, Permission::View) => true,
+            (None, Permission::Edit) => false,
+            (Some(records), Permission::View) => grant.records.contains(&records),
+            (Some(records), Permission::Edit) => grant.can_edit && grant.records.contains(&records),
+        })
+}
+
+/// # Errors
+///   - `AppError::NotFound` if `user` may not see the child at all, so its
+///     existence is not given away
+///   - `AppError::Forbidden` if `user` may see the child but not do this
+pub fn authorize(
+    user: &User,
+    child: &Child,
+    records: Option<Records>,
+    permission: Permission,
+) -> Result<(), AppError> {
+    if permits(user, child, records, permission) {
+        return Ok(());
+    }
+
+    warn!("Refused access to a child's records");
+    if permits(user, child, None, Permission::View) {
+        Err(AppError::Forbidden(String::from(
+            "You have not been given access to these records",
+        )))
+    } else {
+        Err(AppError::NotFound(String::from("Child not found")))
+    }
+}
+
+#[cfg(test)]
+mod test_grants {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::endpoints::register::CreateNewUser;
+
+    fn user(role: Role, grants: Vec<ChildGrant>) -> User {
+        User {
+            id: Some(ObjectId::new()),
+            role,
+            grants,
+            ..User::from(CreateNewUser {
+                email: String::from("sitter@example.com"),
+                password: String::new(),
+                password_2: String::new(),
+                first_name: String::from("Sam"),
+                last_name: String::from("Sitter"),
+            })
+        }
+    }
+
+    fn child(guardians: Vec<ObjectId>) -> Child {
+        Child {
+            id: Some(ObjectId::new()),
+            slug: String::from("aj"),
+            first_name: String::from("AJ"),
+            last_name: String::from("Hunter"),
+            nickname: None,
+            date_of_birth: NaiveDate::default(),
+            profile_photo: None,
+            guardians,
+        }
+    }
+
+    #[test]
+    fn test_guardians_and_admins_may_do_anything() {
+        let guardian = user(Role::Guardian, Vec::new());
+        let child = child(guardian.id.into_iter().collect());
+        assert!(permits(&guardian, &child, None, Permission::Edit));
+        assert!(permits(
+            &user(Role::Admin, Vec::new()),
+            &child,
+            Some(Records::Medical),
+            Permission::Edit
+        ));
+    }
+
+    #[test]
+    fn test_grants_open_only_what_they_name() {
+        let child = child(Vec::new());
+        let sitter = user(
+            Role::Viewer,
+            vec![ChildGrant {
+                child_id: child.id.unwrap_or_default(),
+                records: vec![Records::Photos],
+                can_edit: false,
+            }],
+        );
+
+        assert!(permits(&sitter, &child, None, Permission::View));
+        assert!(permits(
+            &sitter,
+            &child,
+            Some(Records::Photos),
+            Permission::View
+        ));
+        assert!(!permits(
+            &sitter,
+            &child,
+            Some(Records::Photos),
+            Permission::Edit
+        ));
+        assert!(!permits(
+            &sitter,
+            &child,
+            Some(Records::Medical),
+            Permission::View
+        ));
+        assert!(!permits(&sitter, &child, None, Permission::Edit));
+        assert_eq!(
+            authorize(&sitter, &child, Some(Records::Medical), Permission::View),
+            Err(AppError::Forbidden(String::from(
+                "You have not been given access to these records"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_strangers_cannot_tell_the_child_exists() {
+        assert_eq!(
+            authorize(
+                &user(Role::Guardian, Vec::new()),
+                &child(Vec::new()),
+                None,
+                Permission::View
+            ),
+            Err(AppError::NotFound(String::from("Child not found")))
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use mongodb::bson::oid::ObjectId;
+use tracing::{debug, instrument};
+
+use crate::models::mongo::User;
+
+/// Header HTMX adds to every request it makes
+const HX_REQUEST: &str = "HX-Request";
+/// Header HTMX uses to say which page made the request
+const HX_CURRENT_URL: &str = "HX-Current-URL";
+/// Header that tells HTMX to load a whole new page
+const HX_REDIRECT: &str = "HX-Redirect";
+
+/// The signed in user, as loaded by `sign_out_stale_sessions`. Taking it as a
+/// handler argument is what makes a route need a login.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: ObjectId,
+    pub user: User,
+}
+
+impl AuthenticatedUser {
+    /// # Result
+    ///   - The signed in user the session middleware found for `req`
+    #[must_use]
+    pub fn from_extensions(req: &HttpRequest) -> Option<Self> {
+        let user = req.extensions().get::<User>().cloned()?;
+        Some(Self { id: user.id?, user })
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = LoginRedirect;
+
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::from_extensions(req).ok_or_else(|| LoginRedirect::for_request(req)))
+    }
+}
+
+/// Sends a visitor who is not logged in to the login page, remembering where
+/// they were headed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginRedirect {
+    next: String,
+    htmx: bool,
+}
+
+impl LoginRedirect {
+    /// # Result
+    ///   - A redirect back to the page `req` came from once logged in. For
+    ///     HTMX that is the page hosting the fragment, not the fragment.
+    #[must_use]
+    pub fn for_request(req: &HttpRequest) -> Self {
+        let htmx = req.headers().contains_key(HX_REQUEST);
+        let from_page = req
+            .headers()
+            .get(HX_CURRENT_URL)
+            .and_then(|url| url.to_str().ok())
+            .map(path_of);
+
+        let next = match from_page {
+            Some(page) if htmx => page.to_string(),
+            _ => req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_string(), ToString::to_string),
+        };
+
+        Self { next, htmx }
+    }
+
+    /// # Result
+    ///   - The login page URL, carrying `next` when it is worth returning to
+    #[must_use]
+    pub fn location(&self) -> String {
+        match safe_next(&self.next) {
+            Some(next) if next != "/" => format!("/?next={}", encode(next)),
+            _ => String::from("/"),
+        }
+    }
+}
+
+impl Display for LoginRedirect {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Log in to see {}", self.next)
+    }
+}
+
+impl ResponseError for LoginRedirect {
+    fn status_code(&self) -> StatusCode {
+        if self.htmx {
+            StatusCode::OK
+        } else {
+            StatusCode::SEE_OTHER
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        debug!("Sending the visitor to log in");
+        let location = self.location();
+        if self.htmx {
+            HttpResponse::Ok()
+                .insert_header((HX_REDIRECT, location))
+                .finish()
+        } else {
+            HttpResponse::SeeOther()
+                .insert_header((header::LOCATION, location))
+                .finish()
+        }
+    }
+}
+
+/// Middleware for whole scopes whose every route needs a login
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Require login",
+    level = "debug",
+    target = "kid_data",
+    skip(req, next),
+    fields(path = %req.path())
+)]
+pub async fn require_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    if AuthenticatedUser::from_extensions(req.request()).is_some() {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let redirect = LoginRedirect::for_request(req.request());
+    Ok(req.into_response(redirect.error_response()))
+}
+
+/// # Result
+///   - `next` if it is a path on this site, so a login link cannot send the
+///     user somewhere else
+#[must_use]
+pub fn safe_next(next: &str) -> Option<&str> {
+    let local = next.starts_with('/') && !next.starts_with("//") && !next.contains('\\');
+    local.then_some(next)
+}
+
+/// # Result
+///   - The path and query of an absolute URL, or the input if it has none
+fn path_of(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| {
+        rest.find('/').map_or("/", |at| &rest[at..])
+    })
+}
+
+/// # Result
+///   - `value` percent encoded for use as a query parameter
+pub(crate) fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_guard {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_plain_requests_get_a_see_other() {
+        let req = TestRequest::get().uri("/child/aj?tab=2").to_http_request();
+        let response = LoginRedirect::for_request(&req).error_response();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response
+                .headers()
+                .get(header::LOCATION)
+                .map(header::HeaderValue::as_bytes),
+            Some(b"/?next=/child/aj%3Ftab%3D2".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_htmx_requests_return_to_the_hosting_page() {
+        let req = TestRequest::get()
+            .uri("/doctor_card/abc")
+            .insert_header((HX_REQUEST, "true"))
+            .insert_header((HX_CURRENT_URL, "http://localhost:8081/doctor_logs/aj"))
+            .to_http_request();
+        let response = LoginRedirect::for_request(&req).error_response();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get(HX_REDIRECT)
+                .map(header::HeaderValue::as_bytes),
+            Some(b"/?next=/doctor_logs/aj".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_next_stays_on_this_site() {
+        assert_eq!(safe_next("/child/aj"), Some("/child/aj"));
+        assert_eq!(safe_next("//evil.example"), None);
+        assert_eq!(safe_next("https://evil.example"), None);
+        assert_eq!(safe_next("/\\evil.example"), None);
+    }
+}
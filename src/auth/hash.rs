@@ -1,53 +1,42 @@
+use actix_web::rt::task;
 use argon2::{
     password_hash::{self, SaltString},
-    Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
 };
 use rand::rngs::OsRng;
-use tracing::{error, instrument, warn};
+use tracing::{error, instrument};
+
+use crate::settings::Argon2Cost;
 
 /// # Result
 ///   - A string containing the hash of the password
 /// # Errors
 ///   - ``password_hash::Error`` if the password cannot be hashed
-/// # Panics
-///   - If the password cannot be hashed
+/// # Notes
+/// Hashes at `cost`, normally ``Settings.secret.argon2``, on the blocking
+/// pool so the async workers keep serving requests meanwhile.
 #[instrument(
     name = "Password hashing",
     level = "info",
     target = "kid_data",
     skip(password)
 )]
-pub async fn pw(password: String) -> Result<String, password_hash::Error> {
-    // OS RNG
-    let salt = SaltString::generate(&mut OsRng);
-    // let salt = salt.as_ref().as_bytes();
-    // let mut pwd_buffer: [u8; 32] = [0; 32];
-    let pw_buffer = Argon2::new(
-        argon2::Algorithm::Argon2id,
-        argon2::Version::V0x13,
-        Params::new(15_000, 2, 1, None).expect("Failed to create Argon2 parameters"),
-    )
-    .hash_password(password.as_bytes(), &salt)
-    .expect("Failed to hash password");
-
-    match PasswordHash::new(&pw_buffer.to_string()) {
-        Ok(hash) => {
-            warn!("Password hash created: {}", hash.to_string());
-            Ok(hash.to_string())
-        }
-        Err(err) => {
-            error!("Failed to make password hash object: {err}");
-            Err(password_hash::Error::Password)
-        }
-    }
+pub async fn pw(password: String, cost: Argon2Cost) -> Result<String, password_hash::Error> {
+    task::spawn_blocking(move || hash_with(&cost, &password))
+        .await
+        .map_err(|err| {
+            error!("Password hashing task failed: {err}");
+            password_hash::Error::Crypto
+        })?
 }
 
 /// # Result
 ///   - Ok(()) if the password is correct
 /// # Errors
-///   - ``password_hash::Error`` if the password is incorrect
-/// # Panics
-///   - If the hash is not parsable
+///   - ``password_hash::Error`` if the password is incorrect or the stored hash is not parsable
+/// # Notes
+/// The cost is read from the stored hash, so hashes made before the
+/// configured cost changed still verify. Runs on the blocking pool.
 #[instrument(
     name = "Password verification",
     level = "info",
@@ -58,12 +47,89 @@ pub async fn verify_pw(
     registered_creds: String,
     user_attempt: String,
 ) -> Result<(), password_hash::Error> {
-    let argon2 = Argon2::default();
+    task::spawn_blocking(move || {
+        let registered_creds = PasswordHash::new(&registered_creds)
+            .inspect_err(|err| error!("Failed to parse password hash: {err}"))?;
+
+        Argon2::default()
+            .verify_password(user_attempt.as_bytes(), &registered_creds)
+            .inspect_err(|err| error!("Failed to verify password: {err}"))
+    })
+    .await
+    .map_err(|err| {
+        error!("Password verification task failed: {err}");
+        password_hash::Error::Crypto
+    })?
+}
+
+/// # Result
+///   - `true` if the stored hash was made with another algorithm or cost
+///     than `cost`, so it should be replaced at the next login
+#[must_use]
+pub fn needs_rehash(stored: &str, cost: &Argon2Cost) -> bool {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        // Nothing can log in with it, so there is nothing to upgrade
+        return false;
+    };
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&hash).map_or(true, |params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                != (cost.memory_kib, cost.iterations, cost.parallelism)
+        })
+}
 
-    let registered_creds =
-        PasswordHash::new(&registered_creds).expect("Failed to parse password hash");
+/// # Errors
+///   - ``password_hash::Error`` if the cost is out of argon2's range or the
+///     password cannot be hashed
+fn hash_with(cost: &Argon2Cost, password: &str) -> Result<String, password_hash::Error> {
+    let params =
+        Params::new(cost.memory_kib, cost.iterations, cost.parallelism, None).map_err(|err| {
+            error!("Failed to create Argon2 parameters: {err}");
+            password_hash::Error::ParamsMaxExceeded
+        })?;
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .inspect_err(|err| error!("Failed to hash password: {err}"))
+}
+
+#[cfg(test)]
+mod test_hash {
+    use super::*;
 
-    argon2
-        .verify_password(user_attempt.as_bytes(), &registered_creds)
-        .inspect_err(|err| error!("Failed to verify password: {err}"))
+    /// Argon2's smallest cost, to keep the tests quick
+    const CHEAP: Argon2Cost = Argon2Cost {
+        memory_kib: 8,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_hashes_verify_whatever_cost_they_were_made_with() {
+        let hash = hash_with(&CHEAP, "hunter22").expect("password hashes");
+        let hash = PasswordHash::new(&hash).expect("hash parses");
+        assert!(Argon2::default()
+            .verify_password(b"hunter22", &hash)
+            .is_ok());
+        assert!(Argon2::default()
+            .verify_password(b"hunter23", &hash)
+            .is_err());
+    }
+
+    #[test]
+    fn test_hashes_made_at_another_cost_are_outdated() {
+        let hash = hash_with(&CHEAP, "hunter22").expect("password hashes");
+        assert!(!needs_rehash(&hash, &CHEAP));
+        assert!(needs_rehash(
+            &hash,
+            &Argon2Cost {
+                memory_kib: 16,
+                ..CHEAP
+            }
+        ));
+        assert!(!needs_rehash("not a hash", &CHEAP));
+    }
 }
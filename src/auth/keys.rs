@@ -0,0 +1,444 @@
+use std::{env, fs};
+
+use actix_web::cookie::Key;
+use pasetors::{
+    claims::{Claims, ClaimsValidationRules},
+    footer::Footer,
+    keys::SymmetricKey,
+    local,
+    paserk::{FormatAsPaserk, Id},
+    token::{TrustedToken, UntrustedToken},
+    version4::V4,
+    Local,
+};
+use tracing::{instrument, warn};
+
+use crate::settings::{self, Environment, KeyConfig, Secret, SecretSource};
+
+/// Bytes in every key of the ring, as V4 local tokens require
+const KEY_BYTES: usize = 32;
+
+/// The HMAC secret `settings/development.yaml` used to hold. It is in the
+/// repository's history, so production refuses to start with it.
+const COMMITTED_DEVELOPMENT_SECRET: &str =
+    "2oslbzxkbzlcy2yuwc797hzzc7b2fqkxyw5ubl8ixxne2gwbpzxg3c6phl9ra8hb";
+
+/// # Result
+///   - The secret `source` names, without surrounding whitespace
+/// # Errors
+///   - ``String``: The variable or file cannot be read, is empty, or
+///     `source` does not name exactly one of them
+fn read_secret(what: &str, source: &SecretSource) -> Result<String, String> {
+    let secret = match (&source.env, &source.file) {
+        (Some(var), None) => {
+            env::var(var).map_err(|err| format!("Cannot read {what} from ${var}: {err}"))?
+        }
+        (None, Some(path)) => fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read {what} from {path}: {err}"))?,
+        _ => return Err(format!("The {what} needs either an env or a file")),
+    };
+
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(format!("The {what} is empty"));
+    }
+    Ok(secret.to_string())
+}
+
+/// # Result
+///   - `false` if `hmac_secret` is the public development secret and
+///     `environment` is production
+fn usable_in(environment: &Environment, hmac_secret: &str) -> bool {
+    !matches!(environment, Environment::Production) || hmac_secret != COMMITTED_DEVELOPMENT_SECRET
+}
+
+/// A key of the ring, loaded and ready to use
+struct RingKey {
+    name: String,
+    /// PASERK ID written in the footer of every token this key encrypts
+    id: String,
+    bytes: [u8; KEY_BYTES],
+    key: SymmetricKey<V4>,
+    retired: bool,
+}
+
+impl RingKey {
+    /// # Result
+    ///   - The key `config` describes
+    /// # Errors
+    ///   - ``String``: The key's variable or file cannot be read, or the
+    ///     key is not 32 bytes of hex
+    fn load(config: &KeyConfig) -> Result<Self, String> {
+        let name = &config.name;
+        let hex_key = read_secret(&format!("key {name}"), &config.source)?;
+
+        let mut bytes = [0u8; KEY_BYTES];
+        hex::decode_to_slice(&hex_key, &mut bytes)
+            .map_err(|err| format!("Key {name} is not {KEY_BYTES} bytes of hex: {err}"))?;
+        let key = SymmetricKey::<V4>::from(&bytes)
+            .map_err(|err| format!("Cannot create key {name}: {err:?}"))?;
+
+        let mut id = String::new();
+        Id::from(&key)
+            .fmt(&mut id)
+            .map_err(|err| format!("Cannot name key {name}: {err}"))?;
+
+        Ok(Self {
+            name: name.clone(),
+            id,
+            bytes,
+            key,
+            retired: config.retired,
+        })
+    }
+}
+
+/// The keys that encrypt and decrypt PASETO tokens.
+///
+/// New tokens are encrypted with the signing key and carry its ID in their
+/// footer, so the right key decrypts them after another signing key is
+/// added. A key stays in the ring until it is marked `retired`, at which
+/// point its tokens are refused.
+pub struct KeyRing {
+    keys: Vec<RingKey>,
+    /// Index in `keys` of the signing key
+    signing: usize,
+    /// Bound to every token without being written in it, and keys CSRF
+    /// tokens
+    hmac_secret: Vec<u8>,
+    /// Minutes a verification or email change token lives
+    token_expiration: i64,
+}
+
+impl KeyRing {
+    /// # Result
+    ///   - The ring of keys configured under `secret.keys`
+    /// # Errors
+    ///   - ``String``: A key or the HMAC secret cannot be loaded, two keys
+    ///     share a name, the signing key is missing or retired, or production
+    ///     is still using the development HMAC secret
+    #[instrument(name = "Load key ring", level = "debug", target = "kid_data", skip_all)]
+    pub fn new(secret: &Secret) -> Result<Self, String> {
+        let hmac_secret = read_secret("HMAC secret", &secret.hmac_secret)?;
+        if !usable_in(&settings::environment()?, &hmac_secret) {
+            return Err(String::from(
+                "The HMAC secret is the public development one; make a new one for production",
+            ));
+        }
+
+        let mut keys: Vec<RingKey> = Vec::with_capacity(secret.keys.len());
+        for config in &secret.keys {
+            if keys.iter().any(|key| key.name == config.name) {
+                return Err(format!("More than one key is named {}", config.name));
+            }
+            keys.push(RingKey::load(config)?);
+        }
+
+        let signing = keys
+            .iter()
+            .position(|key| key.name == secret.signing_key)
+            .ok_or_else(|| format!("The signing key {} is not in the ring", secret.signing_key))?;
+        if keys[signing].retired {
+            return Err(format!(
+                "The signing key {} has been retired",
+                secret.signing_key
+            ));
+        }
+
+        Ok(Self {
+            keys,
+            signing,
+            hmac_secret: hmac_secret.into_bytes(),
+            token_expiration: secret.token_expiration,
+        })
+    }
+
+    /// # Result
+    ///   - A V4 local token holding `claims`, encrypted with the signing key
+    /// # Errors
+    ///   - ``String``: The token cannot be built
+    pub fn encrypt(&self, claims: &Claims) -> Result<String, String> {
+        let signing = &self.keys[self.signing];
+        let mut footer = Footer::new();
+        footer.key_id(&Id::from(&signing.key));
+
+        local::encrypt(&signing.key, claims, Some(&footer), Some(&self.hmac_secret))
+            .map_err(|err| format!("Cannot encrypt token: {err:?}"))
+    }
+
+    /// # Result
+    ///   - The token decrypted with the key its footer names. Tokens issued
+    ///     before keys had IDs are tried against every key still in use.
+    /// # Errors
+    ///   - ``String``: The token is malformed, forged or expired, or its key
+    ///     is retired or unknown
+    pub fn decrypt(&self, token: &str) -> Result<TrustedToken, String> {
+        let untrusted_token = UntrustedToken::<Local, V4>::try_from(token)
+            .map_err(|err| format!("TokenValidator: {err}"))?;
+
+        let candidates: Vec<&RingKey> = match key_id(&untrusted_token)? {
+            Some(id) => {
+                let key = self
+                    .keys
+                    .iter()
+                    .find(|key| key.id == id)
+                    .ok_or_else(|| String::from("Token was encrypted with an unknown key"))?;
+                if key.retired {
+                    warn!("Refused a token encrypted with retired key {}", key.name);
+                    return Err(format!("Key {} has been retired", key.name));
+                }
+                vec![key]
+            }
+            None => self.keys.iter().filter(|key| !key.retired).collect(),
+        };
+
+        let mut failure = String::from("No key can decrypt this token");
+        for key in candidates {
+            match local::decrypt(
+                &key.key,
+                &untrusted_token,
+                &ClaimsValidationRules::new(),
+                None,
+                Some(&self.hmac_secret),
+            ) {
+                Ok(trusted_token) => return Ok(trusted_token),
+                Err(err) => failure = format!("Pasetor: {err}"),
+            }
+        }
+        Err(failure)
+    }
+
+    /// # Result
+    ///   - The key session cookies are signed and encrypted with, derived
+    ///     from the signing key
+    /// # Notes
+    /// Changing the signing key signs everyone out.
+    #[must_use]
+    pub fn cookie_key(&self) -> Key {
+        Key::derive_from(&self.keys[self.signing].bytes)
+    }
+
+    /// # Result
+    ///   - The secret CSRF tokens are signed with
+    #[must_use]
+    pub fn hmac_secret(&self) -> &[u8] {
+        &self.hmac_secret
+    }
+
+    /// # Result
+    ///   - Minutes a verification or email change token lives
+    #[must_use]
+    pub const fn token_expiration(&self) -> i64 {
+        self.token_expiration
+    }
+
+    /// # Result
+    ///   - The name of the key new tokens are encrypted with
+    #[must_use]
+    pub fn signing_key(&self) -> &str {
+        &self.keys[self.signing].name
+    }
+}
+
+/// Variable the HMAC secret of test rings is read from
+#[cfg(test)]
+const TEST_HMAC_SECRET_VAR: &str = "KID_DATA_TEST_HMAC_SECRET";
+
+#[cfg(test)]
+impl KeyRing {
+    /// A ring of one key, for tests elsewhere in the crate
+    pub(crate) fn for_tests() -> Self {
+        let var = "KID_DATA_TEST_RING_KEY";
+        env::set_var(
+            var,
+            "357685dcf1e83645e9b83695e9e5e7bd5a7fbcc84d5aa40e5ef5f15ac6fece0e",
+        );
+        env::set_var(TEST_HMAC_SECRET_VAR, "implicit assertion");
+        Self::new(&Secret {
+            signing_key: String::from("test"),
+            keys: vec![KeyConfig {
+                name: String::from("test"),
+                source: SecretSource {
+                    env: Some(String::from(var)),
+                    file: None,
+                },
+                retired: false,
+            }],
+            token_expiration: 30,
+            hmac_secret: SecretSource {
+                env: Some(String::from(TEST_HMAC_SECRET_VAR)),
+                file: None,
+            },
+            argon2: crate::settings::Argon2Cost::default(),
+        })
+        .expect("test ring loads")
+    }
+}
+
+/// # Result
+///   - The key ID in the token's footer, if it has a footer
+/// # Errors
+///   - ``String``: The footer is not one this server writes
+fn key_id(token: &UntrustedToken<Local, V4>) -> Result<Option<String>, String> {
+    if token.untrusted_footer().is_empty() {
+        return Ok(None);
+    }
+
+    let mut footer = Footer::new();
+    footer
+        .parse_bytes(token.untrusted_footer())
+        .map_err(|err| format!("Cannot parse token footer: {err:?}"))?;
+    footer
+        .get_claim("kid")
+        .and_then(serde_json::Value::as_str)
+        .map(|id| Some(id.to_string()))
+        .ok_or_else(|| String::from("Token footer names no key"))
+}
+
+#[cfg(test)]
+mod test_keys {
+    use super::*;
+    use crate::settings::Argon2Cost;
+
+    const OLD: &str = "61676133424c6b2a757563615e435168404b335e6e326d4358574d624c74414b";
+    const NEW: &str = "357685dcf1e83645e9b83695e9e5e7bd5a7fbcc84d5aa40e5ef5f15ac6fece0e";
+
+    /// A key read from its own environment variable, so tests running at
+    /// the same time never see each other's keys
+    fn key(name: &str, secret: &str, retired: bool) -> KeyConfig {
+        let var = format!("KID_DATA_TEST_KEY_{}", hex::encode(secret));
+        env::set_var(&var, secret);
+        KeyConfig {
+            name: name.to_string(),
+            source: SecretSource {
+                env: Some(var),
+                file: None,
+            },
+            retired,
+        }
+    }
+
+    fn secret(signing_key: &str, keys: Vec<KeyConfig>) -> Secret {
+        env::set_var(TEST_HMAC_SECRET_VAR, "implicit assertion");
+        Secret {
+            signing_key: signing_key.to_string(),
+            keys,
+            token_expiration: 30,
+            hmac_secret: SecretSource {
+                env: Some(String::from(TEST_HMAC_SECRET_VAR)),
+                file: None,
+            },
+            argon2: Argon2Cost::default(),
+        }
+    }
+
+    fn claims() -> Claims {
+        let mut claims = Claims::new().expect("claims build");
+        claims
+            .add_additional("user_id", "abc")
+            .expect("claim is added");
+        claims
+    }
+
+    #[test]
+    fn test_old_tokens_decrypt_after_a_new_signing_key_is_added() {
+        let old_ring = KeyRing::new(&secret("old", vec![key("old", OLD, false)])).expect("ring");
+        let token = old_ring.encrypt(&claims()).expect("token");
+
+        let new_ring = KeyRing::new(&secret(
+            "new",
+            vec![key("new", NEW, false), key("old", OLD, false)],
+        ))
+        .expect("ring");
+        assert!(new_ring.decrypt(&token).is_ok());
+        assert!(new_ring
+            .decrypt(&new_ring.encrypt(&claims()).expect("token"))
+            .is_ok());
+
+        let retired = KeyRing::new(&secret(
+            "new",
+            vec![key("new", NEW, false), key("old", OLD, true)],
+        ))
+        .expect("ring");
+        assert_eq!(
+            retired.decrypt(&token).err(),
+            Some(String::from("Key old has been retired"))
+        );
+
+        let forgotten = KeyRing::new(&secret("new", vec![key("new", NEW, false)])).expect("ring");
+        assert!(forgotten.decrypt(&token).is_err());
+    }
+
+    #[test]
+    fn test_tokens_without_a_key_id_still_decrypt() {
+        let sk = SymmetricKey::<V4>::from(&hex::decode(OLD).expect("hex")).expect("key");
+        let token =
+            local::encrypt(&sk, &claims(), None, Some(b"implicit assertion")).expect("token");
+
+        let ring = KeyRing::new(&secret(
+            "new",
+            vec![key("new", NEW, false), key("old", OLD, false)],
+        ))
+        .expect("ring");
+        assert!(ring.decrypt(&token).is_ok());
+    }
+
+    #[test]
+    fn test_bad_rings_are_refused() {
+        for (signing_key, keys) in [
+            ("old", vec![key("old", &OLD[..32], false)]),
+            ("old", vec![key("old", OLD, true)]),
+            ("new", vec![key("old", OLD, false)]),
+            ("old", vec![key("old", OLD, false), key("old", NEW, false)]),
+        ] {
+            assert!(KeyRing::new(&secret(signing_key, keys)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_keys_load_from_files() {
+        let path = std::env::temp_dir().join(format!("kid_data_key_{}", std::process::id()));
+        fs::write(&path, format!("{NEW}\n")).expect("key file is written");
+
+        let ring = KeyRing::new(&secret(
+            "file",
+            vec![KeyConfig {
+                name: String::from("file"),
+                source: SecretSource {
+                    env: None,
+                    file: Some(path.display().to_string()),
+                },
+                retired: false,
+            }],
+        ));
+        fs::remove_file(&path).expect("key file is removed");
+        assert_eq!(
+            ring.map(|ring| ring.signing_key().to_string()),
+            Ok(String::from("file"))
+        );
+    }
+
+    #[test]
+    fn test_blank_secrets_are_refused() {
+        env::set_var("KID_DATA_TEST_BLANK_SECRET", " \n");
+        let blank = SecretSource {
+            env: Some(String::from("KID_DATA_TEST_BLANK_SECRET")),
+            file: None,
+        };
+        assert!(read_secret("HMAC secret", &blank).is_err());
+        assert!(read_secret("HMAC secret", &SecretSource::default()).is_err());
+    }
+
+    #[test]
+    fn test_production_refuses_the_development_secret() {
+        assert!(usable_in(
+            &Environment::Development,
+            COMMITTED_DEVELOPMENT_SECRET
+        ));
+        assert!(!usable_in(
+            &Environment::Production,
+            COMMITTED_DEVELOPMENT_SECRET
+        ));
+        assert!(usable_in(&Environment::Production, "a secret of its own"));
+    }
+}
@@ -1,2 +1,13 @@
+pub mod api_tokens;
+pub mod csrf;
+pub mod grants;
+pub mod guard;
 pub mod hash;
+pub mod keys;
+pub mod passkeys;
+pub mod policy;
+pub mod session;
+pub mod store;
+pub mod throttle;
 pub mod tokens;
+pub mod totp;
@@ -0,0 +1,131 @@
+use data_encoding::BASE64URL_NOPAD;
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{CredentialID, Passkey, Url, Uuid, Webauthn, WebauthnBuilder};
+
+use crate::settings::Settings;
+
+/// Name the browser shows when asking to make or use a passkey
+const RELYING_PARTY_NAME: &str = "Child Data";
+
+/// One of a user's passkeys, as stored on their `User`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPasskey {
+    /// The credential ID in base64url, which names the passkey in URLs
+    pub id: String,
+    /// What the owner called it, such as "Grandma's phone"
+    pub name: String,
+    pub passkey: Passkey,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime>,
+}
+
+impl StoredPasskey {
+    #[must_use]
+    pub fn new(name: String, passkey: Passkey) -> Self {
+        Self {
+            id: credential_id(passkey.cred_id()),
+            name,
+            passkey,
+            created_at: DateTime::now(),
+            last_used_at: None,
+        }
+    }
+}
+
+/// # Result
+///   - How `id` is written in URLs and stored passkeys
+#[must_use]
+pub fn credential_id(id: &CredentialID) -> String {
+    BASE64URL_NOPAD.encode(id.as_ref())
+}
+
+/// # Result
+///   - The stable `WebAuthn` user handle for the user with `user_id`
+#[must_use]
+pub fn user_handle(user_id: ObjectId) -> Uuid {
+    let mut bytes = [0u8; 16];
+    bytes[..12].copy_from_slice(&user_id.bytes());
+    Uuid::from_bytes(bytes)
+}
+
+/// # Result
+///   - The relying party passkeys are made for: the site at `frontend_url`
+/// # Errors
+///   - ``String``: `frontend_url` is not a URL with a host name
+pub fn relying_party(settings: &Settings) -> Result<Webauthn, String> {
+    let origin = Url::parse(&settings.frontend_url)
+        .map_err(|err| format!("frontend_url is not a URL: {err}"))?;
+    let rp_id = origin
+        .host_str()
+        .ok_or_else(|| String::from("frontend_url has no host"))?;
+
+    WebauthnBuilder::new(rp_id, &origin)
+        .and_then(|builder| builder.rp_name(RELYING_PARTY_NAME).build())
+        .map_err(|err| format!("Cannot set up passkeys for {origin}: {err}"))
+}
+
+#[cfg(test)]
+mod test_passkeys {
+    use mongodb::bson;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
+
+    use super::*;
+    use crate::settings;
+
+    #[test]
+    fn test_user_handles_are_stable_and_distinct() {
+        let (one, two) = (ObjectId::new(), ObjectId::new());
+        assert_eq!(user_handle(one), user_handle(one));
+        assert_ne!(user_handle(one), user_handle(two));
+    }
+
+    #[test]
+    fn test_a_software_passkey_registers_and_logs_in() {
+        let settings = settings::get().expect("settings load");
+        let webauthn = relying_party(&settings).expect("relying party");
+        let origin = Url::parse(&settings.frontend_url).expect("frontend_url");
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        let (challenge, state) = webauthn
+            .start_passkey_registration(
+                user_handle(ObjectId::new()),
+                "kid@example.com",
+                "Kid",
+                None,
+            )
+            .expect("registration starts");
+        let answer = authenticator
+            .do_registration(origin.clone(), challenge)
+            .expect("authenticator registers");
+        // The browser posts its answer as JSON in the form
+        let answer: RegisterPublicKeyCredential =
+            serde_json::from_str(&serde_json::to_string(&answer).expect("json")).expect("json");
+        let passkey = webauthn
+            .finish_passkey_registration(&answer, &state)
+            .expect("registration finishes");
+
+        // Passkeys are kept on the user document
+        let stored = StoredPasskey::new(String::from("Phone"), passkey);
+        let mut stored: StoredPasskey =
+            bson::from_document(bson::to_document(&stored).expect("bson")).expect("bson");
+
+        for _ in 0..2 {
+            let (challenge, state) = webauthn
+                .start_passkey_authentication(&[stored.passkey.clone()])
+                .expect("login starts");
+            let answer = authenticator
+                .do_authentication(origin.clone(), challenge)
+                .expect("authenticator signs");
+            let answer: PublicKeyCredential =
+                serde_json::from_str(&serde_json::to_string(&answer).expect("json")).expect("json");
+            let result = webauthn
+                .finish_passkey_authentication(&answer, &state)
+                .expect("login finishes");
+            assert_eq!(credential_id(result.cred_id()), stored.id);
+            stored.passkey.update_credential(&result);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt::{self, Display, Formatter};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    HttpMessage, ResponseError,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::{auth::guard::LoginRedirect, endpoints::error::AppError, models::mongo::User};
+
+/// What an account may do. Variants are ordered from least to most trusted,
+/// so a role grants everything the roles before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Reads records
+    #[default]
+    Viewer,
+    /// Reads and edits records
+    Guardian,
+    /// Owns the site; manages accounts through `/v1`
+    Admin,
+}
+
+impl Role {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Guardian => "guardian",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Who may reach a route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone, signed in or not
+    Public,
+    /// Signed in users holding at least the role
+    Role(Role),
+}
+
+/// Routes anyone may reach
+const PUBLIC_PATHS: [&str; 16] = [
+    "/",
+    "/login",
+    "/login/two_factor",
+    "/login/passkey/start",
+    "/login/passkey/finish",
+    "/logout",
+    "/registration",
+    "/register",
+    "/favicon",
+    "/stylesheet",
+    "/style.css.map",
+    "/htmx",
+    "/response-targets",
+    "/health_check",
+    "/invitations/accept",
+    "/users/email/confirm",
+];
+
+/// Route prefixes anyone may reach
+const PUBLIC_PREFIXES: [&str; 4] = ["/register/", "/users/password/", "/static/", "/images/"];
+
+/// Routes where users manage their own account, whatever their role
+const SELF_SERVICE_PREFIXES: [&str; 4] = [
+    "/users/profile",
+    "/users/devices",
+    "/users/two_factor",
+    "/users/passkeys",
+];
+
+/// Routes that make and revoke API tokens, which only the account API takes
+const API_TOKENS_PREFIX: &str = "/users/tokens";
+
+/// Routes that change one child's records. Their handlers check the user's
+/// grants for the child, so anyone signed in may reach them.
+const GRANT_CHECKED_PREFIXES: [&str; 11] = [
+    "/doctor_logs/",
+    "/doctor_card/",
+    "/dental_logs/",
+    "/dental_card/",
+    "/immunization_logs/",
+    "/immunization_card/",
+    "/school_logs/",
+    "/school_year/",
+    "/grade/",
+    "/photo_logs/",
+    "/photo/",
+];
+
+/// # Result
+///   - `true` for the account API, which API tokens may call
+#[must_use]
+pub fn is_api_path(path: &str) -> bool {
+    path == "/v1" || path.starts_with("/v1/")
+}
+
+/// # Result
+///   - The access the route needs. Reads need a viewer, writes a guardian and
+///     the account API and its tokens an admin. Any user may manage their own
+///     profile, devices and second factor, and change the records of a child
+///     they hold an editable grant for.
+#[must_use]
+pub fn required_access(method: &Method, path: &str) -> Access {
+    if is_api_path(path) || path.starts_with(API_TOKENS_PREFIX) {
+        return Access::Role(Role::Admin);
+    }
+
+    if PUBLIC_PATHS.contains(&path)
+        || PUBLIC_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+    {
+        return Access::Public;
+    }
+
+    if SELF_SERVICE_PREFIXES
+        .iter()
+        .chain(GRANT_CHECKED_PREFIXES.iter())
+        .any(|prefix| path.starts_with(prefix))
+    {
+        return Access::Role(Role::Viewer);
+    }
+
+    if method == Method::GET || method == Method::HEAD {
+        Access::Role(Role::Viewer)
+    } else {
+        Access::Role(Role::Guardian)
+    }
+}
+
+/// Middleware that holds every request to the route's `required_access`.
+/// Relies on `sign_out_stale_sessions` having put the signed in `User` in the
+/// request extensions.
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Enforce policy",
+    level = "debug",
+    target = "kid_data",
+    skip(req, next),
+    fields(path = %req.path())
+)]
+pub async fn enforce_policy(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Access::Role(needed) = required_access(req.method(), req.path()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let role = req.extensions().get::<User>().map(|user| user.role);
+    let denied = match role {
+        Some(role) if role >= needed => {
+            return Ok(next.call(req).await?.map_into_boxed_body());
+        }
+        Some(role) => {
+            warn!("A {role} tried a route for a {needed}");
+            AppError::Forbidden(format!("This needs the {needed} role")).error_response()
+        }
+        None if needed == Role::Admin => {
+            AppError::Unauthorized(String::from("Log in first")).error_response()
+        }
+        None => LoginRedirect::for_request(req.request()).error_response(),
+    };
+
+    Ok(req.into_response(denied))
+}
+
+#[cfg(test)]
+mod test_policy {
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_roles_grant_what_lesser_roles_do() {
+        assert!(Role::Admin > Role::Guardian);
+        assert!(Role::Guardian > Role::Viewer);
+    }
+
+    #[test]
+    fn test_account_api_is_for_admins() {
+        assert_eq!(
+            required_access(&Method::GET, "/v1/users"),
+            Access::Role(Role::Admin)
+        );
+        assert_eq!(
+            required_access(&Method::DELETE, "/v1/user/abc"),
+            Access::Role(Role::Admin)
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/users/tokens"),
+            Access::Role(Role::Admin)
+        );
+        assert_eq!(
+            required_access(&Method::DELETE, "/users/tokens/abc"),
+            Access::Role(Role::Admin)
+        );
+    }
+
+    #[test]
+    fn test_reads_need_a_viewer_and_writes_a_guardian() {
+        assert_eq!(
+            required_access(&Method::GET, "/child/aj"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::PUT, "/child/aj"),
+            Access::Role(Role::Guardian)
+        );
+    }
+
+    #[test]
+    fn test_viewers_manage_their_own_devices() {
+        assert_eq!(
+            required_access(&Method::DELETE, "/users/devices/abc"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/users/devices/logout_all"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/users/two_factor/setup"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/users/passkeys/register/start"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::POST, "/users/profile/delete"),
+            Access::Role(Role::Viewer)
+        );
+    }
+
+    #[test]
+    fn test_grants_decide_who_changes_records() {
+        assert_eq!(
+            required_access(&Method::POST, "/doctor_logs/aj"),
+            Access::Role(Role::Viewer)
+        );
+        assert_eq!(
+            required_access(&Method::DELETE, "/grade/abc"),
+            Access::Role(Role::Viewer)
+        );
+        for path in [
+            "/child",
+            "/providers",
+            "/schools",
+            "/teachers",
+            "/invitations",
+        ] {
+            assert_eq!(
+                required_access(&Method::POST, path),
+                Access::Role(Role::Guardian)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sign_in_routes_are_public() {
+        for path in [
+            "/",
+            "/login",
+            "/login/two_factor",
+            "/login/passkey/finish",
+            "/register/confirm",
+            "/users/password/reset",
+            "/users/email/confirm",
+        ] {
+            assert_eq!(required_access(&Method::POST, path), Access::Public);
+        }
+        assert_ne!(
+            required_access(&Method::GET, "/v1x"),
+            Access::Role(Role::Admin)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_signed_out_requests_cannot_reach_the_account_api() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(enforce_policy))
+                .route("/v1/users", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/v1/users").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_signed_out_pages_redirect_to_login() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(enforce_policy))
+                .route("/child/aj", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/child/aj").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    }
+}
@@ -0,0 +1,69 @@
+use actix_session::SessionExt;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Data,
+    HttpMessage, ResponseError,
+};
+use mongodb::Database;
+use tracing::{error, instrument, warn};
+
+use crate::{
+    endpoints::{error::AppError, login::session_user_id},
+    models::{mongo::UserRepo, repository::RepoError},
+    types::Types,
+};
+
+/// Middleware that signs out sessions started before the user's password was
+/// last reset.
+///
+/// The session is purged, so the handler sees a visitor who never
+/// logged in. A session that is still good has its `User` put in the request
+/// extensions for the middleware and handlers after it.
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Sign out stale sessions",
+    level = "debug",
+    target = "kid_data",
+    skip(req, next)
+)]
+pub async fn sign_out_stale_sessions(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let session = req.get_session();
+    let Ok(user_id) = session_user_id(&session) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let Some(db) = req.app_data::<Data<Database>>().cloned() else {
+        error!("No database to check the session against");
+        let response = AppError::Backend(String::from("Database not configured")).error_response();
+        return Ok(req.into_response(response));
+    };
+
+    let version = session
+        .get::<u32>(&Types::SessionVersionKey.to_string())
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    match UserRepo::new(db.as_ref())
+        .get_user(Some(user_id), None)
+        .await
+    {
+        Ok(user) if user.session_version == version => {
+            req.extensions_mut().insert(user);
+        }
+        Ok(_) | Err(RepoError::NotFound(_)) => {
+            warn!("Signing out a session that is no longer valid");
+            session.purge();
+        }
+        Err(err) => return Ok(req.into_response(AppError::from(err).error_response())),
+    }
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
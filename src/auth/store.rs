@@ -0,0 +1,410 @@
+use std::{cmp::Reverse, collections::HashMap, net::IpAddr};
+
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::{cookie::time::Duration, http::header::X_FORWARDED_FOR, web::Data, HttpRequest};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use deadpool_redis::{
+    redis::{AsyncCommands, Pipeline},
+    Connection, Pool,
+};
+use mongodb::bson::oid::ObjectId;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::{settings::Settings, types::Types};
+
+/// Prefix of the key holding a session's state
+const SESSION_PREFIX: &str = "kid_data_session:";
+/// Prefix of the set holding every session key a user has
+const USER_INDEX_PREFIX: &str = "kid_data_user_sessions:";
+/// Length of a generated session key, per OWASP's entropy advice
+const SESSION_KEY_LENGTH: usize = 64;
+
+/// State of one session as `actix_session` hands it over: every value is JSON
+type SessionState = HashMap<String, String>;
+
+/// The browser or app a session was started from, stored in the session at
+/// login
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    /// Names the session without revealing its key
+    pub id: String,
+    pub logged_in_at: DateTime<Utc>,
+    pub user_agent: String,
+    pub ip: String,
+}
+
+impl Device {
+    /// # Result
+    ///   - The device that sent `req`, logged in now
+    #[must_use]
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get(actix_web::http::header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .unwrap_or("Unknown")
+            .to_string();
+
+        Self {
+            id: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+            logged_in_at: Utc::now(),
+            user_agent,
+            ip: client_ip(req),
+        }
+    }
+}
+
+/// # Result
+///   - The address `req` came from. Forwarded headers are only believed
+///     from a proxy in ``application.trusted_proxies``, and then only the
+///     address that proxy appended, so a client cannot pick its own.
+#[must_use]
+pub fn client_ip(req: &HttpRequest) -> String {
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+        return String::from("Unknown");
+    };
+    let trusted = req
+        .app_data::<Data<Settings>>()
+        .is_some_and(|settings| settings.application.trusted_proxies.contains(&peer));
+    if !trusted {
+        return peer.to_string();
+    }
+
+    req.headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .and_then(|forwarded| forwarded.trim().parse::<IpAddr>().ok())
+        .unwrap_or(peer)
+        .to_string()
+}
+
+/// Server side session storage in Redis. Alongside each session it keeps a
+/// set per user of their session keys, so the sessions of one account can be
+/// listed and ended together.
+#[derive(Clone)]
+pub struct RedisSessionStore {
+    pool: Pool,
+}
+
+impl RedisSessionStore {
+    #[must_use]
+    pub const fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// # Result
+    ///   - Every live session of the user, newest first
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "List devices",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn devices(&self, user_id: ObjectId) -> anyhow::Result<Vec<Device>> {
+        let mut conn = self.pool.get().await?;
+        let mut devices: Vec<Device> = sessions_of(&mut conn, user_id)
+            .await?
+            .into_iter()
+            .filter_map(|(_, state)| state_device(&state))
+            .collect();
+        devices.sort_by_key(|device| Reverse(device.logged_in_at));
+        Ok(devices)
+    }
+
+    /// # Result
+    ///   - `true` if the user had a session on the device and it has ended
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "Revoke device",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn revoke(&self, user_id: ObjectId, device_id: &str) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get().await?;
+        let Some((key, _)) = sessions_of(&mut conn, user_id)
+            .await?
+            .into_iter()
+            .find(|(_, state)| state_device(state).is_some_and(|device| device.id == device_id))
+        else {
+            return Ok(false);
+        };
+
+        let () = Pipeline::new()
+            .del(session_key(&key))
+            .srem(user_index(user_id), &key)
+            .query_async(&mut conn)
+            .await?;
+        Ok(true)
+    }
+
+    /// # Result
+    ///   - How many sessions the user had, all of which have now ended
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "Revoke all devices",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn revoke_all(&self, user_id: ObjectId) -> anyhow::Result<usize> {
+        let mut conn = self.pool.get().await?;
+        let keys: Vec<String> = conn.smembers(user_index(user_id)).await?;
+
+        let mut pipeline = Pipeline::new();
+        for key in &keys {
+            pipeline.del(session_key(key));
+        }
+        let () = pipeline
+            .del(user_index(user_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(keys.len())
+    }
+
+    /// Stores the state under `key`, adding the key to its user's index
+    /// # Result
+    ///   - `false` if `only_if_exists` was set and there was nothing to replace
+    async fn write(
+        &self,
+        key: &str,
+        state: &SessionState,
+        ttl: &Duration,
+        only_if_exists: bool,
+    ) -> anyhow::Result<bool> {
+        let body = serde_json::to_string(state)?;
+        let mut conn = self.pool.get().await?;
+
+        let mut set = deadpool_redis::redis::cmd("SET");
+        set.arg(session_key(key))
+            .arg(body)
+            .arg(if only_if_exists { "XX" } else { "NX" })
+            .arg("EX")
+            .arg(ttl.whole_seconds());
+        let written: Option<String> = set.query_async(&mut conn).await?;
+        if written.is_none() {
+            return Ok(false);
+        }
+
+        if let Some(user_id) = state_user_id(state) {
+            let index = user_index(user_id);
+            let () = Pipeline::new()
+                .sadd(&index, key)
+                .expire(&index, ttl.whole_seconds())
+                .query_async(&mut conn)
+                .await?;
+        }
+        Ok(true)
+    }
+}
+
+impl SessionStore for RedisSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| LoadError::Other(err.into()))?;
+        let body: Option<String> = conn
+            .get(self::session_key(session_key.as_ref()))
+            .await
+            .map_err(|err| LoadError::Other(err.into()))?;
+
+        body.map(|body| serde_json::from_str(&body))
+            .transpose()
+            .map_err(|err| LoadError::Deserialization(err.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let key = Alphanumeric.sample_string(&mut rand::thread_rng(), SESSION_KEY_LENGTH);
+        if !self
+            .write(&key, &session_state, ttl, false)
+            .await
+            .map_err(SaveError::Other)?
+        {
+            return Err(SaveError::Other(anyhow!("Session key collision")));
+        }
+
+        SessionKey::try_from(key).map_err(|err| SaveError::Other(err.into()))
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let updated = self
+            .write(session_key.as_ref(), &session_state, ttl, true)
+            .await
+            .map_err(UpdateError::Other)?;
+        if updated {
+            return Ok(session_key);
+        }
+
+        // Expired or revoked between load and update; start a new session
+        // rather than bring the old key back
+        debug!("Session vanished before it could be updated");
+        self.save(session_state, ttl)
+            .await
+            .map_err(|err| match err {
+                SaveError::Serialization(err) => UpdateError::Serialization(err),
+                SaveError::Other(err) => UpdateError::Other(err),
+            })
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        let mut conn = self.pool.get().await?;
+        let _: bool = conn
+            .expire(self::session_key(session_key.as_ref()), ttl.whole_seconds())
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        let key = session_key.as_ref();
+        let mut conn = self.pool.get().await?;
+        let body: Option<String> = conn.get(self::session_key(key)).await?;
+
+        let mut pipeline = Pipeline::new();
+        pipeline.del(self::session_key(key));
+        if let Some(user_id) = body
+            .and_then(|body| serde_json::from_str::<SessionState>(&body).ok())
+            .as_ref()
+            .and_then(state_user_id)
+        {
+            pipeline.srem(user_index(user_id), key);
+        }
+        let () = pipeline.query_async(&mut conn).await?;
+        Ok(())
+    }
+}
+
+/// # Result
+///   - Each session key in the user's index with its state. Keys whose
+///     session has expired are dropped from the index.
+async fn sessions_of(
+    conn: &mut Connection,
+    user_id: ObjectId,
+) -> anyhow::Result<Vec<(String, SessionState)>> {
+    let keys: Vec<String> = conn.smembers(user_index(user_id)).await?;
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let bodies: Vec<Option<String>> = deadpool_redis::redis::cmd("MGET")
+        .arg(keys.iter().map(|key| session_key(key)).collect::<Vec<_>>())
+        .query_async(conn)
+        .await?;
+
+    let mut sessions = Vec::new();
+    let mut expired = Vec::new();
+    for (key, body) in keys.into_iter().zip(bodies) {
+        match body.map(|body| serde_json::from_str::<SessionState>(&body)) {
+            Some(Ok(state)) => sessions.push((key, state)),
+            Some(Err(err)) => warn!("Unreadable session state: {err}"),
+            None => expired.push(key),
+        }
+    }
+
+    if !expired.is_empty() {
+        let _: usize = conn.srem(user_index(user_id), expired).await?;
+    }
+    Ok(sessions)
+}
+
+/// # Result
+///   - The user a session belongs to, if it is logged in
+fn state_user_id(state: &SessionState) -> Option<ObjectId> {
+    let value = state.get(&Types::UserIdKey.to_string())?;
+    let user_id = serde_json::from_str::<String>(value).ok()?;
+    ObjectId::parse_str(user_id).ok()
+}
+
+/// # Result
+///   - The device recorded when the session logged in
+fn state_device(state: &SessionState) -> Option<Device> {
+    serde_json::from_str(state.get(&Types::DeviceKey.to_string())?).ok()
+}
+
+fn session_key(key: &str) -> String {
+    format!("{SESSION_PREFIX}{key}")
+}
+
+fn user_index(user_id: ObjectId) -> String {
+    format!("{USER_INDEX_PREFIX}{user_id}")
+}
+
+#[cfg(test)]
+mod test_store {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn state_of(user_id: ObjectId, device: &Device) -> SessionState {
+        HashMap::from([
+            (
+                Types::UserIdKey.to_string(),
+                serde_json::json!(user_id.to_string()).to_string(),
+            ),
+            (
+                Types::DeviceKey.to_string(),
+                serde_json::to_string(device).expect("devices serialize"),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_session_state_names_its_user_and_device() {
+        let user_id = ObjectId::new();
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((actix_web::http::header::USER_AGENT, "Firefox"))
+            .to_http_request();
+        let device = Device::from_request(&req);
+        let state = state_of(user_id, &device);
+
+        assert_eq!(state_user_id(&state), Some(user_id));
+        assert_eq!(state_device(&state), Some(device));
+    }
+
+    fn request_from(peer: &str, trusted_proxies: Vec<IpAddr>) -> TestRequest {
+        let mut settings = crate::settings::get().expect("settings load from ./settings");
+        settings.application.trusted_proxies = trusted_proxies;
+        TestRequest::default()
+            .peer_addr(peer.parse().expect("peer address parses"))
+            .app_data(Data::new(settings))
+            .insert_header((X_FORWARDED_FOR, "203.0.113.9, 198.51.100.7"))
+    }
+
+    #[test]
+    fn test_forwarded_addresses_from_strangers_are_ignored() {
+        let req = request_from("192.0.2.1:4000", vec![]).to_http_request();
+        assert_eq!(client_ip(&req), "192.0.2.1");
+    }
+
+    #[test]
+    fn test_trusted_proxies_name_the_address_they_saw() {
+        let proxy: IpAddr = "10.0.0.2".parse().expect("proxy address parses");
+        let req = request_from("10.0.0.2:4000", vec![proxy]).to_http_request();
+        assert_eq!(client_ip(&req), "198.51.100.7");
+    }
+
+    #[test]
+    fn test_signed_out_state_has_no_user() {
+        assert_eq!(state_user_id(&SessionState::new()), None);
+        assert_eq!(state_device(&SessionState::new()), None);
+    }
+}
@@ -0,0 +1,253 @@
+use deadpool_redis::{
+    redis::{self, Pipeline},
+    Pool,
+};
+use tracing::{instrument, warn};
+
+/// Prefix of the counter of recent failed logins for an account or address
+const FAILURES_PREFIX: &str = "kid_data_login_failures:";
+/// Prefix of the key that, while it lives, makes an account or address wait
+const WAIT_PREFIX: &str = "kid_data_login_wait:";
+/// Prefix of the key that, while it lives, locks an account
+const LOCK_PREFIX: &str = "kid_data_login_lock:";
+/// Seconds a failed login counts against an account or address
+const FAILURE_WINDOW_SECONDS: i64 = 15 * 60;
+/// Failures an account gets before each try has to wait
+const ACCOUNT_FREE_ATTEMPTS: i64 = 3;
+/// Failures an address gets before each try has to wait. Higher than an
+/// account's, since a household shares one address
+const ADDRESS_FREE_ATTEMPTS: i64 = 20;
+/// Failures in a row that lock an account
+pub const LOCKOUT_FAILURES: i64 = 10;
+/// Seconds an account stays locked unless an admin unlocks it
+pub const LOCKOUT_SECONDS: i64 = 30 * 60;
+/// Longest wait between tries, however many have failed
+const MAX_WAIT_SECONDS: i64 = 5 * 60;
+
+/// Why a login cannot be tried yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    /// Seconds until the account or address may try again
+    Wait(i64),
+    /// Seconds until the account unlocks
+    Locked(i64),
+}
+
+impl Throttled {
+    #[must_use]
+    pub const fn retry_after(self) -> i64 {
+        match self {
+            Self::Wait(seconds) | Self::Locked(seconds) => seconds,
+        }
+    }
+}
+
+/// Counts failed logins in Redis, per account and per address.
+///
+/// Further tries wait exponentially longer, and enough failures in a row lock
+/// the account for a while. It runs before the password is hashed, so
+/// guessing cannot tie up the CPU either.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    pool: Pool,
+}
+
+impl LoginThrottle {
+    #[must_use]
+    pub const fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+
+    /// # Result
+    ///   - Why a login to `email` from `ip` has to wait, if it does
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "Check login throttle",
+        level = "debug",
+        target = "kid_data",
+        skip(self, email)
+    )]
+    pub async fn check(&self, email: &str, ip: &str) -> anyhow::Result<Option<Throttled>> {
+        let mut conn = self.pool.get().await?;
+        let (locked, account_wait, address_wait): (i64, i64, i64) = Pipeline::new()
+            .ttl(lock_key(email))
+            .ttl(wait_key(&account(email)))
+            .ttl(wait_key(&address(ip)))
+            .query_async(&mut conn)
+            .await?;
+
+        // TTL answers negative for keys that are gone
+        if locked > 0 {
+            return Ok(Some(Throttled::Locked(locked)));
+        }
+        let wait = account_wait.max(address_wait);
+        Ok((wait > 0).then_some(Throttled::Wait(wait)))
+    }
+
+    /// Counts a failed login to `email` from `ip`
+    /// # Result
+    ///   - `true` if this failure locked the account
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "Record failed login",
+        level = "debug",
+        target = "kid_data",
+        skip(self, email)
+    )]
+    pub async fn record_failure(&self, email: &str, ip: &str) -> anyhow::Result<bool> {
+        let (account, address) = (account(email), address(ip));
+        let mut conn = self.pool.get().await?;
+        let (account_failures, address_failures): (i64, i64) = Pipeline::new()
+            .incr(failures_key(&account), 1)
+            .expire(failures_key(&account), FAILURE_WINDOW_SECONDS)
+            .ignore()
+            .incr(failures_key(&address), 1)
+            .expire(failures_key(&address), FAILURE_WINDOW_SECONDS)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        if account_failures >= LOCKOUT_FAILURES {
+            let locked: Option<String> = redis::cmd("SET")
+                .arg(lock_key(email))
+                .arg(account_failures)
+                .arg("NX")
+                .arg("EX")
+                .arg(LOCKOUT_SECONDS)
+                .query_async(&mut conn)
+                .await?;
+            // Counting starts over once the lock runs out
+            let () = Pipeline::new()
+                .del(failures_key(&account))
+                .del(wait_key(&account))
+                .query_async(&mut conn)
+                .await?;
+            if locked.is_some() {
+                warn!("Account locked after {account_failures} failed logins");
+            }
+            return Ok(locked.is_some());
+        }
+
+        let mut pipeline = Pipeline::new();
+        for (scope, wait) in [
+            (&account, backoff(account_failures, ACCOUNT_FREE_ATTEMPTS)),
+            (&address, backoff(address_failures, ADDRESS_FREE_ATTEMPTS)),
+        ] {
+            if let Some(wait) = wait {
+                pipeline
+                    .set_ex(wait_key(scope), "", wait.unsigned_abs())
+                    .ignore();
+            }
+        }
+        let () = pipeline.query_async(&mut conn).await?;
+        Ok(false)
+    }
+
+    /// Forgets the failed logins to `email`, once it has signed in
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(name = "Record login", level = "debug", target = "kid_data", skip_all)]
+    pub async fn record_success(&self, email: &str) -> anyhow::Result<()> {
+        let account = account(email);
+        let mut conn = self.pool.get().await?;
+        let () = Pipeline::new()
+            .del(failures_key(&account))
+            .del(wait_key(&account))
+            .query_async(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Lifts a lock on `email` and forgets its failed logins
+    /// # Result
+    ///   - `true` if the account was locked
+    /// # Errors
+    ///   - Redis is unreachable
+    #[instrument(
+        name = "Unlock account",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn unlock(&self, email: &str) -> anyhow::Result<bool> {
+        let account = account(email);
+        let mut conn = self.pool.get().await?;
+        let (unlocked,): (i64,) = Pipeline::new()
+            .del(lock_key(email))
+            .del(failures_key(&account))
+            .ignore()
+            .del(wait_key(&account))
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(unlocked > 0)
+    }
+}
+
+/// # Result
+///   - Seconds to wait after `failures` failed logins, doubling for each one
+///     past the `free` ones, or `None` while they are free
+#[must_use]
+fn backoff(failures: i64, free: i64) -> Option<i64> {
+    let over = failures.saturating_sub(free);
+    if over <= 0 {
+        return None;
+    }
+    Some(
+        u32::try_from(over - 1)
+            .ok()
+            .and_then(|exponent| 2_i64.checked_pow(exponent))
+            .map_or(MAX_WAIT_SECONDS, |wait| wait.min(MAX_WAIT_SECONDS)),
+    )
+}
+
+/// Emails are matched however they were typed
+fn account(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+fn address(ip: &str) -> String {
+    format!("address:{ip}")
+}
+
+fn failures_key(scope: &str) -> String {
+    format!("{FAILURES_PREFIX}{scope}")
+}
+
+fn wait_key(scope: &str) -> String {
+    format!("{WAIT_PREFIX}{scope}")
+}
+
+fn lock_key(email: &str) -> String {
+    format!("{LOCK_PREFIX}{}", account(email))
+}
+
+#[cfg(test)]
+mod test_throttle {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_after_the_free_attempts() {
+        assert_eq!(backoff(0, 3), None);
+        assert_eq!(backoff(3, 3), None);
+        assert_eq!(backoff(4, 3), Some(1));
+        assert_eq!(backoff(5, 3), Some(2));
+        assert_eq!(backoff(8, 3), Some(16));
+        assert_eq!(backoff(40, 3), Some(MAX_WAIT_SECONDS));
+        assert_eq!(backoff(i64::MAX, 3), Some(MAX_WAIT_SECONDS));
+    }
+
+    #[test]
+    fn test_accounts_are_keyed_however_the_email_was_typed() {
+        assert_eq!(
+            lock_key(" Parent@Example.com "),
+            lock_key("parent@example.com")
+        );
+        assert_ne!(
+            failures_key(&account("1.2.3.4")),
+            failures_key(&address("1.2.3.4"))
+        );
+    }
+}
@@ -1,29 +1,63 @@
 use deadpool_redis::redis::{aio, AsyncCommands, RedisError};
 use mongodb::bson::oid::ObjectId;
-use pasetors::{
-    claims::{Claims, ClaimsValidationRules},
-    keys::SymmetricKey,
-    local,
-    token::UntrustedToken,
-    version4::V4,
-    Local,
-};
+use pasetors::claims::Claims;
 use rand::{rngs::OsRng, RngCore};
-use tracing::{debug, error, info, instrument, warn};
+use tracing::{debug, error, info, instrument};
 
-use crate::{
-    settings::{self, Settings},
-    types::tokens::ConfirmationToken,
-};
+use crate::{auth::keys::KeyRing, types::tokens::ConfirmationToken};
 
 const SESSION_KEY_PREFIX: &str = "kid_data_{}";
 
+/// Days an invitation link stays usable
+pub const INVITATION_LIFETIME_DAYS: i64 = 7;
+
+/// What a confirmation token was issued for. Each purpose marks its tokens
+/// unused under its own redis key, so a token only works for its purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Purpose {
+    Verification,
+    PasswordReset,
+    Invitation,
+    EmailChange,
+}
+
+impl Purpose {
+    /// # Result
+    ///   - The purpose named by the ``is_for_password_change`` flag the
+    ///     public functions take
+    const fn from_flag(is_for_password_change: Option<bool>) -> Self {
+        if is_for_password_change.is_some() {
+            Self::PasswordReset
+        } else {
+            Self::Verification
+        }
+    }
+
+    const fn key_suffix(self) -> &'static str {
+        match self {
+            Self::Verification => "",
+            Self::PasswordReset => "is_for_password_change",
+            Self::Invitation => "is_for_invitation",
+            Self::EmailChange => "is_for_email_change",
+        }
+    }
+
+    const fn time_to_live(self, key_ring: &KeyRing) -> chrono::Duration {
+        match self {
+            Self::Verification | Self::EmailChange => {
+                chrono::Duration::minutes(key_ring.token_expiration())
+            }
+            Self::PasswordReset => chrono::Duration::hours(1),
+            Self::Invitation => chrono::Duration::days(INVITATION_LIFETIME_DAYS),
+        }
+    }
+}
+
 /// # Result
 ///   - Ok(String): A token has been issued successfully
 /// # Errors
-///   - ``RedisError``: An error occurred while interacting with redis
-/// # Panics
-///   - If the settings cannot be loaded
+///   - ``RedisError``: An error occurred while interacting with redis, or the
+///     token cannot be encrypted
 /// # Notes
 /// Issues a pasetor token to a user. The token has the user's id encoded.
 /// A ``session_key`` is also encoded. This key is used to destroy the token
@@ -33,27 +67,82 @@ const SESSION_KEY_PREFIX: &str = "kid_data_{}";
     name = "Issue confirmation token",
     level = "info",
     target = "kid_data",
-    skip(redis_connection, is_for_password_change)
+    skip(redis_connection, key_ring, is_for_password_change)
 )]
 pub async fn issue_confirmation_token(
     user_id: ObjectId,
     redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
     is_for_password_change: Option<bool>,
 ) -> Result<String, RedisError> {
     info!("issue_confirmation_token called");
+    issue_token(
+        user_id,
+        redis_connection,
+        key_ring,
+        Purpose::from_flag(is_for_password_change),
+    )
+    .await
+}
+
+/// # Result
+///   - Ok(String): The token for an invitation link
+/// # Errors
+///   - ``RedisError``: As for ``issue_confirmation_token``
+/// # Notes
+/// The token encodes the invitation's ID and lives for
+/// ``INVITATION_LIFETIME_DAYS``.
+#[instrument(
+    name = "Issue invitation token",
+    level = "info",
+    target = "kid_data",
+    skip(redis_connection, key_ring)
+)]
+pub async fn issue_invitation_token(
+    invitation_id: ObjectId,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+) -> Result<String, RedisError> {
+    issue_token(
+        invitation_id,
+        redis_connection,
+        key_ring,
+        Purpose::Invitation,
+    )
+    .await
+}
+
+/// # Result
+///   - Ok(String): The token for the link that confirms a new email address
+/// # Errors
+///   - ``RedisError``: As for ``issue_confirmation_token``
+#[instrument(
+    name = "Issue email change token",
+    level = "info",
+    target = "kid_data",
+    skip(redis_connection, key_ring)
+)]
+pub async fn issue_email_change_token(
+    user_id: ObjectId,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+) -> Result<String, RedisError> {
+    issue_token(user_id, redis_connection, key_ring, Purpose::EmailChange).await
+}
+
+async fn issue_token(
+    user_id: ObjectId,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+    purpose: Purpose,
+) -> Result<String, RedisError> {
     let session_key: String = {
         let mut buff = [0u8; 128];
         OsRng.fill_bytes(&mut buff);
         hex::encode(buff)
     };
 
-    let redis_key = {
-        if is_for_password_change.is_some() {
-            format!("{SESSION_KEY_PREFIX}{session_key}is_for_password_change")
-        } else {
-            format!("{SESSION_KEY_PREFIX}{user_id}")
-        }
-    };
+    let redis_key = redis_key(&session_key, purpose);
 
     let () = redis_connection
         .set(redis_key.clone(), String::new())
@@ -63,24 +152,7 @@ pub async fn issue_confirmation_token(
             err
         })?;
 
-    let settings: Settings = settings::get().expect("Cannot load settings for token issuance");
-
-    let current_date_time = chrono::Local::now();
-    let dt = {
-        if is_for_password_change.is_some() {
-            current_date_time + chrono::Duration::hours(1)
-        } else {
-            current_date_time + chrono::Duration::hours(settings.secret.token_expiration)
-        }
-    };
-
-    let time_to_live = {
-        if is_for_password_change.is_some() {
-            chrono::Duration::hours(1)
-        } else {
-            chrono::Duration::minutes(settings.secret.token_expiration)
-        }
-    };
+    let time_to_live = purpose.time_to_live(key_ring);
 
     let () = redis_connection
         .expire(redis_key.clone(), time_to_live.num_seconds())
@@ -90,175 +162,323 @@ pub async fn issue_confirmation_token(
             err
         })?;
 
+    seal_token(user_id, &session_key, time_to_live, key_ring)
+}
+
+/// # Result
+///   - Ok(String): The encrypted token carrying ``user_id`` and
+///     ``session_key``, expiring after ``time_to_live``
+/// # Errors
+///   - ``RedisError``: The claims cannot be built or encrypted
+/// # Notes
+/// The ID is written as a hex string, the same as API tokens, so
+/// ``decode_confirmation_token`` can read it back.
+fn seal_token(
+    user_id: ObjectId,
+    session_key: &str,
+    time_to_live: chrono::Duration,
+    key_ring: &KeyRing,
+) -> Result<String, RedisError> {
+    let dt = chrono::Local::now() + time_to_live;
+
     let mut claims = match Claims::new() {
         Ok(claims) => claims,
         Err(err) => {
             error!("Cannot create claims: {err:?}");
-            return Err(RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RedisError::from(std::io::Error::other(
                 "Cannot create claims",
             )));
         }
     };
 
-    // Set default expiry time for the token; 1 hour
+    // The token expires along with its redis key
     match claims.expiration(&dt.to_rfc3339()) {
         Ok(claims) => claims,
         Err(err) => {
             error!("Cannot set expiration time: {err:?}");
-            return Err(RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RedisError::from(std::io::Error::other(
                 "Cannot set expiration time",
             )));
         }
     }
 
-    match claims.add_additional("user_id", serde_json::json!(user_id)) {
+    match claims.add_additional("user_id", user_id.to_hex()) {
         Ok(claims) => claims,
         Err(err) => {
             error!("Cannot add additional claims: {err:?}");
-            return Err(RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RedisError::from(std::io::Error::other(
                 "Cannot add additional claims",
             )));
         }
     }
 
-    match claims.add_additional("session_key", serde_json::json!(session_key)) {
+    match claims.add_additional("session_key", session_key) {
         Ok(claims) => claims,
         Err(err) => {
             error!("Cannot add additional claims: {err:?}");
-            return Err(RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RedisError::from(std::io::Error::other(
                 "Cannot add additional claims",
             )));
         }
     }
 
-    let secret_key = {
-        if settings.secret.secret_key.len() > 32 {
-            warn!(
-                "The secret key is longer than 32 bytes: {}. It will be truncated to 32 bytes",
-                settings.secret.secret_key.len()
-            );
-            &settings.secret.secret_key.as_bytes()[0..32]
-        } else {
-            warn!("The secret key is less than 32 bytes.");
-            settings.secret.secret_key.as_bytes()
-        }
-    };
-
-    let sk = match SymmetricKey::<V4>::from(secret_key) {
-        Ok(sk) => sk,
-        Err(err) => {
-            error!("Cannot create symmetric key: {err:?}");
-            return Err(RedisError::from(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Cannot create symmetric key",
-            )));
-        }
-    };
-
-    Ok(
-        match local::encrypt(
-            &sk,
-            &claims,
-            None,
-            Some(settings.secret.hmac_secret.as_bytes()),
-        ) {
-            Ok(token) => token,
-            Err(err) => {
-                error!("Cannot encrypt token: {err:?}");
-                return Err(RedisError::from(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Cannot encrypt token",
-                )));
-            }
-        },
-    )
+    key_ring.encrypt(&claims).map_err(|err| {
+        error!("{err}");
+        RedisError::from(std::io::Error::other("Cannot encrypt token"))
+    })
 }
 
 /// # Result
 ///   - Ok(ConfirmationToken): The token has been verified and destroyed
 /// # Errors
 ///   - ``String``: An error occurred while verifying the token
-/// # Panics
-///   - If the settings cannot be loaded
 /// # Notes
 /// Verifies and destroys a token. A token is destroyed immediately
 /// it has successfully been verified and all encoded data extracted.
-/// Redis is used for such destruction.
+/// Redis is used for such destruction, so a token only verifies once.
 #[instrument(
     name = "Verify confirmation token",
     level = "info",
     target = "kid_data",
-    skip(redis_connection, is_password)
+    skip(token, redis_connection, key_ring, is_password)
 )]
 pub async fn verify_confirmation_token_pasetor(
     token: &str,
     redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
     is_password: Option<bool>,
 ) -> Result<ConfirmationToken, String> {
     info!("verify_confirmation_token_pasetor called");
-    let settings = settings::get().expect("Cannot load settings");
-    let sk = SymmetricKey::<V4>::from(settings.secret.secret_key.as_bytes())
-        .expect("Cannot create symmetric key");
+    spend_token(
+        token,
+        redis_connection,
+        key_ring,
+        Purpose::from_flag(is_password),
+    )
+    .await
+}
 
-    let validation_rules = ClaimsValidationRules::new();
-    debug!("Creating untrusted token");
-    let untrusted_token = UntrustedToken::<Local, V4>::try_from(token)
-        .map_err(|err| format!("TokenValidator: {err}"))?;
+/// # Result
+///   - Ok(ObjectId): The ID of the invitation the token was issued for; the
+///     token is destroyed
+/// # Errors
+///   - ``String``: The token is malformed, expired or already used
+#[instrument(
+    name = "Verify invitation token",
+    level = "info",
+    target = "kid_data",
+    skip(token, redis_connection, key_ring)
+)]
+pub async fn verify_invitation_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+) -> Result<ObjectId, String> {
+    spend_token(token, redis_connection, key_ring, Purpose::Invitation)
+        .await
+        .map(|token| token.user_id)
+}
 
-    debug!("Decrypting token");
-    let trusted_token = local::decrypt(
-        &sk,
-        &untrusted_token,
-        &validation_rules,
-        None,
-        Some(settings.secret.hmac_secret.as_bytes()),
+/// # Result
+///   - Ok(ObjectId): The ID of the user whose new address the token
+///     confirms; the token is destroyed
+/// # Errors
+///   - ``String``: The token is malformed, expired or already used
+#[instrument(
+    name = "Verify email change token",
+    level = "info",
+    target = "kid_data",
+    skip(token, redis_connection, key_ring)
+)]
+pub async fn verify_email_change_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+) -> Result<ObjectId, String> {
+    spend_token(token, redis_connection, key_ring, Purpose::EmailChange)
+        .await
+        .map(|token| token.user_id)
+}
+
+async fn spend_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+    purpose: Purpose,
+) -> Result<ConfirmationToken, String> {
+    let (user_id, session_key) = decode_confirmation_token(token, key_ring)?;
+
+    // `DEL` reports how many keys it removed, so only the first caller to
+    // present the token sees a 1
+    let deleted: u32 = redis_connection
+        .del(redis_key(&session_key, purpose))
+        .await
+        .map_err(|err| format!("RedisError (del): {err}"))?;
+
+    if deleted == 0 {
+        return Err(String::from("Token has already been used or has expired"));
+    }
+
+    Ok(ConfirmationToken { user_id })
+}
+
+/// # Result
+///   - Ok(ConfirmationToken): The token is valid and has not been used
+/// # Errors
+///   - ``String``: The token is malformed, expired or already used
+/// # Notes
+/// Unlike ``verify_confirmation_token_pasetor`` the token is left in place,
+/// so a form can be shown before the token is spent.
+#[instrument(
+    name = "Check confirmation token",
+    level = "info",
+    target = "kid_data",
+    skip(token, redis_connection, key_ring, is_password)
+)]
+pub async fn check_confirmation_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+    is_password: Option<bool>,
+) -> Result<ConfirmationToken, String> {
+    peek_token(
+        token,
+        redis_connection,
+        key_ring,
+        Purpose::from_flag(is_password),
     )
-    .map_err(|err| format!("Pasetor: {err}"))?;
+    .await
+}
+
+/// # Result
+///   - Ok(ObjectId): The ID of the invitation the token was issued for; the
+///     token is left in place
+/// # Errors
+///   - ``String``: The token is malformed, expired or already used
+#[instrument(
+    name = "Check invitation token",
+    level = "info",
+    target = "kid_data",
+    skip(token, redis_connection, key_ring)
+)]
+pub async fn check_invitation_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+) -> Result<ObjectId, String> {
+    peek_token(token, redis_connection, key_ring, Purpose::Invitation)
+        .await
+        .map(|token| token.user_id)
+}
+
+async fn peek_token(
+    token: &str,
+    redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
+    purpose: Purpose,
+) -> Result<ConfirmationToken, String> {
+    let (user_id, session_key) = decode_confirmation_token(token, key_ring)?;
+
+    let exists: bool = redis_connection
+        .exists(redis_key(&session_key, purpose))
+        .await
+        .map_err(|err| format!("RedisError (exists): {err}"))?;
+
+    if !exists {
+        return Err(String::from("Token has already been used or has expired"));
+    }
+
+    Ok(ConfirmationToken { user_id })
+}
+
+/// # Result
+///   - The user ID and session key encoded in a token this server issued
+/// # Errors
+///   - ``String``: The token is malformed, forged or expired
+fn decode_confirmation_token(
+    token: &str,
+    key_ring: &KeyRing,
+) -> Result<(ObjectId, String), String> {
+    debug!("Decrypting token");
+    let trusted_token = key_ring.decrypt(token)?;
 
     let claims = trusted_token
         .payload_claims()
-        .expect("Cannot get payload claims");
-
-    let uid = serde_json::to_value(claims.get_claim("user_id").expect("Cannot get claim"))
-        .map_err(|err| format!("Cannot serialize user_id: {err}"))?;
-
-    debug!("Saving the session key to db");
-    match serde_json::from_value::<String>(uid) {
-        Ok(uuid_string) => match ObjectId::parse_str(&uuid_string) {
-            Ok(user_uuid) => {
-                let sss_key = serde_json::to_value(
-                    claims.get_claim("session_key").expect("Cannot get claim"),
-                )
-                .expect("Cannot serialize session_key");
-                let session_key = match serde_json::from_value::<String>(sss_key) {
-                    Ok(session_key) => session_key,
-                    Err(err) => return Err(format!("Cannot deserialize session_key: {err}")),
-                };
-
-                let redis_key = {
-                    if is_password.is_some() {
-                        format!("{SESSION_KEY_PREFIX}{session_key}is_for_password_change")
-                    } else {
-                        format!("{SESSION_KEY_PREFIX}{session_key}")
-                    }
-                };
-
-                let _ = redis_connection.get::<_, String>(redis_key.clone()).await;
-
-                let () = redis_connection
-                    .del(redis_key.clone())
-                    .await
-                    .map_err(|err| format!("RedisError (del): {err}"))?;
-
-                Ok(ConfirmationToken { user_id: user_uuid })
-            }
-            Err(err) => Err(format!("Cannot parse user_id: {err}")),
-        },
+        .ok_or_else(|| String::from("Token has no payload claims"))?;
+
+    let claim = |name: &str| -> Result<String, String> {
+        let value = claims
+            .get_claim(name)
+            .ok_or_else(|| format!("Token has no {name} claim"))?;
+        serde_json::from_value::<String>(value.clone())
+            .map_err(|err| format!("Cannot deserialize {name}: {err}"))
+    };
+
+    let user_id = ObjectId::parse_str(claim("user_id")?)
+        .map_err(|err| format!("Cannot parse user_id: {err}"))?;
+
+    Ok((user_id, claim("session_key")?))
+}
+
+/// # Result
+///   - The redis key that marks a token as unused
+fn redis_key(session_key: &str, purpose: Purpose) -> String {
+    format!("{SESSION_KEY_PREFIX}{session_key}{}", purpose.key_suffix())
+}
+
+#[cfg(test)]
+mod test_tokens {
+    use super::*;
+
+    #[test]
+    fn test_password_tokens_do_not_share_keys_with_verification() {
+        assert_ne!(
+            redis_key("abc", Purpose::PasswordReset),
+            redis_key("abc", Purpose::Verification)
+        );
+    }
+
+    #[test]
+    fn test_invitation_tokens_have_their_own_keys() {
+        assert_ne!(
+            redis_key("abc", Purpose::Invitation),
+            redis_key("abc", Purpose::PasswordReset)
+        );
+        assert_ne!(
+            redis_key("abc", Purpose::Invitation),
+            redis_key("abc", Purpose::Verification)
+        );
+    }
+
+    #[test]
+    fn test_email_change_tokens_cannot_verify_an_account() {
+        assert_ne!(
+            redis_key("abc", Purpose::EmailChange),
+            redis_key("abc", Purpose::Verification)
+        );
+    }
+
+    #[test]
+    fn test_garbage_is_not_a_token() {
+        let key_ring = KeyRing::for_tests();
+        assert!(decode_confirmation_token("v4.local.garbage", &key_ring).is_err());
+    }
+
+    #[test]
+    fn test_sealed_claims_decode_to_the_same_user() {
+        let key_ring = KeyRing::for_tests();
+        let user_id = ObjectId::new();
+        let token = seal_token(
+            user_id,
+            "session-key",
+            Purpose::PasswordReset.time_to_live(&key_ring),
+            &key_ring,
+        )
+        .expect("token is issued");
 
-        Err(err) => Err(format!("Cannot parse user_id: {err}")),
+        let (decoded_id, session_key) =
+            decode_confirmation_token(&token, &key_ring).expect("token decodes");
+        assert_eq!(decoded_id, user_id);
+        assert_eq!(session_key, "session-key");
     }
 }
@@ -0,0 +1,210 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+    RngCore,
+};
+use sha1::Sha1;
+use tracing::{error, instrument};
+
+use crate::{
+    auth::{
+        guard::encode,
+        hash::{pw, verify_pw},
+    },
+    settings::Argon2Cost,
+};
+
+/// Name authenticator apps list the account under
+pub const ISSUER: &str = "Child Data";
+/// Recovery codes handed out when two-factor is turned on
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Seconds each code is good for, per RFC 6238
+const STEP_SECONDS: i64 = 30;
+/// Steps either side of now still accepted, for clock drift
+const DRIFT_STEPS: i64 = 1;
+/// Digits in a code
+const DIGITS: u32 = 6;
+/// Bytes in a secret; the size of an HMAC-SHA1 key
+const SECRET_BYTES: usize = 20;
+
+/// # Result
+///   - A new base32 secret to share with an authenticator app
+#[must_use]
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// # Result
+///   - The `otpauth://` URI an authenticator app scans to enroll
+#[must_use]
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    let issuer = encode(ISSUER);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        encode(account)
+    )
+}
+
+/// # Result
+///   - The time step `code` was issued for, if it is right for `secret` at
+///     `now` and newer than `last_step`, so no code works twice
+#[must_use]
+#[instrument(
+    name = "Verify TOTP code",
+    level = "debug",
+    target = "kid_data",
+    skip(secret, code)
+)]
+pub fn verify(secret: &str, code: &str, now: i64, last_step: i64) -> Option<i64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = now.div_euclid(STEP_SECONDS);
+    (current - DRIFT_STEPS..=current + DRIFT_STEPS)
+        .filter(|&step| step > last_step)
+        .find(|&step| u64::try_from(step).is_ok_and(|step| code_at(&key, step) == Some(code)))
+}
+
+/// # Result
+///   - The HOTP value for the counter, per RFC 4226
+fn code_at(key: &[u8], counter: u64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = usize::from(digest.last()? & 0x0f);
+    let truncated: [u8; 4] = digest.get(offset..offset + 4)?.try_into().ok()?;
+    Some((u32::from_be_bytes(truncated) & 0x7fff_ffff) % 10u32.pow(DIGITS))
+}
+
+/// # Result
+///   - Fresh recovery codes, shown to the user once
+#[must_use]
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = Alphanumeric
+                .sample_string(&mut OsRng, 10)
+                .to_ascii_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// # Result
+///   - The argon2 hashes of the codes, in the same order
+/// # Errors
+///   - ``password_hash::Error`` if a code cannot be hashed
+pub async fn hash_recovery_codes(
+    codes: &[String],
+    cost: Argon2Cost,
+) -> Result<Vec<String>, argon2::password_hash::Error> {
+    let mut hashes = Vec::with_capacity(codes.len());
+    for code in codes {
+        hashes.push(pw(code.clone(), cost).await?);
+    }
+    Ok(hashes)
+}
+
+/// # Result
+///   - `true` if `attempt` is shaped like a `generate_recovery_codes` code,
+///     so guesses that cannot match never cost an argon2 verification
+fn looks_like_recovery_code(attempt: &str) -> bool {
+    attempt.len() == 11
+        && attempt.char_indices().all(|(at, c)| {
+            if at == 5 {
+                c == '-'
+            } else {
+                c.is_ascii_alphanumeric()
+            }
+        })
+}
+
+/// # Result
+///   - The stored hash `attempt` matches, if any
+#[instrument(
+    name = "Find recovery code",
+    level = "debug",
+    target = "kid_data",
+    skip(hashes, attempt)
+)]
+pub async fn find_recovery_code<'a>(hashes: &'a [String], attempt: &str) -> Option<&'a String> {
+    let attempt = attempt.trim().to_ascii_lowercase();
+    if !looks_like_recovery_code(&attempt) {
+        return None;
+    }
+    for hash in hashes {
+        match verify_pw(hash.clone(), attempt.clone()).await {
+            Ok(()) => return Some(hash),
+            Err(argon2::password_hash::Error::Password) => {}
+            Err(err) => error!("Unreadable recovery code hash: {err}"),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test_totp {
+    use super::*;
+
+    /// The SHA1 secret from RFC 6238's test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_codes_match_rfc_6238() {
+        for (time, expected) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+        ] {
+            assert_eq!(code_at(RFC_SECRET, time / 30), Some(expected), "at {time}");
+        }
+    }
+
+    #[test]
+    fn test_codes_work_once_and_only_near_now() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        assert_eq!(verify(&secret, "287082", 59, 0), Some(1));
+        assert_eq!(verify(&secret, " 287082 ", 89, 0), Some(1));
+        assert_eq!(verify(&secret, "287082", 59, 1), None);
+        assert_eq!(verify(&secret, "287082", 300, 0), None);
+        assert_eq!(verify(&secret, "28708", 59, 0), None);
+    }
+
+    #[test]
+    fn test_secrets_decode_to_hmac_keys() {
+        let secret = generate_secret();
+        assert_eq!(
+            BASE32_NOPAD.decode(secret.as_bytes()).map(|key| key.len()),
+            Ok(SECRET_BYTES)
+        );
+        assert!(provisioning_uri(&secret, "a@b.c").contains(&format!("secret={secret}")));
+    }
+
+    #[test]
+    fn test_recovery_codes_are_distinct() {
+        let mut codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| looks_like_recovery_code(code)));
+    }
+
+    #[test]
+    fn test_only_recovery_shaped_guesses_are_checked() {
+        assert!(!looks_like_recovery_code("123456"));
+        assert!(!looks_like_recovery_code("abcde-fghij-"));
+        assert!(!looks_like_recovery_code("abcdefghijk"));
+        assert!(!looks_like_recovery_code("abcd\u{e9}-fghi"));
+    }
+}
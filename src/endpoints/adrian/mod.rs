@@ -1,4 +0,0 @@
-pub mod dentist;
-pub mod doctor;
-pub mod landing;
-pub mod school;
@@ -0,0 +1,179 @@
+use actix_web::{
+    delete, post,
+    web::{Data, Form, Path},
+    HttpResponse, ResponseError,
+};
+use chrono::{Duration, Utc};
+use mongodb::{
+    bson::{oid::ObjectId, DateTime},
+    Database,
+};
+use serde::Deserialize;
+use tracing::{error, info, instrument};
+
+use crate::{
+    auth::{
+        api_tokens::{issue_api_token, ApiScope, LIFETIME_DAYS},
+        guard::AuthenticatedUser,
+        keys::KeyRing,
+    },
+    endpoints::{error::AppError, profile::render_profile_with_token},
+    models::api_token::{ApiToken, ApiTokenRepo},
+};
+
+/// Longest name a token may be given
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiToken {
+    pub name: String,
+    pub lifetime_days: i64,
+    /// Checkbox; present when ticked
+    #[serde(default)]
+    pub read: Option<String>,
+    /// Checkbox; present when ticked
+    #[serde(default)]
+    pub write: Option<String>,
+}
+
+impl NewApiToken {
+    /// # Result
+    ///   - The trimmed name and the scopes asked for
+    /// # Errors
+    ///   - `AppError::Validation` if the name, lifetime or scopes are unusable
+    fn check(&self) -> Result<(&str, Vec<ApiScope>), AppError> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(AppError::Validation(format!(
+                "Token names need 1 to {MAX_NAME_LENGTH} characters"
+            )));
+        }
+        if !LIFETIME_DAYS.contains(&self.lifetime_days) {
+            return Err(AppError::Validation(String::from(
+                "Pick one of the offered lifetimes",
+            )));
+        }
+
+        let scopes: Vec<ApiScope> = [(ApiScope::Read, &self.read), (ApiScope::Write, &self.write)]
+            .into_iter()
+            .filter_map(|(scope, ticked)| ticked.is_some().then_some(scope))
+            .collect();
+        if scopes.is_empty() {
+            return Err(AppError::Validation(String::from(
+                "A token needs at least one scope",
+            )));
+        }
+        Ok((name, scopes))
+    }
+}
+
+/// Makes a personal access token for the account API. The token is shown
+/// once on the profile page; only its record is kept. The route policy keeps
+/// this to admins, the only users the account API serves.
+#[post("/users/tokens")]
+#[instrument(
+    name = "Create API token",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, key_ring, input)
+)]
+pub async fn create_api_token(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    key_ring: Data<KeyRing>,
+    Form(input): Form<NewApiToken>,
+) -> HttpResponse {
+    let (name, scopes) = match input.check() {
+        Ok(checked) => checked,
+        Err(err) => return err.error_response(),
+    };
+
+    let expires_at = Utc::now() + Duration::days(input.lifetime_days);
+    let tokens = ApiTokenRepo::new(pool.as_ref());
+    let token_id = match tokens
+        .insert(&ApiToken {
+            id: None,
+            user_id: user.id,
+            name: name.to_string(),
+            scopes,
+            created_at: DateTime::now(),
+            expires_at: DateTime::from_millis(expires_at.timestamp_millis()),
+            last_used_at: None,
+        })
+        .await
+    {
+        Ok(token_id) => token_id,
+        Err(err) => return AppError::from(err).error_response(),
+    };
+
+    match issue_api_token(&key_ring, token_id, user.id, expires_at) {
+        Ok(issued) => {
+            info!("API token created");
+            render_profile_with_token(pool.as_ref(), user.id, "", &issued).await
+        }
+        Err(err) => {
+            error!("Unable to issue API token: {err}");
+            // A record with no token anyone holds is only clutter
+            if let Err(err) = tokens.revoke(token_id, user.id).await {
+                error!("Unable to remove the unissued token: {err}");
+            }
+            AppError::Backend(String::from("Unable to issue the token")).error_response()
+        }
+    }
+}
+
+#[delete("/users/tokens/{id}")]
+#[instrument(
+    name = "Revoke API token",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, path)
+)]
+pub async fn revoke_api_token(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    path: Path<String>,
+) -> HttpResponse {
+    let Ok(token_id) = ObjectId::parse_str(path.into_inner()) else {
+        return AppError::Validation(String::from("Malformed token ID")).error_response();
+    };
+
+    match ApiTokenRepo::new(pool.as_ref())
+        .revoke(token_id, user.id)
+        .await
+    {
+        Ok(result) if result.deleted_count == 1 => {
+            info!("API token revoked");
+            HttpResponse::Ok().finish()
+        }
+        Ok(_) => AppError::NotFound(String::from("No such token")).error_response(),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod test_api_tokens {
+    use super::*;
+
+    fn request(name: &str, lifetime_days: i64, read: bool, write: bool) -> NewApiToken {
+        NewApiToken {
+            name: name.to_string(),
+            lifetime_days,
+            read: read.then(|| String::from("on")),
+            write: write.then(|| String::from("on")),
+        }
+    }
+
+    #[test]
+    fn test_tokens_need_a_name_a_lifetime_and_a_scope() {
+        assert!(request(" ", 30, true, false).check().is_err());
+        assert!(request("backup", 31, true, false).check().is_err());
+        assert!(request("backup", 30, false, false).check().is_err());
+        assert_eq!(
+            request(" backup ", 30, true, true)
+                .check()
+                .map(|(name, scopes)| (name.to_string(), scopes)),
+            Ok((String::from("backup"), ApiScope::ALL.to_vec()))
+        );
+    }
+}
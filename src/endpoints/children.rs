@@ -0,0 +1,435 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{Data, Form, Path},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::NaiveDate;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::Deserialize;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        error::{render_error, AppError, TRY_AGAIN},
+        index::index_body,
+        templates::{ChildFormPage, ChildLanding},
+    },
+    models::{
+        child::{Child, ChildRepo},
+        helpers::{non_empty, slugify},
+        mongo::UserRepo,
+    },
+};
+
+/// Form data submitted from the child create and edit pages
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChildInput {
+    pub first_name: String,
+    pub last_name: String,
+    pub nickname: String,
+    pub slug: String,
+    pub date_of_birth: NaiveDate,
+    pub profile_photo: String,
+    /// Comma separated list of guardian email addresses
+    pub guardians: String,
+}
+
+/// # Result
+///   - A `Child` built from the submitted form with guardian emails resolved to user IDs
+/// # Errors
+///   - `String` naming the first guardian email that does not belong to a registered user
+#[instrument(
+    name = "Child from form",
+    level = "debug",
+    target = "kid_data",
+    skip(input, users)
+)]
+async fn child_from_input(
+    input: ChildInput,
+    users: &UserRepo,
+    current_user: Option<ObjectId>,
+) -> Result<Child, String> {
+    let mut guardians: Vec<ObjectId> = current_user.into_iter().collect();
+
+    for email in input.guardians.split(',').filter_map(non_empty) {
+        match users.get_user(None, Some(&email)).await {
+            Ok(user) => {
+                if let Some(id) = user.id {
+                    if !guardians.contains(&id) {
+                        guardians.push(id);
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("Guardian lookup failed: {err}");
+                return Err(format!("No registered user with the email {email}"));
+            }
+        }
+    }
+
+    let slug =
+        non_empty(&input.slug).map_or_else(|| slugify(&input.first_name), |slug| slugify(&slug));
+
+    Ok(Child {
+        id: None,
+        slug,
+        first_name: input.first_name.trim().to_string(),
+        last_name: input.last_name.trim().to_string(),
+        nickname: non_empty(&input.nickname),
+        date_of_birth: input.date_of_birth,
+        profile_photo: non_empty(&input.profile_photo),
+        guardians,
+    })
+}
+
+/// # Result
+///   - The guardian emails of `child` joined for the edit form
+#[instrument(
+    name = "Guardian emails",
+    level = "debug",
+    target = "kid_data",
+    skip(child, users)
+)]
+async fn guardian_emails(child: &Child, users: &UserRepo) -> String {
+    let mut emails = Vec::with_capacity(child.guardians.len());
+    for id in &child.guardians {
+        match users.get_user(Some(*id), None).await {
+            Ok(user) => emails.push(user.email),
+            Err(err) => warn!("Guardian {id} no longer exists: {err}"),
+        }
+    }
+    emails.join(", ")
+}
+
+/// # Result
+///   - The child with `slug`, if `user` may do `permission` to its
+///     `records`, or to its profile when `records` is `None`
+/// # Errors
+///   - The error response to send when there is no such child, or it is
+///     off limits
+#[instrument(
+    name = "Child for user",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+pub async fn child_for(
+    pool: &Database,
+    slug: &str,
+    user: &AuthenticatedUser,
+    records: Option<Records>,
+    permission: Permission,
+) -> Result<Child, HttpResponse> {
+    let child = ChildRepo::new(pool).get_child(slug).await.map_err(|err| {
+        error!("Child lookup failed: {err}");
+        AppError::from(err).error_response()
+    })?;
+    authorize(&user.user, &child, records, permission).map_err(|err| err.error_response())?;
+    Ok(child)
+}
+
+#[instrument(
+    name = "Render child landing",
+    level = "debug",
+    target = "kid_data",
+    skip(child),
+    fields(slug = %child.slug)
+)]
+fn render_landing(child: Child) -> HttpResponse {
+    let template = ChildLanding {
+        title: child.display_name().to_string(),
+        age: child.age(),
+        child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render child page: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/new")]
+#[instrument(
+    name = "New child form",
+    level = "info",
+    target = "kid_data",
+    skip(_user)
+)]
+pub async fn new_child(_user: AuthenticatedUser) -> HttpResponse {
+    info!("Rendering the new child form");
+    let template = ChildFormPage {
+        title: "Add a child",
+        editing: false,
+        original_slug: "",
+        first_name: "",
+        last_name: "",
+        nickname: "",
+        slug: "",
+        date_of_birth: "",
+        profile_photo: "",
+        guardians: "",
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render child form: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("")]
+#[instrument(
+    name = "Create child",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn create_child(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    Form(input): Form<ChildInput>,
+) -> HttpResponse {
+    info!("Create child endpoint hit");
+    let current_user = Some(user.id);
+
+    let users = UserRepo::new(pool.as_ref());
+    let children = ChildRepo::new(pool.as_ref());
+
+    let child = match child_from_input(input, &users, current_user).await {
+        Ok(child) => child,
+        Err(err) => {
+            return render_error(StatusCode::BAD_REQUEST, "Unable to add child", Some(&err));
+        }
+    };
+
+    if child.slug.is_empty() {
+        warn!("No usable slug could be built");
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add child",
+            Some("A name or slug with letters or numbers is required"),
+        );
+    }
+
+    if children.get_child(&child.slug).await.is_ok() {
+        warn!("Slug already in use: {}", child.slug);
+        return render_error(
+            StatusCode::CONFLICT,
+            "Unable to add child",
+            Some("Another child already uses that slug"),
+        );
+    }
+
+    match children.create_child(&child).await {
+        Ok(id) => {
+            debug!("Child created: {id}");
+            render_landing(Child {
+                id: Some(id),
+                ..child
+            })
+        }
+        Err(err) => {
+            error!("Error creating child: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to add child",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[get("/{slug}")]
+#[instrument(
+    name = "Child landing",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn child_landing(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: Path<String>,
+) -> HttpResponse {
+    info!("Rendering child landing page");
+    match child_for(pool.as_ref(), &slug, &user, None, Permission::View).await {
+        Ok(child) => render_landing(child),
+        Err(response) => response,
+    }
+}
+
+#[get("/{slug}/edit")]
+#[instrument(
+    name = "Edit child form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn edit_child(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: Path<String>,
+) -> HttpResponse {
+    info!("Rendering the edit child form");
+    let users = UserRepo::new(pool.as_ref());
+
+    let child = match child_for(pool.as_ref(), &slug, &user, None, Permission::Edit).await {
+        Ok(child) => child,
+        Err(response) => return response,
+    };
+
+    let guardians = guardian_emails(&child, &users).await;
+    let date_of_birth = child.date_of_birth.to_string();
+
+    let template = ChildFormPage {
+        title: "Edit child",
+        editing: true,
+        original_slug: &child.slug,
+        first_name: &child.first_name,
+        last_name: &child.last_name,
+        nickname: child.nickname.as_deref().unwrap_or_default(),
+        slug: &child.slug,
+        date_of_birth: &date_of_birth,
+        profile_photo: child.profile_photo.as_deref().unwrap_or_default(),
+        guardians: &guardians,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render child form: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[put("/{slug}")]
+#[instrument(
+    name = "Update child",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn update_child(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: Path<String>,
+    Form(input): Form<ChildInput>,
+) -> HttpResponse {
+    info!("Update child endpoint hit");
+    let users = UserRepo::new(pool.as_ref());
+    let children = ChildRepo::new(pool.as_ref());
+
+    let existing = match child_for(pool.as_ref(), &slug, &user, None, Permission::Edit).await {
+        Ok(child) => child,
+        Err(response) => return response,
+    };
+
+    // A guardian stays one while editing, so the form cannot lock them out
+    let editor = user.user.id.filter(|id| existing.guardians.contains(id));
+    let child = match child_from_input(input, &users, editor).await {
+        Ok(child) => Child {
+            id: existing.id,
+            ..child
+        },
+        Err(err) => {
+            return render_error(
+                StatusCode::BAD_REQUEST,
+                "Unable to update child",
+                Some(&err),
+            );
+        }
+    };
+
+    if child.slug != existing.slug && children.get_child(&child.slug).await.is_ok() {
+        warn!("Slug already in use: {}", child.slug);
+        return render_error(
+            StatusCode::CONFLICT,
+            "Unable to update child",
+            Some("Another child already uses that slug"),
+        );
+    }
+
+    match children.update_child(&slug, child.clone()).await {
+        Ok(_) => render_landing(child),
+        Err(err) => {
+            error!("Error updating child: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to update child",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[delete("/{slug}")]
+#[instrument(
+    name = "Delete child",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_child(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: Path<String>,
+) -> HttpResponse {
+    info!("Delete child endpoint hit");
+    let children = ChildRepo::new(pool.as_ref());
+
+    let child = match child_for(pool.as_ref(), &slug, &user, None, Permission::Edit).await {
+        Ok(child) => child,
+        Err(response) => return response,
+    };
+
+    match children.count_records(child.id.unwrap_or_default()).await {
+        Ok(0) => {}
+        Ok(records) => {
+            warn!("Refusing to delete a child with {records} records");
+            return render_error(
+                StatusCode::CONFLICT,
+                "Unable to delete child",
+                Some("Delete the child's visits, school years, photos and shots first"),
+            );
+        }
+        Err(err) => {
+            error!("Unable to count child records: {err}");
+            return AppError::from(err).error_response();
+        }
+    }
+
+    match children.delete_child(&slug).await {
+        Ok(result) if result.deleted_count == 1 => {
+            debug!("Child deleted");
+            match index_body(pool.as_ref(), &user.user, "Child Data").await {
+                Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+                Err(err) => {
+                    error!("Error rendering template: {err:#?}");
+                    HttpResponse::InternalServerError().finish()
+                }
+            }
+        }
+        Ok(_) => render_error(StatusCode::NOT_FOUND, "Child not found", Some(&slug)),
+        Err(err) => {
+            error!("Error deleting child: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete child",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
@@ -1,2 +0,0 @@
-pub mod landing;
-pub mod school;
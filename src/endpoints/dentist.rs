@@ -0,0 +1,552 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::{Local, NaiveDate};
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        children::child_for,
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        providers::{picked_provider, provider_choices, provider_name},
+        templates::{Dental, DentalCardList, DentalForm, DentalVisitPart},
+    },
+    models::{
+        child::{Child, ChildRepo},
+        dental::{next_cleaning_due, DentalProcedure, DentalRepo, DentalVisit},
+        helpers::non_empty,
+        provider::ProviderKind,
+    },
+};
+
+/// All things regarding cleanings, fillings, x-rays, and braces
+
+#[derive(Debug, Default, Serialize)]
+pub struct DentalCards {
+    pub date: String,
+    pub description: String,
+    pub db_id: ObjectId,
+}
+
+impl From<&DentalVisit> for DentalCards {
+    fn from(visit: &DentalVisit) -> Self {
+        Self {
+            date: visit.date.format("%b %d, %Y").to_string(),
+            description: visit
+                .procedures
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", "),
+            db_id: visit.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// Form data submitted when creating or editing a dental visit.
+/// Each procedure is a checkbox, present only when ticked.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DentalInput {
+    pub date: NaiveDate,
+    pub provider_id: String,
+    pub cleaning: Option<String>,
+    pub exam: Option<String>,
+    pub x_ray: Option<String>,
+    pub filling: Option<String>,
+    pub sealant: Option<String>,
+    pub orthodontic: Option<String>,
+    pub cavities: u32,
+    /// Comma separated tooth numbers or names
+    pub teeth: String,
+    /// One note per line
+    pub notes: String,
+}
+
+impl DentalInput {
+    fn procedures(&self) -> Vec<DentalProcedure> {
+        [
+            (DentalProcedure::Cleaning, &self.cleaning),
+            (DentalProcedure::Exam, &self.exam),
+            (DentalProcedure::XRay, &self.x_ray),
+            (DentalProcedure::Filling, &self.filling),
+            (DentalProcedure::Sealant, &self.sealant),
+            (DentalProcedure::Orthodontic, &self.orthodontic),
+        ]
+        .into_iter()
+        .filter_map(|(procedure, checked)| checked.as_ref().map(|_| procedure))
+        .collect()
+    }
+
+    fn into_visit(self, child_id: ObjectId, provider_id: ObjectId) -> DentalVisit {
+        DentalVisit {
+            id: None,
+            child_id,
+            procedures: self.procedures(),
+            provider_id,
+            date: self.date,
+            cavities: self.cavities,
+            teeth: self.teeth.split(',').filter_map(non_empty).collect(),
+            notes: self.notes.lines().filter_map(non_empty).collect(),
+        }
+    }
+}
+
+/// # Result
+///   - Every procedure paired with its form field, label, and whether the visit included it
+fn procedure_choices(visit: Option<&DentalVisit>) -> Vec<(&'static str, String, bool)> {
+    DentalProcedure::ALL
+        .iter()
+        .map(|procedure| {
+            (
+                procedure.as_str(),
+                procedure.to_string(),
+                visit.is_some_and(|visit| visit.has(*procedure)),
+            )
+        })
+        .collect()
+}
+
+/// # Result
+///   - The rendered form, blank when `visit` is `None`
+#[instrument(
+    name = "Render dental form",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, visit)
+)]
+async fn render_form(pool: &Database, action: &str, visit: Option<&DentalVisit>) -> HttpResponse {
+    let current = visit.map(|visit| visit.provider_id);
+    let providers = match provider_choices(pool, &ProviderKind::DENTAL, current).await {
+        Ok(providers) if providers.is_empty() => {
+            return render_error(
+                StatusCode::CONFLICT,
+                "Add a provider first",
+                Some("Dentists and orthodontists are managed from the PROVIDERS page"),
+            );
+        }
+        Ok(providers) => providers,
+        Err(http_resp) => return http_resp,
+    };
+
+    let date = visit
+        .map(|visit| visit.date.to_string())
+        .unwrap_or_default();
+
+    let template = DentalForm {
+        action,
+        editing: visit.is_some(),
+        date: &date,
+        providers,
+        procedures: procedure_choices(visit),
+        cavities: visit.map_or(0, |visit| visit.cavities),
+        teeth: visit
+            .map(|visit| visit.teeth.join(", "))
+            .unwrap_or_default(),
+        notes: visit
+            .map(|visit| visit.notes.join("\n"))
+            .unwrap_or_default(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered card list for the child
+#[instrument(
+    name = "Render dental cards",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, child),
+    fields(slug = %child.slug)
+)]
+async fn render_cards(pool: &Database, child: &Child) -> HttpResponse {
+    let visits = match DentalRepo::new(pool)
+        .get_visits_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(visits) => visits,
+        Err(err) => {
+            error!("Unable to load dental visits: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load dental visits",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = DentalCardList {
+        slug: &child.slug,
+        card_data: visits.iter().map(DentalCards::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered visit details
+#[instrument(
+    name = "Render dental visit",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, visit, child),
+    fields(slug = %child.slug)
+)]
+async fn render_visit(pool: &Database, visit: &DentalVisit, child: &Child) -> HttpResponse {
+    let id = visit.id.unwrap_or_default().to_hex();
+    let provider = provider_name(pool, visit.provider_id).await;
+    let date = visit.date.format("%A %B %d, %Y").to_string();
+    let procedures: Vec<String> = visit.procedures.iter().map(ToString::to_string).collect();
+
+    let template = DentalVisitPart {
+        id: &id,
+        slug: &child.slug,
+        date: &date,
+        provider: &provider,
+        procedures,
+        cavities: visit.cavities,
+        teeth: visit.teeth.join(", "),
+        notes: visit.notes.iter().map(String::as_str).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The dental visit and the child it belongs to, if `user` may do
+///     `permission` to the child's dental records
+/// # Errors
+///   - A rendered error page when either lookup fails or access is refused
+#[instrument(
+    name = "Visit with child",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+async fn visit_with_child(
+    pool: &Database,
+    id: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<(DentalVisit, Child), HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    let visit = DentalRepo::new(pool).get_visit(id).await.map_err(|err| {
+        error!("Unable to find the data for the ID passed in");
+        AppError::from(err).error_response()
+    })?;
+
+    let child = ChildRepo::new(pool)
+        .get_child_by_id(visit.child_id)
+        .await
+        .map_err(|err| {
+            error!("Dental visit belongs to a missing child");
+            AppError::from(err).error_response()
+        })?;
+    authorize(&user.user, &child, Some(Records::Dental), permission)
+        .map_err(|err| err.error_response())?;
+
+    Ok((visit, child))
+}
+
+#[get("/dental_logs/{slug}")]
+#[instrument(
+    name = "dental data",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn dental_data(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Dental),
+        Permission::View,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let visits = match DentalRepo::new(pool.as_ref())
+        .get_visits_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(visits) => visits,
+        Err(err) => {
+            error!("Unable to load dental visits: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load dental visits",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let provider = match visits.first() {
+        Some(visit) => provider_name(pool.as_ref(), visit.provider_id).await,
+        None => String::from("No dentist on file"),
+    };
+
+    let next_cleaning = next_cleaning_due(&visits);
+    let template = Dental {
+        title: "Dental Data",
+        name: &child.full_name(),
+        provider: &provider,
+        next_cleaning: next_cleaning.map_or_else(
+            || String::from("No cleaning on record"),
+            |due| due.format("%B %d, %Y").to_string(),
+        ),
+        cleaning_overdue: next_cleaning.is_some_and(|due| due < Local::now().date_naive()),
+        slug: &child.slug,
+        age: child.age(),
+        card_data: visits.iter().map(DentalCards::from).collect(),
+        child: &child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/dental_logs/{slug}/new")]
+#[instrument(
+    name = "dental form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_dental_visit(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    if let Err(http_resp) = child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Dental),
+        Permission::Edit,
+    )
+    .await
+    {
+        return http_resp;
+    }
+
+    render_form(pool.as_ref(), &format!("/dental_logs/{slug}"), None).await
+}
+
+#[post("/dental_logs/{slug}")]
+#[instrument(
+    name = "add dental visit",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn add_dental_visit(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    Form(input): Form<DentalInput>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Dental),
+        Permission::Edit,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::DENTAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let mut visit = input.into_visit(
+        child.id.unwrap_or_default(),
+        provider.id.unwrap_or_default(),
+    );
+
+    match DentalRepo::new(pool.as_ref()).create_visit(&visit).await {
+        Ok(id) => {
+            debug!("Dental visit saved: {id}");
+            visit.id = Some(id);
+            render_visit(pool.as_ref(), &visit, &child).await
+        }
+        Err(err) => {
+            error!("Unable to save dental visit: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save dental visit",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/dental_card/{id}")]
+#[instrument(
+    name = "recorded dental visit",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn dental_card(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match visit_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((visit, child)) => render_visit(pool.as_ref(), &visit, &child).await,
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/dental_card/{id}/edit")]
+#[instrument(
+    name = "edit dental form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn edit_dental_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match visit_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok((visit, _)) => {
+            render_form(pool.as_ref(), &format!("/dental_card/{id}"), Some(&visit)).await
+        }
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[put("/dental_card/{id}")]
+#[instrument(
+    name = "update dental visit",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn update_dental_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<DentalInput>,
+) -> HttpResponse {
+    let (existing, child) =
+        match visit_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::DENTAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let visit = DentalVisit {
+        id: existing.id,
+        ..input.into_visit(existing.child_id, provider.id.unwrap_or_default())
+    };
+
+    match DentalRepo::new(pool.as_ref())
+        .update_visit(existing.id.unwrap_or_default(), visit.clone())
+        .await
+    {
+        Ok(_) => render_visit(pool.as_ref(), &visit, &child).await,
+        Err(err) => {
+            error!("Unable to update dental visit: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to update dental visit",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[delete("/dental_card/{id}")]
+#[instrument(
+    name = "delete dental visit",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_dental_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (visit, child) = match visit_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    match DentalRepo::new(pool.as_ref())
+        .delete_visit(visit.id.unwrap_or_default())
+        .await
+    {
+        Ok(_) => {
+            info!("Dental visit deleted");
+            render_cards(pool.as_ref(), &child).await
+        }
+        Err(err) => {
+            error!("Unable to delete dental visit: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete dental visit",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
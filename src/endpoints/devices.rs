@@ -0,0 +1,125 @@
+use actix_session::Session;
+use actix_web::{
+    delete, get, post,
+    web::{Data, Path},
+    HttpResponse, ResponseError,
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        guard::AuthenticatedUser,
+        store::{Device, RedisSessionStore},
+    },
+    endpoints::{
+        error::AppError,
+        templates::{render_page, DevicesPage},
+    },
+    types::Types,
+};
+
+/// # Result
+///   - The device the current session logged in from
+fn current_device(session: &Session) -> Option<Device> {
+    session
+        .get::<Device>(&Types::DeviceKey.to_string())
+        .unwrap_or_else(|err| {
+            warn!("Unreadable device in session: {err}");
+            None
+        })
+}
+
+/// Ends the current session and sends the browser back to log in
+fn sign_out(session: &Session) -> HttpResponse {
+    session.purge();
+    HttpResponse::Ok()
+        .insert_header(("HX-Redirect", "/"))
+        .finish()
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/users/devices")]
+#[instrument(
+    name = "Devices page",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, store)
+)]
+pub async fn devices_page(
+    user: AuthenticatedUser,
+    session: Session,
+    store: Data<RedisSessionStore>,
+) -> HttpResponse {
+    info!("Listing signed in devices");
+    let devices = match store.devices(user.id).await {
+        Ok(devices) => devices,
+        Err(err) => {
+            error!("Unable to list devices: {err:#}");
+            return AppError::Backend(String::from("Unable to list your devices")).error_response();
+        }
+    };
+
+    let current = current_device(&session).map(|device| device.id);
+    render_page(&DevicesPage {
+        title: "Your devices",
+        devices,
+        current: current.as_deref().unwrap_or_default(),
+    })
+}
+
+/// Ends the session on one device. Ending the current one signs out here too.
+#[allow(clippy::future_not_send)]
+#[delete("/users/devices/{id}")]
+#[instrument(
+    name = "Revoke device",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, store)
+)]
+pub async fn revoke_device(
+    user: AuthenticatedUser,
+    session: Session,
+    store: Data<RedisSessionStore>,
+    path: Path<String>,
+) -> HttpResponse {
+    let device_id = path.into_inner();
+    match store.revoke(user.id, &device_id).await {
+        Ok(true) => {
+            info!("Device signed out");
+            if current_device(&session).is_some_and(|device| device.id == device_id) {
+                return sign_out(&session);
+            }
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => AppError::NotFound(String::from("No session on that device")).error_response(),
+        Err(err) => {
+            error!("Unable to revoke device: {err:#}");
+            AppError::Backend(String::from("Unable to sign the device out")).error_response()
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[post("/users/devices/logout_all")]
+#[instrument(
+    name = "Log out everywhere",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, store)
+)]
+pub async fn logout_everywhere(
+    user: AuthenticatedUser,
+    session: Session,
+    store: Data<RedisSessionStore>,
+) -> HttpResponse {
+    match store.revoke_all(user.id).await {
+        Ok(count) => {
+            info!("Signed out of {count} sessions");
+            sign_out(&session)
+        }
+        Err(err) => {
+            error!("Unable to revoke sessions: {err:#}");
+            AppError::Backend(String::from("Unable to sign your devices out")).error_response()
+        }
+    }
+}
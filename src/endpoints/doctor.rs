@@ -0,0 +1,561 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::NaiveDate;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        children::child_for,
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        providers::{picked_provider, provider_choices, provider_name},
+        templates::{AppointmentForm, DoctorCardList, DoctorData, DoctorVisit},
+    },
+    models::{
+        appointment::{Appointment, AppointmentRepo},
+        child::{Child, ChildRepo},
+        helpers::non_empty,
+        provider::{Provider, ProviderKind, ProviderRepo},
+    },
+};
+
+/// All things regarding doctor visits, checkups, and notes
+
+#[derive(Debug, Default, Serialize)]
+pub struct DoctorCards {
+    pub date: String,
+    pub description: String,
+    pub image: String,
+    pub db_id: ObjectId,
+}
+
+impl From<&Appointment> for DoctorCards {
+    fn from(appointment: &Appointment) -> Self {
+        Self {
+            date: appointment.date.format("%b %d, %Y").to_string(),
+            description: appointment.purpose.clone(),
+            image: String::from("/images/doctor_image"),
+            db_id: appointment.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// Form data submitted when creating or editing an appointment
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppointmentInput {
+    pub date: NaiveDate,
+    pub provider_id: String,
+    pub purpose: String,
+    /// One note per line
+    pub notes: String,
+}
+
+impl AppointmentInput {
+    fn into_appointment(self, child_id: ObjectId, provider_id: ObjectId) -> Appointment {
+        Appointment {
+            id: None,
+            child_id,
+            provider_id,
+            date: self.date,
+            purpose: self.purpose.trim().to_string(),
+            notes: self.notes.lines().filter_map(non_empty).collect(),
+        }
+    }
+}
+
+/// # Result
+///   - The rendered card list for the child
+#[instrument(
+    name = "Render doctor cards",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, child),
+    fields(slug = %child.slug)
+)]
+async fn render_cards(pool: &Database, child: &Child) -> HttpResponse {
+    let appointments = match AppointmentRepo::new(pool)
+        .get_appointments_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(appointments) => appointments,
+        Err(err) => {
+            error!("Unable to load appointments: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load appointments",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = DoctorCardList {
+        slug: &child.slug,
+        card_data: appointments.iter().map(DoctorCards::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered visit details for the appointment
+#[instrument(
+    name = "Render doctor visit",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, appointment, child),
+    fields(slug = %child.slug)
+)]
+async fn render_visit(pool: &Database, appointment: &Appointment, child: &Child) -> HttpResponse {
+    let id = appointment.id.unwrap_or_default().to_hex();
+    let date = appointment.date.format("%A %B %d, %Y").to_string();
+    let provider = provider_name(pool, appointment.provider_id).await;
+
+    let template = DoctorVisit {
+        id: &id,
+        slug: &child.slug,
+        date: &date,
+        provider: &provider,
+        notes: appointment.notes.iter().map(String::as_str).collect(),
+        purpose: &appointment.purpose,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The appointment and the child it belongs to, if `user` may do
+///     `permission` to the child's medical records
+/// # Errors
+///   - A rendered error page when either lookup fails or access is refused
+#[instrument(
+    name = "Appointment with child",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+async fn appointment_with_child(
+    pool: &Database,
+    id: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<(Appointment, Child), HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    let appointment = AppointmentRepo::new(pool)
+        .get_appointment(id)
+        .await
+        .map_err(|err| {
+            error!("Unable to find the data for the ID passed in");
+            AppError::from(err).error_response()
+        })?;
+
+    let child = ChildRepo::new(pool)
+        .get_child_by_id(appointment.child_id)
+        .await
+        .map_err(|err| {
+            error!("Appointment belongs to a missing child");
+            AppError::from(err).error_response()
+        })?;
+    authorize(&user.user, &child, Some(Records::Medical), permission)
+        .map_err(|err| err.error_response())?;
+
+    Ok((appointment, child))
+}
+
+/// # Result
+///   - The provider of the most recent appointment, else the first active pediatrician
+#[instrument(
+    name = "Current provider",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, appointments)
+)]
+async fn current_provider(pool: &Database, appointments: &[Appointment]) -> Option<Provider> {
+    let repo = ProviderRepo::new(pool);
+
+    if let Some(latest) = appointments.first() {
+        match repo.get_provider(latest.provider_id).await {
+            Ok(provider) => return Some(provider),
+            Err(err) => error!("Latest appointment points at a missing provider: {err}"),
+        }
+    }
+
+    match repo
+        .get_providers(&[ProviderKind::Pediatrician], true)
+        .await
+    {
+        Ok(providers) => providers.into_iter().next(),
+        Err(err) => {
+            error!("Unable to load pediatricians: {err}");
+            None
+        }
+    }
+}
+
+#[get("/doctor_logs/{slug}")]
+#[instrument(
+    name = "doctor data",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn doctor_data(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::View,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let appointments = match AppointmentRepo::new(pool.as_ref())
+        .get_appointments_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(appointments) => appointments,
+        Err(err) => {
+            error!("Unable to load appointments: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load appointments",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let provider = current_provider(pool.as_ref(), &appointments).await;
+    let speciality = provider
+        .as_ref()
+        .map(|provider| provider.kind.to_string())
+        .unwrap_or_default();
+
+    let template = DoctorData {
+        title: "Doctor Data",
+        name: provider
+            .as_ref()
+            .map_or("No pediatrician on file", |provider| provider.name.as_str()),
+        photo: provider
+            .as_ref()
+            .map_or("/images/doctor_image", Provider::photo),
+        email: provider
+            .as_ref()
+            .map(|provider| provider.email.as_str())
+            .unwrap_or_default(),
+        phone: provider
+            .as_ref()
+            .map(|provider| provider.phone.as_str())
+            .unwrap_or_default(),
+        address: provider
+            .as_ref()
+            .map(|provider| provider.address.as_str())
+            .unwrap_or_default(),
+        speciality: &speciality,
+        slug: &child.slug,
+        age: child.age(),
+        card_data: appointments.iter().map(DoctorCards::from).collect(),
+        child: &child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/doctor_logs/{slug}/new")]
+#[instrument(
+    name = "appointment form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_doctor_visit(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    if let Err(http_resp) = child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::Edit,
+    )
+    .await
+    {
+        return http_resp;
+    }
+
+    let providers = match provider_choices(pool.as_ref(), &ProviderKind::MEDICAL, None).await {
+        Ok(providers) if providers.is_empty() => {
+            return render_error(
+                StatusCode::CONFLICT,
+                "Add a provider first",
+                Some("Pediatricians and specialists are managed from the PROVIDERS page"),
+            );
+        }
+        Ok(providers) => providers,
+        Err(http_resp) => return http_resp,
+    };
+
+    let action = format!("/doctor_logs/{slug}");
+    let template = AppointmentForm {
+        action: &action,
+        editing: false,
+        date: "",
+        providers,
+        purpose: "",
+        notes: String::new(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/doctor_logs/{slug}")]
+#[instrument(
+    name = "add appointment",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn add_doctor_visit(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    Form(input): Form<AppointmentInput>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::Edit,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::MEDICAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let mut appointment = input.into_appointment(
+        child.id.unwrap_or_default(),
+        provider.id.unwrap_or_default(),
+    );
+
+    match AppointmentRepo::new(pool.as_ref())
+        .create_appointment(&appointment)
+        .await
+    {
+        Ok(id) => {
+            debug!("Appointment saved: {id}");
+            appointment.id = Some(id);
+            render_visit(pool.as_ref(), &appointment, &child).await
+        }
+        Err(err) => {
+            error!("Unable to save appointment: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save appointment",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/doctor_card/{id}")]
+#[instrument(
+    name = "recorded appointment",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn doctor_card(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match appointment_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((appointment, child)) => render_visit(pool.as_ref(), &appointment, &child).await,
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[get("/doctor_card/{id}/edit")]
+#[instrument(
+    name = "edit appointment form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn edit_doctor_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (appointment, _) =
+        match appointment_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    let providers = match provider_choices(
+        pool.as_ref(),
+        &ProviderKind::MEDICAL,
+        Some(appointment.provider_id),
+    )
+    .await
+    {
+        Ok(providers) => providers,
+        Err(http_resp) => return http_resp,
+    };
+
+    let action = format!("/doctor_card/{id}");
+    let date = appointment.date.to_string();
+    let template = AppointmentForm {
+        action: &action,
+        editing: true,
+        date: &date,
+        providers,
+        purpose: &appointment.purpose,
+        notes: appointment.notes.join("\n"),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[put("/doctor_card/{id}")]
+#[instrument(
+    name = "update appointment",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn update_doctor_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<AppointmentInput>,
+) -> HttpResponse {
+    let (existing, child) =
+        match appointment_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::MEDICAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let appointment = Appointment {
+        id: existing.id,
+        ..input.into_appointment(existing.child_id, provider.id.unwrap_or_default())
+    };
+
+    match AppointmentRepo::new(pool.as_ref())
+        .update_appointment(existing.id.unwrap_or_default(), appointment.clone())
+        .await
+    {
+        Ok(_) => render_visit(pool.as_ref(), &appointment, &child).await,
+        Err(err) => {
+            error!("Unable to update appointment: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to update appointment",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[delete("/doctor_card/{id}")]
+#[instrument(
+    name = "delete appointment",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_doctor_visit(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (appointment, child) =
+        match appointment_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    match AppointmentRepo::new(pool.as_ref())
+        .delete_appointment(appointment.id.unwrap_or_default())
+        .await
+    {
+        Ok(_) => {
+            info!("Appointment deleted");
+            render_cards(pool.as_ref(), &child).await
+        }
+        Err(err) => {
+            error!("Unable to delete appointment: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete appointment",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
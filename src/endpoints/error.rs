@@ -1,15 +1,106 @@
-use actix_web::{http::StatusCode, HttpResponse};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    middleware::Next,
+    HttpResponse, ResponseError,
+};
 use askama::Template;
-use tracing::{info, instrument};
+use mongodb::bson::oid::ObjectId;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    endpoints::templates::{CsrfErrorPage, ErrorPage, ErrorPart},
+    models::repository::RepoError,
+};
+
+/// Shown in place of the details of a server side failure, which are logged
+pub const TRY_AGAIN: &str = "Please try again later";
+
+/// Header HTMX adds to every request it makes
+const HX_REQUEST: &str = "HX-Request";
+
+/// What a rendered error said, kept on the response so it can be re-rendered
+/// as a fragment for HTMX
+#[derive(Debug, Clone)]
+struct ErrorDetails {
+    message: String,
+    error: String,
+}
+
+/// Why a request failed, as far as the client is concerned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppError {
+    NotFound(String),
+    Conflict(String),
+    Validation(String),
+    Unauthorized(String),
+    Forbidden(String),
+    TooManyRequests(String),
+    /// Details are logged, never shown
+    Backend(String),
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::Validation(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::TooManyRequests(message)
+            | Self::Backend(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl From<RepoError> for AppError {
+    fn from(err: RepoError) -> Self {
+        match err {
+            RepoError::NotFound(message) => Self::NotFound(message),
+            RepoError::Conflict(message) => Self::Conflict(message),
+            RepoError::Validation(message) => Self::Validation(message),
+            RepoError::Backend(message) => Self::Backend(message),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Validation(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 
-use crate::endpoints::templates::ErrorPage;
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let reason = status.canonical_reason().unwrap_or("Error");
+
+        if let Self::Backend(message) = self {
+            error!("Backend failure: {message}");
+            return render_error(status, reason, Some(TRY_AGAIN));
+        }
+
+        render_error(status, reason, Some(&self.to_string()))
+    }
+}
 
 /// # Result
-///   - `HttpResponse` with status code 404 and error message.
+///   - `HttpResponse` with the given status code and the rendered error page
 /// # Errors
 ///   - None
-/// # Panics
-///   - Failing to render error page.
 #[must_use]
 #[instrument(
     name = "Render error",
@@ -26,18 +117,182 @@ pub fn render_error<'a>(
     err: Option<&'a str>,
 ) -> HttpResponse {
     info!("Rendering error page.");
-    let error = ErrorPage {
+    let error = err.unwrap_or("");
+    let page = ErrorPage {
         title: status.as_str(),
         code: status.as_u16(),
-        error: err.unwrap_or(""),
+        error,
+        message,
+    };
+
+    let mut response = match page.render() {
+        Ok(body) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body),
+        Err(err) => {
+            error!("Failed to render error page: {err:#?}");
+            HttpResponse::build(status)
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("ERROR: {message} {error}"))
+        }
+    };
+
+    response.extensions_mut().insert(ErrorDetails {
+        message: message.to_string(),
+        error: error.to_string(),
+    });
+
+    response
+}
+
+/// # Result
+///   - A 403 with the page explaining a request failed the CSRF check
+#[must_use]
+#[instrument(name = "Render forgery error", level = "info")]
+pub fn render_forgery_error() -> HttpResponse {
+    info!("Rendering forgery error page.");
+    let status = StatusCode::FORBIDDEN;
+    let message = "This page has expired";
+    let error = "Reload the page and try again";
+    let page = CsrfErrorPage {
+        title: status.as_str(),
+        error,
         message,
     };
 
-    // warn!("Error Page error: {error}");
+    let mut response = match page.render() {
+        Ok(body) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body),
+        Err(err) => {
+            error!("Failed to render forgery error page: {err:#?}");
+            HttpResponse::build(status)
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("ERROR: {message} {error}"))
+        }
+    };
+
+    response.extensions_mut().insert(ErrorDetails {
+        message: message.to_string(),
+        error: error.to_string(),
+    });
+
+    response
+}
+
+/// Middleware that swaps a rendered error page for just the error fragment
+/// when the request came from HTMX, keeping the status code
+/// # Errors
+///   - Whatever the wrapped service returns
+#[allow(clippy::future_not_send)]
+pub async fn htmx_error_fragments(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let from_htmx = req.headers().contains_key(HX_REQUEST);
+    let res = next.call(req).await?.map_into_boxed_body();
+
+    if !from_htmx {
+        return Ok(res);
+    }
+
+    let Some(details) = res.response().extensions().get::<ErrorDetails>().cloned() else {
+        return Ok(res);
+    };
+
+    let fragment = ErrorPart {
+        error: &details.error,
+        message: &details.message,
+    };
+
+    match fragment.render() {
+        Ok(body) => Ok(res.map_body(|_, _| BoxBody::new(body))),
+        Err(err) => {
+            error!("Failed to render error fragment: {err:#?}");
+            Ok(res)
+        }
+    }
+}
+
+/// # Result
+///   - The `ObjectId` parsed from a path segment
+/// # Errors
+///   - A rendered 400 page if the segment is not an `ObjectId`
+pub fn parse_object_id(id: &str) -> Result<ObjectId, HttpResponse> {
+    ObjectId::from_str(id).map_err(|err| {
+        warn!("Passed in data is not an Object ID: {err}");
+        AppError::Validation(String::from("The ID passed in is not valid")).error_response()
+    })
+}
+
+#[cfg(test)]
+mod test_error {
+    use actix_web::{
+        middleware::from_fn,
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_repo_errors_keep_their_meaning() {
+        let cases = [
+            (
+                RepoError::NotFound("Child not found".into()),
+                StatusCode::NOT_FOUND,
+            ),
+            (RepoError::Conflict("Taken".into()), StatusCode::CONFLICT),
+            (RepoError::Validation("Bad".into()), StatusCode::BAD_REQUEST),
+            (
+                RepoError::Backend("Timed out".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+
+        for (err, status) in cases {
+            assert_eq!(AppError::from(err).status_code(), status);
+        }
+    }
+
+    #[test]
+    fn test_render_error_uses_the_status_given() {
+        let response = render_error(StatusCode::CONFLICT, "Conflict", None);
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_unauthorized_response() {
+        let response = AppError::Unauthorized("Log in first".into()).error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_htmx_requests_get_a_fragment_with_the_same_status() {
+        let app = init_service(App::new().wrap(from_fn(htmx_error_fragments)).route(
+            "/",
+            web::get().to(|| async {
+                AppError::NotFound(String::from("Child not found")).error_response()
+            }),
+        ))
+        .await;
 
-    let error_template = error.render().expect("Failed to render error page.");
+        let page = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(page.status(), StatusCode::NOT_FOUND);
+        let page = String::from_utf8_lossy(&read_body(page).await).to_string();
+        assert!(page.contains("<html"));
 
-    HttpResponse::NotFound()
-        .content_type("text/html; charset=utf-8")
-        .body(error_template)
+        let fragment = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header((HX_REQUEST, "true"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(fragment.status(), StatusCode::NOT_FOUND);
+        let fragment = String::from_utf8_lossy(&read_body(fragment).await).to_string();
+        assert!(!fragment.contains("<html"));
+        assert!(fragment.contains("Child not found"));
+    }
 }
@@ -0,0 +1,630 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::{Local, NaiveDate};
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        children::child_for,
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        providers::{picked_provider, provider_choices, provider_name},
+        templates::{ImmunizationCardList, ImmunizationForm, ImmunizationPart, Immunizations},
+    },
+    models::{
+        child::{Child, ChildRepo},
+        helpers::non_empty,
+        immunization::{
+            schedule_status, DoseState, DoseStatus, Immunization, ImmunizationRepo, Vaccine,
+        },
+        provider::ProviderKind,
+    },
+};
+
+/// Shots given, and where each child stands on the routine schedule
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImmunizationCards {
+    pub date: String,
+    pub description: String,
+    pub db_id: ObjectId,
+}
+
+impl From<&Immunization> for ImmunizationCards {
+    fn from(immunization: &Immunization) -> Self {
+        Self {
+            date: immunization.date.format("%b %d, %Y").to_string(),
+            description: format!("{} dose {}", immunization.vaccine, immunization.dose),
+            db_id: immunization.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// One line of the schedule table
+#[derive(Debug, Serialize)]
+pub struct ScheduleRow {
+    pub vaccine: String,
+    pub dose: u32,
+    /// The age the dose is recommended at
+    pub age: String,
+    pub due: String,
+    pub given: String,
+    pub state: String,
+    pub overdue: bool,
+}
+
+impl From<&DoseStatus> for ScheduleRow {
+    fn from(status: &DoseStatus) -> Self {
+        Self {
+            vaccine: status.scheduled.vaccine.to_string(),
+            dose: status.scheduled.dose,
+            age: age_label(status.scheduled.due_months),
+            due: status.due.format("%b %d, %Y").to_string(),
+            given: status
+                .given
+                .map(|given| given.format("%b %d, %Y").to_string())
+                .unwrap_or_default(),
+            state: status.state.to_string(),
+            overdue: status.state == DoseState::Overdue,
+        }
+    }
+}
+
+/// # Result
+///   - `months` the way the schedule is usually written: birth, months until
+///     two, years after that
+fn age_label(months: u32) -> String {
+    match months {
+        0 => String::from("Birth"),
+        1 => String::from("1 month"),
+        months if months < 24 => format!("{months} months"),
+        months if months % 12 == 0 => format!("{} years", months / 12),
+        months => format!("{} years {} months", months / 12, months % 12),
+    }
+}
+
+/// Form data submitted when recording or editing a dose
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImmunizationInput {
+    pub date: NaiveDate,
+    pub provider_id: String,
+    pub vaccine: Vaccine,
+    pub dose: u32,
+    pub lot_number: String,
+}
+
+impl ImmunizationInput {
+    /// # Errors
+    ///   - `AppError::Validation` if the dose number or lot number is unusable
+    fn into_immunization(
+        self,
+        child_id: ObjectId,
+        provider_id: ObjectId,
+    ) -> Result<Immunization, AppError> {
+        if self.dose == 0 {
+            return Err(AppError::Validation(String::from(
+                "Doses are numbered from 1",
+            )));
+        }
+        let Some(lot_number) = non_empty(&self.lot_number) else {
+            return Err(AppError::Validation(String::from(
+                "The lot number is printed on the vaccine record card",
+            )));
+        };
+
+        Ok(Immunization {
+            id: None,
+            child_id,
+            provider_id,
+            vaccine: self.vaccine,
+            dose: self.dose,
+            date: self.date,
+            lot_number,
+        })
+    }
+}
+
+/// # Result
+///   - Every vaccine paired with its form value, label, and whether it is the one given
+fn vaccine_choices(immunization: Option<&Immunization>) -> Vec<(&'static str, String, bool)> {
+    Vaccine::ALL
+        .iter()
+        .map(|vaccine| {
+            (
+                vaccine.as_str(),
+                vaccine.to_string(),
+                immunization.is_some_and(|immunization| immunization.vaccine == *vaccine),
+            )
+        })
+        .collect()
+}
+
+/// # Result
+///   - The rendered form, blank when `immunization` is `None`
+#[instrument(
+    name = "Render immunization form",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, immunization)
+)]
+async fn render_form(
+    pool: &Database,
+    action: &str,
+    immunization: Option<&Immunization>,
+) -> HttpResponse {
+    let current = immunization.map(|immunization| immunization.provider_id);
+    let providers = match provider_choices(pool, &ProviderKind::MEDICAL, current).await {
+        Ok(providers) if providers.is_empty() => {
+            return render_error(
+                StatusCode::CONFLICT,
+                "Add a provider first",
+                Some("Pediatricians and specialists are managed from the PROVIDERS page"),
+            );
+        }
+        Ok(providers) => providers,
+        Err(http_resp) => return http_resp,
+    };
+
+    let date = immunization
+        .map(|immunization| immunization.date.to_string())
+        .unwrap_or_default();
+
+    let template = ImmunizationForm {
+        action,
+        editing: immunization.is_some(),
+        date: &date,
+        providers,
+        vaccines: vaccine_choices(immunization),
+        dose: immunization.map_or(1, |immunization| immunization.dose),
+        lot_number: immunization.map_or("", |immunization| immunization.lot_number.as_str()),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered card list for the child
+#[instrument(
+    name = "Render immunization cards",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, child),
+    fields(slug = %child.slug)
+)]
+async fn render_cards(pool: &Database, child: &Child) -> HttpResponse {
+    let immunizations = match ImmunizationRepo::new(pool)
+        .get_immunizations_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(immunizations) => immunizations,
+        Err(err) => {
+            error!("Unable to load immunizations: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load immunizations",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = ImmunizationCardList {
+        slug: &child.slug,
+        card_data: immunizations.iter().map(ImmunizationCards::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered dose details
+#[instrument(
+    name = "Render immunization",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, immunization, child),
+    fields(slug = %child.slug)
+)]
+async fn render_immunization(
+    pool: &Database,
+    immunization: &Immunization,
+    child: &Child,
+) -> HttpResponse {
+    let id = immunization.id.unwrap_or_default().to_hex();
+    let provider = provider_name(pool, immunization.provider_id).await;
+    let date = immunization.date.format("%A %B %d, %Y").to_string();
+
+    let template = ImmunizationPart {
+        id: &id,
+        slug: &child.slug,
+        vaccine: immunization.vaccine.to_string(),
+        dose: immunization.dose,
+        date: &date,
+        lot_number: &immunization.lot_number,
+        provider: &provider,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The immunization and the child it belongs to, if `user` may do
+///     `permission` to the child's medical records
+/// # Errors
+///   - A rendered error page when either lookup fails or access is refused
+#[instrument(
+    name = "Immunization with child",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+async fn immunization_with_child(
+    pool: &Database,
+    id: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<(Immunization, Child), HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    let immunization = ImmunizationRepo::new(pool)
+        .get_immunization(id)
+        .await
+        .map_err(|err| {
+            error!("Unable to find the data for the ID passed in");
+            AppError::from(err).error_response()
+        })?;
+
+    let child = ChildRepo::new(pool)
+        .get_child_by_id(immunization.child_id)
+        .await
+        .map_err(|err| {
+            error!("Immunization belongs to a missing child");
+            AppError::from(err).error_response()
+        })?;
+    authorize(&user.user, &child, Some(Records::Medical), permission)
+        .map_err(|err| err.error_response())?;
+
+    Ok((immunization, child))
+}
+
+#[get("/immunization_logs/{slug}")]
+#[instrument(
+    name = "immunization data",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn immunization_data(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::View,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let immunizations = match ImmunizationRepo::new(pool.as_ref())
+        .get_immunizations_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(immunizations) => immunizations,
+        Err(err) => {
+            error!("Unable to load immunizations: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load immunizations",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let provider = match immunizations.first() {
+        Some(immunization) => provider_name(pool.as_ref(), immunization.provider_id).await,
+        None => String::from("No shots on record"),
+    };
+
+    let statuses = schedule_status(
+        child.date_of_birth,
+        &immunizations,
+        Local::now().date_naive(),
+    );
+    let count = |state| {
+        statuses
+            .iter()
+            .filter(|status| status.state == state)
+            .count()
+    };
+
+    let template = Immunizations {
+        title: "Immunizations",
+        name: &child.full_name(),
+        provider: &provider,
+        overdue: count(DoseState::Overdue),
+        due: count(DoseState::Due),
+        schedule: statuses.iter().map(ScheduleRow::from).collect(),
+        slug: &child.slug,
+        age: child.age(),
+        card_data: immunizations.iter().map(ImmunizationCards::from).collect(),
+        child: &child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/immunization_logs/{slug}/new")]
+#[instrument(
+    name = "immunization form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_immunization(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    if let Err(http_resp) = child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::Edit,
+    )
+    .await
+    {
+        return http_resp;
+    }
+
+    render_form(pool.as_ref(), &format!("/immunization_logs/{slug}"), None).await
+}
+
+#[post("/immunization_logs/{slug}")]
+#[instrument(
+    name = "add immunization",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn add_immunization(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    Form(input): Form<ImmunizationInput>,
+) -> HttpResponse {
+    let child = match child_for(
+        pool.as_ref(),
+        &slug,
+        &user,
+        Some(Records::Medical),
+        Permission::Edit,
+    )
+    .await
+    {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::MEDICAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let mut immunization = match input.into_immunization(
+        child.id.unwrap_or_default(),
+        provider.id.unwrap_or_default(),
+    ) {
+        Ok(immunization) => immunization,
+        Err(err) => return err.error_response(),
+    };
+
+    match ImmunizationRepo::new(pool.as_ref())
+        .create_immunization(&immunization)
+        .await
+    {
+        Ok(id) => {
+            debug!("Immunization saved: {id}");
+            immunization.id = Some(id);
+            render_immunization(pool.as_ref(), &immunization, &child).await
+        }
+        Err(err) => {
+            error!("Unable to save immunization: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save immunization",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/immunization_card/{id}")]
+#[instrument(
+    name = "recorded immunization",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn immunization_card(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match immunization_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((immunization, child)) => {
+            render_immunization(pool.as_ref(), &immunization, &child).await
+        }
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/immunization_card/{id}/edit")]
+#[instrument(
+    name = "edit immunization form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn edit_immunization(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match immunization_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok((immunization, _)) => {
+            render_form(
+                pool.as_ref(),
+                &format!("/immunization_card/{id}"),
+                Some(&immunization),
+            )
+            .await
+        }
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[put("/immunization_card/{id}")]
+#[instrument(
+    name = "update immunization",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn update_immunization(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<ImmunizationInput>,
+) -> HttpResponse {
+    let (existing, child) =
+        match immunization_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    let provider =
+        match picked_provider(pool.as_ref(), &input.provider_id, &ProviderKind::MEDICAL).await {
+            Ok(provider) => provider,
+            Err(http_resp) => return http_resp,
+        };
+
+    let immunization =
+        match input.into_immunization(existing.child_id, provider.id.unwrap_or_default()) {
+            Ok(immunization) => Immunization {
+                id: existing.id,
+                ..immunization
+            },
+            Err(err) => return err.error_response(),
+        };
+
+    match ImmunizationRepo::new(pool.as_ref())
+        .update_immunization(existing.id.unwrap_or_default(), immunization.clone())
+        .await
+    {
+        Ok(_) => render_immunization(pool.as_ref(), &immunization, &child).await,
+        Err(err) => {
+            error!("Unable to update immunization: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to update immunization",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[delete("/immunization_card/{id}")]
+#[instrument(
+    name = "delete immunization",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_immunization(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (immunization, child) =
+        match immunization_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+            Ok(found) => found,
+            Err(http_resp) => return http_resp,
+        };
+
+    match ImmunizationRepo::new(pool.as_ref())
+        .delete_immunization(immunization.id.unwrap_or_default())
+        .await
+    {
+        Ok(_) => {
+            info!("Immunization deleted");
+            render_cards(pool.as_ref(), &child).await
+        }
+        Err(err) => {
+            error!("Unable to delete immunization: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete immunization",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_immunizations {
+    use super::*;
+
+    #[test]
+    fn test_ages_read_like_the_printed_schedule() {
+        assert_eq!(age_label(0), "Birth");
+        assert_eq!(age_label(1), "1 month");
+        assert_eq!(age_label(15), "15 months");
+        assert_eq!(age_label(48), "4 years");
+        assert_eq!(age_label(138), "11 years 6 months");
+    }
+}
@@ -1,17 +1,49 @@
-use actix_web::{get, HttpResponse};
+use actix_web::{get, web::Data, HttpResponse};
 use askama::Template;
+use mongodb::Database;
 use tracing::{debug, error, info, instrument};
 
-use crate::endpoints::templates::Index;
+use crate::{
+    auth::{
+        grants::{permits, Permission},
+        guard::AuthenticatedUser,
+    },
+    endpoints::templates::Index,
+    models::{child::ChildRepo, mongo::User},
+};
+
+/// # Result
+///   - The rendered index page listing every child profile `user` may see
+/// # Errors
+///   - `askama::Error` if the template fails to render
+#[instrument(
+    name = "Index body",
+    level = "debug",
+    target = "kid_data",
+    skip(db, user)
+)]
+pub async fn index_body(db: &Database, user: &User, title: &str) -> Result<String, askama::Error> {
+    let children = match ChildRepo::new(db).get_all_children().await {
+        Ok(children) => children
+            .into_iter()
+            .filter(|child| permits(user, child, None, Permission::View))
+            .collect(),
+        Err(err) => {
+            error!("Unable to load the children for the index page: {err}");
+            Vec::new()
+        }
+    };
+
+    Index { title, children }.render()
+}
 
 #[get("/main")]
-#[instrument(name = "Main page", level = "debug")]
-pub async fn index() -> HttpResponse {
+#[instrument(name = "Main page", level = "debug", skip(user, db))]
+pub async fn index(user: AuthenticatedUser, db: Data<Database>) -> HttpResponse {
     info!("Rendering the index page");
-    let template = Index { title: "Quiz site" };
 
     debug!("rendering the main page");
-    let body = match template.render() {
+    let body = match index_body(db.as_ref(), &user.user, "Quiz site").await {
         Ok(body) => body,
         Err(err) => {
             error!("Error rendering template: {err:#?}");
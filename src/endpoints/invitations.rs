@@ -0,0 +1,507 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, Data, Form, Path},
+    HttpResponse, ResponseError,
+};
+use chrono::{Duration, Utc};
+use deadpool_redis::Pool;
+use mongodb::{
+    bson::{oid::ObjectId, DateTime},
+    Database,
+};
+use serde::Deserialize;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        grants::{authorize, permits, ChildGrant, Permission, Records},
+        guard::AuthenticatedUser,
+        keys::KeyRing,
+        tokens::{
+            check_invitation_token, issue_invitation_token, verify_invitation_token,
+            INVITATION_LIFETIME_DAYS,
+        },
+    },
+    endpoints::{
+        error::AppError,
+        password::check_passwords,
+        register::CreateNewUser,
+        templates::{render_page, AcceptInvitationPage, InvitationsPage, LoginPage},
+    },
+    models::{
+        child::ChildRepo,
+        invitation::{Invitation, InvitationRepo},
+        mongo::UserRepo,
+        repository::RepoError,
+    },
+    settings::Settings,
+    utils::emails::send_invitation_email,
+};
+
+/// Shown for every bad, spent, withdrawn or expired invitation link
+const INVALID_LINK: &str = "This invitation is invalid or has expired";
+
+const TITLE: &str = "Invite family";
+
+/// Form data from the invitation page. Each ticked box sends its own pair,
+/// so the form is read as a list of pairs rather than a struct.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NewInvitation {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub grants: Vec<ChildGrant>,
+}
+
+impl NewInvitation {
+    /// # Result
+    ///   - The invitation described by the form. `records` pairs look like
+    ///     `<child ID>:<records>` and `edit` pairs name a child ID.
+    /// # Errors
+    ///   - `AppError::Validation` if the invitee is not named, or no records
+    ///     are picked
+    fn from_pairs(pairs: Vec<(String, String)>) -> Result<Self, AppError> {
+        let mut invitation = Self {
+            email: String::new(),
+            first_name: String::new(),
+            last_name: String::new(),
+            grants: Vec::new(),
+        };
+        let mut editable = Vec::new();
+
+        for (name, value) in pairs {
+            match name.as_str() {
+                "email" => invitation.email = value.trim().to_string(),
+                "first_name" => invitation.first_name = value.trim().to_string(),
+                "last_name" => invitation.last_name = value.trim().to_string(),
+                "records" => {
+                    let (child_id, records) = value
+                        .split_once(':')
+                        .and_then(|(child_id, records)| {
+                            Some((
+                                ObjectId::parse_str(child_id).ok()?,
+                                Records::parse(records)?,
+                            ))
+                        })
+                        .ok_or_else(|| AppError::Validation(format!("Unknown records {value}")))?;
+                    invitation.grant(child_id, records);
+                }
+                "edit" => editable.push(
+                    ObjectId::parse_str(&value)
+                        .map_err(|_| AppError::Validation(format!("Unknown child {value}")))?,
+                ),
+                _ => {}
+            }
+        }
+
+        if !invitation.email.contains('@') {
+            return Err(AppError::Validation(String::from(
+                "An email address is needed",
+            )));
+        }
+        if invitation.first_name.is_empty() || invitation.last_name.is_empty() {
+            return Err(AppError::Validation(String::from(
+                "The invitee's first and last name are needed",
+            )));
+        }
+        if invitation.grants.is_empty() {
+            return Err(AppError::Validation(String::from(
+                "Pick at least one child's records to share",
+            )));
+        }
+
+        for grant in &mut invitation.grants {
+            grant.can_edit = editable.contains(&grant.child_id);
+        }
+        Ok(invitation)
+    }
+
+    fn grant(&mut self, child_id: ObjectId, records: Records) {
+        match self
+            .grants
+            .iter_mut()
+            .find(|grant| grant.child_id == child_id)
+        {
+            Some(grant) if grant.records.contains(&records) => {}
+            Some(grant) => grant.records.push(records),
+            None => self.grants.push(ChildGrant {
+                child_id,
+                records: vec![records],
+                can_edit: false,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitationToken {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInvitation {
+    pub token: String,
+    pub password: String,
+    pub password_2: String,
+}
+
+/// # Result
+///   - The invitation page: the children `user` may invite people to, and
+///     the invitations they have not had answered
+async fn render_invitations(
+    db: &Database,
+    user: &AuthenticatedUser,
+    sent_to: &str,
+) -> HttpResponse {
+    let children = match ChildRepo::new(db).get_all_children().await {
+        Ok(children) => children
+            .into_iter()
+            .filter(|child| permits(&user.user, child, None, Permission::Edit))
+            .collect(),
+        Err(err) => return AppError::from(err).error_response(),
+    };
+
+    match InvitationRepo::new(db).sent_by(user.id).await {
+        Ok(invitations) => render_page(&InvitationsPage {
+            title: TITLE,
+            children,
+            records: &Records::ALL,
+            invitations,
+            sent_to,
+        }),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// # Errors
+///   - `AppError` if a child does not exist, or `user` may not invite
+///     people to it
+async fn check_grants(
+    db: &Database,
+    user: &AuthenticatedUser,
+    grants: &[ChildGrant],
+) -> Result<(), AppError> {
+    let children = ChildRepo::new(db);
+    for grant in grants {
+        let child = children.get_child_by_id(grant.child_id).await?;
+        authorize(&user.user, &child, None, Permission::Edit)?;
+    }
+    Ok(())
+}
+
+#[get("/invitations")]
+#[instrument(
+    name = "Invitations page",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn invitations_page(user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    render_invitations(pool.as_ref(), &user, "").await
+}
+
+/// Invites someone by email to see, or also edit, some of the records of
+/// children the sender looks after
+#[post("/invitations")]
+#[instrument(
+    name = "Send invitation",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, redis_pool, key_ring, pairs)
+)]
+pub async fn send_invitation(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    Form(pairs): Form<Vec<(String, String)>>,
+) -> HttpResponse {
+    let input = match NewInvitation::from_pairs(pairs) {
+        Ok(input) => input,
+        Err(err) => return err.error_response(),
+    };
+    if let Err(err) = check_grants(pool.as_ref(), &user, &input.grants).await {
+        return err.error_response();
+    }
+
+    match UserRepo::new(pool.as_ref())
+        .get_user(None, Some(&input.email))
+        .await
+    {
+        Ok(_) => {
+            return AppError::Conflict(String::from("An account already uses that email"))
+                .error_response()
+        }
+        Err(RepoError::NotFound(_)) => {}
+        Err(err) => return AppError::from(err).error_response(),
+    }
+
+    let invitation = Invitation {
+        id: None,
+        email: input.email,
+        first_name: input.first_name,
+        last_name: input.last_name,
+        invited_by: user.id,
+        grants: input.grants,
+        created_at: DateTime::now(),
+        expires_at: DateTime::from_millis(
+            (Utc::now() + Duration::days(INVITATION_LIFETIME_DAYS)).timestamp_millis(),
+        ),
+    };
+    let invitations = InvitationRepo::new(pool.as_ref());
+    let invitation_id = match invitations.insert(&invitation).await {
+        Ok(invitation_id) => invitation_id,
+        Err(err) => return AppError::from(err).error_response(),
+    };
+
+    let sent = match redis_pool.get().await {
+        Ok(mut redis_conn) => issue_invitation_token(invitation_id, &mut redis_conn, &key_ring)
+            .await
+            .map_err(|err| format!("Could not issue invitation token: {err}")),
+        Err(err) => Err(format!("Error getting redis connection: {err}")),
+    };
+    let sent = match sent {
+        Ok(token) => {
+            let inviter = format!("{} {}", user.user.first_name, user.user.last_name);
+            send_invitation_email(
+                invitation.email.clone(),
+                invitation.first_name.clone(),
+                invitation.last_name.clone(),
+                inviter,
+                &token,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = sent {
+        error!("Invitation not sent: {err}");
+        // An invitation nobody was told about is only clutter
+        if let Err(err) = invitations.remove(invitation_id).await {
+            error!("Unable to remove the unsent invitation: {err}");
+        }
+        return AppError::Backend(String::from("Unable to send the invitation")).error_response();
+    }
+
+    info!("Invitation sent");
+    render_invitations(pool.as_ref(), &user, &invitation.email).await
+}
+
+#[delete("/invitations/{id}")]
+#[instrument(
+    name = "Withdraw invitation",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, path)
+)]
+pub async fn withdraw_invitation(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    path: Path<String>,
+) -> HttpResponse {
+    let Ok(invitation_id) = ObjectId::parse_str(path.into_inner()) else {
+        return AppError::Validation(String::from("Malformed invitation ID")).error_response();
+    };
+
+    match InvitationRepo::new(pool.as_ref())
+        .revoke(invitation_id, user.id)
+        .await
+    {
+        Ok(result) if result.deleted_count == 1 => {
+            info!("Invitation withdrawn");
+            HttpResponse::Ok().finish()
+        }
+        Ok(_) => AppError::NotFound(String::from("No such invitation")).error_response(),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// # Result
+///   - The invitation an unspent link names
+/// # Errors
+///   - `AppError` if the link is bad, spent or withdrawn, or redis or
+///     Mongo are unreachable
+async fn invitation_for(
+    db: &Database,
+    redis_pool: &Pool,
+    key_ring: &KeyRing,
+    token: &str,
+) -> Result<Invitation, AppError> {
+    let mut redis_conn = redis_pool
+        .get()
+        .await
+        .map_err(|err| AppError::Backend(format!("Error getting redis connection: {err}")))?;
+
+    let invitation_id = check_invitation_token(token, &mut redis_conn, key_ring)
+        .await
+        .map_err(|err| {
+            warn!("Invitation link rejected: {err}");
+            AppError::Validation(String::from(INVALID_LINK))
+        })?;
+
+    match InvitationRepo::new(db).find_live(invitation_id).await {
+        Ok(invitation) => Ok(invitation),
+        Err(RepoError::NotFound(_)) => {
+            warn!("Invitation link names a withdrawn invitation");
+            Err(AppError::Validation(String::from(INVALID_LINK)))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/invitations/accept")]
+#[instrument(
+    name = "Accept invitation page",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, query)
+)]
+pub async fn accept_invitation_page(
+    query: web::Query<InvitationToken>,
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+) -> HttpResponse {
+    match invitation_for(pool.as_ref(), redis_pool.as_ref(), &key_ring, &query.token).await {
+        Ok(invitation) => render_page(&AcceptInvitationPage {
+            title: "Accept invitation",
+            token: &query.token,
+            first_name: &invitation.first_name,
+            email: &invitation.email,
+        }),
+        Err(err) => err.error_response(),
+    }
+}
+
+/// Makes the invited account, with its grants in place, and spends the link
+#[post("/invitations/accept")]
+#[instrument(
+    name = "Accept invitation",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, settings, input)
+)]
+pub async fn accept_invitation(
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    settings: Data<Settings>,
+    Form(input): Form<AcceptInvitation>,
+) -> HttpResponse {
+    if let Err(err) = check_passwords(&input.password, &input.password_2) {
+        return err.error_response();
+    }
+
+    let invitation =
+        match invitation_for(pool.as_ref(), redis_pool.as_ref(), &key_ring, &input.token).await {
+            Ok(invitation) => invitation,
+            Err(err) => return err.error_response(),
+        };
+
+    // Emails are unique, so a second submission of the form stops here
+    match UserRepo::new(pool.as_ref())
+        .create_invited_user(
+            CreateNewUser {
+                email: invitation.email,
+                password: input.password,
+                password_2: input.password_2,
+                first_name: invitation.first_name,
+                last_name: invitation.last_name,
+            },
+            invitation.grants,
+            settings.secret.argon2,
+        )
+        .await
+    {
+        Ok(_) => info!("Invited account created"),
+        Err(RepoError::Conflict(_)) => {
+            return AppError::Conflict(String::from("An account already uses that email"))
+                .error_response();
+        }
+        Err(err) => return AppError::from(err).error_response(),
+    }
+
+    match redis_pool.get().await {
+        Ok(mut redis_conn) => {
+            if let Err(err) =
+                verify_invitation_token(&input.token, &mut redis_conn, &key_ring).await
+            {
+                warn!("Invitation link not spent: {err}");
+            }
+        }
+        Err(err) => error!("Invitation link not spent: {err}"),
+    }
+    if let Some(invitation_id) = invitation.id {
+        if let Err(err) = InvitationRepo::new(pool.as_ref())
+            .remove(invitation_id)
+            .await
+        {
+            error!("Accepted invitation not removed: {err}");
+        }
+    }
+
+    render_page(&LoginPage {
+        title: "Welcome",
+        next: "",
+    })
+}
+
+#[cfg(test)]
+mod test_invitations {
+    use super::*;
+
+    fn pairs(extra: &[(&str, String)]) -> Vec<(String, String)> {
+        [
+            ("email", String::from(" nana@example.com ")),
+            ("first_name", String::from("Nana")),
+            ("last_name", String::from("Hunter")),
+        ]
+        .iter()
+        .chain(extra)
+        .map(|(name, value)| ((*name).to_string(), value.clone()))
+        .collect()
+    }
+
+    #[test]
+    fn test_ticked_records_become_grants() {
+        let (aj, cj) = (ObjectId::new(), ObjectId::new());
+        let invitation = NewInvitation::from_pairs(pairs(&[
+            ("records", format!("{aj}:medical")),
+            ("records", format!("{aj}:photos")),
+            ("records", format!("{cj}:school")),
+            ("edit", cj.to_hex()),
+        ]))
+        .expect("form parses");
+
+        assert_eq!(invitation.email, "nana@example.com");
+        assert_eq!(
+            invitation.grants,
+            vec![
+                ChildGrant {
+                    child_id: aj,
+                    records: vec![Records::Medical, Records::Photos],
+                    can_edit: false,
+                },
+                ChildGrant {
+                    child_id: cj,
+                    records: vec![Records::School],
+                    can_edit: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invitations_must_share_something_known() {
+        assert!(NewInvitation::from_pairs(pairs(&[])).is_err());
+        assert!(NewInvitation::from_pairs(pairs(&[(
+            "records",
+            format!("{}:diary", ObjectId::new())
+        )]))
+        .is_err());
+        assert!(
+            NewInvitation::from_pairs(pairs(&[("records", String::from("aj:medical"))])).is_err()
+        );
+    }
+}
@@ -1,42 +1,60 @@
 use actix_session::Session;
 use actix_web::{
     get,
-    http::StatusCode,
-    post,
-    rt::task,
-    web::{Data, Form},
-    HttpResponse,
+    http::{
+        header::{HeaderValue, RETRY_AFTER},
+        StatusCode,
+    },
+    post, rt,
+    web::{Data, Form, Query},
+    HttpRequest, HttpResponse, ResponseError,
 };
 use askama::Template;
+use chrono::Utc;
 use mongodb::{bson::oid::ObjectId, Database};
 use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
-    auth::hash::verify_pw,
+    auth::{
+        csrf::bind_csrf_tokens,
+        guard::safe_next,
+        hash::{needs_rehash, pw, verify_pw},
+        store::{client_ip, Device},
+        throttle::{LoginThrottle, Throttled, LOCKOUT_SECONDS},
+    },
     endpoints::{
-        error::render_error,
-        structure::Login,
-        templates::{ErrorPage, Index, LoginPage},
+        error::{render_error, AppError},
+        index::index_body,
+        structure::{HashUpgrade, Login, LoginQuery, PendingLogin, SecondFactor},
+        templates::{render_page, LoginPage, TwoFactorLoginPage},
+        two_factor::check_second_factor,
     },
-    models::mongo::{MongoRepo, User},
+    models::mongo::{User, UserRepo},
+    settings::{Argon2Cost, Settings},
     types::Types,
+    utils::emails::send_lockout_email,
 };
 
-#[allow(clippy::future_not_send)]
+/// Wrong codes allowed before the password must be entered again
+const MAX_SECOND_FACTOR_ATTEMPTS: u8 = 5;
+
 #[get("/")]
 #[instrument(
     name = "Login page",
     level = "info",
     target = "kid_data",
-    skip(_db, session)
+    skip(_db, query)
 )]
-pub async fn login(session: Session, _db: Data<Database>) -> HttpResponse {
+pub async fn login(_db: Data<Database>, query: Query<LoginQuery>) -> HttpResponse {
     info!("Rendering login page");
 
-    warn!("The Session: {:#?}", session.status());
-
     let template = LoginPage {
         title: "Child Data",
+        next: query
+            .next
+            .as_deref()
+            .and_then(safe_next)
+            .unwrap_or_default(),
     };
 
     let body = match template.render() {
@@ -57,88 +75,361 @@ pub async fn login(session: Session, _db: Data<Database>) -> HttpResponse {
     name = "Login user",
     level = "debug",
     target = "kid_data",
-    skip(pool, user, session)
+    skip(pool, throttle, settings, user, session, req)
 )]
 pub async fn login_user(
     pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    settings: Data<Settings>,
     Form(user): Form<Login>,
     session: Session,
+    req: HttpRequest,
 ) -> HttpResponse {
     info!("Login endpoint");
-    // Authorization logic
-
-    let tasker = |registered_user: User| {
-        debug!("Creating spawn_blocking task to verify password.");
-        task::spawn_blocking(move || {
-            let registered_creds = registered_user.password;
-            let user_attempt = user.password;
-            verify_pw(registered_creds, user_attempt)
-        })
-    };
+    let ip = client_ip(&req);
+    // Checked before the password is hashed, so guesses cost nothing
+    match throttle.check(&user.email, &ip).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return throttled_response(throttled),
+        Err(err) => {
+            return AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                .error_response()
+        }
+    }
 
-    let pool = MongoRepo::new(&pool.as_ref().to_owned(), None);
+    // Authorization logic
+    let db = pool.as_ref().to_owned();
+    let pool = UserRepo::new(&db);
 
     match pool.get_user(None, Some(&user.email)).await {
-        Ok(logged_in_user) => match tasker(logged_in_user.clone())
-            .await
-            .expect("Async blocking failed")
-            .await
-        {
-            Ok(()) => {
-                info!("User logged in successfully.");
-                debug!("Renewing cookie session");
-                session.renew();
-                match session.insert(
-                    Types::UserIdKey,
-                    logged_in_user.id.expect("No DB ID found").to_string(),
-                ) {
-                    Ok(()) => {
-                        info!("`user_id` inserted into session");
-                        debug!("Changing user Active state to active");
-                        pool.toggle_activity(logged_in_user.id.expect("No ID found"), true)
-                            .await
-                            .expect("User activity not updated");
+        Ok(logged_in_user) => {
+            let verification =
+                verify_pw(logged_in_user.password.clone(), user.password.clone()).await;
+            let cost = settings.secret.argon2;
+
+            match verification {
+                Ok(()) if logged_in_user.email_verified_at.is_none() => {
+                    warn!("Login refused until the email is verified");
+                    AppError::Unauthorized(String::from(
+                        "Verify your email address before logging in",
+                    ))
+                    .error_response()
+                }
+                Ok(()) if logged_in_user.totp_secret.is_some() => {
+                    info!("Password accepted; asking for the second factor");
+                    let upgrade = rehash(&logged_in_user, user.password, cost).await;
+                    ask_for_second_factor(&session, &logged_in_user, &user.next, upgrade)
+                }
+                Ok(()) => {
+                    info!("User logged in successfully.");
+                    let response =
+                        start_session(&session, &db, &throttle, &logged_in_user, &req, &user.next)
+                            .await;
+                    if response.status().is_success() {
+                        rt::spawn(async move {
+                            if let Some(upgrade) =
+                                rehash(&logged_in_user, user.password, cost).await
+                            {
+                                upgrade_hash(db, logged_in_user, upgrade).await;
+                            }
+                        });
                     }
-                    Err(err) => error!("`user_id` cannot be inserted into session: {err:#?}"),
+                    response
                 }
+                Err(err) => {
+                    error!("Basic User login failed: {err:#?}",);
+                    count_failure(&throttle, &user.email, &ip, Some(&logged_in_user)).await;
+                    AppError::Unauthorized(String::from("Invalid email or password"))
+                        .error_response()
+                }
+            }
+        }
+        Err(err) => {
+            warn!("PW verification failed");
+            error!("User login failed: {err:#?}");
+            count_failure(&throttle, &user.email, &ip, None).await;
 
-                // match session.insert(types::USER_EMAIL_KEY, logged_in_user.email) {
-                //     Ok(()) => info!("`user_email` inserted into session"),
-                //     Err(err) => error!("`user_email` cannot be inserted into session: {err:#?}"),
-                // }
-
-                warn!("Session set: {:#?}", session.entries());
+            AppError::Unauthorized(String::from("Invalid email or password")).error_response()
+        }
+    }
+}
 
-                let template = Index {
-                    title: "Child Data",
-                };
+/// # Result
+///   - `password` hashed at `cost`, if `user`'s stored hash was made at
+///     another cost and the new hash could be made
+async fn rehash(user: &User, password: String, cost: Argon2Cost) -> Option<HashUpgrade> {
+    if !needs_rehash(&user.password, &cost) {
+        return None;
+    }
+    match pw(password, cost).await {
+        Ok(fresh) => Some(HashUpgrade {
+            stale: user.password.clone(),
+            fresh,
+        }),
+        Err(err) => {
+            error!("Password hash not upgraded: {err}");
+            None
+        }
+    }
+}
 
-                let body = template.render().expect("Index template rendering");
+/// Replaces a password hash made at an older argon2 cost. Only called once
+/// the login it came from has fully succeeded.
+#[instrument(
+    name = "Upgrade password hash",
+    level = "info",
+    target = "kid_data",
+    skip_all
+)]
+async fn upgrade_hash(db: Database, user: User, upgrade: HashUpgrade) {
+    let Some(user_id) = user.id else {
+        error!("Stored user has no ID");
+        return;
+    };
+    match UserRepo::new(&db)
+        .upgrade_password(user_id, &upgrade.stale, &upgrade.fresh)
+        .await
+    {
+        Ok(result) if result.modified_count == 1 => info!("Password hash upgraded"),
+        // The password was changed meanwhile; that hash is current
+        Ok(_) => debug!("Password hash already replaced"),
+        Err(err) => error!("Password hash not upgraded: {err}"),
+    }
+}
 
-                HttpResponse::Ok()
-                    .content_type("text/html")
-                    .append_header(("Authorization", "Bearer token"))
-                    .body(body)
+/// Signs `user` in on `session` and sends them to `next`, or home
+#[allow(clippy::future_not_send)]
+#[instrument(name = "Start session", level = "debug", target = "kid_data", skip_all)]
+pub(crate) async fn start_session(
+    session: &Session,
+    db: &Database,
+    throttle: &LoginThrottle,
+    user: &User,
+    req: &HttpRequest,
+    next: &str,
+) -> HttpResponse {
+    let Some(user_id) = user.id else {
+        return AppError::Backend(String::from("Stored user has no ID")).error_response();
+    };
+    if let Err(err) = throttle.record_success(&user.email).await {
+        error!("Failed logins not cleared: {err}");
+    }
+    debug!("Renewing cookie session");
+    session.renew();
+    session.remove(&Types::PendingLoginKey.to_string());
+    if let Err(err) = bind_csrf_tokens(session) {
+        error!("CSRF tokens not bound to the session: {err:#?}");
+    }
+    if let Err(err) = session.insert(Types::SessionVersionKey, user.session_version) {
+        error!("`session_version` cannot be inserted into session: {err:#?}");
+    }
+    if let Err(err) = session.insert(Types::DeviceKey, Device::from_request(req)) {
+        error!("`device` cannot be inserted into session: {err:#?}");
+    }
+    match session.insert(Types::UserIdKey, user_id.to_string()) {
+        Ok(()) => {
+            info!("`user_id` inserted into session");
+            debug!("Changing user Active state to active");
+            if let Err(err) = UserRepo::new(db).toggle_activity(user_id, true).await {
+                error!("User activity not updated: {err}");
             }
-            Err(err) => {
-                error!("Basic User login failed: {err:#?}",);
-                render_error(
-                    StatusCode::UNAUTHORIZED,
-                    "Invalid email or password",
-                    Some("login error"),
-                )
+        }
+        Err(err) => error!("`user_id` cannot be inserted into session: {err:#?}"),
+    }
+
+    // match session.insert(types::USER_EMAIL_KEY, logged_in_user.email) {
+    //     Ok(()) => info!("`user_email` inserted into session"),
+    //     Err(err) => error!("`user_email` cannot be inserted into session: {err:#?}"),
+    // }
+
+    if let Some(next) = safe_next(next).filter(|next| *next != "/") {
+        debug!("Returning to {next}");
+        return HttpResponse::Ok()
+            .insert_header(("HX-Redirect", next))
+            .finish();
+    }
+
+    match index_body(db, user, "Child Data").await {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => AppError::Backend(format!("Index template rendering: {err}")).error_response(),
+    }
+}
+
+/// Counts a failed login, and tells the owner if it locked their account
+#[instrument(
+    name = "Count failed login",
+    level = "debug",
+    target = "kid_data",
+    skip_all
+)]
+pub(crate) async fn count_failure(
+    throttle: &LoginThrottle,
+    email: &str,
+    ip: &str,
+    user: Option<&User>,
+) {
+    match throttle.record_failure(email, ip).await {
+        Ok(true) => {
+            if let Some(user) = user {
+                rt::spawn(send_lockout(user.clone()));
             }
-        },
+        }
+        Ok(false) => {}
+        Err(err) => error!("Failed login not counted: {err}"),
+    }
+}
+
+#[instrument(
+    name = "Send lockout notice",
+    level = "info",
+    target = "kid_data",
+    skip_all
+)]
+async fn send_lockout(user: User) {
+    if let Err(err) = send_lockout_email(
+        user.email,
+        user.first_name,
+        user.last_name,
+        LOCKOUT_SECONDS / 60,
+    )
+    .await
+    {
+        error!("Error sending lockout email: {err}");
+    }
+}
+
+/// # Result
+///   - A 429 telling the client how long to wait
+pub(crate) fn throttled_response(throttled: Throttled) -> HttpResponse {
+    let message = match throttled {
+        Throttled::Wait(seconds) => {
+            format!("Too many failed logins. Try again in {seconds} seconds")
+        }
+        Throttled::Locked(seconds) => format!(
+            "Too many failed logins. This account is locked for {} more minutes",
+            (seconds + 59) / 60
+        ),
+    };
+    let mut response = AppError::TooManyRequests(message).error_response();
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(throttled.retry_after()));
+    response
+}
+
+/// Remembers that `user` got their password right and asks for a code
+fn ask_for_second_factor(
+    session: &Session,
+    user: &User,
+    next: &str,
+    upgrade: Option<HashUpgrade>,
+) -> HttpResponse {
+    let Some(user_id) = user.id else {
+        return AppError::Backend(String::from("Stored user has no ID")).error_response();
+    };
+    session.renew();
+    let pending = PendingLogin {
+        user_id,
+        next: next.to_string(),
+        started_at: Utc::now().timestamp(),
+        attempts: 0,
+        upgrade,
+    };
+    if let Err(err) = session.insert(Types::PendingLoginKey, pending) {
+        return AppError::Backend(format!("Unable to hold the login open: {err}")).error_response();
+    }
+
+    render_page(&TwoFactorLoginPage {
+        title: "Two-factor check",
+    })
+}
+
+/// The second login step: a code from the user's authenticator app, or one
+/// of their recovery codes
+#[allow(clippy::future_not_send)]
+#[post("/login/two_factor")]
+#[instrument(
+    name = "Login second factor",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, throttle, session, req, input)
+)]
+pub async fn login_two_factor(
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    session: Session,
+    req: HttpRequest,
+    Form(input): Form<SecondFactor>,
+) -> HttpResponse {
+    let key = Types::PendingLoginKey.to_string();
+    let pending = match session.get::<PendingLogin>(&key) {
+        Ok(Some(pending)) if !pending.expired(Utc::now().timestamp()) => pending,
+        Ok(_) | Err(_) => {
+            session.remove(&key);
+            return AppError::Unauthorized(String::from("Log in again to continue"))
+                .error_response();
+        }
+    };
+
+    let users = UserRepo::new(pool.as_ref());
+    let user = match users.get_user(Some(pending.user_id), None).await {
+        Ok(user) => user,
         Err(err) => {
-            warn!("PW verification failed");
-            error!("User login failed: {err:#?}");
+            session.remove(&key);
+            return AppError::from(err).error_response();
+        }
+    };
 
-            render_error(
-                StatusCode::UNAUTHORIZED,
-                "Invalid email or password",
-                Some("login error"),
+    let ip = client_ip(&req);
+    match throttle.check(&user.email, &ip).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return throttled_response(throttled),
+        Err(err) => {
+            return AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                .error_response()
+        }
+    }
+
+    match check_second_factor(&users, &user, &input.code).await {
+        Ok(true) => {
+            let response = start_session(
+                &session,
+                pool.as_ref(),
+                &throttle,
+                &user,
+                &req,
+                &pending.next,
             )
+            .await;
+            if let Some(upgrade) = pending.upgrade.filter(|_| response.status().is_success()) {
+                rt::spawn(upgrade_hash(pool.as_ref().clone(), user, upgrade));
+            }
+            response
         }
+        Ok(false) => {
+            warn!("Wrong second factor");
+            count_failure(&throttle, &user.email, &ip, Some(&user)).await;
+            let attempts = pending.attempts + 1;
+            if attempts >= MAX_SECOND_FACTOR_ATTEMPTS {
+                session.remove(&key);
+                return AppError::Unauthorized(String::from(
+                    "Too many wrong codes. Log in again to continue",
+                ))
+                .error_response();
+            }
+            if let Err(err) = session.insert(
+                Types::PendingLoginKey,
+                PendingLogin {
+                    attempts,
+                    ..pending
+                },
+            ) {
+                error!("Unable to count the attempt: {err}");
+                session.remove(&key);
+            }
+            AppError::Unauthorized(String::from("That code is not right")).error_response()
+        }
+        Err(err) => err.error_response(),
     }
 }
 
@@ -156,19 +447,18 @@ pub async fn logout(session: Session, pool: Data<Database>) -> HttpResponse {
         Ok(user_id) => {
             info!("User retreived from db.");
             session.purge();
-            let pool = MongoRepo::new(&pool.as_ref().to_owned(), None);
+            let pool = UserRepo::new(pool.as_ref());
             match pool.toggle_activity(user_id, false).await {
                 Ok(_) => info!("user activity updated"),
                 Err(err) => {
-                    if pool
+                    let still_active = pool
                         .get_user(Some(user_id), None)
                         .await
-                        .expect("User not found")
-                        .is_active
-                        .expect("No activity available")
-                    {
+                        .is_ok_and(|user| user.is_active.unwrap_or(false));
+                    if still_active {
                         let template = LoginPage {
                             title: "Child Data",
+                            next: "",
                         };
 
                         let body = match template.render() {
@@ -191,6 +481,7 @@ pub async fn logout(session: Session, pool: Data<Database>) -> HttpResponse {
             }
             let template = LoginPage {
                 title: "Child Data",
+                next: "",
             };
 
             let body = match template.render() {
@@ -207,18 +498,11 @@ pub async fn logout(session: Session, pool: Data<Database>) -> HttpResponse {
         Err(err) => {
             error!("Failed to get user from session: {err:#?}");
 
-            let error_template = ErrorPage {
-                title: "Child Data",
-                code: 500,
-                message: "Unable to logout user. Please try again.",
-                error: "Internal Server Error",
-            };
-
-            let body = error_template.render().expect("Error template rendering");
-
-            HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body(body)
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to logout user. Please try again.",
+                Some("Internal Server Error"),
+            )
         }
     }
 }
@@ -229,36 +513,10 @@ pub async fn logout(session: Session, pool: Data<Database>) -> HttpResponse {
     target = "kid_data",
     skip(session)
 )]
-fn session_user_id(session: &Session) -> Result<ObjectId, String> {
+pub(crate) fn session_user_id(session: &Session) -> Result<ObjectId, String> {
     info!("Retrieving user ID from session");
     match session.get(&Types::UserIdKey.to_string()) {
         Ok(user_id) => user_id.map_or_else(|| Err("You are not authenticated".to_string()), Ok),
         Err(err) => Err(err.to_string()),
     }
 }
-
-#[instrument(
-    name = "Send to login page if no cookie",
-    level = "info",
-    target = "kid_data",
-    skip(session)
-)]
-pub fn validate_session(session: Session) -> Option<HttpResponse> {
-    if session.entries().is_empty() {
-        let template = LoginPage {
-            title: "Not logged in",
-        };
-
-        let body = match template.render() {
-            Ok(body) => body,
-            Err(err) => {
-                error!("Failed to render login page: {err:#?}",);
-                return Some(HttpResponse::InternalServerError().finish());
-            }
-        };
-        info!("Login page rendered successfully");
-
-        return Some(HttpResponse::Ok().content_type("text/html").body(body));
-    }
-    None
-}
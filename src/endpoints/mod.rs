@@ -1,11 +1,23 @@
-pub mod adrian;
-pub mod corbin;
-mod error;
+pub mod api_tokens;
+pub mod children;
+pub mod dentist;
+pub mod devices;
+pub mod doctor;
+pub mod error;
 pub mod health;
 pub mod images;
+pub mod immunizations;
 pub mod index;
+pub mod invitations;
 pub mod login;
+pub mod passkeys;
+pub mod password;
+pub mod photos;
+pub mod profile;
+pub mod providers;
 pub mod register;
+pub mod school;
 mod structure;
 pub mod templates;
+pub mod two_factor;
 pub mod users;
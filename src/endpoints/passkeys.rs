@@ -0,0 +1,383 @@
+use actix_session::Session;
+use actix_web::{
+    delete, get, post,
+    web::{Data, Form, Path},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::Utc;
+use mongodb::Database;
+use tracing::{error, info, instrument, warn};
+use webauthn_rs::prelude::{Passkey, PublicKeyCredential, RegisterPublicKeyCredential, Webauthn};
+
+use crate::{
+    auth::{
+        guard::AuthenticatedUser,
+        passkeys::{credential_id, relying_party, user_handle, StoredPasskey},
+        store::client_ip,
+        throttle::LoginThrottle,
+    },
+    endpoints::{
+        error::AppError,
+        login::{count_failure, start_session, throttled_response},
+        structure::{
+            PasskeyLogin, PasskeyName, PasskeyResponse, PendingPasskey, PendingPasskeyLogin,
+        },
+        templates::{render_page, PasskeysPage},
+    },
+    models::mongo::UserRepo,
+    settings::Settings,
+    types::Types,
+};
+
+const TITLE: &str = "Passkeys";
+
+/// Longest name a passkey may be given
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Given for every passkey login that cannot start, so the answer does not
+/// reveal which accounts exist
+const NO_PASSKEY: &str = "No passkey can log in to this account";
+
+/// # Result
+///   - The relying party passkeys are made for and checked against
+/// # Errors
+///   - A logged 500 if `frontend_url` cannot be used
+fn webauthn(settings: &Settings) -> Result<Webauthn, HttpResponse> {
+    relying_party(settings).map_err(|err| AppError::Backend(err).error_response())
+}
+
+#[get("/users/passkeys")]
+#[instrument(
+    name = "Passkeys page",
+    level = "info",
+    target = "kid_data",
+    skip(user)
+)]
+pub async fn passkeys_page(user: AuthenticatedUser) -> HttpResponse {
+    render_page(&PasskeysPage {
+        title: TITLE,
+        passkeys: &user.user.passkeys,
+    })
+}
+
+/// Asks the browser to make a passkey; answers with the options for
+/// `navigator.credentials.create`
+#[allow(clippy::future_not_send)]
+#[post("/users/passkeys/register/start")]
+#[instrument(
+    name = "Start passkey registration",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, settings, input)
+)]
+pub async fn start_passkey_registration(
+    user: AuthenticatedUser,
+    session: Session,
+    settings: Data<Settings>,
+    Form(input): Form<PasskeyName>,
+) -> HttpResponse {
+    let name = input.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return AppError::Validation(format!(
+            "Give the passkey a name of 1 to {MAX_NAME_LENGTH} characters"
+        ))
+        .error_response();
+    }
+
+    let webauthn = match webauthn(&settings) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    // The browser refuses to make a second passkey on the same authenticator
+    let existing = user
+        .user
+        .passkeys
+        .iter()
+        .map(|stored| stored.passkey.cred_id().clone())
+        .collect();
+    let (challenge, state) = match webauthn.start_passkey_registration(
+        user_handle(user.id),
+        &user.user.email,
+        &format!("{} {}", user.user.first_name, user.user.last_name),
+        Some(existing),
+    ) {
+        Ok(started) => started,
+        Err(err) => {
+            return AppError::Backend(format!("Unable to start passkey registration: {err}"))
+                .error_response()
+        }
+    };
+
+    let pending = PendingPasskey {
+        name: name.to_string(),
+        state,
+        started_at: Utc::now().timestamp(),
+    };
+    if let Err(err) = session.insert(Types::PasskeyRegistrationKey, pending) {
+        return AppError::Backend(format!("Unable to hold the registration open: {err}"))
+            .error_response();
+    }
+
+    HttpResponse::Ok().json(challenge)
+}
+
+/// Saves the passkey the browser made
+#[allow(clippy::future_not_send)]
+#[post("/users/passkeys/register/finish")]
+#[instrument(
+    name = "Finish passkey registration",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, pool, settings, input)
+)]
+pub async fn finish_passkey_registration(
+    user: AuthenticatedUser,
+    session: Session,
+    pool: Data<Database>,
+    settings: Data<Settings>,
+    Form(input): Form<PasskeyResponse>,
+) -> HttpResponse {
+    let pending =
+        match session.remove_as::<PendingPasskey>(&Types::PasskeyRegistrationKey.to_string()) {
+            Some(Ok(pending)) if !pending.expired(Utc::now().timestamp()) => pending,
+            _ => {
+                return AppError::Validation(String::from("Start adding the passkey again"))
+                    .error_response()
+            }
+        };
+    let Ok(credential) = serde_json::from_str::<RegisterPublicKeyCredential>(&input.credential)
+    else {
+        return AppError::Validation(String::from("The browser's answer was not understood"))
+            .error_response();
+    };
+
+    let webauthn = match webauthn(&settings) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let passkey = match webauthn.finish_passkey_registration(&credential, &pending.state) {
+        Ok(passkey) => passkey,
+        Err(err) => {
+            warn!("Passkey registration refused: {err}");
+            return AppError::Validation(String::from("The passkey could not be added"))
+                .error_response();
+        }
+    };
+
+    let stored = StoredPasskey::new(pending.name, passkey);
+    match UserRepo::new(pool.as_ref())
+        .add_passkey(user.id, &stored)
+        .await
+    {
+        Ok(_) => {
+            info!("Passkey added");
+            let mut passkeys = user.user.passkeys;
+            passkeys.push(stored);
+            render_page(&PasskeysPage {
+                title: TITLE,
+                passkeys: &passkeys,
+            })
+        }
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+#[delete("/users/passkeys/{id}")]
+#[instrument(
+    name = "Remove passkey",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn remove_passkey(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    path: Path<String>,
+) -> HttpResponse {
+    match UserRepo::new(pool.as_ref())
+        .remove_passkey(user.id, &path)
+        .await
+    {
+        Ok(result) if result.modified_count == 1 => {
+            info!("Passkey removed");
+            HttpResponse::Ok().finish()
+        }
+        Ok(_) => AppError::NotFound(String::from("Passkey not found")).error_response(),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// The first step of a passkey login, in place of the password: answers
+/// with the options for `navigator.credentials.get`
+#[allow(clippy::future_not_send)]
+#[post("/login/passkey/start")]
+#[instrument(
+    name = "Start passkey login",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, throttle, session, settings, req, input)
+)]
+pub async fn start_passkey_login(
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    session: Session,
+    settings: Data<Settings>,
+    req: HttpRequest,
+    Form(input): Form<PasskeyLogin>,
+) -> HttpResponse {
+    match throttle.check(&input.email, &client_ip(&req)).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return throttled_response(throttled),
+        Err(err) => {
+            return AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                .error_response()
+        }
+    }
+
+    let user = match UserRepo::new(pool.as_ref())
+        .get_user(None, Some(&input.email))
+        .await
+    {
+        Ok(user) if !user.passkeys.is_empty() => user,
+        Ok(_) | Err(_) => {
+            warn!("No passkey to log in with");
+            return AppError::Unauthorized(String::from(NO_PASSKEY)).error_response();
+        }
+    };
+    let Some(user_id) = user.id else {
+        return AppError::Backend(String::from("Stored user has no ID")).error_response();
+    };
+
+    let webauthn = match webauthn(&settings) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let passkeys: Vec<Passkey> = user
+        .passkeys
+        .iter()
+        .map(|stored| stored.passkey.clone())
+        .collect();
+    let (challenge, state) = match webauthn.start_passkey_authentication(&passkeys) {
+        Ok(started) => started,
+        Err(err) => {
+            return AppError::Backend(format!("Unable to start passkey login: {err}"))
+                .error_response()
+        }
+    };
+
+    let pending = PendingPasskeyLogin {
+        user_id,
+        next: input.next,
+        state,
+        started_at: Utc::now().timestamp(),
+    };
+    if let Err(err) = session.insert(Types::PasskeyLoginKey, pending) {
+        return AppError::Backend(format!("Unable to hold the login open: {err}")).error_response();
+    }
+
+    HttpResponse::Ok().json(challenge)
+}
+
+/// Logs in once the browser signs the challenge with one of the user's
+/// passkeys. A passkey proves both possession and the user's PIN or
+/// biometric, so two-factor is not asked for as well.
+#[allow(clippy::future_not_send)]
+#[post("/login/passkey/finish")]
+#[instrument(
+    name = "Finish passkey login",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, throttle, session, settings, req, input)
+)]
+pub async fn finish_passkey_login(
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    session: Session,
+    settings: Data<Settings>,
+    req: HttpRequest,
+    Form(input): Form<PasskeyResponse>,
+) -> HttpResponse {
+    let pending =
+        match session.remove_as::<PendingPasskeyLogin>(&Types::PasskeyLoginKey.to_string()) {
+            Some(Ok(pending)) if !pending.expired(Utc::now().timestamp()) => pending,
+            _ => {
+                return AppError::Unauthorized(String::from("Log in again to continue"))
+                    .error_response()
+            }
+        };
+
+    let users = UserRepo::new(pool.as_ref());
+    let user = match users.get_user(Some(pending.user_id), None).await {
+        Ok(user) => user,
+        Err(err) => return AppError::from(err).error_response(),
+    };
+    let ip = client_ip(&req);
+    match throttle.check(&user.email, &ip).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return throttled_response(throttled),
+        Err(err) => {
+            return AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                .error_response()
+        }
+    }
+
+    let Ok(credential) = serde_json::from_str::<PublicKeyCredential>(&input.credential) else {
+        return AppError::Validation(String::from("The browser's answer was not understood"))
+            .error_response();
+    };
+    let webauthn = match webauthn(&settings) {
+        Ok(webauthn) => webauthn,
+        Err(response) => return response,
+    };
+    let result = match webauthn.finish_passkey_authentication(&credential, &pending.state) {
+        Ok(result) => result,
+        Err(err) => {
+            warn!("Passkey login refused: {err}");
+            count_failure(&throttle, &user.email, &ip, Some(&user)).await;
+            return AppError::Unauthorized(String::from("The passkey was not accepted"))
+                .error_response();
+        }
+    };
+
+    let signed_with = credential_id(result.cred_id());
+    let Some(stored) = user.passkeys.iter().find(|stored| stored.id == signed_with) else {
+        return AppError::Unauthorized(String::from(NO_PASSKEY)).error_response();
+    };
+    // Keeps the sign count current, so a cloned authenticator is noticed
+    let mut passkey = stored.passkey.clone();
+    passkey.update_credential(&result);
+    match users
+        .use_passkey(pending.user_id, &stored.id, &passkey)
+        .await
+    {
+        Ok(update) if update.matched_count == 1 => {}
+        Ok(_) => {
+            warn!("Passkey removed during login");
+            return AppError::Unauthorized(String::from(NO_PASSKEY)).error_response();
+        }
+        Err(err) => {
+            error!("Passkey use not saved: {err}");
+            return AppError::from(err).error_response();
+        }
+    }
+
+    if user.email_verified_at.is_none() {
+        warn!("Passkey login refused until the email is verified");
+        return AppError::Unauthorized(String::from("Verify your email address before logging in"))
+            .error_response();
+    }
+
+    // No TOTP step: webauthn-rs only accepts passkey assertions made with
+    // user verification, so the device and its PIN or biometric are already
+    // two factors
+    info!("User logged in with a passkey");
+    start_session(
+        &session,
+        pool.as_ref(),
+        &throttle,
+        &user,
+        &req,
+        &pending.next,
+    )
+    .await
+}
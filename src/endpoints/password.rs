@@ -0,0 +1,271 @@
+use actix_web::{
+    get, post, rt,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use deadpool_redis::Pool;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::Deserialize;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        keys::KeyRing,
+        tokens::{check_confirmation_token, verify_confirmation_token_pasetor},
+    },
+    endpoints::{
+        error::AppError,
+        templates::{render_page, ChangePasswordPage, LoginPage, PasswordResetPage},
+    },
+    models::{
+        mongo::{User, UserRepo},
+        repository::RepoError,
+    },
+    settings::Settings,
+    utils::emails::{send_multipart_email, PASSWORD_RESET_TEMPLATE},
+};
+
+/// Shortest password a reset will accept
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Shown for every bad, spent or expired reset link
+const INVALID_LINK: &str = "This reset link is invalid or has expired";
+
+/// # Errors
+///   - `AppError::Validation` if the passwords differ or are too short
+pub(crate) fn check_passwords(password: &str, password_2: &str) -> Result<(), AppError> {
+    if password != password_2 {
+        return Err(AppError::Validation(String::from("Passwords do not match")));
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::Validation(format!(
+            "Passwords need at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetToken {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    pub token: String,
+    pub password: String,
+    pub password_2: String,
+}
+
+impl NewPassword {
+    /// # Errors
+    ///   - `AppError::Validation` if the passwords differ or are too short
+    fn check(&self) -> Result<(), AppError> {
+        check_passwords(&self.password, &self.password_2)
+    }
+}
+
+#[get("/users/password/reset")]
+#[instrument(name = "Password reset page", level = "info", target = "kid_data")]
+pub async fn password_reset_page() -> HttpResponse {
+    render_page(&PasswordResetPage {
+        title: "Reset Password",
+        sent: false,
+    })
+}
+
+/// Always answers with the same page, so the response does not reveal
+/// whether an account uses the email
+#[post("/users/password/reset")]
+#[instrument(
+    name = "Request password reset",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, request)
+)]
+pub async fn request_password_reset(
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    Form(request): Form<ResetRequest>,
+) -> HttpResponse {
+    info!("Password reset requested");
+
+    match UserRepo::new(pool.as_ref())
+        .get_user(None, Some(request.email.trim()))
+        .await
+    {
+        Ok(user) => {
+            // Sent in the background so the reply takes as long either way
+            if let Some(user_id) = user.id {
+                rt::spawn(send_reset_email(redis_pool, key_ring, user_id, user));
+            } else {
+                error!("Stored user has no ID");
+            }
+        }
+        Err(RepoError::NotFound(_)) => debug!("No account for the reset request"),
+        Err(err) => error!("Unable to look up the account to reset: {err}"),
+    }
+
+    render_page(&PasswordResetPage {
+        title: "Reset Password",
+        sent: true,
+    })
+}
+
+#[instrument(
+    name = "Send password reset email",
+    level = "info",
+    target = "kid_data",
+    skip(redis_pool, key_ring, user)
+)]
+async fn send_reset_email(
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    user_id: ObjectId,
+    user: User,
+) {
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Error getting redis connection: {err}");
+            return;
+        }
+    };
+
+    if let Err(err) = send_multipart_email(
+        String::from("Child Data - Reset your password"),
+        user_id,
+        user.email,
+        user.first_name,
+        user.last_name,
+        PASSWORD_RESET_TEMPLATE,
+        &mut redis_conn,
+        &key_ring,
+    )
+    .await
+    {
+        error!("Error sending password reset email: {err}");
+    }
+}
+
+#[get("/users/password/confirm/change_password")]
+#[instrument(
+    name = "Change password page",
+    level = "info",
+    target = "kid_data",
+    skip(redis_pool, key_ring, query)
+)]
+pub async fn change_password_page(
+    query: web::Query<ResetToken>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+) -> HttpResponse {
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            return AppError::Backend(format!("Error getting redis connection: {err}"))
+                .error_response();
+        }
+    };
+
+    if let Err(err) =
+        check_confirmation_token(&query.token, &mut redis_conn, &key_ring, Some(true)).await
+    {
+        warn!("Reset link rejected: {err}");
+        return AppError::Validation(String::from(INVALID_LINK)).error_response();
+    }
+
+    render_page(&ChangePasswordPage {
+        title: "Change Password",
+        token: &query.token,
+    })
+}
+
+/// Spends the reset token, stores the new password and signs out every
+/// session the account had
+#[post("/users/password/confirm/change_password")]
+#[instrument(
+    name = "Change password",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, settings, input)
+)]
+pub async fn change_password(
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    settings: Data<Settings>,
+    Form(input): Form<NewPassword>,
+) -> HttpResponse {
+    if let Err(err) = input.check() {
+        return err.error_response();
+    }
+
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            return AppError::Backend(format!("Error getting redis connection: {err}"))
+                .error_response();
+        }
+    };
+
+    let token = match verify_confirmation_token_pasetor(
+        &input.token,
+        &mut redis_conn,
+        &key_ring,
+        Some(true),
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(err) => {
+            warn!("Reset link rejected: {err}");
+            return AppError::Validation(String::from(INVALID_LINK)).error_response();
+        }
+    };
+
+    match UserRepo::new(pool.as_ref())
+        .reset_password(token.user_id, input.password, settings.secret.argon2)
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => {
+            info!("Password changed");
+            render_page(&LoginPage {
+                title: "Password changed",
+                next: "",
+            })
+        }
+        Ok(_) => AppError::NotFound(String::from("Account not found")).error_response(),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod test_password {
+    use super::*;
+
+    fn new_password(password: &str, password_2: &str) -> NewPassword {
+        NewPassword {
+            token: String::new(),
+            password: password.to_string(),
+            password_2: password_2.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_passwords_must_match() {
+        assert!(new_password("long enough", "long enougH").check().is_err());
+    }
+
+    #[test]
+    fn test_passwords_must_be_long_enough() {
+        assert!(new_password("short", "short").check().is_err());
+        assert!(new_password("long enough", "long enough").check().is_ok());
+    }
+}
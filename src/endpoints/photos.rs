@@ -0,0 +1,720 @@
+use std::path::{Path, PathBuf};
+
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Query},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::Local;
+use futures_util::TryStreamExt;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        children::child_for,
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        templates::{PhotoDetail, PhotoGallery, PhotoGrid},
+    },
+    models::{
+        child::{Child, ChildRepo},
+        helpers::non_empty,
+        photo::{process_image, thumbnail_name, Photo, PhotoRepo, DEFAULT_ALBUM, PAGE_SIZE},
+    },
+    settings::{Media, Settings},
+};
+
+/// All things regarding uploaded pictures, albums, and thumbnails
+
+#[derive(Debug, Default, Serialize)]
+pub struct PhotoTile {
+    pub caption: String,
+    pub date: String,
+    pub db_id: ObjectId,
+}
+
+impl From<&Photo> for PhotoTile {
+    fn from(photo: &Photo) -> Self {
+        Self {
+            caption: photo.caption.clone().unwrap_or_default(),
+            date: photo.taken_at.format("%b %d, %Y").to_string(),
+            db_id: photo.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// Album filter and page requested by the gallery
+#[derive(Debug, Deserialize)]
+pub struct GalleryQuery {
+    pub album: Option<String>,
+    pub page: Option<u64>,
+}
+
+impl GalleryQuery {
+    fn album(&self) -> Option<String> {
+        self.album.as_deref().and_then(non_empty)
+    }
+}
+
+/// Fields read from an upload form
+#[derive(Debug, Default)]
+struct Upload {
+    album: Option<String>,
+    caption: Option<String>,
+    files: Vec<Vec<u8>>,
+}
+
+/// Why an uploaded file could not be stored
+#[derive(Debug)]
+enum StoreError {
+    NotAnImage(image::ImageError),
+    Io(std::io::Error),
+}
+
+/// # Result
+///   - The directory a child's photos are written to
+fn child_dir(settings: &Settings, child_id: ObjectId) -> PathBuf {
+    Path::new(&settings.media.path).join(child_id.to_hex())
+}
+
+/// # Result
+///   - The next page number when `count` filled the current page
+const fn next_page(page: u64, count: usize) -> Option<u64> {
+    if count as u64 == PAGE_SIZE {
+        Some(page + 1)
+    } else {
+        None
+    }
+}
+
+/// # Result
+///   - The album, caption, and file bytes sent with the upload form
+/// # Errors
+///   - A rendered 400 page for a malformed body, 413 when a file, the whole
+///     upload, or the number of photos is over the `limits`
+#[allow(clippy::future_not_send)]
+#[instrument(
+    name = "Read upload",
+    level = "debug",
+    target = "kid_data",
+    skip(payload, limits)
+)]
+async fn read_upload(mut payload: Multipart, limits: &Media) -> Result<Upload, HttpResponse> {
+    let malformed = |err: actix_multipart::MultipartError| {
+        warn!("Malformed upload: {err}");
+        render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to read upload",
+            Some(&err.to_string()),
+        )
+    };
+    let too_large = |reason: &str| {
+        render_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Unable to read upload",
+            Some(reason),
+        )
+    };
+
+    let mut upload = Upload::default();
+    let mut total = 0;
+
+    while let Some(mut field) = payload.try_next().await.map_err(malformed)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "photos" && upload.files.len() == limits.max_upload_files {
+            warn!("Upload has more than {} photos", limits.max_upload_files);
+            return Err(too_large(&format!(
+                "Upload at most {} photos at a time",
+                limits.max_upload_files
+            )));
+        }
+        let mut bytes = Vec::new();
+
+        while let Some(chunk) = field.try_next().await.map_err(malformed)? {
+            if bytes.len() + chunk.len() > limits.max_upload_bytes {
+                warn!(
+                    "Upload field {name} is over {} bytes",
+                    limits.max_upload_bytes
+                );
+                return Err(too_large("The file is too large"));
+            }
+            total += chunk.len();
+            if total > limits.max_request_bytes {
+                warn!("Upload is over {} bytes", limits.max_request_bytes);
+                return Err(too_large("The photos are too large to upload together"));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        match name.as_str() {
+            "album" => upload.album = non_empty(&String::from_utf8_lossy(&bytes)),
+            "caption" => upload.caption = non_empty(&String::from_utf8_lossy(&bytes)),
+            "photos" if !bytes.is_empty() => upload.files.push(bytes),
+            _ => debug!("Ignoring upload field {name}"),
+        }
+    }
+
+    Ok(upload)
+}
+
+/// # Result
+///   - The photo record after the original and thumbnail are written to disk
+/// # Errors
+///   - A rendered 415 page when the bytes are not an image, 500 when writing fails
+#[instrument(
+    name = "Store photo",
+    level = "debug",
+    target = "kid_data",
+    skip(bytes, dir)
+)]
+async fn store_photo(
+    bytes: Vec<u8>,
+    dir: PathBuf,
+    child_id: ObjectId,
+    album: String,
+    caption: Option<String>,
+    uploaded_by: Option<ObjectId>,
+) -> Result<Photo, HttpResponse> {
+    let stored = web::block(move || {
+        let processed = process_image(&bytes).map_err(StoreError::NotAnImage)?;
+
+        let extension = processed
+            .format
+            .extensions_str()
+            .first()
+            .copied()
+            .unwrap_or("img");
+        let file_name = format!("{}.{extension}", Uuid::new_v4());
+
+        std::fs::create_dir_all(&dir)
+            .and_then(|()| std::fs::write(dir.join(&file_name), &bytes))
+            .and_then(|()| {
+                std::fs::write(dir.join(thumbnail_name(&file_name)), &processed.thumbnail)
+            })
+            .map_err(StoreError::Io)?;
+
+        Ok((processed, file_name))
+    })
+    .await;
+
+    let (processed, file_name) = match stored {
+        Ok(Ok(result)) => result,
+        Ok(Err(StoreError::NotAnImage(err))) => {
+            warn!("Upload is not a supported image: {err}");
+            return Err(render_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unable to save photo",
+                Some("Only JPEG, PNG, GIF, and WebP images are supported"),
+            ));
+        }
+        Ok(Err(StoreError::Io(err))) => {
+            error!("Unable to write photo to disk: {err}");
+            return Err(render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save photo",
+                Some(TRY_AGAIN),
+            ));
+        }
+        Err(err) => {
+            error!("Photo processing was cancelled: {err}");
+            return Err(render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save photo",
+                Some(TRY_AGAIN),
+            ));
+        }
+    };
+
+    let now = Local::now().naive_local();
+    Ok(Photo {
+        id: None,
+        child_id,
+        album,
+        caption,
+        uploaded_by,
+        uploaded_at: now,
+        taken_at: processed.taken_at.unwrap_or(now),
+        content_type: processed.format.to_mime_type().to_string(),
+        file_name,
+        width: processed.width,
+        height: processed.height,
+    })
+}
+
+/// # Result
+///   - The rendered gallery page for the child
+#[instrument(
+    name = "Render gallery",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, child),
+    fields(slug = %child.slug)
+)]
+async fn render_gallery(pool: &Database, child: &Child, album: Option<&str>) -> HttpResponse {
+    let repo = PhotoRepo::new(pool);
+    let child_id = child.id.unwrap_or_default();
+
+    let (photos, albums) = match (
+        repo.get_photos_for_child(child_id, album, 0).await,
+        repo.get_albums(child_id).await,
+    ) {
+        (Ok(photos), Ok(albums)) => (photos, albums),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Unable to load photos: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load photos",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = PhotoGallery {
+        title: "Photos",
+        name: &child.full_name(),
+        age: child.age(),
+        slug: &child.slug,
+        album: album.unwrap_or_default(),
+        albums,
+        next_page: next_page(0, photos.len()),
+        tiles: photos.iter().map(PhotoTile::from).collect(),
+        child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The child with the matching slug, if `user` may do `permission` to
+///     its photos
+/// # Errors
+///   - A rendered error page when no child uses the slug or access is refused
+async fn child_by_slug(
+    pool: &Database,
+    slug: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<Child, HttpResponse> {
+    child_for(pool, slug, user, Some(Records::Photos), permission).await
+}
+
+/// # Result
+///   - The photo and the child it belongs to, if `user` may do `permission`
+///     to the child's photos
+/// # Errors
+///   - A rendered error page when either lookup fails or access is refused
+#[instrument(
+    name = "Photo with child",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+async fn photo_with_child(
+    pool: &Database,
+    id: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<(Photo, Child), HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    let photo = PhotoRepo::new(pool).get_photo(id).await.map_err(|err| {
+        error!("Unable to find the data for the ID passed in");
+        AppError::from(err).error_response()
+    })?;
+
+    let child = ChildRepo::new(pool)
+        .get_child_by_id(photo.child_id)
+        .await
+        .map_err(|err| {
+            error!("Photo belongs to a missing child");
+            AppError::from(err).error_response()
+        })?;
+    authorize(&user.user, &child, Some(Records::Photos), permission)
+        .map_err(|err| err.error_response())?;
+
+    Ok((photo, child))
+}
+
+/// # Result
+///   - The stored file streamed back to the browser
+#[instrument(
+    name = "Serve media",
+    level = "debug",
+    target = "kid_data",
+    skip(req, path)
+)]
+fn serve_file(req: &HttpRequest, path: PathBuf) -> HttpResponse {
+    match NamedFile::open(path) {
+        Ok(file) => file.use_last_modified(true).into_response(req),
+        Err(err) => {
+            error!("Error opening file: {err:#?}");
+            render_error(
+                StatusCode::NOT_FOUND,
+                "Photo not found",
+                Some("The file for this photo is missing"),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/photo_logs/{slug}")]
+#[instrument(
+    name = "photo gallery",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn photo_gallery(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    query: Query<GalleryQuery>,
+) -> HttpResponse {
+    match child_by_slug(pool.as_ref(), &slug, &user, Permission::View).await {
+        Ok(child) => render_gallery(pool.as_ref(), &child, query.album().as_deref()).await,
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[get("/photo_logs/{slug}/page")]
+#[instrument(
+    name = "photo page",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn photo_page(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    query: Query<GalleryQuery>,
+) -> HttpResponse {
+    let child = match child_by_slug(pool.as_ref(), &slug, &user, Permission::View).await {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let album = query.album();
+    let page = query.page.unwrap_or_default();
+
+    let photos = match PhotoRepo::new(pool.as_ref())
+        .get_photos_for_child(child.id.unwrap_or_default(), album.as_deref(), page)
+        .await
+    {
+        Ok(photos) => photos,
+        Err(err) => {
+            error!("Unable to load photos: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load photos",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = PhotoGrid {
+        slug: &child.slug,
+        album: album.as_deref().unwrap_or_default(),
+        next_page: next_page(page, photos.len()),
+        tiles: photos.iter().map(PhotoTile::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[post("/photo_logs/{slug}")]
+#[instrument(
+    name = "upload photos",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, settings, payload)
+)]
+pub async fn upload_photos(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    settings: Settings,
+    slug: web::Path<String>,
+    payload: Multipart,
+) -> HttpResponse {
+    let uploaded_by = Some(user.id);
+
+    let child = match child_by_slug(pool.as_ref(), &slug, &user, Permission::Edit).await {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let upload = match read_upload(payload, &settings.media).await {
+        Ok(upload) if upload.files.is_empty() => {
+            return render_error(
+                StatusCode::BAD_REQUEST,
+                "Unable to save photo",
+                Some("No photo was attached"),
+            );
+        }
+        Ok(upload) => upload,
+        Err(http_resp) => return http_resp,
+    };
+
+    let child_id = child.id.unwrap_or_default();
+    let album = upload
+        .album
+        .clone()
+        .unwrap_or_else(|| DEFAULT_ALBUM.to_string());
+    let repo = PhotoRepo::new(pool.as_ref());
+
+    for bytes in upload.files {
+        let photo = match store_photo(
+            bytes,
+            child_dir(&settings, child_id),
+            child_id,
+            album.clone(),
+            upload.caption.clone(),
+            uploaded_by,
+        )
+        .await
+        {
+            Ok(photo) => photo,
+            Err(http_resp) => return http_resp,
+        };
+
+        if let Err(err) = repo.create_photo(&photo).await {
+            error!("Unable to save photo: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save photo",
+                Some(TRY_AGAIN),
+            );
+        }
+    }
+
+    info!("Photos uploaded");
+    render_gallery(pool.as_ref(), &child, Some(&album)).await
+}
+
+#[get("/photo/{id}")]
+#[instrument(
+    name = "photo detail",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn photo_detail(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (photo, child) = match photo_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    let template = PhotoDetail {
+        id: &id,
+        slug: &child.slug,
+        album: &photo.album,
+        caption: photo.caption.as_deref().unwrap_or_default(),
+        taken: photo.taken_at.format("%A %B %d, %Y %I:%M %p").to_string(),
+        uploaded: photo.uploaded_at.format("%B %d, %Y").to_string(),
+        width: photo.width,
+        height: photo.height,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[delete("/photo/{id}")]
+#[instrument(
+    name = "delete photo",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, settings)
+)]
+pub async fn delete_photo(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    settings: Settings,
+) -> HttpResponse {
+    let (photo, child) = match photo_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    if let Err(err) = PhotoRepo::new(pool.as_ref())
+        .delete_photo(photo.id.unwrap_or_default())
+        .await
+    {
+        error!("Unable to delete photo: {err}");
+        return render_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to delete photo",
+            Some(TRY_AGAIN),
+        );
+    }
+
+    let dir = child_dir(&settings, photo.child_id);
+    for file in [photo.file_name.clone(), photo.thumbnail_name()] {
+        if let Err(err) = std::fs::remove_file(dir.join(&file)) {
+            warn!("Unable to remove {file}: {err}");
+        }
+    }
+
+    info!("Photo deleted");
+    render_gallery(pool.as_ref(), &child, Some(&photo.album)).await
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/media/{id}")]
+#[instrument(
+    name = "photo original",
+    level = "info",
+    target = "kid_data",
+    skip(user, req, pool, settings)
+)]
+pub async fn photo_original(
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    settings: Settings,
+) -> HttpResponse {
+    match photo_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((photo, _)) => serve_file(
+            &req,
+            child_dir(&settings, photo.child_id).join(&photo.file_name),
+        ),
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/media/{id}/thumb")]
+#[instrument(
+    name = "photo thumbnail",
+    level = "info",
+    target = "kid_data",
+    skip(user, req, pool, settings)
+)]
+pub async fn photo_thumbnail(
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    settings: Settings,
+) -> HttpResponse {
+    match photo_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((photo, _)) => serve_file(
+            &req,
+            child_dir(&settings, photo.child_id).join(photo.thumbnail_name()),
+        ),
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[cfg(test)]
+mod test_photos {
+    use std::fmt::Write;
+
+    use actix_web::{
+        http::header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+        web::Bytes,
+    };
+    use futures_util::stream;
+
+    use super::*;
+
+    const BOUNDARY: &str = "photo-boundary";
+
+    fn limits() -> Media {
+        Media {
+            path: String::from("./media"),
+            max_upload_bytes: 8,
+            max_request_bytes: 12,
+            max_upload_files: 2,
+        }
+    }
+
+    /// A multipart body with one `photos` field per file
+    fn payload(files: &[&str]) -> Multipart {
+        let mut body = String::new();
+        for (index, file) in files.iter().enumerate() {
+            write!(
+                body,
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"photos\"; \
+                 filename=\"{index}.jpg\"\r\nContent-Type: image/jpeg\r\n\r\n{file}\r\n"
+            )
+            .expect("writing to a string succeeds");
+        }
+        write!(body, "--{BOUNDARY}--\r\n").expect("writing to a string succeeds");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={BOUNDARY}"))
+                .expect("header is valid"),
+        );
+        Multipart::new(
+            &headers,
+            stream::iter([Ok::<_, actix_web::error::PayloadError>(Bytes::from(body))]),
+        )
+    }
+
+    fn status(result: Result<Upload, HttpResponse>) -> StatusCode {
+        result.map_or_else(|response| response.status(), |_| StatusCode::OK)
+    }
+
+    #[actix_web::test]
+    async fn test_uploads_within_the_limits_are_read() {
+        let upload = read_upload(payload(&["abcd", "efgh"]), &limits())
+            .await
+            .expect("upload is read");
+        assert_eq!(upload.files, vec![b"abcd".to_vec(), b"efgh".to_vec()]);
+    }
+
+    #[actix_web::test]
+    async fn test_uploads_over_the_limits_are_refused() {
+        assert_eq!(
+            status(read_upload(payload(&["abcdefghi"]), &limits()).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status(read_upload(payload(&["abcdefg", "hijklmn"]), &limits()).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status(read_upload(payload(&["a", "b", "c"]), &limits()).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
@@ -0,0 +1,657 @@
+use std::{path::PathBuf, time::Duration as StdDuration};
+
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_session::Session;
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post, rt,
+    web::{self, Data, Form, Query},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::{Duration, Utc};
+use deadpool_redis::Pool;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{oid::ObjectId, DateTime},
+    Database,
+};
+use serde::Deserialize;
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        api_tokens::LIFETIME_DAYS,
+        guard::AuthenticatedUser,
+        hash::verify_pw,
+        keys::KeyRing,
+        policy::Role,
+        store::client_ip,
+        throttle::LoginThrottle,
+        tokens::{issue_email_change_token, verify_email_change_token},
+    },
+    endpoints::{
+        error::{render_error, AppError},
+        login::{count_failure, throttled_response},
+        password::check_passwords,
+        structure::{ConfirmPassword, EmailChange, PasswordChange, ProfileName},
+        templates::{render_page, LoginPage, ProfilePage},
+    },
+    models::{
+        api_token::ApiTokenRepo,
+        mongo::{User, UserRepo},
+        photo::process_image,
+        repository::RepoError,
+    },
+    settings::Settings,
+    types::Types,
+    utils::emails::send_email_change_email,
+};
+
+const TITLE: &str = "Your profile";
+
+/// Days a closed account is kept, so its owner can change their mind
+pub const DELETION_GRACE_DAYS: i64 = 14;
+
+/// How often accounts whose grace period ran out are deleted
+const PURGE_INTERVAL: StdDuration = StdDuration::from_hours(1);
+
+/// Shown for every bad, spent or expired email change link
+const INVALID_LINK: &str = "This confirmation link is invalid or has expired";
+
+#[derive(Deserialize, Debug)]
+pub struct EmailChangeToken {
+    pub token: String,
+}
+
+/// # Result
+///   - The directory avatars are written to
+fn avatar_dir(media_path: &str) -> PathBuf {
+    PathBuf::from(media_path).join("avatars")
+}
+
+/// Deletes an avatar file on the blocking pool
+/// # Errors
+///   - ``String`` if the file cannot be removed
+async fn remove_avatar(path: PathBuf) -> Result<(), String> {
+    web::block(move || std::fs::remove_file(path))
+        .await
+        .map_err(|err| format!("Avatar removal was cancelled: {err}"))?
+        .map_err(|err| err.to_string())
+}
+
+/// # Result
+///   - The trimmed address to move the account to
+/// # Errors
+///   - `AppError::Validation` if it is not an address, or is the one the
+///     account already uses
+fn check_new_email(email: &str, current: &str) -> Result<String, AppError> {
+    let email = email.trim();
+    if !email.contains('@') {
+        return Err(AppError::Validation(String::from(
+            "An email address is needed",
+        )));
+    }
+    if email.eq_ignore_ascii_case(current) {
+        return Err(AppError::Validation(String::from(
+            "The account already uses that email",
+        )));
+    }
+    Ok(email.to_string())
+}
+
+/// # Result
+///   - The profile of the user with `user_id`, as it is stored now
+async fn render_profile(pool: &Database, user_id: ObjectId, notice: &str) -> HttpResponse {
+    render_profile_with_token(pool, user_id, notice, "").await
+}
+
+/// # Result
+///   - The profile of the user with `user_id`, showing `issued` once if an
+///     API token was just made. Only admins can call the account API, so
+///     only they are offered tokens.
+#[instrument(
+    name = "Render profile",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, notice, issued)
+)]
+pub(crate) async fn render_profile_with_token(
+    pool: &Database,
+    user_id: ObjectId,
+    notice: &str,
+    issued: &str,
+) -> HttpResponse {
+    let user = match UserRepo::new(pool).get_user(Some(user_id), None).await {
+        Ok(user) => user,
+        Err(err) => return AppError::from(err).error_response(),
+    };
+
+    let api_access = user.role == Role::Admin;
+    let tokens = if api_access {
+        match ApiTokenRepo::new(pool).for_user(user_id).await {
+            Ok(tokens) => tokens,
+            Err(err) => return AppError::from(err).error_response(),
+        }
+    } else {
+        Vec::new()
+    };
+
+    render_page(&ProfilePage {
+        title: TITLE,
+        user: &user,
+        notice,
+        grace_days: DELETION_GRACE_DAYS,
+        api_access,
+        tokens: &tokens,
+        lifetimes: &LIFETIME_DAYS,
+        issued,
+    })
+}
+
+/// Asks for the password again before a change to the account. Wrong
+/// passwords count toward the login lockout, so a session left open cannot
+/// be used to guess it.
+/// # Errors
+///   - A 401, or 429 once the account is locked out
+#[allow(clippy::future_not_send)]
+async fn confirm_password(
+    throttle: &LoginThrottle,
+    req: &HttpRequest,
+    user: &User,
+    password: &str,
+) -> Result<(), HttpResponse> {
+    let ip = client_ip(req);
+    match throttle.check(&user.email, &ip).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return Err(throttled_response(throttled)),
+        Err(err) => {
+            return Err(
+                AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                    .error_response(),
+            )
+        }
+    }
+
+    if verify_pw(user.password.clone(), password.to_string())
+        .await
+        .is_err()
+    {
+        warn!("Profile change refused: wrong password");
+        count_failure(throttle, &user.email, &ip, Some(user)).await;
+        return Err(
+            AppError::Unauthorized(String::from("Your current password is not right"))
+                .error_response(),
+        );
+    }
+    Ok(())
+}
+
+/// # Result
+///   - The bytes of the `avatar` field of the upload form
+/// # Errors
+///   - A rendered 400 page for a malformed body or no file, 413 when the
+///     file is too large
+#[allow(clippy::future_not_send)]
+async fn read_avatar(mut payload: Multipart, max_bytes: usize) -> Result<Vec<u8>, HttpResponse> {
+    let malformed = |err: actix_multipart::MultipartError| {
+        warn!("Malformed upload: {err}");
+        render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to read upload",
+            Some(&err.to_string()),
+        )
+    };
+
+    let mut bytes = Vec::new();
+    while let Some(mut field) = payload.try_next().await.map_err(malformed)? {
+        let is_avatar = field.name() == Some("avatar");
+        while let Some(chunk) = field.try_next().await.map_err(malformed)? {
+            if !is_avatar {
+                continue;
+            }
+            if bytes.len() + chunk.len() > max_bytes {
+                warn!("Avatar is over {max_bytes} bytes");
+                return Err(render_error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Unable to read upload",
+                    Some("The file is too large"),
+                ));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+    }
+
+    if bytes.is_empty() {
+        return Err(render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to save picture",
+            Some("No picture was attached"),
+        ));
+    }
+    Ok(bytes)
+}
+
+#[get("/users/profile")]
+#[instrument(
+    name = "Profile page",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn profile_page(user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    render_profile(pool.as_ref(), user.id, "").await
+}
+
+#[post("/users/profile/name")]
+#[instrument(
+    name = "Update name",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn update_name(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    Form(input): Form<ProfileName>,
+) -> HttpResponse {
+    let (first_name, last_name) = (input.first_name.trim(), input.last_name.trim());
+    if first_name.is_empty() || last_name.is_empty() {
+        return AppError::Validation(String::from("A first and last name are needed"))
+            .error_response();
+    }
+
+    match UserRepo::new(pool.as_ref())
+        .update_name(user.id, first_name, last_name)
+        .await
+    {
+        Ok(_) => render_profile(pool.as_ref(), user.id, "Name saved").await,
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Stores a thumbnail of the uploaded picture as the user's avatar
+#[allow(clippy::future_not_send)]
+#[post("/users/profile/avatar")]
+#[instrument(
+    name = "Upload avatar",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, settings, payload)
+)]
+pub async fn upload_avatar(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    settings: Data<Settings>,
+    payload: Multipart,
+) -> HttpResponse {
+    let bytes = match read_avatar(payload, settings.media.max_upload_bytes).await {
+        Ok(bytes) => bytes,
+        Err(http_resp) => return http_resp,
+    };
+
+    let dir = avatar_dir(&settings.media.path);
+    let file_name = format!("{}.jpg", Uuid::new_v4());
+    let path = dir.join(&file_name);
+    let stored = web::block(move || {
+        let processed = process_image(&bytes).map_err(|err| err.to_string())?;
+        std::fs::create_dir_all(&dir)
+            .and_then(|()| std::fs::write(path, processed.thumbnail))
+            .map_err(|err| {
+                error!("Unable to write avatar to disk: {err}");
+                String::from("The picture could not be saved")
+            })
+    })
+    .await;
+    match stored {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            warn!("Avatar not stored: {err}");
+            return render_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unable to save picture",
+                Some("Only JPEG, PNG, GIF, and WebP images are supported"),
+            );
+        }
+        Err(err) => {
+            return AppError::Backend(format!("Avatar processing was cancelled: {err}"))
+                .error_response()
+        }
+    }
+
+    if let Err(err) = UserRepo::new(pool.as_ref())
+        .set_thumbnail(user.id, &file_name)
+        .await
+    {
+        return AppError::from(err).error_response();
+    }
+    if let Some(old) = &user.user.thumbnail {
+        if let Err(err) = remove_avatar(avatar_dir(&settings.media.path).join(old)).await {
+            warn!("Old avatar not removed: {err}");
+        }
+    }
+
+    info!("Avatar changed");
+    render_profile(pool.as_ref(), user.id, "Picture changed").await
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/users/profile/avatar")]
+#[instrument(
+    name = "Avatar",
+    level = "debug",
+    target = "kid_data",
+    skip(user, settings, req)
+)]
+pub async fn avatar(
+    user: AuthenticatedUser,
+    settings: Data<Settings>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let Some(thumbnail) = user.user.thumbnail.as_deref() else {
+        return AppError::NotFound(String::from("No picture has been added")).error_response();
+    };
+    // Only ever a name this server gave, but never a path out of the directory
+    let Some(file_name) = std::path::Path::new(thumbnail).file_name() else {
+        return AppError::NotFound(String::from("No picture has been added")).error_response();
+    };
+
+    match NamedFile::open(avatar_dir(&settings.media.path).join(file_name)) {
+        Ok(file) => file.use_last_modified(true).into_response(&req),
+        Err(err) => {
+            error!("Error opening avatar: {err}");
+            AppError::NotFound(String::from("The picture is missing")).error_response()
+        }
+    }
+}
+
+/// Replaces the password once the current one is given, and signs out
+/// every other session the account had
+#[allow(clippy::future_not_send)]
+#[post("/users/profile/password")]
+#[instrument(
+    name = "Change password from profile",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, pool, throttle, settings, req, input)
+)]
+pub async fn change_profile_password(
+    user: AuthenticatedUser,
+    session: Session,
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    settings: Data<Settings>,
+    req: HttpRequest,
+    Form(input): Form<PasswordChange>,
+) -> HttpResponse {
+    if let Err(err) = check_passwords(&input.password, &input.password_2) {
+        return err.error_response();
+    }
+    if let Err(http_resp) =
+        confirm_password(&throttle, &req, &user.user, &input.current_password).await
+    {
+        return http_resp;
+    }
+
+    match UserRepo::new(pool.as_ref())
+        .reset_password(user.id, input.password, settings.secret.argon2)
+        .await
+    {
+        Ok(_) => {
+            // Keeps this session, which the reset would otherwise sign out
+            if let Err(err) =
+                session.insert(Types::SessionVersionKey, user.user.session_version + 1)
+            {
+                error!("`session_version` cannot be inserted into session: {err:#?}");
+            }
+            info!("Password changed from the profile");
+            render_profile(
+                pool.as_ref(),
+                user.id,
+                "Password changed. Your other devices have been logged out.",
+            )
+            .await
+        }
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Sends a link to the new address. The account keeps its current address
+/// until the link is followed.
+#[allow(clippy::future_not_send)]
+#[post("/users/profile/email")]
+#[instrument(
+    name = "Change email",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, redis_pool, key_ring, throttle, req, input)
+)]
+pub async fn change_email(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    throttle: Data<LoginThrottle>,
+    req: HttpRequest,
+    Form(input): Form<EmailChange>,
+) -> HttpResponse {
+    let email = match check_new_email(&input.email, &user.user.email) {
+        Ok(email) => email,
+        Err(err) => return err.error_response(),
+    };
+    if let Err(http_resp) = confirm_password(&throttle, &req, &user.user, &input.password).await {
+        return http_resp;
+    }
+
+    let users = UserRepo::new(pool.as_ref());
+    match users.get_user(None, Some(&email)).await {
+        Ok(_) => {
+            return AppError::Conflict(String::from("An account already uses that email"))
+                .error_response()
+        }
+        Err(RepoError::NotFound(_)) => {}
+        Err(err) => return AppError::from(err).error_response(),
+    }
+    if let Err(err) = users.request_email_change(user.id, &email).await {
+        return AppError::from(err).error_response();
+    }
+
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            return AppError::Backend(format!("Error getting redis connection: {err}"))
+                .error_response();
+        }
+    };
+    let token = match issue_email_change_token(user.id, &mut redis_conn, &key_ring).await {
+        Ok(token) => token,
+        Err(err) => {
+            return AppError::Backend(format!("Unable to issue the email change token: {err}"))
+                .error_response()
+        }
+    };
+
+    let recipient = email.clone();
+    let (first_name, last_name) = (user.user.first_name.clone(), user.user.last_name.clone());
+    rt::spawn(async move {
+        if let Err(err) = send_email_change_email(recipient, first_name, last_name, &token).await {
+            error!("Error sending email change email: {err}");
+        }
+    });
+
+    info!("Email change requested");
+    render_profile(
+        pool.as_ref(),
+        user.id,
+        &format!("We sent a link to {email}. Your email changes once you follow it."),
+    )
+    .await
+}
+
+/// Moves the account to the address the link was sent to. The link proves
+/// the owner reads it, so the account stays verified.
+#[get("/users/email/confirm")]
+#[instrument(
+    name = "Confirm email change",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, query)
+)]
+pub async fn confirm_email_change(
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    query: Query<EmailChangeToken>,
+) -> HttpResponse {
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            return AppError::Backend(format!("Error getting redis connection: {err}"))
+                .error_response();
+        }
+    };
+
+    let user_id = match verify_email_change_token(&query.token, &mut redis_conn, &key_ring).await {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            warn!("Email change link rejected: {err}");
+            return AppError::Validation(String::from(INVALID_LINK)).error_response();
+        }
+    };
+
+    match UserRepo::new(pool.as_ref())
+        .confirm_email_change(user_id)
+        .await
+    {
+        Ok(Some(_)) => {
+            info!("Email changed");
+            render_page(&LoginPage {
+                title: "Email changed",
+                next: "",
+            })
+        }
+        Ok(None) => AppError::Validation(String::from(INVALID_LINK)).error_response(),
+        Err(RepoError::Conflict(_)) => {
+            warn!("Email change lost to another account");
+            AppError::Conflict(String::from("An account already uses that email")).error_response()
+        }
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Closes the account, signing out all its sessions and revoking its API
+/// tokens. It is deleted once ``DELETION_GRACE_DAYS`` pass unless the owner
+/// logs in again and keeps it.
+#[allow(clippy::future_not_send)]
+#[post("/users/profile/delete")]
+#[instrument(
+    name = "Close account",
+    level = "info",
+    target = "kid_data",
+    skip(user, session, pool, throttle, req, input)
+)]
+pub async fn close_account(
+    user: AuthenticatedUser,
+    session: Session,
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    req: HttpRequest,
+    Form(input): Form<ConfirmPassword>,
+) -> HttpResponse {
+    if let Err(http_resp) = confirm_password(&throttle, &req, &user.user, &input.password).await {
+        return http_resp;
+    }
+
+    let at = DateTime::from_millis(
+        (Utc::now() + Duration::days(DELETION_GRACE_DAYS)).timestamp_millis(),
+    );
+    if let Err(err) = UserRepo::new(pool.as_ref())
+        .schedule_deletion(user.id, Some(at))
+        .await
+    {
+        return AppError::from(err).error_response();
+    }
+    if let Err(err) = ApiTokenRepo::new(pool.as_ref()).revoke_all(user.id).await {
+        return AppError::from(err).error_response();
+    }
+
+    info!("Account closed");
+    session.purge();
+    render_page(&LoginPage {
+        title: "Account closed",
+        next: "",
+    })
+}
+
+#[delete("/users/profile/delete")]
+#[instrument(
+    name = "Keep account",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn keep_account(user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    match UserRepo::new(pool.as_ref())
+        .schedule_deletion(user.id, None)
+        .await
+    {
+        Ok(_) => {
+            info!("Account kept");
+            render_profile(pool.as_ref(), user.id, "Your account will be kept").await
+        }
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Deletes, every ``PURGE_INTERVAL``, the accounts whose grace period ran
+/// out, along with their avatars. Runs for as long as the server does.
+#[instrument(
+    name = "Purge closed accounts",
+    level = "info",
+    target = "kid_data",
+    skip_all
+)]
+pub async fn purge_closed_accounts(db: Database, media_path: String) {
+    let users = UserRepo::new(&db);
+    let mut interval = rt::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let deleted = match users.delete_closed_accounts(DateTime::now()).await {
+            Ok(deleted) => deleted,
+            Err(err) => {
+                error!("Closed accounts not purged: {err}");
+                continue;
+            }
+        };
+
+        for user in deleted {
+            info!("Deleted closed account {:?}", user.id);
+            if let Some(thumbnail) = user.thumbnail {
+                if let Err(err) = remove_avatar(avatar_dir(&media_path).join(thumbnail)).await {
+                    debug!("Avatar of a deleted account not removed: {err}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_profile {
+    use super::*;
+
+    #[test]
+    fn test_new_email_must_be_an_address() {
+        assert!(check_new_email("not an address", "kid@example.com").is_err());
+        assert_eq!(
+            check_new_email(" new@example.com ", "kid@example.com").ok(),
+            Some(String::from("new@example.com"))
+        );
+    }
+
+    #[test]
+    fn test_new_email_must_differ() {
+        assert!(check_new_email("KID@example.com", "kid@example.com").is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use actix_web::{
+    get,
+    http::StatusCode,
+    post, put,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, instrument, warn};
+
+use crate::{
+    auth::guard::AuthenticatedUser,
+    endpoints::{
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        templates::{ProviderDetail, ProviderForm, Providers},
+    },
+    models::{
+        helpers::non_empty,
+        provider::{Provider, ProviderKind, ProviderRepo},
+    },
+};
+
+/// All things regarding pediatricians, dentists, orthodontists, and specialists
+
+#[derive(Debug, Default, Serialize)]
+pub struct ProviderCards {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    pub active: bool,
+    pub db_id: ObjectId,
+}
+
+impl From<&Provider> for ProviderCards {
+    fn from(provider: &Provider) -> Self {
+        Self {
+            name: provider.name.clone(),
+            description: provider.kind.to_string(),
+            image: provider.photo().to_string(),
+            active: provider.active,
+            db_id: provider.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// Form data submitted when creating or editing a provider.
+/// `active` is a checkbox, present only when ticked.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProviderInput {
+    pub kind: ProviderKind,
+    pub name: String,
+    pub practice: String,
+    pub email: String,
+    pub phone: String,
+    pub address: String,
+    pub photo: String,
+    pub active: Option<String>,
+}
+
+impl ProviderInput {
+    fn into_provider(self) -> Provider {
+        Provider {
+            id: None,
+            kind: self.kind,
+            name: self.name.trim().to_string(),
+            practice: self.practice.trim().to_string(),
+            email: self.email.trim().to_string(),
+            phone: self.phone.trim().to_string(),
+            address: self.address.trim().to_string(),
+            photo: non_empty(&self.photo),
+            active: self.active.is_some(),
+        }
+    }
+}
+
+/// # Result
+///   - ID, label, and selected state of every active provider of `kinds`, plus the
+///     `current` provider even when it has since been retired
+/// # Errors
+///   - A rendered error page when the providers fail to load
+#[instrument(
+    name = "Provider choices",
+    level = "debug",
+    target = "kid_data",
+    skip(pool)
+)]
+pub async fn provider_choices(
+    pool: &Database,
+    kinds: &[ProviderKind],
+    current: Option<ObjectId>,
+) -> Result<Vec<(String, String, bool)>, HttpResponse> {
+    let providers = ProviderRepo::new(pool)
+        .get_providers(kinds, false)
+        .await
+        .map_err(|err| {
+            error!("Unable to load providers: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load providers",
+                Some(TRY_AGAIN),
+            )
+        })?;
+
+    Ok(providers
+        .iter()
+        .filter(|provider| provider.active || provider.id == current)
+        .map(|provider| {
+            (
+                provider.id.unwrap_or_default().to_hex(),
+                format!("{} ({})", provider.name, provider.kind),
+                provider.id == current,
+            )
+        })
+        .collect())
+}
+
+/// # Result
+///   - The provider picked on a visit form
+/// # Errors
+///   - A rendered 400 page when the ID is bad or names a provider of another kind
+#[instrument(
+    name = "Picked provider",
+    level = "debug",
+    target = "kid_data",
+    skip(pool)
+)]
+pub async fn picked_provider(
+    pool: &Database,
+    id: &str,
+    kinds: &[ProviderKind],
+) -> Result<Provider, HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    match ProviderRepo::new(pool).get_provider(id).await {
+        Ok(provider) if kinds.contains(&provider.kind) => Ok(provider),
+        Ok(provider) => {
+            warn!("A {} cannot be picked here", provider.kind);
+            Err(render_error(
+                StatusCode::BAD_REQUEST,
+                "Wrong kind of provider",
+                Some(&format!("{} is a {}", provider.name, provider.kind)),
+            ))
+        }
+        Err(err) => {
+            warn!("Unknown provider picked: {err}");
+            Err(render_error(
+                StatusCode::BAD_REQUEST,
+                "Provider not found",
+                Some("The provider picked does not exist"),
+            ))
+        }
+    }
+}
+
+/// # Result
+///   - The provider's name, or a placeholder when it has been removed
+#[instrument(
+    name = "Provider name",
+    level = "debug",
+    target = "kid_data",
+    skip(pool)
+)]
+pub async fn provider_name(pool: &Database, id: ObjectId) -> String {
+    match ProviderRepo::new(pool).get_provider(id).await {
+        Ok(provider) => provider.name,
+        Err(err) => {
+            warn!("Visit points at a missing provider: {err}");
+            String::from("Unknown provider")
+        }
+    }
+}
+
+/// # Result
+///   - The rendered form, blank when `provider` is `None`
+#[instrument(
+    name = "Render provider form",
+    level = "debug",
+    target = "kid_data",
+    skip(provider)
+)]
+fn render_form(action: &str, provider: Option<&Provider>) -> HttpResponse {
+    let template = ProviderForm {
+        action,
+        editing: provider.is_some(),
+        kinds: ProviderKind::ALL
+            .iter()
+            .map(|kind| {
+                (
+                    kind.as_str(),
+                    kind.to_string(),
+                    provider.is_some_and(|provider| provider.kind == *kind),
+                )
+            })
+            .collect(),
+        name: provider.map(|p| p.name.as_str()).unwrap_or_default(),
+        practice: provider.map(|p| p.practice.as_str()).unwrap_or_default(),
+        email: provider.map(|p| p.email.as_str()).unwrap_or_default(),
+        phone: provider.map(|p| p.phone.as_str()).unwrap_or_default(),
+        address: provider.map(|p| p.address.as_str()).unwrap_or_default(),
+        photo: provider
+            .and_then(|p| p.photo.as_deref())
+            .unwrap_or_default(),
+        active: provider.is_none_or(|p| p.active),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered provider details
+#[instrument(
+    name = "Render provider",
+    level = "debug",
+    target = "kid_data",
+    skip(provider)
+)]
+fn render_provider(provider: &Provider) -> HttpResponse {
+    let id = provider.id.unwrap_or_default().to_hex();
+    let kind = provider.kind.to_string();
+
+    let template = ProviderDetail {
+        id: &id,
+        kind: &kind,
+        provider,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The provider with the ID from the path
+/// # Errors
+///   - A rendered error page when the ID is bad or unknown
+async fn provider_by_id(pool: &Database, id: &str) -> Result<Provider, HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    ProviderRepo::new(pool)
+        .get_provider(id)
+        .await
+        .map_err(|err| {
+            error!("Unable to find the data for the ID passed in");
+            AppError::from(err).error_response()
+        })
+}
+
+#[get("/providers")]
+#[instrument(
+    name = "provider directory",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool)
+)]
+pub async fn provider_directory(_user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    let providers = match ProviderRepo::new(pool.as_ref())
+        .get_providers(&[], false)
+        .await
+    {
+        Ok(providers) => providers,
+        Err(err) => {
+            error!("Unable to load providers: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load providers",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = Providers {
+        title: "Providers",
+        card_data: providers.iter().map(ProviderCards::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/providers/new")]
+#[instrument(
+    name = "provider form",
+    level = "info",
+    target = "kid_data",
+    skip(_user)
+)]
+pub async fn new_provider(_user: AuthenticatedUser) -> HttpResponse {
+    render_form("/providers", None)
+}
+
+#[post("/providers")]
+#[instrument(
+    name = "add provider",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool, input)
+)]
+pub async fn add_provider(
+    _user: AuthenticatedUser,
+    pool: Data<Database>,
+    Form(input): Form<ProviderInput>,
+) -> HttpResponse {
+    let mut provider = input.into_provider();
+    if provider.name.is_empty() {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add provider",
+            Some("A name is required"),
+        );
+    }
+
+    match ProviderRepo::new(pool.as_ref())
+        .create_provider(&provider)
+        .await
+    {
+        Ok(id) => {
+            debug!("Provider saved: {id}");
+            provider.id = Some(id);
+            render_provider(&provider)
+        }
+        Err(err) => {
+            error!("Unable to save provider: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save provider",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[get("/providers/{id}")]
+#[instrument(
+    name = "provider card",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool)
+)]
+pub async fn provider_card(
+    _user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match provider_by_id(pool.as_ref(), &id).await {
+        Ok(provider) => render_provider(&provider),
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[get("/providers/{id}/edit")]
+#[instrument(
+    name = "edit provider form",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool)
+)]
+pub async fn edit_provider(
+    _user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match provider_by_id(pool.as_ref(), &id).await {
+        Ok(provider) => render_form(&format!("/providers/{id}"), Some(&provider)),
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[put("/providers/{id}")]
+#[instrument(
+    name = "update provider",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool, input)
+)]
+pub async fn update_provider(
+    _user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<ProviderInput>,
+) -> HttpResponse {
+    let existing = match provider_by_id(pool.as_ref(), &id).await {
+        Ok(provider) => provider,
+        Err(http_resp) => return http_resp,
+    };
+
+    let provider = Provider {
+        id: existing.id,
+        ..input.into_provider()
+    };
+    if provider.name.is_empty() {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to update provider",
+            Some("A name is required"),
+        );
+    }
+
+    match ProviderRepo::new(pool.as_ref())
+        .update_provider(existing.id.unwrap_or_default(), provider.clone())
+        .await
+    {
+        Ok(_) => render_provider(&provider),
+        Err(err) => {
+            error!("Unable to update provider: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to update provider",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use actix_web::{
     get,
     http::StatusCode,
-    post,
+    post, rt,
     web::{self, Data, Form},
-    HttpResponse,
+    HttpResponse, ResponseError,
 };
 use askama::Template;
 use deadpool_redis::Pool;
@@ -12,17 +12,19 @@ use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
-    auth,
+    auth::{self, keys::KeyRing},
     endpoints::{
-        error::render_error,
-        templates::{ErrorPage, RegisterPage},
+        error::{render_error, AppError},
+        templates::{render_page, ErrorPage, LoginPage, RegisterPage, VerifyEmailPage},
     },
-    models::mongo::MongoRepo,
+    models::{
+        mongo::{User, UserRepo},
+        repository::RepoError,
+    },
+    settings::Settings,
     utils::emails::send_multipart_email,
 };
 
-use super::templates::Index;
-
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CreateNewUser {
     pub email: String,
@@ -32,10 +34,36 @@ pub struct CreateNewUser {
     pub last_name: String,
 }
 
+/// # Errors
+///   - An error page once anyone has an account. The first account owns the
+///     site; everyone after joins through an invitation.
+async fn registration_open(db: &Database) -> Result<(), HttpResponse> {
+    match UserRepo::new(db).has_accounts().await {
+        Ok(false) => Ok(()),
+        Ok(true) => {
+            warn!("Registration attempted after the first account");
+            Err(render_error(
+                StatusCode::FORBIDDEN,
+                "Registration closed",
+                Some("Ask a guardian to send you an invitation"),
+            ))
+        }
+        Err(err) => Err(AppError::from(err).error_response()),
+    }
+}
+
 #[get("/registration")]
-#[instrument(name = "Registration page", level = "info", target = "kid_data")]
-pub async fn registration() -> HttpResponse {
+#[instrument(
+    name = "Registration page",
+    level = "info",
+    target = "kid_data",
+    skip(pool)
+)]
+pub async fn registration(pool: Data<Database>) -> HttpResponse {
     info!("Rendering registration page");
+    if let Err(http_resp) = registration_open(pool.as_ref()).await {
+        return http_resp;
+    }
     let template = RegisterPage {
         title: "Registration",
     };
@@ -73,7 +101,7 @@ pub async fn registration() -> HttpResponse {
     name = "Register user",
     level = "info",
     target = "kid_data",
-    skip(pool, redis_pool, new_user),
+    skip(pool, redis_pool, key_ring, settings, new_user),
     fields(
 	email = %new_user.email,
 	first_name = %new_user.first_name,
@@ -83,10 +111,15 @@ pub async fn register(
     pool: Data<Database>,
     Form(new_user): Form<CreateNewUser>,
     redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    settings: Data<Settings>,
 ) -> HttpResponse {
     info!("register endpoint hit");
+    if let Err(http_resp) = registration_open(pool.as_ref()).await {
+        return http_resp;
+    }
 
-    let pool = MongoRepo::new(&pool.as_ref().to_owned(), None);
+    let pool = UserRepo::new(pool.as_ref());
 
     // Check if passwords match
     if new_user.password != new_user.password_2 {
@@ -97,12 +130,20 @@ pub async fn register(
             "Registration Error",
             Some("Passwords do not match"),
         );
-    };
+    }
 
-    let user_id = match pool.create_user(new_user.clone()).await {
+    let user_id = match pool
+        .create_user(new_user.clone(), settings.secret.argon2)
+        .await
+    {
         Ok(user_id) => {
             info!("User created successfully");
-            user_id.inserted_id
+            user_id
+        }
+        Err(RepoError::Conflict(_)) => {
+            warn!("Registration with an email already in use");
+            return AppError::Conflict(String::from("An account already uses that email"))
+                .error_response();
         }
         Err(err) => {
             error!("Error creating user: {err}");
@@ -110,46 +151,108 @@ pub async fn register(
         }
     };
 
+    if let Err(err) =
+        send_verification_email(&redis_pool, &key_ring, user_id, &User::from(new_user)).await
+    {
+        error!("Error sending email: {err}");
+        return render_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Registration Error",
+            Some("Your account was created but the verification email could not be sent. Ask for another from the resend page."),
+        );
+    }
+    info!("Email sent successfully");
+
+    render_page(&VerifyEmailPage {
+        title: "Verify Email",
+        sent: true,
+    })
+}
+
+/// # Errors
+///   - Err(String) if redis is unreachable or the email cannot be sent
+async fn send_verification_email(
+    redis_pool: &Pool,
+    key_ring: &KeyRing,
+    user_id: ObjectId,
+    user: &User,
+) -> Result<(), String> {
     let mut redis_conn = redis_pool
         .get()
         .await
-        .map_err(|err| {
-            error!("Error getting redis connection: {err}");
-            render_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Registration Error",
-                Some("Unable to send verification email"),
-            )
-        })
-        .expect("Error getting redis connection");
+        .map_err(|err| format!("Error getting redis connection: {err}"))?;
 
-    match send_multipart_email(
+    send_multipart_email(
         String::from("AJ's study site - Let's get you verified"),
-        user_id.as_object_id().expect("Error getting object id"),
-        new_user.email.clone(),
-        new_user.first_name.clone(),
-        new_user.last_name.clone(),
+        user_id,
+        user.email.clone(),
+        user.first_name.clone(),
+        user.last_name.clone(),
         "verification_email.html",
         &mut redis_conn,
+        key_ring,
     )
     .await
+}
+
+#[get("/register/resend")]
+#[instrument(name = "Resend verification page", level = "info", target = "kid_data")]
+pub async fn resend_verification_page() -> HttpResponse {
+    render_page(&VerifyEmailPage {
+        title: "Verify Email",
+        sent: false,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+/// Always answers with the same page, so the response does not reveal
+/// whether an account uses the email or is already verified
+#[post("/register/resend")]
+#[instrument(
+    name = "Resend verification",
+    level = "info",
+    target = "kid_data",
+    skip(pool, redis_pool, key_ring, request)
+)]
+pub async fn resend_verification(
+    pool: Data<Database>,
+    redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
+    Form(request): Form<ResendVerification>,
+) -> HttpResponse {
+    info!("Verification email requested");
+
+    match UserRepo::new(pool.as_ref())
+        .get_user(None, Some(request.email.trim()))
+        .await
     {
-        Ok(()) => {
-            info!("Email sent successfully");
-        }
-        Err(err) => {
-            error!("Error sending email: {err}");
-            return render_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Registration Error",
-                Some("Unable to send verification email"),
-            );
+        Ok(user) if user.email_verified_at.is_some() => debug!("Account is already verified"),
+        Ok(user) => {
+            if let Some(user_id) = user.id {
+                // Sent in the background so the reply takes as long either way
+                rt::spawn(async move {
+                    if let Err(err) =
+                        send_verification_email(&redis_pool, &key_ring, user_id, &user).await
+                    {
+                        error!("Error resending verification email: {err}");
+                    }
+                });
+            } else {
+                error!("Stored user has no ID");
+            }
         }
+        Err(RepoError::NotFound(_)) => debug!("No account for the verification request"),
+        Err(err) => error!("Unable to look up the account to verify: {err}"),
     }
 
-    HttpResponse::Ok().content_type("text/html").body(
-        "<h1>Registration successful</h1> <p>Please check your email to verify your account</p>",
-    )
+    render_page(&VerifyEmailPage {
+        title: "Verify Email",
+        sent: true,
+    })
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -162,38 +265,30 @@ struct Parameters {
     name = "Register confirm",
     level = "info",
     target = "kid_data",
-    skip(pool, redis_pool, parameters)
+    skip(pool, redis_pool, key_ring, parameters)
 )]
 pub async fn confirm(
     parameters: web::Query<Parameters>,
-    pool: Data<MongoRepo>,
+    pool: Data<Database>,
     redis_pool: Data<Pool>,
+    key_ring: Data<KeyRing>,
 ) -> HttpResponse {
     info!("Register confirm endpoint hit");
 
     debug!("Getting redis connection");
-    let mut redis_conn = redis_pool
-        .get()
-        .await
-        .map_err(|err| {
-            error!("Error getting redis connection: {err}");
-            let error = ErrorPage {
-                title: "Internal Server Error",
-                code: 500,
-                message: "Unable to activate your account at this time. Please try again later.",
-                error: &err.to_string(),
-            };
-
-            HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body(error.render().expect("Error rendering template"))
-        })
-        .expect("Error getting redis connection");
+    let mut redis_conn = match redis_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            return AppError::Backend(format!("Error getting redis connection: {err}"))
+                .error_response();
+        }
+    };
 
     info!("Verifying token");
     let confirmation_token = match auth::tokens::verify_confirmation_token_pasetor(
         &parameters.token.clone(),
         &mut redis_conn,
+        &key_ring,
         None,
     )
     .await
@@ -201,70 +296,29 @@ pub async fn confirm(
         Ok(token) => token,
         Err(err) => {
             error!("Error verifying token: {err}");
-            let error = ErrorPage {
-                title: "Internal Server Error",
-                code: 500,
-                message: "Unable to activate your account at this time. Please try again later.",
-                error: &err,
-            };
-
-            return HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body(error.render().expect("Error rendering template"));
+            return AppError::Validation(String::from(
+                "This confirmation link is invalid or has expired",
+            ))
+            .error_response();
         }
     };
 
-    info!("Activating user");
-    match activate_new_user(&pool, confirmation_token.user_id).await {
-        Ok(()) => {
-            info!("User activated successfully");
-            let template = Index { title: "Home" };
-
-            let body = template.render().expect("Error rendering template");
-
-            HttpResponse::Ok().content_type("text/html").body(body)
-        }
-
-        Err(err) => {
-            error!("Error activating user: {err}");
-            let error = ErrorPage {
-                title: "Internal Server Error",
-                code: 500,
-                message: "Unable to activate your account at this time. Please try again later.",
-                error: &err.to_string(),
-            };
-
-            HttpResponse::InternalServerError()
-                .content_type("text/html")
-                .body(error.render().expect("Error rendering template"))
-        }
-    }
-}
-
-#[instrument(
-    name = "Activate new user",
-    level = "debug",
-    target = "kid_data",
-    skip(pool, user_id)
-)]
-async fn activate_new_user(
-    pool: &MongoRepo,
-    user_id: ObjectId,
-) -> Result<(), mongodb::bson::extjson::de::Error> {
-    info!("Activate new user method hit");
-    match pool.get_user(Some(user_id), None).await {
-        Ok(mut user) => {
-            debug!("User found");
-            user.is_active = Some(true);
-            info!("Updating user to active status");
-            pool.update_user(user_id, user)
-                .await
-                .expect("Error activating user");
+    info!("Marking email verified");
+    match UserRepo::new(pool.as_ref())
+        .mark_email_verified(confirmation_token.user_id)
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => {
+            info!("Email verified");
+            render_page(&LoginPage {
+                title: "Email verified",
+                next: "",
+            })
         }
+        Ok(_) => AppError::NotFound(String::from("Account not found")).error_response(),
         Err(err) => {
-            error!("Marking user active: {err}");
-            return Err(err);
+            error!("Error verifying email: {err}");
+            AppError::from(err).error_response()
         }
     }
-    Ok(())
 }
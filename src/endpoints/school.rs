@@ -0,0 +1,965 @@
+use actix_web::{
+    delete, get,
+    http::StatusCode,
+    post,
+    web::{self, Data, Form},
+    HttpResponse, ResponseError,
+};
+use askama::Template;
+use chrono::NaiveDate;
+use mongodb::{bson::oid::ObjectId, Database};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        grants::{authorize, Permission, Records},
+        guard::AuthenticatedUser,
+    },
+    endpoints::{
+        children::child_for,
+        error::{parse_object_id, render_error, AppError, TRY_AGAIN},
+        templates::{
+            ClassForm, GradeForm, School, SchoolDirectory, SchoolYearForm, SchoolYearList,
+            SchoolYearPart,
+        },
+    },
+    models::{
+        child::{Child, ChildRepo},
+        helpers::non_empty,
+        school::{
+            report_card, Class, Grade, School as SchoolRecord, SchoolRepo, SchoolYear, Teacher,
+            DEFAULT_MARKING_PERIODS,
+        },
+    },
+};
+
+/// All things regarding school years, teachers, classes, and report cards
+
+#[derive(Debug, Default, Serialize)]
+pub struct SchoolYearCards {
+    pub label: String,
+    pub description: String,
+    pub db_id: ObjectId,
+}
+
+impl From<&SchoolYear> for SchoolYearCards {
+    fn from(year: &SchoolYear) -> Self {
+        Self {
+            label: year.label.clone(),
+            description: year.grade_level.clone(),
+            db_id: year.id.unwrap_or_default(),
+        }
+    }
+}
+
+/// One recorded grade as listed under the report card
+#[derive(Debug, Default, Serialize)]
+pub struct GradeLine {
+    pub db_id: ObjectId,
+    pub marking_period: String,
+    pub subject: String,
+    pub mark: String,
+    pub comments: String,
+}
+
+impl From<&Grade> for GradeLine {
+    fn from(grade: &Grade) -> Self {
+        Self {
+            db_id: grade.id.unwrap_or_default(),
+            marking_period: grade.marking_period.clone(),
+            subject: grade.subject.clone(),
+            mark: grade.mark.clone(),
+            comments: grade.comments.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Form data submitted when adding a school year
+#[derive(Debug, Deserialize, Clone)]
+pub struct SchoolYearInput {
+    pub label: String,
+    pub grade_level: String,
+    pub school_id: String,
+    /// Empty when no homeroom teacher is picked
+    pub homeroom_teacher_id: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    /// Comma separated marking period names
+    pub marking_periods: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClassInput {
+    pub subject: String,
+    /// Empty when no teacher is picked
+    pub teacher_id: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GradeInput {
+    pub marking_period: String,
+    pub subject: String,
+    pub mark: String,
+    pub comments: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SchoolInput {
+    pub name: String,
+    pub address: String,
+    pub phone: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TeacherInput {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    /// Empty when the teacher is not tied to a school
+    pub school_id: String,
+}
+
+/// # Result
+///   - `None` for an empty select, otherwise the parsed `ObjectId`
+/// # Errors
+///   - A rendered 400 page if the value is not an `ObjectId`
+fn optional_id(raw: &str) -> Result<Option<ObjectId>, HttpResponse> {
+    non_empty(raw).map(|id| parse_object_id(&id)).transpose()
+}
+
+/// # Result
+///   - The teacher's name, or `fallback` when there is no teacher
+fn teacher_name(teachers: &[Teacher], id: Option<ObjectId>, fallback: &str) -> String {
+    id.and_then(|id| teachers.iter().find(|teacher| teacher.id == Some(id)))
+        .map_or_else(|| fallback.to_string(), Teacher::full_name)
+}
+
+/// # Result
+///   - ID and name of every teacher for the select inputs
+fn teacher_choices(teachers: &[Teacher]) -> Vec<(String, String)> {
+    teachers
+        .iter()
+        .map(|teacher| (teacher.id.unwrap_or_default().to_hex(), teacher.full_name()))
+        .collect()
+}
+
+/// # Result
+///   - The rendered card list for the child
+#[instrument(
+    name = "Render school year cards",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, child),
+    fields(slug = %child.slug)
+)]
+async fn render_cards(pool: &Database, child: &Child) -> HttpResponse {
+    let years = match SchoolRepo::new(pool)
+        .get_years_for_child(child.id.unwrap_or_default())
+        .await
+    {
+        Ok(years) => years,
+        Err(err) => {
+            error!("Unable to load school years: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load school years",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = SchoolYearList {
+        slug: &child.slug,
+        card_data: years.iter().map(SchoolYearCards::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered report card for the school year
+#[instrument(
+    name = "Render school year",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, year, child),
+    fields(slug = %child.slug)
+)]
+async fn render_year(pool: &Database, year: &SchoolYear, child: &Child) -> HttpResponse {
+    let repo = SchoolRepo::new(pool);
+    let year_id = year.id.unwrap_or_default();
+
+    let (school, teachers, grades) = match (
+        repo.get_school(year.school_id).await,
+        repo.get_teachers().await,
+        repo.get_grades_for_year(year_id).await,
+    ) {
+        (Ok(school), Ok(teachers), Ok(grades)) => (school, teachers, grades),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            error!("Unable to load the school year: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load the school year",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let card = report_card(year, &grades);
+    let rows = card
+        .rows
+        .into_iter()
+        .map(|(subject, marks)| {
+            let teacher = year
+                .classes
+                .iter()
+                .find(|class| class.subject == subject)
+                .and_then(|class| class.teacher_id);
+            let teacher = teacher_name(&teachers, teacher, "");
+            (subject, teacher, marks)
+        })
+        .collect();
+
+    let id = year_id.to_hex();
+    let homeroom = teacher_name(&teachers, year.homeroom_teacher_id, "None on file");
+
+    let template = SchoolYearPart {
+        id: &id,
+        slug: &child.slug,
+        label: &year.label,
+        grade_level: &year.grade_level,
+        school: &school.name,
+        homeroom: &homeroom,
+        dates: format!(
+            "{} to {}",
+            year.start_date.format("%B %d, %Y"),
+            year.end_date.format("%B %d, %Y")
+        ),
+        periods: card.periods,
+        rows,
+        grades: grades.iter().map(GradeLine::from).collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The rendered schools and teachers page
+#[instrument(
+    name = "Render school directory",
+    level = "debug",
+    target = "kid_data",
+    skip(pool)
+)]
+async fn render_directory(pool: &Database) -> HttpResponse {
+    let repo = SchoolRepo::new(pool);
+
+    let (schools, teachers) = match (repo.get_schools().await, repo.get_teachers().await) {
+        (Ok(schools), Ok(teachers)) => (schools, teachers),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Unable to load schools and teachers: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load schools and teachers",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let teachers = teachers
+        .iter()
+        .map(|teacher| {
+            let school = teacher
+                .school_id
+                .and_then(|id| schools.iter().find(|school| school.id == Some(id)))
+                .map(|school| school.name.clone())
+                .unwrap_or_default();
+            (
+                teacher.full_name(),
+                teacher.email.clone().unwrap_or_default(),
+                school,
+            )
+        })
+        .collect();
+
+    let template = SchoolDirectory {
+        title: "Schools",
+        schools,
+        teachers,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// # Result
+///   - The child with the matching slug, if `user` may do `permission` to
+///     its school records
+/// # Errors
+///   - A rendered error page when no child uses the slug or access is refused
+async fn child_by_slug(
+    pool: &Database,
+    slug: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<Child, HttpResponse> {
+    child_for(pool, slug, user, Some(Records::School), permission).await
+}
+
+/// # Result
+///   - The school year and the child it belongs to, if `user` may do
+///     `permission` to the child's school records
+/// # Errors
+///   - A rendered error page when either lookup fails or access is refused
+#[instrument(
+    name = "Year with child",
+    level = "debug",
+    target = "kid_data",
+    skip(pool, user)
+)]
+async fn year_with_child(
+    pool: &Database,
+    id: &str,
+    user: &AuthenticatedUser,
+    permission: Permission,
+) -> Result<(SchoolYear, Child), HttpResponse> {
+    let id = parse_object_id(id)?;
+
+    let year = SchoolRepo::new(pool).get_year(id).await.map_err(|err| {
+        error!("Unable to find the data for the ID passed in");
+        AppError::from(err).error_response()
+    })?;
+
+    let child = ChildRepo::new(pool)
+        .get_child_by_id(year.child_id)
+        .await
+        .map_err(|err| {
+            error!("School year belongs to a missing child");
+            AppError::from(err).error_response()
+        })?;
+    authorize(&user.user, &child, Some(Records::School), permission)
+        .map_err(|err| err.error_response())?;
+
+    Ok((year, child))
+}
+
+#[get("/school_logs/{slug}")]
+#[instrument(
+    name = "school data",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn school_data(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    let child = match child_by_slug(pool.as_ref(), &slug, &user, Permission::View).await {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    let repo = SchoolRepo::new(pool.as_ref());
+    let years = match repo.get_years_for_child(child.id.unwrap_or_default()).await {
+        Ok(years) => years,
+        Err(err) => {
+            error!("Unable to load school years: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load school years",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let current = match years.first() {
+        Some(year) => match repo.get_school(year.school_id).await {
+            Ok(school) => format!("{}: {}", school.name, year.grade_level),
+            Err(err) => {
+                warn!("School year points at a missing school: {err}");
+                year.grade_level.clone()
+            }
+        },
+        None => String::from("No school years on file"),
+    };
+
+    let template = School {
+        title: "School Data",
+        name: &child.full_name(),
+        current,
+        slug: &child.slug,
+        age: child.age(),
+        card_data: years.iter().map(SchoolYearCards::from).collect(),
+        child: &child,
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/school_logs/{slug}/new")]
+#[instrument(
+    name = "school year form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_school_year(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+) -> HttpResponse {
+    if let Err(http_resp) = child_by_slug(pool.as_ref(), &slug, &user, Permission::Edit).await {
+        return http_resp;
+    }
+
+    let repo = SchoolRepo::new(pool.as_ref());
+    let (schools, teachers) = match (repo.get_schools().await, repo.get_teachers().await) {
+        (Ok(schools), Ok(teachers)) => (schools, teachers),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Unable to load schools and teachers: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load schools and teachers",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    if schools.is_empty() {
+        warn!("No schools to pick from");
+        return render_error(
+            StatusCode::CONFLICT,
+            "Add a school first",
+            Some("Schools and teachers are managed from the SCHOOLS & TEACHERS page"),
+        );
+    }
+
+    let template = SchoolYearForm {
+        action: &format!("/school_logs/{slug}"),
+        schools: schools
+            .iter()
+            .map(|school| (school.id.unwrap_or_default().to_hex(), school.name.clone()))
+            .collect(),
+        teachers: teacher_choices(&teachers),
+        marking_periods: DEFAULT_MARKING_PERIODS.join(", "),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/school_logs/{slug}")]
+#[instrument(
+    name = "add school year",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn add_school_year(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    slug: web::Path<String>,
+    Form(input): Form<SchoolYearInput>,
+) -> HttpResponse {
+    let child = match child_by_slug(pool.as_ref(), &slug, &user, Permission::Edit).await {
+        Ok(child) => child,
+        Err(http_resp) => return http_resp,
+    };
+
+    if input.end_date < input.start_date {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add school year",
+            Some("The year must end after it starts"),
+        );
+    }
+
+    let (school_id, homeroom_teacher_id) = match (
+        parse_object_id(&input.school_id),
+        optional_id(&input.homeroom_teacher_id),
+    ) {
+        (Ok(school_id), Ok(teacher_id)) => (school_id, teacher_id),
+        (Err(http_resp), _) | (_, Err(http_resp)) => return http_resp,
+    };
+
+    let repo = SchoolRepo::new(pool.as_ref());
+    if let Err(err) = repo.get_school(school_id).await {
+        warn!("Unknown school picked: {err}");
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add school year",
+            Some("The school picked does not exist"),
+        );
+    }
+
+    let mut marking_periods: Vec<String> = input
+        .marking_periods
+        .split(',')
+        .filter_map(non_empty)
+        .collect();
+    if marking_periods.is_empty() {
+        marking_periods = DEFAULT_MARKING_PERIODS.map(String::from).to_vec();
+    }
+
+    let mut year = SchoolYear {
+        id: None,
+        child_id: child.id.unwrap_or_default(),
+        school_id,
+        label: input.label.trim().to_string(),
+        grade_level: input.grade_level.trim().to_string(),
+        start_date: input.start_date,
+        end_date: input.end_date,
+        homeroom_teacher_id,
+        marking_periods,
+        classes: Vec::new(),
+    };
+
+    match repo.create_year(&year).await {
+        Ok(id) => {
+            debug!("School year saved: {id}");
+            year.id = Some(id);
+            render_year(pool.as_ref(), &year, &child).await
+        }
+        Err(err) => {
+            error!("Unable to save school year: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save school year",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[get("/school_year/{id}")]
+#[instrument(
+    name = "school year",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn school_year(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    match year_with_child(pool.as_ref(), &id, &user, Permission::View).await {
+        Ok((year, child)) => render_year(pool.as_ref(), &year, &child).await,
+        Err(http_resp) => http_resp,
+    }
+}
+
+#[delete("/school_year/{id}")]
+#[instrument(
+    name = "delete school year",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_school_year(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (year, child) = match year_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    match SchoolRepo::new(pool.as_ref())
+        .delete_year(year.id.unwrap_or_default())
+        .await
+    {
+        Ok(_) => {
+            info!("School year deleted");
+            render_cards(pool.as_ref(), &child).await
+        }
+        Err(err) => {
+            error!("Unable to delete school year: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to delete school year",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[get("/school_year/{id}/class")]
+#[instrument(
+    name = "class form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_class(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    if let Err(http_resp) = year_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        return http_resp;
+    }
+
+    let teachers = match SchoolRepo::new(pool.as_ref()).get_teachers().await {
+        Ok(teachers) => teachers,
+        Err(err) => {
+            error!("Unable to load teachers: {err}");
+            return render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to load teachers",
+                Some(TRY_AGAIN),
+            );
+        }
+    };
+
+    let template = ClassForm {
+        action: &format!("/school_year/{id}/class"),
+        teachers: teacher_choices(&teachers),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/school_year/{id}/class")]
+#[instrument(
+    name = "add class",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn add_class(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<ClassInput>,
+) -> HttpResponse {
+    let (mut year, child) = match year_with_child(pool.as_ref(), &id, &user, Permission::Edit).await
+    {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    let Some(subject) = non_empty(&input.subject) else {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add class",
+            Some("A subject is required"),
+        );
+    };
+
+    if year.classes.iter().any(|class| class.subject == subject) {
+        warn!("Class already on the school year: {subject}");
+        return render_error(
+            StatusCode::CONFLICT,
+            "Unable to add class",
+            Some("That subject is already on this school year"),
+        );
+    }
+
+    let class = Class {
+        subject,
+        teacher_id: match optional_id(&input.teacher_id) {
+            Ok(teacher_id) => teacher_id,
+            Err(http_resp) => return http_resp,
+        },
+    };
+
+    match SchoolRepo::new(pool.as_ref())
+        .add_class(year.id.unwrap_or_default(), class.clone())
+        .await
+    {
+        Ok(_) => {
+            year.classes.push(class);
+            render_year(pool.as_ref(), &year, &child).await
+        }
+        Err(err) => {
+            error!("Unable to add class: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to add class",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[get("/school_year/{id}/grade")]
+#[instrument(
+    name = "grade form",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn new_grade(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let (year, _) = match year_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    if year.classes.is_empty() {
+        return render_error(
+            StatusCode::CONFLICT,
+            "Add a class first",
+            Some("Grades are recorded against the classes of a school year"),
+        );
+    }
+
+    let template = GradeForm {
+        action: &format!("/school_year/{id}/grade"),
+        periods: &year.marking_periods,
+        subjects: year
+            .classes
+            .iter()
+            .map(|class| class.subject.clone())
+            .collect(),
+    };
+
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => {
+            error!("Failed to render template: {err:#?}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[post("/school_year/{id}/grade")]
+#[instrument(
+    name = "save grade",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, input)
+)]
+pub async fn save_grade(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+    Form(input): Form<GradeInput>,
+) -> HttpResponse {
+    let (year, child) = match year_with_child(pool.as_ref(), &id, &user, Permission::Edit).await {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    if !year.marking_periods.contains(&input.marking_period) {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to save grade",
+            Some("That marking period is not part of this school year"),
+        );
+    }
+
+    let Some(mark) = non_empty(&input.mark) else {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to save grade",
+            Some("A mark is required"),
+        );
+    };
+
+    let grade = Grade {
+        id: None,
+        child_id: year.child_id,
+        school_year_id: year.id.unwrap_or_default(),
+        marking_period: input.marking_period,
+        subject: input.subject.trim().to_string(),
+        mark,
+        comments: non_empty(&input.comments),
+    };
+
+    match SchoolRepo::new(pool.as_ref()).save_grade(grade).await {
+        Ok(_) => render_year(pool.as_ref(), &year, &child).await,
+        Err(err) => {
+            error!("Unable to save grade: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to save grade",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[allow(clippy::future_not_send)]
+#[delete("/grade/{id}")]
+#[instrument(
+    name = "delete grade",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn delete_grade(
+    user: AuthenticatedUser,
+    id: web::Path<String>,
+    pool: Data<Database>,
+) -> HttpResponse {
+    let id = match parse_object_id(&id) {
+        Ok(id) => id,
+        Err(http_resp) => return http_resp,
+    };
+
+    let repo = SchoolRepo::new(pool.as_ref());
+    let grade = match repo.get_grade(id).await {
+        Ok(grade) => grade,
+        Err(err) => {
+            error!("Unable to find the data for the ID passed in");
+            return AppError::from(err).error_response();
+        }
+    };
+    let (year, child) = match year_with_child(
+        pool.as_ref(),
+        &grade.school_year_id.to_hex(),
+        &user,
+        Permission::Edit,
+    )
+    .await
+    {
+        Ok(found) => found,
+        Err(http_resp) => return http_resp,
+    };
+
+    if let Err(err) = repo.delete_grade(id).await {
+        error!("Unable to delete grade: {err}");
+        return render_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to delete grade",
+            Some(TRY_AGAIN),
+        );
+    }
+
+    render_year(pool.as_ref(), &year, &child).await
+}
+
+#[get("/schools")]
+#[instrument(
+    name = "schools",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool)
+)]
+pub async fn school_directory(_user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    render_directory(pool.as_ref()).await
+}
+
+#[post("/schools")]
+#[instrument(
+    name = "add school",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool, input)
+)]
+pub async fn add_school(
+    _user: AuthenticatedUser,
+    pool: Data<Database>,
+    Form(input): Form<SchoolInput>,
+) -> HttpResponse {
+    let Some(name) = non_empty(&input.name) else {
+        return render_error(
+            StatusCode::BAD_REQUEST,
+            "Unable to add school",
+            Some("A school name is required"),
+        );
+    };
+
+    let school = SchoolRecord {
+        id: None,
+        name,
+        address: input.address.trim().to_string(),
+        phone: input.phone.trim().to_string(),
+    };
+
+    match SchoolRepo::new(pool.as_ref()).create_school(&school).await {
+        Ok(_) => render_directory(pool.as_ref()).await,
+        Err(err) => {
+            error!("Unable to add school: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to add school",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
+
+#[post("/teachers")]
+#[instrument(
+    name = "add teacher",
+    level = "info",
+    target = "kid_data",
+    skip(_user, pool, input)
+)]
+pub async fn add_teacher(
+    _user: AuthenticatedUser,
+    pool: Data<Database>,
+    Form(input): Form<TeacherInput>,
+) -> HttpResponse {
+    let school_id = match optional_id(&input.school_id) {
+        Ok(school_id) => school_id,
+        Err(http_resp) => return http_resp,
+    };
+
+    let teacher = Teacher {
+        id: None,
+        first_name: input.first_name.trim().to_string(),
+        last_name: input.last_name.trim().to_string(),
+        email: non_empty(&input.email),
+        school_id,
+    };
+
+    match SchoolRepo::new(pool.as_ref())
+        .create_teacher(&teacher)
+        .await
+    {
+        Ok(_) => render_directory(pool.as_ref()).await,
+        Err(err) => {
+            error!("Unable to add teacher: {err}");
+            render_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to add teacher",
+                Some(TRY_AGAIN),
+            )
+        }
+    }
+}
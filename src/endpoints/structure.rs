@@ -2,4 +2,139 @@
 pub struct Login {
     pub email: String,
     pub password: String,
+    /// Where to go once logged in
+    #[serde(default)]
+    pub next: String,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct LoginQuery {
+    pub next: Option<String>,
+}
+
+/// A login whose password was right, waiting on its second factor
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct PendingLogin {
+    pub user_id: mongodb::bson::oid::ObjectId,
+    pub next: String,
+    /// Unix time the password was accepted
+    pub started_at: i64,
+    pub attempts: u8,
+    /// The password hashed at the configured cost, stored once the code
+    /// is right
+    #[serde(default)]
+    pub upgrade: Option<HashUpgrade>,
+}
+
+/// A password hash made at an older cost, and the same password hashed at
+/// the configured one
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct HashUpgrade {
+    pub stale: String,
+    pub fresh: String,
+}
+
+impl PendingLogin {
+    /// Seconds the user has to enter their code
+    const LIFETIME: i64 = 300;
+
+    /// # Result
+    ///   - `true` once the password has to be entered again
+    #[must_use]
+    pub const fn expired(&self, now: i64) -> bool {
+        now - self.started_at > Self::LIFETIME
+    }
+}
+
+/// A passkey the browser is making, waiting for its response
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PendingPasskey {
+    pub name: String,
+    pub state: webauthn_rs::prelude::PasskeyRegistration,
+    /// Unix time the browser was asked for the passkey
+    pub started_at: i64,
+}
+
+impl PendingPasskey {
+    /// # Result
+    ///   - `true` once registration has to start over
+    #[must_use]
+    pub const fn expired(&self, now: i64) -> bool {
+        now - self.started_at > PendingLogin::LIFETIME
+    }
+}
+
+/// A passkey login waiting for the browser to sign its challenge
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PendingPasskeyLogin {
+    pub user_id: mongodb::bson::oid::ObjectId,
+    pub next: String,
+    pub state: webauthn_rs::prelude::PasskeyAuthentication,
+    /// Unix time the challenge was issued
+    pub started_at: i64,
+}
+
+impl PendingPasskeyLogin {
+    /// # Result
+    ///   - `true` once the login has to start over
+    #[must_use]
+    pub const fn expired(&self, now: i64) -> bool {
+        now - self.started_at > PendingLogin::LIFETIME
+    }
+}
+
+/// Who is logging in with a passkey
+#[derive(serde::Deserialize)]
+pub struct PasskeyLogin {
+    pub email: String,
+    /// Where to go once logged in
+    #[serde(default)]
+    pub next: String,
+}
+
+/// What to call a new passkey
+#[derive(serde::Deserialize)]
+pub struct PasskeyName {
+    pub name: String,
+}
+
+/// The browser's answer to a passkey ceremony, as JSON
+#[derive(serde::Deserialize)]
+pub struct PasskeyResponse {
+    pub credential: String,
+}
+
+/// A code from an authenticator app, or a recovery code
+#[derive(serde::Deserialize)]
+pub struct SecondFactor {
+    pub code: String,
+}
+
+/// The name shown for an account
+#[derive(serde::Deserialize)]
+pub struct ProfileName {
+    pub first_name: String,
+    pub last_name: String,
+}
+
+/// A new password, given with the one it replaces
+#[derive(serde::Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub password: String,
+    pub password_2: String,
+}
+
+/// A new email address, confirmed with the password
+#[derive(serde::Deserialize)]
+pub struct EmailChange {
+    pub email: String,
+    pub password: String,
+}
+
+/// The password, asked for again before a change that cannot be undone
+/// from another device
+#[derive(serde::Deserialize)]
+pub struct ConfirmPassword {
+    pub password: String,
 }
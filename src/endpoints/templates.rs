@@ -1,22 +1,54 @@
 use std::path::PathBuf;
 
 use actix_files::NamedFile;
-use actix_web::{get, HttpResponse, Responder};
+use actix_web::{get, HttpResponse, Responder, ResponseError};
 use askama::Template;
+use mongodb::bson::DateTime;
 use tracing::{error, info, instrument};
 
-use super::adrian::{doctor::DoctorCards, school::Grade};
+use super::{
+    dentist::DentalCards,
+    doctor::DoctorCards,
+    immunizations::{ImmunizationCards, ScheduleRow},
+    photos::PhotoTile,
+    providers::ProviderCards,
+    school::{GradeLine, SchoolYearCards},
+};
+use crate::{
+    auth::{
+        grants::{ChildGrant, Records},
+        passkeys::StoredPasskey,
+        store::Device,
+    },
+    endpoints::error::AppError,
+    models::{
+        api_token::ApiToken, child::Child, invitation::Invitation, mongo::User, provider::Provider,
+        school,
+    },
+};
+
+/// # Result
+///   - A 200 HTML page, or a logged 500 when the template fails to render
+pub(crate) fn render_page(template: &impl Template) -> HttpResponse {
+    match template.render() {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => AppError::Backend(format!("Failed to render template: {err}")).error_response(),
+    }
+}
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct Index<'a> {
     pub title: &'a str,
+    pub children: Vec<Child>,
 }
 
 #[derive(Template)]
 #[template(path = "login.html")]
 pub struct LoginPage<'a> {
     pub title: &'a str,
+    /// Where to go once logged in; empty for the home page
+    pub next: &'a str,
 }
 
 #[derive(Template)]
@@ -25,6 +57,102 @@ pub struct RegisterPage<'a> {
     pub title: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+pub struct VerifyEmailPage<'a> {
+    pub title: &'a str,
+    pub sent: bool,
+}
+
+#[derive(Template)]
+#[template(path = "password_reset.html")]
+pub struct PasswordResetPage<'a> {
+    pub title: &'a str,
+    pub sent: bool,
+}
+
+#[derive(Template)]
+#[template(path = "change_password.html")]
+pub struct ChangePasswordPage<'a> {
+    pub title: &'a str,
+    pub token: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "login_two_factor.html")]
+pub struct TwoFactorLoginPage<'a> {
+    pub title: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "two_factor.html")]
+pub struct TwoFactorPage<'a> {
+    pub title: &'a str,
+    pub enabled: bool,
+    /// SVG of the enrollment QR code; empty unless enrolling
+    pub qr_code: &'a str,
+    /// The same secret as text, for typing in by hand
+    pub secret: &'a str,
+    /// Shown once, right after two-factor is turned on
+    pub recovery_codes: &'a [String],
+    /// Recovery codes not yet used
+    pub remaining: usize,
+}
+
+#[derive(Template)]
+#[template(path = "devices.html")]
+pub struct DevicesPage<'a> {
+    pub title: &'a str,
+    pub devices: Vec<Device>,
+    /// ID of the device viewing the page
+    pub current: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+pub struct ProfilePage<'a> {
+    pub title: &'a str,
+    pub user: &'a User,
+    /// What the last change did, shown above the forms
+    pub notice: &'a str,
+    /// Days a closed account is kept before it is deleted
+    pub grace_days: i64,
+    /// Whether the user may call the account API, and so hold API tokens
+    pub api_access: bool,
+    pub tokens: &'a [ApiToken],
+    /// Days a new API token can be made to last
+    pub lifetimes: &'a [i64],
+    /// An API token just made, shown this once
+    pub issued: &'a str,
+}
+
+impl ProfilePage<'_> {
+    /// # Result
+    ///   - `at` as shown on the page
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Askama passes arguments by reference
+    fn when(at: &DateTime) -> String {
+        chrono::DateTime::from_timestamp_millis(at.timestamp_millis())
+            .map_or_else(String::new, |at| at.format("%B %d, %Y").to_string())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "passkeys.html")]
+pub struct PasskeysPage<'a> {
+    pub title: &'a str,
+    pub passkeys: &'a [StoredPasskey],
+}
+
+impl PasskeysPage<'_> {
+    /// # Result
+    ///   - `at` as shown on the page
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Askama passes arguments by reference
+    fn when(at: &DateTime) -> String {
+        chrono::DateTime::from_timestamp_millis(at.timestamp_millis())
+            .map_or_else(String::new, |at| at.format("%Y-%m-%d").to_string())
+    }
+}
+
 #[derive(Template)]
 #[template(path = "errors.html")]
 pub struct ErrorPage<'a> {
@@ -34,6 +162,23 @@ pub struct ErrorPage<'a> {
     pub message: &'a str,
 }
 
+/// Shown instead of `ErrorPage` when a request fails the CSRF check, since
+/// reloading the page is what fixes it
+#[derive(Template)]
+#[template(path = "csrf_error.html")]
+pub struct CsrfErrorPage<'a> {
+    pub title: &'a str,
+    pub error: &'a str,
+    pub message: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "parts/error.part.html")]
+pub struct ErrorPart<'a> {
+    pub error: &'a str,
+    pub message: &'a str,
+}
+
 #[derive(Template)]
 #[template(path = "email.html")]
 pub struct EmailPage {
@@ -45,21 +190,106 @@ pub struct EmailPage {
 }
 
 #[derive(Template)]
-#[template(path = "adrian.html")]
-pub struct AdrianLanding {
+#[template(path = "password_reset_email.html")]
+pub struct PasswordResetEmail {
     pub title: String,
-    pub name: String,
-    pub age: u8,
-    pub grade: Grade,
+    pub confirmation_link: String,
+    pub domain: String,
+    pub expiration_time: String,
+    pub exact_time: String,
 }
 
 #[derive(Template)]
-#[template(path = "corbin.html")]
-pub struct CorbinLanding {
+#[template(path = "invitations.html")]
+pub struct InvitationsPage<'a> {
+    pub title: &'a str,
+    /// Children the viewer may invite people to
+    pub children: Vec<Child>,
+    pub records: &'a [Records],
+    /// Invitations the viewer sent that are still open
+    pub invitations: Vec<Invitation>,
+    /// Who was just invited, if anyone
+    pub sent_to: &'a str,
+}
+
+impl InvitationsPage<'_> {
+    /// # Result
+    ///   - What `grants` share, e.g. "AJ: medical, photos (can edit)"
+    fn shared(&self, grants: &[ChildGrant]) -> String {
+        grants
+            .iter()
+            .map(|grant| {
+                let name = self
+                    .children
+                    .iter()
+                    .find(|child| child.id == Some(grant.child_id))
+                    .map_or("A child", Child::display_name);
+                let records: Vec<&str> = grant.records.iter().map(|r| r.as_str()).collect();
+                let edit = if grant.can_edit { " (can edit)" } else { "" };
+                format!("{name}: {}{edit}", records.join(", "))
+            })
+            .collect::<Vec<String>>()
+            .join("; ")
+    }
+
+    /// # Result
+    ///   - `at` as shown on the page
+    #[allow(clippy::trivially_copy_pass_by_ref)] // Askama passes arguments by reference
+    fn when(at: &DateTime) -> String {
+        chrono::DateTime::from_timestamp_millis(at.timestamp_millis())
+            .map_or_else(String::new, |at| at.format("%Y-%m-%d").to_string())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "accept_invitation.html")]
+pub struct AcceptInvitationPage<'a> {
+    pub title: &'a str,
+    pub token: &'a str,
+    pub first_name: &'a str,
+    pub email: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "invitation_email.html")]
+pub struct InvitationEmail {
+    pub title: String,
+    pub inviter: String,
+    pub accept_link: String,
+    pub expiration_days: String,
+    pub exact_time: String,
+}
+
+#[derive(Template)]
+#[template(path = "lockout_email.html")]
+pub struct LockoutEmail {
+    pub title: String,
+    pub reset_link: String,
+    pub lockout_time: String,
+    pub exact_time: String,
+}
+
+#[derive(Template)]
+#[template(path = "child.html")]
+pub struct ChildLanding {
     pub title: String,
-    pub name: String,
-    pub age: u8,
-    pub grade: Grade,
+    pub child: Child,
+    pub age: u32,
+}
+
+#[derive(Template)]
+#[template(path = "child_form.html")]
+pub struct ChildFormPage<'a> {
+    pub title: &'a str,
+    pub editing: bool,
+    pub original_slug: &'a str,
+    pub first_name: &'a str,
+    pub last_name: &'a str,
+    pub nickname: &'a str,
+    pub slug: &'a str,
+    pub date_of_birth: &'a str,
+    pub profile_photo: &'a str,
+    pub guardians: &'a str,
 }
 
 #[derive(Template)]
@@ -67,26 +297,212 @@ pub struct CorbinLanding {
 pub struct DoctorData<'a> {
     pub title: &'a str,
     pub name: &'a str,
+    pub photo: &'a str,
     pub email: &'a str,
     pub phone: &'a str,
     pub address: &'a str,
     pub speciality: &'a str,
+    pub child: &'a Child,
+    pub age: u32,
+    pub slug: &'a str,
+    pub card_data: Vec<DoctorCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/doctor_cards.part.html")]
+pub struct DoctorCardList<'a> {
+    pub slug: &'a str,
     pub card_data: Vec<DoctorCards>,
 }
 
 #[derive(Template)]
 #[template(path = "parts/doctor_visit.part.html")]
 pub struct DoctorVisit<'a> {
+    pub id: &'a str,
+    pub slug: &'a str,
     pub date: &'a str,
+    pub provider: &'a str,
     pub notes: Vec<&'a str>,
     pub purpose: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "parts/appointment_form.part.html")]
+pub struct AppointmentForm<'a> {
+    pub action: &'a str,
+    pub editing: bool,
+    pub date: &'a str,
+    /// ID, label, and selected state of every provider that can be picked
+    pub providers: Vec<(String, String, bool)>,
+    pub purpose: &'a str,
+    pub notes: String,
+}
+
 #[derive(Template)]
 #[template(path = "dentist.html")]
 pub struct Dental<'a> {
     pub title: &'a str,
     pub name: &'a str,
+    pub provider: &'a str,
+    pub next_cleaning: String,
+    pub cleaning_overdue: bool,
+    pub child: &'a Child,
+    pub age: u32,
+    pub slug: &'a str,
+    pub card_data: Vec<DentalCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/dental_cards.part.html")]
+pub struct DentalCardList<'a> {
+    pub slug: &'a str,
+    pub card_data: Vec<DentalCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/dental_visit.part.html")]
+pub struct DentalVisitPart<'a> {
+    pub id: &'a str,
+    pub slug: &'a str,
+    pub date: &'a str,
+    pub provider: &'a str,
+    pub procedures: Vec<String>,
+    pub cavities: u32,
+    pub teeth: String,
+    pub notes: Vec<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/dental_form.part.html")]
+pub struct DentalForm<'a> {
+    pub action: &'a str,
+    pub editing: bool,
+    pub date: &'a str,
+    /// ID, label, and selected state of every provider that can be picked
+    pub providers: Vec<(String, String, bool)>,
+    /// Form field, label, and checked state for every procedure
+    pub procedures: Vec<(&'static str, String, bool)>,
+    pub cavities: u32,
+    pub teeth: String,
+    pub notes: String,
+}
+
+#[derive(Template)]
+#[template(path = "immunizations.html")]
+pub struct Immunizations<'a> {
+    pub title: &'a str,
+    pub name: &'a str,
+    pub provider: &'a str,
+    /// Scheduled doses past the end of their window
+    pub overdue: usize,
+    /// Scheduled doses inside their window
+    pub due: usize,
+    pub schedule: Vec<ScheduleRow>,
+    pub child: &'a Child,
+    pub age: u32,
+    pub slug: &'a str,
+    pub card_data: Vec<ImmunizationCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/immunization_cards.part.html")]
+pub struct ImmunizationCardList<'a> {
+    pub slug: &'a str,
+    pub card_data: Vec<ImmunizationCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/immunization.part.html")]
+pub struct ImmunizationPart<'a> {
+    pub id: &'a str,
+    pub slug: &'a str,
+    pub vaccine: String,
+    pub dose: u32,
+    pub date: &'a str,
+    pub lot_number: &'a str,
+    pub provider: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "parts/immunization_form.part.html")]
+pub struct ImmunizationForm<'a> {
+    pub action: &'a str,
+    pub editing: bool,
+    pub date: &'a str,
+    /// ID, label, and selected state of every provider that can be picked
+    pub providers: Vec<(String, String, bool)>,
+    /// Form value, label, and selected state for every vaccine
+    pub vaccines: Vec<(&'static str, String, bool)>,
+    pub dose: u32,
+    pub lot_number: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "providers.html")]
+pub struct Providers<'a> {
+    pub title: &'a str,
+    pub card_data: Vec<ProviderCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/provider.part.html")]
+pub struct ProviderDetail<'a> {
+    pub id: &'a str,
+    pub kind: &'a str,
+    pub provider: &'a Provider,
+}
+
+#[derive(Template)]
+#[template(path = "parts/provider_form.part.html")]
+pub struct ProviderForm<'a> {
+    pub action: &'a str,
+    pub editing: bool,
+    /// Form value, label, and selected state for every kind
+    pub kinds: Vec<(&'static str, String, bool)>,
+    pub name: &'a str,
+    pub practice: &'a str,
+    pub email: &'a str,
+    pub phone: &'a str,
+    pub address: &'a str,
+    pub photo: &'a str,
+    pub active: bool,
+}
+
+#[derive(Template)]
+#[template(path = "photos.html")]
+pub struct PhotoGallery<'a> {
+    pub title: &'a str,
+    pub name: &'a str,
+    pub child: &'a Child,
+    pub age: u32,
+    pub slug: &'a str,
+    /// Album being browsed; empty for every album
+    pub album: &'a str,
+    pub albums: Vec<String>,
+    pub tiles: Vec<PhotoTile>,
+    pub next_page: Option<u64>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/photo_grid.part.html")]
+pub struct PhotoGrid<'a> {
+    pub slug: &'a str,
+    pub album: &'a str,
+    pub tiles: Vec<PhotoTile>,
+    pub next_page: Option<u64>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/photo.part.html")]
+pub struct PhotoDetail<'a> {
+    pub id: &'a str,
+    pub slug: &'a str,
+    pub album: &'a str,
+    pub caption: &'a str,
+    pub taken: String,
+    pub uploaded: String,
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Template)]
@@ -94,6 +510,70 @@ pub struct Dental<'a> {
 pub struct School<'a> {
     pub title: &'a str,
     pub name: &'a str,
+    /// The school and grade of the most recent year
+    pub current: String,
+    pub child: &'a Child,
+    pub age: u32,
+    pub slug: &'a str,
+    pub card_data: Vec<SchoolYearCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/school_years.part.html")]
+pub struct SchoolYearList<'a> {
+    pub slug: &'a str,
+    pub card_data: Vec<SchoolYearCards>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/school_year.part.html")]
+pub struct SchoolYearPart<'a> {
+    pub id: &'a str,
+    pub slug: &'a str,
+    pub label: &'a str,
+    pub grade_level: &'a str,
+    pub school: &'a str,
+    pub homeroom: &'a str,
+    pub dates: String,
+    pub periods: Vec<String>,
+    /// Subject, teacher, and the mark for every marking period
+    pub rows: Vec<(String, String, Vec<String>)>,
+    pub grades: Vec<GradeLine>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/school_year_form.part.html")]
+pub struct SchoolYearForm<'a> {
+    pub action: &'a str,
+    /// ID and name of every school
+    pub schools: Vec<(String, String)>,
+    /// ID and name of every teacher
+    pub teachers: Vec<(String, String)>,
+    pub marking_periods: String,
+}
+
+#[derive(Template)]
+#[template(path = "parts/class_form.part.html")]
+pub struct ClassForm<'a> {
+    pub action: &'a str,
+    pub teachers: Vec<(String, String)>,
+}
+
+#[derive(Template)]
+#[template(path = "parts/grade_form.part.html")]
+pub struct GradeForm<'a> {
+    pub action: &'a str,
+    pub periods: &'a [String],
+    pub subjects: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "schools.html")]
+pub struct SchoolDirectory<'a> {
+    pub title: &'a str,
+    pub schools: Vec<school::School>,
+    /// Name, email, and school of every teacher
+    pub teachers: Vec<(String, String, String)>,
 }
 
 #[get("/favicon")]
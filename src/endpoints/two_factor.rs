@@ -0,0 +1,258 @@
+use actix_web::{
+    get, post,
+    web::{Data, Form},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::Utc;
+use mongodb::Database;
+use qrcode::{render::svg, EcLevel, QrCode};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    auth::{
+        guard::AuthenticatedUser,
+        store::client_ip,
+        throttle::LoginThrottle,
+        totp::{
+            self, find_recovery_code, generate_recovery_codes, generate_secret,
+            hash_recovery_codes, provisioning_uri,
+        },
+    },
+    endpoints::{
+        error::AppError,
+        login::{count_failure, throttled_response},
+        structure::SecondFactor,
+        templates::{render_page, TwoFactorPage},
+    },
+    models::mongo::{User, UserRepo},
+    settings::Settings,
+};
+
+const TITLE: &str = "Two-factor authentication";
+
+/// # Result
+///   - `uri` as an inline SVG QR code, or `None` if it is too long to encode
+fn qr_svg(uri: &str) -> Option<String> {
+    let code = QrCode::with_error_correction_level(uri, EcLevel::M).ok()?;
+    let image = code.render::<svg::Color>().min_dimensions(200, 200).build();
+    // The XML declaration is only wanted in a standalone file
+    Some(
+        image
+            .trim_start_matches(r#"<?xml version="1.0" standalone="yes"?>"#)
+            .to_string(),
+    )
+}
+
+/// # Result
+///   - `true` if `code` is a fresh authenticator code or an unused recovery
+///     code for `user`. Either is spent by the check.
+/// # Errors
+///   - `AppError` if the user cannot be updated
+pub(crate) async fn check_second_factor(
+    users: &UserRepo,
+    user: &User,
+    code: &str,
+) -> Result<bool, AppError> {
+    let Some(user_id) = user.id else {
+        return Err(AppError::Backend(String::from("Stored user has no ID")));
+    };
+
+    if let Some(step) = user
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| totp::verify(secret, code, Utc::now().timestamp(), user.totp_last_step))
+    {
+        // Losing this race means another request just used the same code
+        return Ok(users.use_totp_step(user_id, step).await?.matched_count == 1);
+    }
+
+    match find_recovery_code(&user.recovery_codes, code).await {
+        Some(hash) => {
+            info!("Recovery code used");
+            Ok(users.use_recovery_code(user_id, hash).await?.modified_count == 1)
+        }
+        None => Ok(false),
+    }
+}
+
+#[get("/users/two_factor")]
+#[instrument(
+    name = "Two-factor page",
+    level = "info",
+    target = "kid_data",
+    skip(user)
+)]
+pub async fn two_factor_page(user: AuthenticatedUser) -> HttpResponse {
+    render_page(&TwoFactorPage {
+        title: TITLE,
+        enabled: user.user.totp_secret.is_some(),
+        qr_code: "",
+        secret: "",
+        recovery_codes: &[],
+        remaining: user.user.recovery_codes.len(),
+    })
+}
+
+/// Makes a new secret and shows it as a QR code. It is not used for logins
+/// until a code from it is confirmed.
+#[post("/users/two_factor/setup")]
+#[instrument(
+    name = "Set up two-factor",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool)
+)]
+pub async fn setup_two_factor(user: AuthenticatedUser, pool: Data<Database>) -> HttpResponse {
+    if user.user.totp_secret.is_some() {
+        return AppError::Validation(String::from("Two-factor is already on")).error_response();
+    }
+
+    let secret = generate_secret();
+    if let Err(err) = UserRepo::new(pool.as_ref())
+        .begin_two_factor(user.id, &secret)
+        .await
+    {
+        return AppError::from(err).error_response();
+    }
+
+    let Some(qr_code) = qr_svg(&provisioning_uri(&secret, &user.user.email)) else {
+        error!("Enrollment URI is too long for a QR code");
+        return AppError::Backend(String::from("Unable to draw the QR code")).error_response();
+    };
+
+    render_page(&TwoFactorPage {
+        title: TITLE,
+        enabled: false,
+        qr_code: &qr_code,
+        secret: &secret,
+        recovery_codes: &[],
+        remaining: 0,
+    })
+}
+
+/// Turns two-factor on once the user proves their app has the secret, and
+/// hands out recovery codes
+#[post("/users/two_factor/activate")]
+#[instrument(
+    name = "Activate two-factor",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, settings, input)
+)]
+pub async fn activate_two_factor(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    settings: Data<Settings>,
+    Form(input): Form<SecondFactor>,
+) -> HttpResponse {
+    let Some(secret) = user.user.totp_pending_secret.as_deref() else {
+        return AppError::Validation(String::from("Start setting up two-factor first"))
+            .error_response();
+    };
+    let Some(step) = totp::verify(secret, &input.code, Utc::now().timestamp(), 0) else {
+        warn!("Enrollment code rejected");
+        return AppError::Validation(String::from("That code is not right")).error_response();
+    };
+
+    let codes = generate_recovery_codes();
+    let hashes = match hash_recovery_codes(&codes, settings.secret.argon2).await {
+        Ok(hashes) => hashes,
+        Err(err) => {
+            return AppError::Backend(format!("Unable to hash recovery codes: {err}"))
+                .error_response();
+        }
+    };
+
+    match UserRepo::new(pool.as_ref())
+        .activate_two_factor(user.id, secret, step, hashes)
+        .await
+    {
+        Ok(result) if result.matched_count == 1 => {
+            info!("Two-factor turned on");
+            render_page(&TwoFactorPage {
+                title: TITLE,
+                enabled: true,
+                qr_code: "",
+                secret: "",
+                recovery_codes: &codes,
+                remaining: codes.len(),
+            })
+        }
+        Ok(_) => AppError::Validation(String::from(
+            "Two-factor setup was restarted elsewhere. Start again",
+        ))
+        .error_response(),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Turns two-factor off; takes a current code so a stolen session cannot
+#[allow(clippy::future_not_send)]
+#[post("/users/two_factor/disable")]
+#[instrument(
+    name = "Disable two-factor",
+    level = "info",
+    target = "kid_data",
+    skip(user, pool, throttle, req, input)
+)]
+pub async fn disable_two_factor(
+    user: AuthenticatedUser,
+    pool: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    req: HttpRequest,
+    Form(input): Form<SecondFactor>,
+) -> HttpResponse {
+    if user.user.totp_secret.is_none() {
+        return AppError::Validation(String::from("Two-factor is already off")).error_response();
+    }
+
+    // Wrong codes count against the same limit as wrong passwords
+    let ip = client_ip(&req);
+    match throttle.check(&user.user.email, &ip).await {
+        Ok(None) => {}
+        Ok(Some(throttled)) => return throttled_response(throttled),
+        Err(err) => {
+            return AppError::Backend(format!("Unable to check the login throttle: {err}"))
+                .error_response()
+        }
+    }
+
+    let users = UserRepo::new(pool.as_ref());
+    match check_second_factor(&users, &user.user, &input.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Wrong code when turning two-factor off");
+            count_failure(&throttle, &user.user.email, &ip, Some(&user.user)).await;
+            return AppError::Unauthorized(String::from("That code is not right")).error_response();
+        }
+        Err(err) => return err.error_response(),
+    }
+
+    match users.reset_two_factor(user.id).await {
+        Ok(_) => {
+            info!("Two-factor turned off");
+            render_page(&TwoFactorPage {
+                title: TITLE,
+                enabled: false,
+                qr_code: "",
+                secret: "",
+                recovery_codes: &[],
+                remaining: 0,
+            })
+        }
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+#[cfg(test)]
+mod test_two_factor {
+    use super::*;
+
+    #[test]
+    fn test_enrollment_uri_draws_as_inline_svg() {
+        let svg = qr_svg(&provisioning_uri(&generate_secret(), "parent@example.com"))
+            .expect("a provisioning URI fits in a QR code");
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+    }
+}
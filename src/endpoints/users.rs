@@ -3,42 +3,67 @@ use actix_web::{
     http::StatusCode,
     post, put,
     web::{Data, Json, Path},
-    HttpResponse,
+    HttpResponse, ResponseError,
 };
 use mongodb::{bson::oid::ObjectId, Database};
 use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
-    endpoints::{error::render_error, register::CreateNewUser},
-    models::mongo::{MongoRepo, User},
+    auth::throttle::LoginThrottle,
+    endpoints::{
+        error::{render_error, AppError},
+        register::CreateNewUser,
+    },
+    models::{
+        mongo::{PublicUser, UserChanges, UserRepo},
+        repository::RepoError,
+    },
+    settings::Settings,
 };
 
 #[post("/user")]
-#[instrument(name = "Create user", level = "debug", target = "kid_data", skip(client), fields(id = %new_user.email))]
-pub async fn create(client: Data<Database>, new_user: Json<CreateNewUser>) -> HttpResponse {
+#[instrument(name = "Create user", level = "debug", target = "kid_data", skip(client, settings), fields(id = %new_user.email))]
+pub async fn create(
+    client: Data<Database>,
+    settings: Data<Settings>,
+    new_user: Json<CreateNewUser>,
+) -> HttpResponse {
     info!("Creating user API endpoint");
-    let db = MongoRepo::new(&client.as_ref().to_owned(), None);
+    let db = UserRepo::new(client.as_ref());
     let data = new_user.into_inner();
     debug!("Creating user: {:#?}", data);
 
-    let user_details = db.create_user(data).await;
+    let user_details = db.create_user(data, settings.secret.argon2).await;
 
     user_details.map_or_else(
-        |err| {
-            error!("Error creating user: {err:#?}");
-            HttpResponse::InternalServerError().finish()
+        |err| match err {
+            RepoError::Conflict(_) => HttpResponse::Conflict().finish(),
+            err => {
+                error!("Error creating user: {err:#?}");
+                HttpResponse::InternalServerError().finish()
+            }
         },
-        |user| HttpResponse::Ok().json(user),
+        |id| HttpResponse::Ok().json(serde_json::json!({ "insertedId": id })),
     )
 }
 
 #[get("/user/{id}")]
-#[instrument(name = "Get user", level = "info", target = "kid_data", skip(client, path), fields(id = %path.email))]
-pub async fn get_user(client: Data<Database>, path: Path<User>) -> HttpResponse {
+#[instrument(
+    name = "Get user",
+    level = "info",
+    target = "kid_data",
+    skip(client, path)
+)]
+pub async fn get_user(client: Data<Database>, path: Path<String>) -> HttpResponse {
     info!("Getting user API endpoint");
-    let db = MongoRepo::new(&client.as_ref().to_owned(), None);
+    let db = UserRepo::new(client.as_ref());
+
+    let Ok(user_id) = ObjectId::parse_str(path.into_inner()) else {
+        error!("No valid ID found");
+        return HttpResponse::BadRequest().into();
+    };
 
-    let user_details = db.get_user(Some(path.id.expect("No ID found")), None).await;
+    let user_details = db.get_user(Some(user_id), None).await;
 
     user_details.map_or_else(
         |err| {
@@ -47,7 +72,7 @@ pub async fn get_user(client: Data<Database>, path: Path<User>) -> HttpResponse
 
             HttpResponse::InternalServerError().json(error_message)
         },
-        |user| HttpResponse::Ok().json(user),
+        |user| HttpResponse::Ok().json(PublicUser::from(user)),
     )
 }
 
@@ -56,47 +81,29 @@ pub async fn get_user(client: Data<Database>, path: Path<User>) -> HttpResponse
     name = "Update user",
     level = "info",
     target = "kid_data",
-    skip(client, path, new_user)
+    skip(client, path, changes)
 )]
 pub async fn update_user(
     client: Data<Database>,
     path: Path<String>,
-    new_user: Json<User>,
+    changes: Json<UserChanges>,
 ) -> HttpResponse {
     info!("Updating user API endpoint");
-    let db = MongoRepo::new(&client.as_ref().to_owned(), None);
-
-    let user_id = path.into_inner();
+    let db = UserRepo::new(client.as_ref());
 
-    if user_id.is_empty() {
-        error!("No ID found");
+    let Ok(user_id) = ObjectId::parse_str(path.into_inner()) else {
+        error!("No valid ID found");
         return HttpResponse::BadRequest().into();
-    }
-
-    let data = User {
-        id: Some(ObjectId::parse_str(&user_id).expect("Invalid ID")),
-        first_name: new_user.first_name.clone(),
-        last_name: new_user.last_name.clone(),
-        thumbnail: None,
-        is_active: Some(false),
-        email: new_user.email.clone(),
-        sign_up_date: new_user.sign_up_date,
-        password: String::from("************"),
     };
 
-    let update_result = db
-        .update_user(
-            ObjectId::from_bytes(data.id.expect("No ID found").bytes()),
-            data.clone(),
-        )
-        .await;
+    let update_result = db.update_user(user_id, changes.into_inner()).await;
 
     match update_result {
         Ok(update) => {
             if update.matched_count == 1 {
-                let update_user_info = db.get_user(Some(data.id.expect("No ID found")), None).await;
+                let update_user_info = db.get_user(Some(user_id), None).await;
                 return match update_user_info {
-                    Ok(user) => HttpResponse::Ok().json(user),
+                    Ok(user) => HttpResponse::Ok().json(PublicUser::from(user)),
                     Err(err) => HttpResponse::InternalServerError()
                         .json(format!("Error getting user: {err:#?})")),
                 };
@@ -106,7 +113,7 @@ pub async fn update_user(
         }
         Err(err) => {
             error!("Error updating user: {err:#?}");
-            HttpResponse::InternalServerError().body(format!("Error updating user: {err:#?}"))
+            AppError::from(err).error_response()
         }
     };
     HttpResponse::NotFound().body("User not found")
@@ -121,7 +128,7 @@ pub async fn update_user(
 )]
 pub async fn delete_user(client: Data<Database>, path: Path<String>) -> HttpResponse {
     info!("Deleting user API endpoint");
-    let db = MongoRepo::new(&client.as_ref().to_owned(), None);
+    let db = UserRepo::new(client.as_ref());
     let user_id = path.into_inner();
 
     if user_id.is_empty() {
@@ -132,15 +139,87 @@ pub async fn delete_user(client: Data<Database>, path: Path<String>) -> HttpResp
     debug!("Deleting user");
     let delete_result = db.delete_user(user_id.clone()).await;
 
-    let deleted = delete_result;
-    if deleted.expect("Database error").deleted_count == 1 {
-        debug!("User deleted successfully");
-        return HttpResponse::Ok().body("<h1>User deleted successfully</h1>");
+    match delete_result {
+        Ok(deleted) if deleted.deleted_count == 1 => {
+            debug!("User deleted successfully");
+            return HttpResponse::Ok().body("<h1>User deleted successfully</h1>");
+        }
+        Ok(_) => {}
+        Err(err) => return AppError::from(err).error_response(),
     }
     warn!("User not found");
     render_error(StatusCode::NOT_FOUND, "User not found", None)
 }
 
+/// Turns off a user's second factor, for when they lose their device and
+/// their recovery codes
+#[delete("/user/{id}/two_factor")]
+#[instrument(
+    name = "Reset two-factor",
+    level = "info",
+    target = "kid_data",
+    skip(client, path)
+)]
+pub async fn reset_two_factor(client: Data<Database>, path: Path<String>) -> HttpResponse {
+    info!("Resetting two-factor API endpoint");
+    let db = UserRepo::new(client.as_ref());
+
+    let Ok(user_id) = ObjectId::parse_str(path.into_inner()) else {
+        error!("No valid ID found");
+        return HttpResponse::BadRequest().into();
+    };
+
+    match db.reset_two_factor(user_id).await {
+        Ok(reset) if reset.matched_count == 1 => match db.get_user(Some(user_id), None).await {
+            Ok(user) => HttpResponse::Ok().json(PublicUser::from(user)),
+            Err(err) => AppError::from(err).error_response(),
+        },
+        Ok(_) => render_error(StatusCode::NOT_FOUND, "User not found", None),
+        Err(err) => AppError::from(err).error_response(),
+    }
+}
+
+/// Lifts a lockout from too many failed logins before it runs out
+#[delete("/user/{id}/lockout")]
+#[instrument(
+    name = "Unlock user",
+    level = "info",
+    target = "kid_data",
+    skip(client, throttle, path)
+)]
+pub async fn unlock_user(
+    client: Data<Database>,
+    throttle: Data<LoginThrottle>,
+    path: Path<String>,
+) -> HttpResponse {
+    info!("Unlocking user API endpoint");
+    let Ok(user_id) = ObjectId::parse_str(path.into_inner()) else {
+        error!("No valid ID found");
+        return HttpResponse::BadRequest().into();
+    };
+
+    let user = match UserRepo::new(client.as_ref())
+        .get_user(Some(user_id), None)
+        .await
+    {
+        Ok(user) => user,
+        Err(RepoError::NotFound(_)) => {
+            return render_error(StatusCode::NOT_FOUND, "User not found", None)
+        }
+        Err(err) => return AppError::from(err).error_response(),
+    };
+
+    match throttle.unlock(&user.email).await {
+        Ok(was_locked) => {
+            info!("Account unlocked; it was locked: {was_locked}");
+            HttpResponse::Ok().json(PublicUser::from(user))
+        }
+        Err(err) => {
+            AppError::Backend(format!("Unable to unlock the account: {err}")).error_response()
+        }
+    }
+}
+
 #[get("/users")]
 #[instrument(
     name = "Get all users",
@@ -150,7 +229,7 @@ pub async fn delete_user(client: Data<Database>, path: Path<String>) -> HttpResp
 )]
 pub async fn get_users(client: Data<Database>) -> HttpResponse {
     info!("Getting all users API endpoint");
-    let db = MongoRepo::new(&client.as_ref().to_owned(), None);
+    let db = UserRepo::new(client.as_ref());
     debug!("Getting all users");
     let users = db.get_all_users().await;
 
@@ -159,6 +238,8 @@ pub async fn get_users(client: Data<Database>) -> HttpResponse {
             error!("Error getting users: {err:#?}");
             HttpResponse::InternalServerError().body(format!("Error getting users: {err:#?}"))
         },
-        |users| HttpResponse::Ok().json(users),
+        |users| {
+            HttpResponse::Ok().json(users.into_iter().map(PublicUser::from).collect::<Vec<_>>())
+        },
     )
 }
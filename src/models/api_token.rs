@@ -0,0 +1,163 @@
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    results::DeleteResult,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    auth::api_tokens::ApiScope,
+    models::repository::{Entity, MongoRepository, RepoError, Repository},
+};
+
+/// A personal access token, as the server remembers it. The token itself is
+/// only shown once, when it is issued; it names this document by ID, so
+/// deleting the document revokes the token.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    /// What the owner called it, so they know which script uses it
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: DateTime,
+    /// Mongo deletes the document once this passes
+    pub expires_at: DateTime,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime>,
+}
+
+impl ApiToken {
+    /// # Result
+    ///   - `true` if the token may make a request needing `scope`
+    #[must_use]
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+impl Entity for ApiToken {
+    const COLLECTION: &'static str = "api_tokens";
+    const NAME: &'static str = "API token";
+}
+
+pub struct ApiTokenRepo {
+    repo: MongoRepository<ApiToken>,
+}
+
+impl ApiTokenRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new ApiTokenRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the stored token
+    /// # Errors
+    ///   - Returns a `RepoError` if the token fails to insert
+    #[instrument(
+        name = "Insert API token",
+        level = "debug",
+        target = "kid_data",
+        skip(self, token)
+    )]
+    pub async fn insert(&self, token: &ApiToken) -> Result<ObjectId, RepoError> {
+        self.repo.insert(token).await
+    }
+
+    /// # Results
+    ///   - Returns the user's unexpired tokens, newest first
+    /// # Errors
+    ///   - Returns a `RepoError` if the tokens fail to be read
+    #[instrument(
+        name = "List API tokens",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn for_user(&self, user_id: ObjectId) -> Result<Vec<ApiToken>, RepoError> {
+        self.repo
+            .find_many(
+                doc! { "user_id": user_id, "expires_at": { "$gt": DateTime::now() } },
+                doc! { "created_at": -1 },
+                None,
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the token if it still exists, belongs to the user and has
+    ///     not expired. Mongo's expiry sweep only runs once a minute.
+    /// # Errors
+    ///   - Returns a `RepoError` if there is no such token or it fails to be read
+    #[instrument(
+        name = "Find live API token",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn find_live(&self, id: ObjectId, user_id: ObjectId) -> Result<ApiToken, RepoError> {
+        self.repo
+            .find_one(doc! {
+                "_id": id,
+                "user_id": user_id,
+                "expires_at": { "$gt": DateTime::now() },
+            })
+            .await
+    }
+
+    /// # Errors
+    ///   - Returns a `RepoError` if the token fails to update
+    #[instrument(
+        name = "Touch API token",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn touch(&self, id: ObjectId) -> Result<(), RepoError> {
+        self.repo
+            .update_by_id(id, doc! { "$set": { "last_used_at": DateTime::now() } })
+            .await
+            .map(|_| ())
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult`; nothing is deleted unless the user owns the token
+    /// # Errors
+    ///   - Returns a `RepoError` if the token fails to delete
+    #[instrument(
+        name = "Revoke API token",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn revoke(&self, id: ObjectId, user_id: ObjectId) -> Result<DeleteResult, RepoError> {
+        self.repo
+            .delete_one(doc! { "_id": id, "user_id": user_id })
+            .await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once none of the user's tokens work
+    /// # Errors
+    ///   - Returns a `RepoError` if the tokens fail to delete
+    #[instrument(
+        name = "Revoke all API tokens",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn revoke_all(&self, user_id: ObjectId) -> Result<DeleteResult, RepoError> {
+        self.repo.delete_many(doc! { "user_id": user_id }).await
+    }
+}
@@ -0,0 +1,142 @@
+use chrono::NaiveDate;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    results::{DeleteResult, UpdateResult},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::repository::{Entity, MongoRepository, RepoError, Repository};
+
+/// A single visit to the doctor for one child
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Appointment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    pub provider_id: ObjectId,
+    pub date: NaiveDate,
+    pub purpose: String,
+    pub notes: Vec<String>,
+}
+
+impl Entity for Appointment {
+    const COLLECTION: &'static str = "appointments";
+    const NAME: &'static str = "Appointment";
+}
+
+pub struct AppointmentRepo {
+    repo: MongoRepository<Appointment>,
+}
+
+impl AppointmentRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new AppointmentRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted appointment
+    /// # Errors
+    ///   - Returns a `RepoError` if the appointment fails to insert into the collection
+    #[instrument(
+        name = "Create appointment",
+        level = "debug",
+        target = "kid_data",
+        skip(self, appointment)
+    )]
+    pub async fn create_appointment(
+        &self,
+        appointment: &Appointment,
+    ) -> Result<ObjectId, RepoError> {
+        info!("Adding a doctor's appointment");
+        self.repo.insert(appointment).await
+    }
+
+    /// # Results
+    ///   - Returns the `Appointment` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the appointment fails to be found in the collection
+    #[instrument(
+        name = "Get appointment",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_appointment(&self, id: ObjectId) -> Result<Appointment, RepoError> {
+        info!("Getting appointment");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns every `Appointment` for the child, newest first
+    /// # Errors
+    ///   - Returns a `RepoError` if the appointments fail to be read from the collection
+    #[instrument(
+        name = "Get child appointments",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_appointments_for_child(
+        &self,
+        child_id: ObjectId,
+    ) -> Result<Vec<Appointment>, RepoError> {
+        info!("Getting appointments for child");
+        self.repo
+            .find_many(doc! { "child_id": child_id }, doc! { "date": -1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the appointment is successfully updated
+    /// # Errors
+    ///   - Returns a `RepoError` if the appointment fails to update in the collection
+    #[instrument(
+        name = "Update appointment",
+        level = "debug",
+        target = "kid_data",
+        skip(self, appointment)
+    )]
+    pub async fn update_appointment(
+        &self,
+        id: ObjectId,
+        appointment: Appointment,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Updating appointment");
+        let update = doc! {
+            "$set": {
+                "provider_id": appointment.provider_id,
+                "date": appointment.date.to_string(),
+                "purpose": appointment.purpose,
+                "notes": appointment.notes,
+            }
+        };
+
+        self.repo.update_by_id(id, update).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the appointment is successfully deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the appointment fails to delete from the collection
+    #[instrument(
+        name = "Delete appointment",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_appointment(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting appointment");
+        self.repo.delete_by_id(id).await
+    }
+}
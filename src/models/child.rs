@@ -0,0 +1,247 @@
+use chrono::{Local, NaiveDate};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    results::{DeleteResult, UpdateResult},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::{
+    appointment::Appointment,
+    dental::DentalVisit,
+    helpers::age_on,
+    immunization::Immunization,
+    photo::Photo,
+    repository::{db_error, Entity, MongoRepository, RepoError, Repository},
+    school::{Grade, SchoolYear},
+};
+
+/// A child (or any family member) whose records are kept on the site
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Child {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub slug: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub nickname: Option<String>,
+    pub date_of_birth: NaiveDate,
+    pub profile_photo: Option<String>,
+    pub guardians: Vec<ObjectId>,
+}
+
+impl Child {
+    #[must_use]
+    pub fn full_name(&self) -> String {
+        format!("{} {}", self.first_name, self.last_name)
+    }
+
+    /// # Result
+    ///   - The nickname when one is set, otherwise the first name
+    #[must_use]
+    pub fn display_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.first_name)
+    }
+
+    /// # Result
+    ///   - Age in whole years as of today
+    #[must_use]
+    pub fn age(&self) -> u32 {
+        age_on(self.date_of_birth, Local::now().date_naive())
+    }
+
+    /// # Result
+    ///   - The profile photo route, or the generic placeholder image
+    #[must_use]
+    pub fn photo(&self) -> &str {
+        self.profile_photo
+            .as_deref()
+            .unwrap_or("/images/english_image")
+    }
+}
+
+impl Entity for Child {
+    const COLLECTION: &'static str = "children";
+    const NAME: &'static str = "Child";
+}
+
+pub struct ChildRepo {
+    repo: MongoRepository<Child>,
+    db: Database,
+}
+
+impl ChildRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new ChildRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+            db: db.clone(),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted child
+    /// # Errors
+    ///   - Returns a `RepoError` if the child fails to insert into the collection
+    #[instrument(
+        name = "Create child",
+        level = "debug",
+        target = "kid_data",
+        skip(self, child),
+        fields(slug = %child.slug)
+    )]
+    pub async fn create_child(&self, child: &Child) -> Result<ObjectId, RepoError> {
+        info!("Creating child profile");
+        self.repo.insert(child).await
+    }
+
+    /// # Results
+    ///   - Returns a `Child` if one with the matching slug is found
+    /// # Errors
+    ///   - Returns a `RepoError` if the child fails to be found in the collection
+    #[instrument(name = "Get child", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_child(&self, slug: &str) -> Result<Child, RepoError> {
+        info!("Getting child by slug");
+        self.repo.find_one(doc! { "slug": slug }).await
+    }
+
+    /// # Results
+    ///   - Returns the `Child` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the child fails to be found in the collection
+    #[instrument(
+        name = "Get child by ID",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_child_by_id(&self, id: ObjectId) -> Result<Child, RepoError> {
+        info!("Getting child by ID");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns every `Child` ordered by date of birth
+    /// # Errors
+    ///   - Returns a `RepoError` if the children fail to be read from the collection
+    #[instrument(
+        name = "Get all children",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_all_children(&self) -> Result<Vec<Child>, RepoError> {
+        info!("Getting all children");
+        self.repo
+            .find_many(doc! {}, doc! { "date_of_birth": 1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the child is successfully updated
+    /// # Errors
+    ///   - Returns a `RepoError` if the child fails to update in the collection
+    #[instrument(
+        name = "Update child",
+        level = "debug",
+        target = "kid_data",
+        skip(self, child)
+    )]
+    pub async fn update_child(&self, slug: &str, child: Child) -> Result<UpdateResult, RepoError> {
+        info!("Updating child profile");
+        let update = doc! {
+            "$set": {
+                "slug": child.slug,
+                "first_name": child.first_name,
+                "last_name": child.last_name,
+                "nickname": child.nickname,
+                "date_of_birth": child.date_of_birth.to_string(),
+                "profile_photo": child.profile_photo,
+                "guardians": child.guardians,
+            }
+        };
+
+        self.repo.update_one(doc! { "slug": slug }, update).await
+    }
+
+    /// # Results
+    ///   - Returns how many visits, school records, photos and shots are kept
+    ///     for the child
+    /// # Errors
+    ///   - Returns a `RepoError` if any of the collections fail to be counted
+    #[instrument(
+        name = "Count child records",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn count_records(&self, child_id: ObjectId) -> Result<u64, RepoError> {
+        info!("Counting records kept for child");
+        let filter = doc! { "child_id": child_id };
+        let counts = [
+            MongoRepository::<Appointment>::new(&self.db)
+                .count(filter.clone())
+                .await?,
+            MongoRepository::<DentalVisit>::new(&self.db)
+                .count(filter.clone())
+                .await?,
+            MongoRepository::<SchoolYear>::new(&self.db)
+                .count(filter.clone())
+                .await?,
+            MongoRepository::<Grade>::new(&self.db)
+                .count(filter.clone())
+                .await?,
+            MongoRepository::<Photo>::new(&self.db)
+                .count(filter.clone())
+                .await?,
+            MongoRepository::<Immunization>::new(&self.db)
+                .count(filter)
+                .await?,
+        ];
+        Ok(counts.iter().sum())
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the child is successfully deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the child fails to delete from the collection
+    #[instrument(
+        name = "Delete child",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_child(&self, slug: &str) -> Result<DeleteResult, RepoError> {
+        info!("Deleting child profile");
+        self.repo.delete_one(doc! { "slug": slug }).await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` once the user is no longer a guardian of
+    ///     any child
+    /// # Errors
+    ///   - Returns a `RepoError` if the children fail to update
+    #[instrument(
+        name = "Remove guardian",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn remove_guardian(&self, user_id: ObjectId) -> Result<UpdateResult, RepoError> {
+        self.repo
+            .collection()
+            .update_many(
+                doc! { "guardians": user_id },
+                doc! { "$pull": { "guardians": user_id } },
+            )
+            .await
+            .map_err(|err| db_error("Failed to remove the guardian from children", err))
+    }
+}
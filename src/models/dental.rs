@@ -0,0 +1,270 @@
+use std::fmt::{self, Display, Formatter};
+
+use chrono::{Months, NaiveDate};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    results::{DeleteResult, UpdateResult},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::repository::{db_error, Entity, MongoRepository, RepoError, Repository};
+
+/// How long after a cleaning the next one is due
+pub const CLEANING_INTERVAL_MONTHS: u32 = 6;
+
+/// Work that can be performed during a dental visit
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DentalProcedure {
+    Cleaning,
+    Exam,
+    XRay,
+    Filling,
+    Sealant,
+    Orthodontic,
+}
+
+impl DentalProcedure {
+    pub const ALL: [Self; 6] = [
+        Self::Cleaning,
+        Self::Exam,
+        Self::XRay,
+        Self::Filling,
+        Self::Sealant,
+        Self::Orthodontic,
+    ];
+
+    /// # Result
+    ///   - The stored (and form field) name of the procedure
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cleaning => "cleaning",
+            Self::Exam => "exam",
+            Self::XRay => "x_ray",
+            Self::Filling => "filling",
+            Self::Sealant => "sealant",
+            Self::Orthodontic => "orthodontic",
+        }
+    }
+}
+
+impl Display for DentalProcedure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Cleaning => write!(f, "Cleaning"),
+            Self::Exam => write!(f, "Exam"),
+            Self::XRay => write!(f, "X-Ray"),
+            Self::Filling => write!(f, "Cavity / Filling"),
+            Self::Sealant => write!(f, "Sealant"),
+            Self::Orthodontic => write!(f, "Orthodontic"),
+        }
+    }
+}
+
+/// A single visit to the dentist or orthodontist for one child
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DentalVisit {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    pub provider_id: ObjectId,
+    pub date: NaiveDate,
+    pub procedures: Vec<DentalProcedure>,
+    /// Number of cavities found during the visit
+    pub cavities: u32,
+    /// Teeth worked on (fillings, sealants, brackets), by tooth number or name
+    pub teeth: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+impl DentalVisit {
+    #[must_use]
+    pub fn has(&self, procedure: DentalProcedure) -> bool {
+        self.procedures.contains(&procedure)
+    }
+}
+
+/// # Result
+///   - The date the next cleaning is due, six months after the most recent cleaning
+///   - `None` when no cleaning has been recorded
+#[must_use]
+pub fn next_cleaning_due(visits: &[DentalVisit]) -> Option<NaiveDate> {
+    visits
+        .iter()
+        .filter(|visit| visit.has(DentalProcedure::Cleaning))
+        .map(|visit| visit.date)
+        .max()
+        .and_then(|last| last.checked_add_months(Months::new(CLEANING_INTERVAL_MONTHS)))
+}
+
+impl Entity for DentalVisit {
+    const COLLECTION: &'static str = "dental_visits";
+    const NAME: &'static str = "Dental visit";
+}
+
+pub struct DentalRepo {
+    repo: MongoRepository<DentalVisit>,
+}
+
+impl DentalRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new DentalRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted visit
+    /// # Errors
+    ///   - Returns a `RepoError` if the visit fails to insert into the collection
+    #[instrument(
+        name = "Create dental visit",
+        level = "debug",
+        target = "kid_data",
+        skip(self, visit)
+    )]
+    pub async fn create_visit(&self, visit: &DentalVisit) -> Result<ObjectId, RepoError> {
+        info!("Adding a dental visit");
+        self.repo.insert(visit).await
+    }
+
+    /// # Results
+    ///   - Returns the `DentalVisit` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the visit fails to be found in the collection
+    #[instrument(
+        name = "Get dental visit",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_visit(&self, id: ObjectId) -> Result<DentalVisit, RepoError> {
+        info!("Getting dental visit");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns every `DentalVisit` for the child, newest first
+    /// # Errors
+    ///   - Returns a `RepoError` if the visits fail to be read from the collection
+    #[instrument(
+        name = "Get child dental visits",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_visits_for_child(
+        &self,
+        child_id: ObjectId,
+    ) -> Result<Vec<DentalVisit>, RepoError> {
+        info!("Getting dental visits for child");
+        self.repo
+            .find_many(doc! { "child_id": child_id }, doc! { "date": -1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the visit is successfully updated
+    /// # Errors
+    ///   - Returns a `RepoError` if the visit fails to update in the collection
+    #[instrument(
+        name = "Update dental visit",
+        level = "debug",
+        target = "kid_data",
+        skip(self, visit)
+    )]
+    pub async fn update_visit(
+        &self,
+        id: ObjectId,
+        visit: DentalVisit,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Updating dental visit");
+        let procedures = mongodb::bson::to_bson(&visit.procedures)
+            .map_err(|err| db_error("Failed to serialize procedures", err))?;
+
+        let update = doc! {
+            "$set": {
+                "provider_id": visit.provider_id,
+                "date": visit.date.to_string(),
+                "procedures": procedures,
+                "cavities": visit.cavities,
+                "teeth": visit.teeth,
+                "notes": visit.notes,
+            }
+        };
+
+        self.repo.update_by_id(id, update).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the visit is successfully deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the visit fails to delete from the collection
+    #[instrument(
+        name = "Delete dental visit",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_visit(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting dental visit");
+        self.repo.delete_by_id(id).await
+    }
+}
+
+#[cfg(test)]
+mod test_dental {
+    use super::*;
+
+    fn visit(date: NaiveDate, procedures: Vec<DentalProcedure>) -> DentalVisit {
+        DentalVisit {
+            id: None,
+            child_id: ObjectId::new(),
+            provider_id: ObjectId::new(),
+            date,
+            procedures,
+            cavities: 0,
+            teeth: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).expect("valid date")
+    }
+
+    #[test]
+    fn test_no_cleanings_no_due_date() {
+        let visits = vec![visit(date(2024, 1, 10), vec![DentalProcedure::Exam])];
+        assert_eq!(next_cleaning_due(&visits), None);
+    }
+
+    #[test]
+    fn test_due_six_months_after_latest_cleaning() {
+        let visits = vec![
+            visit(date(2023, 3, 1), vec![DentalProcedure::Cleaning]),
+            visit(
+                date(2024, 2, 15),
+                vec![DentalProcedure::Cleaning, DentalProcedure::XRay],
+            ),
+            visit(date(2024, 5, 1), vec![DentalProcedure::Filling]),
+        ];
+        assert_eq!(next_cleaning_due(&visits), Some(date(2024, 8, 15)));
+    }
+
+    #[test]
+    fn test_due_date_clamps_to_month_end() {
+        let visits = vec![visit(date(2024, 8, 31), vec![DentalProcedure::Cleaning])];
+        assert_eq!(next_cleaning_due(&visits), Some(date(2025, 2, 28)));
+    }
+}
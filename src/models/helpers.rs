@@ -1 +1,89 @@
+use chrono::NaiveDate;
 
+/// # Result
+///   - A lowercase, URL safe version of `input`
+/// # Notes
+///   - Anything that is not ASCII alphanumeric collapses into a single `-`
+#[must_use]
+pub fn slugify(input: &str) -> String {
+    let mut slug = String::with_capacity(input.len());
+
+    input.trim().chars().for_each(|c| {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    });
+
+    slug.trim_end_matches('-').to_string()
+}
+
+/// # Result
+///   - Whole years between `date_of_birth` and `today`; zero if not yet born
+#[must_use]
+pub fn age_on(date_of_birth: NaiveDate, today: NaiveDate) -> u32 {
+    today.years_since(date_of_birth).unwrap_or(0)
+}
+
+/// # Result
+///   - `None` when the trimmed `input` is empty, otherwise the trimmed `input`
+#[must_use]
+pub fn non_empty(input: &str) -> Option<String> {
+    let trimmed = input.trim();
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+/// # Result
+///   - `email` as accounts store and look it up: trimmed and lowercase, so
+///     addresses that differ only in case name the same account
+#[must_use]
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod test_helpers {
+    use super::*;
+
+    #[test]
+    fn test_slugify_simple_name() {
+        assert_eq!(slugify("Adrian"), "adrian");
+    }
+
+    #[test]
+    fn test_slugify_collapses_punctuation() {
+        assert_eq!(slugify("  A.J. Hunter!! "), "a-j-hunter");
+    }
+
+    #[test]
+    fn test_age_before_birthday() {
+        let dob = NaiveDate::from_ymd_opt(2017, 6, 15).expect("valid date");
+        let today = NaiveDate::from_ymd_opt(2024, 6, 14).expect("valid date");
+        assert_eq!(age_on(dob, today), 6);
+    }
+
+    #[test]
+    fn test_age_on_birthday() {
+        let dob = NaiveDate::from_ymd_opt(2017, 6, 15).expect("valid date");
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("valid date");
+        assert_eq!(age_on(dob, today), 7);
+    }
+
+    #[test]
+    fn test_age_not_yet_born() {
+        let dob = NaiveDate::from_ymd_opt(2025, 1, 1).expect("valid date");
+        let today = NaiveDate::from_ymd_opt(2024, 6, 15).expect("valid date");
+        assert_eq!(age_on(dob, today), 0);
+    }
+
+    #[test]
+    fn test_emails_differing_in_case_are_one_address() {
+        assert_eq!(normalize_email(" Alice@Example.com "), "alice@example.com");
+        assert_eq!(normalize_email("Alice@x"), normalize_email("alice@x"));
+    }
+}
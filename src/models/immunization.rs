@@ -0,0 +1,455 @@
+use std::fmt::{self, Display, Formatter};
+
+use chrono::{Months, NaiveDate};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    results::{DeleteResult, UpdateResult},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::repository::{db_error, Entity, MongoRepository, RepoError, Repository};
+
+/// Vaccines that can be recorded for a child
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Vaccine {
+    HepB,
+    Rotavirus,
+    Dtap,
+    Hib,
+    Pcv,
+    Ipv,
+    Mmr,
+    Varicella,
+    HepA,
+    Tdap,
+    Hpv,
+    MenAcwy,
+    /// Given every season, so it has no place in the schedule
+    Influenza,
+}
+
+impl Vaccine {
+    pub const ALL: [Self; 13] = [
+        Self::HepB,
+        Self::Rotavirus,
+        Self::Dtap,
+        Self::Hib,
+        Self::Pcv,
+        Self::Ipv,
+        Self::Mmr,
+        Self::Varicella,
+        Self::HepA,
+        Self::Tdap,
+        Self::Hpv,
+        Self::MenAcwy,
+        Self::Influenza,
+    ];
+
+    /// # Result
+    ///   - The stored (and form field) name of the vaccine
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::HepB => "hep_b",
+            Self::Rotavirus => "rotavirus",
+            Self::Dtap => "dtap",
+            Self::Hib => "hib",
+            Self::Pcv => "pcv",
+            Self::Ipv => "ipv",
+            Self::Mmr => "mmr",
+            Self::Varicella => "varicella",
+            Self::HepA => "hep_a",
+            Self::Tdap => "tdap",
+            Self::Hpv => "hpv",
+            Self::MenAcwy => "men_acwy",
+            Self::Influenza => "influenza",
+        }
+    }
+}
+
+impl Display for Vaccine {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::HepB => write!(f, "Hepatitis B"),
+            Self::Rotavirus => write!(f, "Rotavirus"),
+            Self::Dtap => write!(f, "DTaP"),
+            Self::Hib => write!(f, "Hib"),
+            Self::Pcv => write!(f, "Pneumococcal (PCV)"),
+            Self::Ipv => write!(f, "Polio (IPV)"),
+            Self::Mmr => write!(f, "MMR"),
+            Self::Varicella => write!(f, "Varicella"),
+            Self::HepA => write!(f, "Hepatitis A"),
+            Self::Tdap => write!(f, "Tdap"),
+            Self::Hpv => write!(f, "HPV"),
+            Self::MenAcwy => write!(f, "Meningococcal (MenACWY)"),
+            Self::Influenza => write!(f, "Influenza"),
+        }
+    }
+}
+
+/// A single dose given to one child
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Immunization {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    /// Who gave the dose
+    pub provider_id: ObjectId,
+    pub vaccine: Vaccine,
+    /// Which dose in the series, starting at 1
+    pub dose: u32,
+    pub date: NaiveDate,
+    pub lot_number: String,
+}
+
+/// One dose of the routine childhood schedule
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScheduledDose {
+    pub vaccine: Vaccine,
+    pub dose: u32,
+    /// Age in months the dose is first recommended
+    pub due_months: u32,
+    /// Age in months after which the dose is overdue
+    pub late_months: u32,
+}
+
+const fn scheduled(
+    vaccine: Vaccine,
+    dose: u32,
+    due_months: u32,
+    late_months: u32,
+) -> ScheduledDose {
+    ScheduledDose {
+        vaccine,
+        dose,
+        due_months,
+        late_months,
+    }
+}
+
+/// The routine pediatric schedule, birth through 16 years
+pub const SCHEDULE: [ScheduledDose; 34] = [
+    scheduled(Vaccine::HepB, 1, 0, 1),
+    scheduled(Vaccine::HepB, 2, 1, 3),
+    scheduled(Vaccine::HepB, 3, 6, 19),
+    scheduled(Vaccine::Rotavirus, 1, 2, 3),
+    scheduled(Vaccine::Rotavirus, 2, 4, 5),
+    scheduled(Vaccine::Rotavirus, 3, 6, 7),
+    scheduled(Vaccine::Dtap, 1, 2, 3),
+    scheduled(Vaccine::Dtap, 2, 4, 5),
+    scheduled(Vaccine::Dtap, 3, 6, 7),
+    scheduled(Vaccine::Dtap, 4, 15, 19),
+    scheduled(Vaccine::Dtap, 5, 48, 84),
+    scheduled(Vaccine::Hib, 1, 2, 3),
+    scheduled(Vaccine::Hib, 2, 4, 5),
+    scheduled(Vaccine::Hib, 3, 6, 7),
+    scheduled(Vaccine::Hib, 4, 12, 16),
+    scheduled(Vaccine::Pcv, 1, 2, 3),
+    scheduled(Vaccine::Pcv, 2, 4, 5),
+    scheduled(Vaccine::Pcv, 3, 6, 7),
+    scheduled(Vaccine::Pcv, 4, 12, 16),
+    scheduled(Vaccine::Ipv, 1, 2, 3),
+    scheduled(Vaccine::Ipv, 2, 4, 5),
+    scheduled(Vaccine::Ipv, 3, 6, 19),
+    scheduled(Vaccine::Ipv, 4, 48, 84),
+    scheduled(Vaccine::Mmr, 1, 12, 16),
+    scheduled(Vaccine::Mmr, 2, 48, 84),
+    scheduled(Vaccine::Varicella, 1, 12, 16),
+    scheduled(Vaccine::Varicella, 2, 48, 84),
+    scheduled(Vaccine::HepA, 1, 12, 24),
+    scheduled(Vaccine::HepA, 2, 18, 24),
+    scheduled(Vaccine::Tdap, 1, 132, 156),
+    scheduled(Vaccine::Hpv, 1, 132, 156),
+    scheduled(Vaccine::Hpv, 2, 138, 156),
+    scheduled(Vaccine::MenAcwy, 1, 132, 156),
+    scheduled(Vaccine::MenAcwy, 2, 192, 204),
+];
+
+/// Where a scheduled dose stands for a child
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoseState {
+    /// Recorded
+    Complete,
+    /// Not recorded and past the end of its window
+    Overdue,
+    /// Not recorded and inside its window
+    Due,
+    /// Not recorded and the child is too young for it
+    Upcoming,
+}
+
+impl Display for DoseState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Complete => write!(f, "Complete"),
+            Self::Overdue => write!(f, "Overdue"),
+            Self::Due => write!(f, "Due"),
+            Self::Upcoming => write!(f, "Upcoming"),
+        }
+    }
+}
+
+/// A scheduled dose paired with where the child stands on it
+#[derive(Debug)]
+pub struct DoseStatus {
+    pub scheduled: &'static ScheduledDose,
+    pub state: DoseState,
+    /// The date the dose is first recommended
+    pub due: NaiveDate,
+    /// The date the dose was given, when recorded
+    pub given: Option<NaiveDate>,
+}
+
+/// # Result
+///   - Every scheduled dose with its state for a child born on `date_of_birth`,
+///     judged against `records` as of `today`
+#[must_use]
+pub fn schedule_status(
+    date_of_birth: NaiveDate,
+    records: &[Immunization],
+    today: NaiveDate,
+) -> Vec<DoseStatus> {
+    let at_age = |months| {
+        date_of_birth
+            .checked_add_months(Months::new(months))
+            .unwrap_or(NaiveDate::MAX)
+    };
+
+    SCHEDULE
+        .iter()
+        .map(|scheduled| {
+            let given = records
+                .iter()
+                .filter(|record| {
+                    record.vaccine == scheduled.vaccine && record.dose == scheduled.dose
+                })
+                .map(|record| record.date)
+                .min();
+            let due = at_age(scheduled.due_months);
+
+            let state = if given.is_some() {
+                DoseState::Complete
+            } else if today > at_age(scheduled.late_months) {
+                DoseState::Overdue
+            } else if today >= due {
+                DoseState::Due
+            } else {
+                DoseState::Upcoming
+            };
+
+            DoseStatus {
+                scheduled,
+                state,
+                due,
+                given,
+            }
+        })
+        .collect()
+}
+
+impl Entity for Immunization {
+    const COLLECTION: &'static str = "immunizations";
+    const NAME: &'static str = "Immunization";
+}
+
+pub struct ImmunizationRepo {
+    repo: MongoRepository<Immunization>,
+}
+
+impl ImmunizationRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new ImmunizationRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted immunization
+    /// # Errors
+    ///   - Returns a `RepoError` if the immunization fails to insert into the collection
+    #[instrument(
+        name = "Create immunization",
+        level = "debug",
+        target = "kid_data",
+        skip(self, immunization)
+    )]
+    pub async fn create_immunization(
+        &self,
+        immunization: &Immunization,
+    ) -> Result<ObjectId, RepoError> {
+        info!("Adding an immunization");
+        self.repo.insert(immunization).await
+    }
+
+    /// # Results
+    ///   - Returns the `Immunization` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the immunization fails to be found in the collection
+    #[instrument(
+        name = "Get immunization",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_immunization(&self, id: ObjectId) -> Result<Immunization, RepoError> {
+        info!("Getting immunization");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns every `Immunization` for the child, newest first
+    /// # Errors
+    ///   - Returns a `RepoError` if the immunizations fail to be read from the collection
+    #[instrument(
+        name = "Get child immunizations",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_immunizations_for_child(
+        &self,
+        child_id: ObjectId,
+    ) -> Result<Vec<Immunization>, RepoError> {
+        info!("Getting immunizations for child");
+        self.repo
+            .find_many(doc! { "child_id": child_id }, doc! { "date": -1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the immunization is successfully updated
+    /// # Errors
+    ///   - Returns a `RepoError` if the immunization fails to update in the collection
+    #[instrument(
+        name = "Update immunization",
+        level = "debug",
+        target = "kid_data",
+        skip(self, immunization)
+    )]
+    pub async fn update_immunization(
+        &self,
+        id: ObjectId,
+        immunization: Immunization,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Updating immunization");
+        let vaccine = mongodb::bson::to_bson(&immunization.vaccine)
+            .map_err(|err| db_error("Failed to serialize vaccine", err))?;
+
+        let update = doc! {
+            "$set": {
+                "provider_id": immunization.provider_id,
+                "vaccine": vaccine,
+                "dose": immunization.dose,
+                "date": immunization.date.to_string(),
+                "lot_number": immunization.lot_number,
+            }
+        };
+
+        self.repo.update_by_id(id, update).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the immunization is successfully deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the immunization fails to delete from the collection
+    #[instrument(
+        name = "Delete immunization",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_immunization(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting immunization");
+        self.repo.delete_by_id(id).await
+    }
+}
+
+#[cfg(test)]
+mod test_immunization {
+    use super::*;
+
+    fn shot(vaccine: Vaccine, dose: u32, date: NaiveDate) -> Immunization {
+        Immunization {
+            id: None,
+            child_id: ObjectId::new(),
+            provider_id: ObjectId::new(),
+            vaccine,
+            dose,
+            date,
+            lot_number: String::from("AB123"),
+        }
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).expect("valid date")
+    }
+
+    fn state_of(statuses: &[DoseStatus], vaccine: Vaccine, dose: u32) -> DoseState {
+        statuses
+            .iter()
+            .find(|status| status.scheduled.vaccine == vaccine && status.scheduled.dose == dose)
+            .map(|status| status.state)
+            .expect("dose is scheduled")
+    }
+
+    #[test]
+    fn test_every_series_is_numbered_from_one() {
+        for vaccine in Vaccine::ALL {
+            let doses: Vec<u32> = SCHEDULE
+                .iter()
+                .filter(|scheduled| scheduled.vaccine == vaccine)
+                .map(|scheduled| scheduled.dose)
+                .collect();
+            assert!(doses
+                .iter()
+                .copied()
+                .eq(1..=u32::try_from(doses.len()).expect("few doses")));
+        }
+    }
+
+    #[test]
+    fn test_doses_are_due_overdue_or_upcoming_by_age() {
+        let born = date(2024, 1, 15);
+        let records = vec![
+            shot(Vaccine::HepB, 1, born),
+            shot(Vaccine::HepB, 2, date(2024, 2, 20)),
+        ];
+        let statuses = schedule_status(born, &records, date(2024, 5, 1));
+
+        assert_eq!(state_of(&statuses, Vaccine::HepB, 1), DoseState::Complete);
+        assert_eq!(state_of(&statuses, Vaccine::HepB, 2), DoseState::Complete);
+        assert_eq!(state_of(&statuses, Vaccine::Dtap, 1), DoseState::Overdue);
+        assert_eq!(state_of(&statuses, Vaccine::HepB, 3), DoseState::Upcoming);
+        assert_eq!(state_of(&statuses, Vaccine::Mmr, 1), DoseState::Upcoming);
+    }
+
+    #[test]
+    fn test_a_dose_is_due_through_the_end_of_its_window() {
+        let born = date(2024, 1, 31);
+        let statuses = schedule_status(born, &[], date(2024, 3, 31));
+        assert_eq!(state_of(&statuses, Vaccine::Dtap, 1), DoseState::Due);
+
+        let statuses = schedule_status(born, &[], date(2024, 4, 30));
+        assert_eq!(state_of(&statuses, Vaccine::Dtap, 1), DoseState::Due);
+
+        let statuses = schedule_status(born, &[], date(2024, 5, 1));
+        assert_eq!(state_of(&statuses, Vaccine::Dtap, 1), DoseState::Overdue);
+    }
+
+    #[test]
+    fn test_influenza_is_recorded_but_not_scheduled() {
+        assert!(SCHEDULE
+            .iter()
+            .all(|scheduled| scheduled.vaccine != Vaccine::Influenza));
+    }
+}
@@ -0,0 +1,151 @@
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    results::DeleteResult,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    auth::grants::ChildGrant,
+    models::repository::{Entity, MongoRepository, RepoError, Repository},
+};
+
+/// An invitation for someone to join and see some of the children.
+///
+/// The link emailed to them names this document by ID; accepting it makes
+/// their account with these grants, and deleting the document withdraws it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invitation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub invited_by: ObjectId,
+    pub grants: Vec<ChildGrant>,
+    pub created_at: DateTime,
+    /// Mongo deletes the document once this passes
+    pub expires_at: DateTime,
+}
+
+impl Entity for Invitation {
+    const COLLECTION: &'static str = "invitations";
+    const NAME: &'static str = "Invitation";
+}
+
+pub struct InvitationRepo {
+    repo: MongoRepository<Invitation>,
+}
+
+impl InvitationRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new InvitationRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the stored invitation
+    /// # Errors
+    ///   - Returns a `RepoError` if the invitation fails to insert
+    #[instrument(
+        name = "Insert invitation",
+        level = "debug",
+        target = "kid_data",
+        skip(self, invitation)
+    )]
+    pub async fn insert(&self, invitation: &Invitation) -> Result<ObjectId, RepoError> {
+        self.repo.insert(invitation).await
+    }
+
+    /// # Results
+    ///   - Returns the unexpired invitations the user sent, newest first
+    /// # Errors
+    ///   - Returns a `RepoError` if the invitations fail to be read
+    #[instrument(
+        name = "List invitations",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn sent_by(&self, user_id: ObjectId) -> Result<Vec<Invitation>, RepoError> {
+        self.repo
+            .find_many(
+                doc! { "invited_by": user_id, "expires_at": { "$gt": DateTime::now() } },
+                doc! { "created_at": -1 },
+                None,
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the invitation if it still exists and has not expired.
+    ///     Mongo's expiry sweep only runs once a minute.
+    /// # Errors
+    ///   - Returns a `RepoError` if there is no such invitation or it fails to be read
+    #[instrument(
+        name = "Find live invitation",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn find_live(&self, id: ObjectId) -> Result<Invitation, RepoError> {
+        self.repo
+            .find_one(doc! { "_id": id, "expires_at": { "$gt": DateTime::now() } })
+            .await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult`; nothing is deleted unless the user sent
+    ///     the invitation
+    /// # Errors
+    ///   - Returns a `RepoError` if the invitation fails to delete
+    #[instrument(
+        name = "Revoke invitation",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn revoke(&self, id: ObjectId, user_id: ObjectId) -> Result<DeleteResult, RepoError> {
+        self.repo
+            .delete_one(doc! { "_id": id, "invited_by": user_id })
+            .await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once an accepted invitation is gone
+    /// # Errors
+    ///   - Returns a `RepoError` if the invitation fails to delete
+    #[instrument(
+        name = "Remove accepted invitation",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn remove(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        self.repo.delete_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once every invitation the user sent is
+    ///     withdrawn
+    /// # Errors
+    ///   - Returns a `RepoError` if the invitations fail to delete
+    #[instrument(
+        name = "Withdraw all invitations",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn withdraw_all(&self, user_id: ObjectId) -> Result<DeleteResult, RepoError> {
+        self.repo.delete_many(doc! { "invited_by": user_id }).await
+    }
+}
@@ -0,0 +1,407 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    auth::policy::Role,
+    models::{
+        api_token::ApiToken,
+        appointment::Appointment,
+        child::Child,
+        dental::DentalVisit,
+        immunization::Immunization,
+        invitation::Invitation,
+        mongo::User,
+        photo::Photo,
+        provider::{Provider, ProviderKind},
+        repository::{db_error, Entity, MongoRepository, Page, RepoError, Repository},
+        school::{Grade, SchoolYear},
+    },
+};
+
+/// Collection recording which migrations have been applied
+const MIGRATIONS_COLLECTION: &str = "_migrations";
+
+/// One step in the life of the schema. Versions only ever grow; a shipped
+/// migration is never edited, a new one is added instead.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+}
+
+/// Every migration, oldest first
+pub const MIGRATIONS: [Migration; 9] = [
+    Migration {
+        version: 1,
+        description: "Unique user emails and child slugs",
+    },
+    Migration {
+        version: 2,
+        description: "Lookup indexes for visits, school years, grades, photos and providers",
+    },
+    Migration {
+        version: 3,
+        description: "Link visits that name their provider to provider documents",
+    },
+    Migration {
+        version: 4,
+        description: "Treat accounts made before email verification as verified",
+    },
+    Migration {
+        version: 5,
+        description: "Give existing accounts roles, making the first an admin",
+    },
+    Migration {
+        version: 6,
+        description: "Index API tokens by owner and expire them",
+    },
+    Migration {
+        version: 7,
+        description: "Index invitations by sender and expire them",
+    },
+    Migration {
+        version: 8,
+        description: "Index immunizations by child",
+    },
+    Migration {
+        version: 9,
+        description: "Store user emails in lowercase",
+    },
+];
+
+/// A migration as recorded in `_migrations`
+#[derive(Debug, Serialize, Deserialize)]
+struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: u32,
+    description: String,
+    applied_at: DateTime,
+}
+
+/// # Result
+///   - The migrations not in `applied`, oldest first
+#[must_use]
+pub fn pending(applied: &[u32]) -> Vec<&'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect()
+}
+
+/// # Result
+///   - The versions applied by this call, oldest first
+/// # Errors
+///   - `RepoError` from the first migration that fails; later ones are not attempted
+#[instrument(name = "Run migrations", level = "info", target = "kid_data", skip(db))]
+pub async fn run(db: &Database) -> Result<Vec<u32>, RepoError> {
+    let history: Collection<AppliedMigration> = db.collection(MIGRATIONS_COLLECTION);
+
+    let applied: Vec<u32> = history
+        .find(doc! {})
+        .await
+        .map_err(|err| db_error("Failed to read migration history", err))?
+        .try_collect::<Vec<AppliedMigration>>()
+        .await
+        .map_err(|err| db_error("Failed to read migration history", err))?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+
+    let mut ran = Vec::new();
+    for migration in pending(&applied) {
+        info!(
+            "Applying migration {}: {}",
+            migration.version, migration.description
+        );
+        apply(db, migration.version).await?;
+
+        history
+            .insert_one(AppliedMigration {
+                version: migration.version,
+                description: migration.description.to_string(),
+                applied_at: DateTime::now(),
+            })
+            .await
+            .map_err(|err| db_error("Failed to record migration", err))?;
+
+        ran.push(migration.version);
+    }
+
+    if ran.is_empty() {
+        debug!("Schema is up to date");
+    }
+    Ok(ran)
+}
+
+/// # Errors
+///   - `RepoError` when the migration fails part way
+async fn apply(db: &Database, version: u32) -> Result<(), RepoError> {
+    match version {
+        1 => {
+            create_index::<User>(db, doc! { "email": 1 }, true).await?;
+            create_index::<Child>(db, doc! { "slug": 1 }, true).await
+        }
+        2 => {
+            create_index::<Appointment>(db, doc! { "child_id": 1, "date": -1 }, false).await?;
+            create_index::<DentalVisit>(db, doc! { "child_id": 1, "date": -1 }, false).await?;
+            create_index::<SchoolYear>(db, doc! { "child_id": 1, "start_date": -1 }, false).await?;
+            create_index::<Grade>(
+                db,
+                doc! { "school_year_id": 1, "marking_period": 1, "subject": 1 },
+                true,
+            )
+            .await?;
+            create_index::<Photo>(
+                db,
+                doc! { "child_id": 1, "album": 1, "taken_at": -1 },
+                false,
+            )
+            .await?;
+            create_index::<Provider>(db, doc! { "kind": 1, "active": -1, "name": 1 }, false).await
+        }
+        3 => {
+            link_legacy_providers::<Appointment>(db, ProviderKind::Pediatrician).await?;
+            link_legacy_providers::<DentalVisit>(db, ProviderKind::Dentist).await
+        }
+        4 => verify_existing_users(db).await,
+        5 => assign_roles(db).await,
+        6 => {
+            create_index::<ApiToken>(db, doc! { "user_id": 1, "created_at": -1 }, false).await?;
+            expire_at::<ApiToken>(db, "expires_at").await
+        }
+        7 => {
+            create_index::<Invitation>(db, doc! { "invited_by": 1, "created_at": -1 }, false)
+                .await?;
+            expire_at::<Invitation>(db, "expires_at").await
+        }
+        8 => create_index::<Immunization>(db, doc! { "child_id": 1, "date": -1 }, false).await,
+        9 => lowercase_emails(db).await,
+        _ => Err(RepoError::Validation(format!(
+            "No migration has version {version}"
+        ))),
+    }
+}
+
+/// # Errors
+///   - `RepoError` when the index cannot be built, e.g. duplicates block a unique index
+async fn create_index<T: Entity>(
+    db: &Database,
+    keys: Document,
+    unique: bool,
+) -> Result<(), RepoError> {
+    let index = IndexModel::builder()
+        .keys(keys)
+        .options(IndexOptions::builder().unique(unique).build())
+        .build();
+
+    db.collection::<Document>(T::COLLECTION)
+        .create_index(index)
+        .await
+        .map_err(|err| db_error(&format!("Failed to index {}", T::COLLECTION), err))?;
+
+    debug!("Indexed {}", T::COLLECTION);
+    Ok(())
+}
+
+/// Has Mongo delete each document once the date in `field` passes
+/// # Errors
+///   - `RepoError` when the index cannot be built
+async fn expire_at<T: Entity>(db: &Database, field: &str) -> Result<(), RepoError> {
+    let index = IndexModel::builder()
+        .keys(doc! { field: 1 })
+        .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+        .build();
+
+    db.collection::<Document>(T::COLLECTION)
+        .create_index(index)
+        .await
+        .map_err(|err| db_error(&format!("Failed to expire {}", T::COLLECTION), err))?;
+
+    debug!("Expiring {} on {field}", T::COLLECTION);
+    Ok(())
+}
+
+/// Accounts made before verification existed never got a link to follow, so
+/// they are grandfathered in rather than locked out
+/// # Errors
+///   - `RepoError` when the users fail to update
+async fn verify_existing_users(db: &Database) -> Result<(), RepoError> {
+    let result = db
+        .collection::<Document>(User::COLLECTION)
+        .update_many(
+            doc! { "email_verified_at": { "$exists": false } },
+            doc! { "$set": { "email_verified_at": DateTime::now() } },
+        )
+        .await
+        .map_err(|err| db_error("Failed to verify existing users", err))?;
+
+    info!("Verified {} existing user(s)", result.modified_count);
+    Ok(())
+}
+
+/// Everyone who signed up before roles could edit everything, so they keep
+/// that as guardians. The first account becomes the admin if there is none.
+/// # Errors
+///   - `RepoError` when the users fail to read or update
+async fn assign_roles(db: &Database) -> Result<(), RepoError> {
+    let users = MongoRepository::<User>::new(db);
+
+    users
+        .collection()
+        .update_many(
+            doc! { "role": { "$exists": false } },
+            doc! { "$set": { "role": Role::Guardian.as_str() } },
+        )
+        .await
+        .map_err(|err| db_error("Failed to give users roles", err))?;
+
+    if users.count(doc! { "role": Role::Admin.as_str() }).await? > 0 {
+        return Ok(());
+    }
+    let oldest = users
+        .find_many(
+            doc! {},
+            doc! { "sign_up_date": 1, "_id": 1 },
+            Some(Page::new(0, 1)),
+        )
+        .await?;
+    if let Some(id) = oldest.first().and_then(|user| user.id) {
+        users
+            .update_by_id(id, doc! { "$set": { "role": Role::Admin.as_str() } })
+            .await?;
+        info!("Made the oldest account the admin");
+    }
+    Ok(())
+}
+
+/// Emails are looked up in lowercase, so accounts stored with capitals
+/// could no longer sign in
+/// # Errors
+///   - `RepoError` when the users fail to update, e.g. two accounts differ
+///     only in the case of their email; one must be changed by hand first
+async fn lowercase_emails(db: &Database) -> Result<(), RepoError> {
+    let users = MongoRepository::<User>::new(db);
+
+    for field in ["email", "pending_email"] {
+        let lowercase = doc! { "$toLower": { "$trim": { "input": format!("${field}") } } };
+        users
+            .collection()
+            .update_many(
+                doc! { field: { "$type": "string" } },
+                vec![doc! { "$set": { field: lowercase } }],
+            )
+            .await
+            .map_err(|err| db_error("Failed to lowercase user emails", err))?;
+    }
+    Ok(())
+}
+
+/// Visits written before the provider directory stored the provider's name
+/// in `provider`. Point them at a provider document instead, creating one
+/// per distinct name.
+/// # Errors
+///   - `RepoError` when a visit or provider fails to read or write
+async fn link_legacy_providers<T: Entity>(
+    db: &Database,
+    kind: ProviderKind,
+) -> Result<(), RepoError> {
+    let visits = db.collection::<Document>(T::COLLECTION);
+    let providers = MongoRepository::<Provider>::new(db);
+
+    let legacy: Vec<Document> = visits
+        .find(doc! {
+            "provider": { "$type": "string" },
+            "provider_id": { "$exists": false },
+        })
+        .await
+        .map_err(|err| db_error("Failed to find legacy visits", err))?
+        .try_collect()
+        .await
+        .map_err(|err| db_error("Failed to read legacy visits", err))?;
+
+    let mut linked: HashMap<String, ObjectId> = HashMap::new();
+    for visit in legacy {
+        let (Ok(id), Ok(name)) = (visit.get_object_id("_id"), visit.get_str("provider")) else {
+            warn!("Skipping malformed legacy visit in {}", T::COLLECTION);
+            continue;
+        };
+        let name = name.trim().to_string();
+
+        let provider_id = if let Some(id) = linked.get(&name) {
+            *id
+        } else {
+            let id = match providers
+                .find_one(doc! { "kind": kind.as_str(), "name": &name })
+                .await
+            {
+                Ok(provider) => provider.id.unwrap_or_default(),
+                Err(RepoError::NotFound(_)) => {
+                    providers
+                        .insert(&Provider {
+                            id: None,
+                            kind,
+                            name: name.clone(),
+                            practice: String::new(),
+                            email: String::new(),
+                            phone: String::new(),
+                            address: String::new(),
+                            photo: None,
+                            active: true,
+                        })
+                        .await?
+                }
+                Err(err) => return Err(err),
+            };
+            linked.insert(name, id);
+            id
+        };
+
+        visits
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": { "provider_id": provider_id },
+                    "$unset": { "provider": "" },
+                },
+            )
+            .await
+            .map_err(|err| db_error("Failed to link legacy visit", err))?;
+    }
+
+    info!("Linked {} provider(s) in {}", linked.len(), T::COLLECTION);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_migrations {
+    use super::*;
+
+    #[test]
+    fn test_versions_only_grow() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+    }
+
+    #[test]
+    fn test_fresh_database_runs_everything() {
+        assert_eq!(pending(&[]).len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_applied_migrations_are_skipped() {
+        let versions: Vec<u32> = pending(&[1, 3, 5])
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        assert_eq!(versions, vec![2, 4, 6, 7, 8, 9]);
+    }
+}
@@ -1,2 +1,13 @@
+pub mod api_token;
+pub mod appointment;
+pub mod child;
+pub mod dental;
 pub mod helpers;
+pub mod immunization;
+pub mod invitation;
+pub mod migrations;
 pub mod mongo;
+pub mod photo;
+pub mod provider;
+pub mod repository;
+pub mod school;
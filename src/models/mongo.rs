@@ -1,16 +1,24 @@
-use actix_web::cookie::Cookie;
 use mongodb::{
-    bson::{doc, extjson::de::Error, oid::ObjectId, DateTime, Document},
-    results::{DeleteResult, InsertOneResult, UpdateResult},
-    Collection, Database,
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    results::{DeleteResult, UpdateResult},
+    Database,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
-use tracing::{debug, error, info, instrument, warn};
+use tracing::{debug, info, instrument, warn};
+use webauthn_rs::prelude::Passkey;
 
 use crate::{
-    auth::hash::pw,
-    endpoints::{adrian::doctor::Appointment, register::CreateNewUser},
+    auth::{grants::ChildGrant, hash::pw, passkeys::StoredPasskey, policy::Role},
+    endpoints::register::CreateNewUser,
+    models::{
+        api_token::ApiTokenRepo,
+        child::ChildRepo,
+        helpers::normalize_email,
+        invitation::InvitationRepo,
+        repository::{db_error, Entity, MongoRepository, RepoError, Repository},
+    },
+    settings::Argon2Cost,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -24,6 +32,81 @@ pub struct User {
     pub sign_up_date: Option<DateTime>,
     pub email: String,
     pub password: String,
+    /// Bumped whenever every existing session must be signed out
+    #[serde(default)]
+    pub session_version: u32,
+    /// When the owner proved they read the account's email; `None` until then
+    #[serde(default)]
+    pub email_verified_at: Option<DateTime>,
+    #[serde(default)]
+    pub role: Role,
+    /// Base32 TOTP secret; `Some` once two-factor is on
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// Secret shown during enrollment, until a code from it is confirmed
+    #[serde(default)]
+    pub totp_pending_secret: Option<String>,
+    /// Newest TOTP time step used, so no code is accepted twice
+    #[serde(default)]
+    pub totp_last_step: i64,
+    /// Argon2 hashes of the recovery codes not yet used
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    /// Children this user may see without being their guardian
+    #[serde(default)]
+    pub grants: Vec<ChildGrant>,
+    /// Passkeys that log in without the password
+    #[serde(default)]
+    pub passkeys: Vec<StoredPasskey>,
+    /// Address the owner is moving to, until they prove they read it
+    #[serde(default)]
+    pub pending_email: Option<String>,
+    /// When the account is deleted; `None` unless the owner closed it
+    #[serde(default)]
+    pub deletion_scheduled_at: Option<DateTime>,
+}
+
+/// What the account API shows of a `User`; never the password hash
+#[derive(Debug, Serialize, Clone)]
+pub struct PublicUser {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub first_name: String,
+    pub last_name: String,
+    pub is_active: Option<bool>,
+    pub thumbnail: Option<String>,
+    pub sign_up_date: Option<DateTime>,
+    pub email: String,
+    pub email_verified_at: Option<DateTime>,
+    pub role: Role,
+    pub two_factor: bool,
+}
+
+/// What the account API may change on a `User`. Passwords only change
+/// through a reset.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserChanges {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub role: Option<Role>,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            is_active: user.is_active,
+            thumbnail: user.thumbnail,
+            sign_up_date: user.sign_up_date,
+            email: user.email,
+            email_verified_at: user.email_verified_at,
+            role: user.role,
+            two_factor: user.totp_secret.is_some(),
+        }
+    }
 }
 
 // impl User {
@@ -61,8 +144,19 @@ impl From<CreateNewUser> for User {
             is_active: Some(false),
             thumbnail: None,
             sign_up_date: Some(DateTime::now()),
-            email: new_user.email,
+            email: normalize_email(&new_user.email),
             password: new_user.password,
+            session_version: 0,
+            email_verified_at: None,
+            role: Role::default(),
+            totp_secret: None,
+            totp_pending_secret: None,
+            totp_last_step: 0,
+            recovery_codes: Vec::new(),
+            grants: Vec::new(),
+            passkeys: Vec::new(),
+            pending_email: None,
+            deletion_scheduled_at: None,
         }
     }
 }
@@ -85,60 +179,146 @@ impl Display for User {
     }
 }
 
-#[allow(clippy::module_name_repetitions)]
-pub struct MongoRepo {
-    collection: Collection<User>,
+impl Entity for User {
+    const COLLECTION: &'static str = "user";
+    const NAME: &'static str = "User";
+}
+
+pub struct UserRepo {
+    repo: MongoRepository<User>,
+    db: Database,
 }
 
-impl MongoRepo {
+impl UserRepo {
     #[must_use]
     #[instrument(
-        name = "Create new MongoRepo",
+        name = "Create new UserRepo",
         level = "debug",
         target = "kid_data",
-        skip(collection)
+        skip(db)
     )]
-    pub fn new(collection: &Database, collection_name: Option<&str>) -> Self {
-        let collection =
-            collection.collection(collection_name.map_or_else(|| "user", |collection| collection));
-
-        Self { collection }
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+            db: db.clone(),
+        }
     }
 
     /// # Results
-    ///   - Returns an `InsertOneResult` if the document is successfully inserted into the collection
+    ///   - Returns the database ID of the inserted user. The first account
+    ///     on the site is its admin.
     /// # Errors
-    ///   - Returns an `Error` if the document fails to insert into the collection
-    /// # Panics
-    ///   - If the document fails to insert into the collection
+    ///   - Returns a `RepoError` if the password fails to hash or the document fails to insert into the collection
     #[instrument(
         name = "Create user",
         level = "debug",
         target = "kid_data",
-        skip(self, new_user)
+        skip(self, new_user, cost)
     )]
-    pub async fn create_user(&self, new_user: CreateNewUser) -> Result<InsertOneResult, Error> {
+    pub async fn create_user(
+        &self,
+        new_user: CreateNewUser,
+        cost: Argon2Cost,
+    ) -> Result<ObjectId, RepoError> {
         let mut new_doc = User::from(new_user);
         debug!("Extracted user data from the new user: {}", new_doc.email);
 
-        new_doc.password = pw(new_doc.password).await.expect("Password hashing failed");
+        new_doc.password = pw(new_doc.password, cost)
+            .await
+            .map_err(|err| db_error("Password hashing failed", err))?;
         info!("Clear text Password hashed");
 
-        let user = self
-            .collection
-            .insert_one(new_doc)
+        let id = self.repo.insert(&new_doc).await?;
+        // The account that sets up the site runs it; registration is closed
+        // to everyone after
+        if self.repo.count(doc! {}).await? == 1 {
+            self.repo
+                .update_by_id(id, doc! { "$set": { "role": Role::Admin.as_str() } })
+                .await?;
+            info!("Made the first account the admin");
+        }
+        Ok(id)
+    }
+
+    /// # Results
+    ///   - Returns `true` once anyone has an account
+    /// # Errors
+    ///   - Returns a `RepoError` if the users fail to count
+    #[instrument(
+        name = "Check for accounts",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn has_accounts(&self) -> Result<bool, RepoError> {
+        Ok(self.repo.count(doc! {}).await? > 0)
+    }
+
+    /// # Errors
+    ///   - Returns `RepoError::Conflict` when `object_id` is the only admin
+    ///     whose account is not closing, so demoting or deleting it would
+    ///     leave the site without one
+    ///   - Returns a `RepoError` if the users fail to count
+    async fn keep_an_admin(&self, object_id: ObjectId) -> Result<(), RepoError> {
+        let admin = Role::Admin.as_str();
+        if self
+            .repo
+            .count(doc! { "_id": object_id, "role": admin })
+            .await?
+            == 0
+        {
+            return Ok(());
+        }
+
+        let others = self
+            .repo
+            .count(doc! {
+                "_id": { "$ne": object_id },
+                "role": admin,
+                "deletion_scheduled_at": { "$exists": false },
+            })
+            .await?;
+        if others == 0 {
+            return Err(RepoError::Conflict(String::from(
+                "Make someone else an admin first",
+            )));
+        }
+        Ok(())
+    }
+
+    /// # Results
+    ///   - Returns the database ID of a user created from an invitation. The
+    ///     invitation link proved the email, so it starts verified. It is a
+    ///     viewer: its grants alone decide which records it may change.
+    /// # Errors
+    ///   - Returns a `RepoError` if the password fails to hash or the document fails to insert into the collection
+    #[instrument(
+        name = "Create invited user",
+        level = "debug",
+        target = "kid_data",
+        skip(self, new_user, grants, cost)
+    )]
+    pub async fn create_invited_user(
+        &self,
+        new_user: CreateNewUser,
+        grants: Vec<ChildGrant>,
+        cost: Argon2Cost,
+    ) -> Result<ObjectId, RepoError> {
+        let mut new_doc = User::from(new_user);
+        new_doc.password = pw(new_doc.password, cost)
             .await
-            .expect("Failed to insert document into collection");
+            .map_err(|err| db_error("Password hashing failed", err))?;
+        new_doc.email_verified_at = Some(DateTime::now());
+        new_doc.role = Role::Viewer;
+        new_doc.grants = grants;
 
-        Ok(user)
+        self.repo.insert(&new_doc).await
     }
 
     /// # Results
     ///   - Returns a `User` if the document is successfully found in the collection
     /// # Errors
-    ///   - Returns an `Error` if the document fails to find in the collection
-    /// # Panics
-    ///   - If the document fails to find in the collection
+    ///   - Returns a `RepoError` if neither an ID nor an email is given, or the document fails to find in the collection
     #[instrument(
         name = "Get user",
         level = "debug",
@@ -149,402 +329,617 @@ impl MongoRepo {
         &self,
         object_id: Option<ObjectId>,
         email: Option<&str>,
-    ) -> Result<User, Error> {
+    ) -> Result<User, RepoError> {
         info!("Get users endpoint hit");
-        let filter: Document = object_id.map_or_else(
-            || {
-                doc! {
-                "email": email.expect("Failed to find email")
-                            }
-            },
-            |id| doc! { "_id": id },
-        );
-
-        let user = match self.collection.find_one(filter).await {
-            Ok(user) => user,
-            Err(err) => {
-                error!("Failed to search collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to find document in collection".to_string(),
-                });
+        let filter: Document = match (object_id, email) {
+            (Some(id), _) => doc! { "_id": id },
+            (None, Some(email)) => doc! { "email": normalize_email(email) },
+            (None, None) => {
+                return Err(RepoError::Validation(String::from(
+                    "An ID or email is needed to find a user",
+                )))
             }
         };
 
-        Ok(if let Some(user) = user {
-            user
-        } else {
-            error!("Failed to find user");
-            return Err(Error::DeserializationError {
-                message: "Failed to find user".to_string(),
-            });
-        })
+        self.repo.find_one(filter).await
     }
 
     /// # Results
     ///   - Returns a `User` if the document, filtered on email,  has ``is_active`` == true in the collection
     /// # Errors
-    ///   - Returns an `Error` if the document fails to find in the collection
-    /// # Panics
-    ///   - If the document fails to find in the collection
+    ///   - Returns a `RepoError` if the document fails to find in the collection
     #[instrument(
         name = "Get active user",
         level = "debug",
         target = "kid_data",
         skip(self, email)
     )]
-    pub async fn get_active_user(&self, email: &str) -> Result<User, Error> {
-        let filter = doc! { "email": email, "is_active": true };
+    pub async fn get_active_user(&self, email: &str) -> Result<User, RepoError> {
+        let filter = doc! { "email": normalize_email(email), "is_active": true };
 
         debug!("Filter for search: {:#?}", filter);
 
-        let user = match self.collection.find_one(filter).await {
-            Ok(user) => {
-                debug!("Finder filter returned");
-                user
-            }
-            Err(err) => {
-                error!("Failed to find document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to find document in collection".to_string(),
-                });
-            }
-        };
-
-        Ok(if let Some(user) = user {
-            user
-        } else {
-            error!("Failed to find active user: {user:#?}");
-            return Err(Error::DeserializationError {
-                message: "Failed to find user".to_string(),
-            });
-        })
+        self.repo.find_one(filter).await
     }
 
     /// # Results
-    ///   - Returns an `UpdateResult` if the document is successfully updated in the collection
+    ///   - Returns an `UpdateResult` if the document is successfully updated in the collection. A
+    ///     demoted admin's API tokens are revoked.
     /// # Errors
-    ///   - Returns an `Error` if the document fails to update in the collection
-    /// # Panics
-    ///   - If the document fails to update in the collection
+    ///   - Returns `RepoError::Conflict` if it would demote the last admin
+    ///   - Returns a `RepoError` if the document fails to update in the collection
     #[instrument(
         name = "Update user",
         level = "debug",
         target = "kid_data",
-        skip(self, object_id, new_user),
-	fields(user_to_update = %new_user.email)
+        skip(self, object_id, changes),
+	fields(user_to_update = %changes.email)
     )]
     pub async fn update_user(
         &self,
         object_id: ObjectId,
-        new_user: User,
-    ) -> Result<UpdateResult, Error> {
+        changes: UserChanges,
+    ) -> Result<UpdateResult, RepoError> {
         info!("Update user endpoint hit");
-        let filter = doc! { "_id": object_id };
-        let new_doc = doc! {
-            "$set": {
-        "first_name": new_user.first_name,
-        "last_name": new_user.last_name,
-        "email": new_user.email,
-        "thumbnail": new_user.thumbnail,
-        "sign_up_date": new_user.sign_up_date,
-        "is_active": new_user.is_active,
-        "password": pw(new_user.password).await.expect("Password hashing failed"),
-            }
+        let mut set = doc! {
+            "first_name": changes.first_name,
+            "last_name": changes.last_name,
+            "email": normalize_email(&changes.email),
         };
+        let demoted = changes.role.is_some_and(|role| role != Role::Admin);
+        if demoted {
+            self.keep_an_admin(object_id).await?;
+        }
+        if let Some(role) = changes.role {
+            set.insert("role", role.as_str());
+        }
 
-        let updated_doc = match self.collection.update_one(filter, new_doc).await {
-            Ok(doc) => {
-                debug!("User updated");
-                doc
-            }
-            Err(err) => {
-                error!("Failed to update document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to update document in collection".to_string(),
-                });
-            }
-        };
+        let result = self
+            .repo
+            .update_by_id(object_id, doc! { "$set": set })
+            .await?;
+        // Only admins can call the account API, so their tokens go with the role
+        if demoted {
+            ApiTokenRepo::new(&self.db).revoke_all(object_id).await?;
+        }
+        Ok(result)
+    }
 
-        Ok(updated_doc)
+    /// # Results
+    ///   - Returns an `UpdateResult` once the user's name is changed
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Update profile",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, first_name, last_name)
+    )]
+    pub async fn update_name(
+        &self,
+        object_id: ObjectId,
+        first_name: &str,
+        last_name: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Update name called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! { "$set": { "first_name": first_name, "last_name": last_name } },
+            )
+            .await
     }
 
+    /// # Results
+    ///   - Returns an `UpdateResult` once the user's avatar names `file_name`
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
     #[instrument(
-        name = "Update activity",
+        name = "Set thumbnail",
         level = "debug",
         target = "kid_data",
         skip(self, object_id)
     )]
-    pub async fn toggle_activity(
+    pub async fn set_thumbnail(
         &self,
         object_id: ObjectId,
-        activiy: bool,
-    ) -> Result<UpdateResult, Error> {
-        info!("Toggle activity endpoint hit");
-        let filter = doc! { "_id": object_id };
-        let new_doc = doc! {
-            "$set": {
-            "is_active": activiy,
-            }
-        };
+        file_name: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        self.repo
+            .update_by_id(object_id, doc! { "$set": { "thumbnail": file_name } })
+            .await
+    }
 
-        let updated_doc = match self.collection.update_one(filter, new_doc).await {
-            Ok(doc) => {
-                debug!("User activity updated");
-                doc
-            }
-            Err(err) => {
-                error!("Failed to update document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to update document in collection".to_string(),
-                });
-            }
-        };
+    /// # Results
+    ///   - Returns an `UpdateResult` once the password is replaced and every
+    ///     existing session is signed out
+    /// # Errors
+    ///   - Returns a `RepoError` if the password fails to hash or the document fails to update
+    #[instrument(
+        name = "Reset password",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, password, cost)
+    )]
+    pub async fn reset_password(
+        &self,
+        object_id: ObjectId,
+        password: String,
+        cost: Argon2Cost,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Reset password called");
+        let password = pw(password, cost)
+            .await
+            .map_err(|err| db_error("Password hashing failed", err))?;
 
-        Ok(updated_doc)
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! {
+                    "$set": { "password": password },
+                    "$inc": { "session_version": 1 },
+                },
+            )
+            .await
     }
 
     /// # Results
-    ///   - Returns a `DeleteResult` if the document is successfully deleted from the collection
+    ///   - Returns an `UpdateResult` once `stale_hash` is replaced by
+    ///     `fresh_hash`, the same password hashed at the configured cost.
+    ///     Nothing changes if the stored hash is no longer `stale_hash`, so
+    ///     a password changed meanwhile is kept.
     /// # Errors
-    ///   - Returns an `Error` if the document fails to delete from the collection
-    /// # Panics
-    ///   - If the document fails to delete from the collection
+    ///   - Returns a `RepoError` if the document fails to update
     #[instrument(
-        name = "Delete user",
+        name = "Upgrade password",
         level = "debug",
         target = "kid_data",
-        skip(self, id)
+        skip(self, stale_hash, fresh_hash)
     )]
-    pub async fn delete_user(&self, id: String) -> Result<DeleteResult, Error> {
-        info!("Delete user endpoint hit");
-        let obj_id = match ObjectId::parse_str(id) {
-            Ok(id_data) => id_data,
-            Err(err) => {
-                error!("Failed to parse ObjectId: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to parse ObjectId".to_string(),
-                });
-            }
-        };
-
-        let filter = doc! { "_id": obj_id };
+    pub async fn upgrade_password(
+        &self,
+        object_id: ObjectId,
+        stale_hash: &str,
+        fresh_hash: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        self.repo
+            .update_one(
+                doc! { "_id": object_id, "password": stale_hash },
+                doc! { "$set": { "password": fresh_hash } },
+            )
+            .await
+    }
 
-        let deleted_doc = match self.collection.delete_one(filter).await {
-            Ok(doc) => {
-                debug!("User deleted");
-                doc
-            }
-            Err(err) => {
-                error!("Failed to delete document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to delete document in collection".to_string(),
-                });
-            }
-        };
+    /// # Results
+    ///   - Returns an `UpdateResult` once the user's email is marked verified
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Mark email verified",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id)
+    )]
+    pub async fn mark_email_verified(
+        &self,
+        object_id: ObjectId,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Mark email verified called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! { "$set": { "email_verified_at": DateTime::now() } },
+            )
+            .await
+    }
 
-        Ok(deleted_doc)
+    /// # Results
+    ///   - Returns an `UpdateResult` once `email` awaits confirmation. The
+    ///     account keeps its current address until then.
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Request email change",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, email)
+    )]
+    pub async fn request_email_change(
+        &self,
+        object_id: ObjectId,
+        email: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Request email change called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! { "$set": { "pending_email": normalize_email(email) } },
+            )
+            .await
     }
 
     /// # Results
-    ///   - Returns a `Vec<User>` if the documents are successfully found in the collection
+    ///   - Returns the user's new address once it replaces the old one, or
+    ///     `None` if no change was waiting
     /// # Errors
-    ///   - Returns an `Error` if the documents fail to find in the collection
-    /// # Panics
-    ///   - If the documents fail to find in the collection
+    ///   - Returns a `RepoError::Conflict` if another account took the
+    ///     address meanwhile, or a `RepoError` if the document fails to update
     #[instrument(
-        name = "Get all users",
+        name = "Confirm email change",
         level = "debug",
         target = "kid_data",
-        skip(self)
+        skip(self, object_id)
     )]
-    pub async fn get_all_users(&self) -> Result<Vec<User>, Error> {
-        info!("Get all users endpoint hit");
-        let mut users: Vec<User> = Vec::new();
-
-        let cursor = match self.collection.find(doc! {}).await {
-            Ok(cursor_data) => cursor_data,
-            Err(err) => {
-                error!("Failed to find documents in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to find documents in collection".to_string(),
-                });
-            }
+    pub async fn confirm_email_change(
+        &self,
+        object_id: ObjectId,
+    ) -> Result<Option<String>, RepoError> {
+        info!("Confirm email change called");
+        let Some(email) = self.repo.find_by_id(object_id).await?.pending_email else {
+            return Ok(None);
         };
 
-        let cursor_count = match self.collection.count_documents(doc! {}).await {
-            Ok(count) => count,
-            Err(err) => {
-                error!("Failed to count documents in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to count documents in collection".to_string(),
-                });
-            }
-        };
+        let result = self
+            .repo
+            .update_one(
+                doc! { "_id": object_id, "pending_email": &email },
+                doc! {
+                    "$set": { "email": &email, "email_verified_at": DateTime::now() },
+                    "$unset": { "pending_email": "" },
+                },
+            )
+            .await?;
+        Ok((result.modified_count == 1).then_some(email))
+    }
 
-        for _ in 0..cursor_count {
-            let user = match cursor.deserialize_current() {
-                Ok(user_data) => user_data,
-                Err(err) => {
-                    error!("Failed to deserialize document in collection: {err}");
-                    return Err(Error::DeserializationError {
-                        message: "Failed to deserialize document in collection".to_string(),
-                    });
+    /// # Results
+    ///   - Returns an `UpdateResult` once the account is set to be deleted
+    ///     at `at`, with every session it had signed out, or kept again when
+    ///     `at` is `None`
+    /// # Errors
+    ///   - Returns `RepoError::Conflict` if it would close the last admin
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Schedule deletion",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id)
+    )]
+    pub async fn schedule_deletion(
+        &self,
+        object_id: ObjectId,
+        at: Option<DateTime>,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Schedule deletion called");
+        if at.is_some() {
+            self.keep_an_admin(object_id).await?;
+        }
+        let update = at.map_or_else(
+            || doc! { "$unset": { "deletion_scheduled_at": "" } },
+            |at| {
+                doc! {
+                    "$set": { "deletion_scheduled_at": at },
+                    "$inc": { "session_version": 1 },
                 }
-            };
+            },
+        );
+        self.repo.update_by_id(object_id, update).await
+    }
 
-            users.push(user);
+    /// # Results
+    ///   - Returns the accounts whose deletion came due, once they are
+    ///     deleted along with their API tokens and sent invitations, and
+    ///     taken off the children they were a guardian of. Passkeys are kept
+    ///     on the account, so they go with it.
+    /// # Errors
+    ///   - Returns a `RepoError` if the documents fail to find or delete
+    #[instrument(
+        name = "Delete closed accounts",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_closed_accounts(&self, now: DateTime) -> Result<Vec<User>, RepoError> {
+        let due = doc! { "$lte": now };
+        let mut deleted = Vec::new();
+        for user in self
+            .repo
+            .find_many(doc! { "deletion_scheduled_at": &due }, doc! {}, None)
+            .await?
+        {
+            // Checked again, in case the owner kept the account meanwhile
+            let result = self
+                .repo
+                .delete_one(doc! { "_id": user.id, "deletion_scheduled_at": &due })
+                .await?;
+            if result.deleted_count == 1 {
+                if let Some(user_id) = user.id {
+                    self.forget(user_id).await?;
+                }
+                deleted.push(user);
+            }
         }
+        Ok(deleted)
+    }
 
-        Ok(users)
+    /// Removes what other collections hold of a deleted account
+    /// # Errors
+    ///   - Returns a `RepoError` if any of the collections fail to update
+    async fn forget(&self, user_id: ObjectId) -> Result<(), RepoError> {
+        ChildRepo::new(&self.db).remove_guardian(user_id).await?;
+        ApiTokenRepo::new(&self.db).revoke_all(user_id).await?;
+        InvitationRepo::new(&self.db).withdraw_all(user_id).await?;
+        Ok(())
     }
 
     /// # Results
-    ///   - Returns an `UpdateResult` if the cookie is successfully updated in the collection
+    ///   - Returns an `UpdateResult` once the secret awaits its first code
     /// # Errors
-    ///   - Returns an `Error` if the document fails to update in the collection
-    /// # Panics
-    ///   - If the document fails to update in the collection
+    ///   - Returns a `RepoError` if the document fails to update
     #[instrument(
-        name = "Save cookie",
+        name = "Begin two-factor enrollment",
         level = "debug",
         target = "kid_data",
-        skip(self, user_id, cookie)
+        skip(self, object_id, secret)
     )]
-    pub async fn save_cookie(
+    pub async fn begin_two_factor(
         &self,
-        user_id: User,
-        cookie: Cookie<'_>,
-    ) -> Result<UpdateResult, Error> {
-        info!("Save cookie endpoint hit");
-        let obj_id = match ObjectId::parse_str(user_id.id.unwrap_or_default().to_string().as_str())
-        {
-            Ok(id_data) => id_data,
-            Err(err) => {
-                error!("Failed to parse ObjectId: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to parse ObjectId".to_string(),
-                });
-            }
-        };
+        object_id: ObjectId,
+        secret: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Begin two-factor enrollment called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! { "$set": { "totp_pending_secret": secret } },
+            )
+            .await
+    }
 
-        let filter = doc! { "_id": obj_id };
-        let new_doc = doc! {
-            "$set": {
-            "cookie": cookie.value(),
-            }
-        };
+    /// # Results
+    ///   - Returns an `UpdateResult` that matched nothing if enrollment moved
+    ///     on to another secret in the meantime
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Activate two-factor",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, secret, recovery_codes)
+    )]
+    pub async fn activate_two_factor(
+        &self,
+        object_id: ObjectId,
+        secret: &str,
+        step: i64,
+        recovery_codes: Vec<String>,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Activate two-factor called");
+        self.repo
+            .update_one(
+                doc! { "_id": object_id, "totp_pending_secret": secret },
+                doc! {
+                    "$set": {
+                        "totp_secret": secret,
+                        "totp_last_step": step,
+                        "recovery_codes": recovery_codes,
+                    },
+                    "$unset": { "totp_pending_secret": "" },
+                },
+            )
+            .await
+    }
 
-        let updated_doc = match self.collection.update_one(filter, new_doc).await {
-            Ok(doc) => {
-                debug!("Cookie updated");
-                doc
-            }
-            Err(err) => {
-                error!("Failed to update document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to update document in collection".to_string(),
-                });
-            }
-        };
+    /// # Results
+    ///   - Returns an `UpdateResult` that matched nothing if the step, or a
+    ///     later one, was already used
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Use TOTP step",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id)
+    )]
+    pub async fn use_totp_step(
+        &self,
+        object_id: ObjectId,
+        step: i64,
+    ) -> Result<UpdateResult, RepoError> {
+        self.repo
+            .update_one(
+                doc! { "_id": object_id, "totp_last_step": { "$lt": step } },
+                doc! { "$set": { "totp_last_step": step } },
+            )
+            .await
+    }
 
-        Ok(updated_doc)
+    /// # Results
+    ///   - Returns an `UpdateResult` that modified nothing if the code was
+    ///     already used
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Use recovery code",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, hash)
+    )]
+    pub async fn use_recovery_code(
+        &self,
+        object_id: ObjectId,
+        hash: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        self.repo
+            .update_one(
+                doc! { "_id": object_id, "recovery_codes": hash },
+                doc! { "$pull": { "recovery_codes": hash } },
+            )
+            .await
     }
 
-    #[allow(dead_code)]
     /// # Results
-    ///   - Returns a `User` if the cookie is successfully found in the collection
+    ///   - Returns an `UpdateResult` once the user logs in with a password alone
     /// # Errors
-    ///   - Returns an `Error` if the document fails to find in the collection
-    /// # Panics
-    ///   - If the document fails to find in the collection
+    ///   - Returns a `RepoError` if the document fails to update
     #[instrument(
-        name = "Get cookie",
+        name = "Reset two-factor",
         level = "debug",
         target = "kid_data",
-        skip(self, cookie)
+        skip(self, object_id)
     )]
-    pub async fn get_cookie(&self, cookie: Cookie<'_>) -> Result<User, Error> {
-        info!("Get cookie endpoint hit");
-        let filter = doc! { "cookie": cookie.value() };
-
-        let user = match self.collection.find_one(filter).await {
-            Ok(user) => {
-                debug!("User found");
-                user
-            }
-            Err(err) => {
-                error!("Failed to find document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to find document in collection".to_string(),
-                });
-            }
-        };
+    pub async fn reset_two_factor(&self, object_id: ObjectId) -> Result<UpdateResult, RepoError> {
+        info!("Reset two-factor called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! {
+                    "$set": { "totp_last_step": 0_i64, "recovery_codes": [] },
+                    "$unset": { "totp_secret": "", "totp_pending_secret": "" },
+                },
+            )
+            .await
+    }
 
-        Ok(if let Some(user) = user {
-            user
-        } else {
-            error!("Failed to find user");
-            return Err(Error::DeserializationError {
-                message: "Failed to find user".to_string(),
-            });
-        })
+    /// # Results
+    ///   - Returns an `UpdateResult` once the passkey is added to the user's
+    /// # Errors
+    ///   - Returns a `RepoError` if the passkey fails to serialize or the document fails to update
+    #[instrument(
+        name = "Add passkey",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id, passkey)
+    )]
+    pub async fn add_passkey(
+        &self,
+        object_id: ObjectId,
+        passkey: &StoredPasskey,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Add passkey called");
+        let passkey = mongodb::bson::to_bson(passkey)
+            .map_err(|err| db_error("Failed to serialize passkey", err))?;
+        self.repo
+            .update_by_id(object_id, doc! { "$push": { "passkeys": passkey } })
+            .await
     }
 
     /// # Results
-    ///   - Returns a `DeleteResult` if the cookie is successfully deleted from the collection
+    ///   - Returns an `UpdateResult` once the passkey's sign count and last
+    ///     use are saved; it matches nothing if the passkey was removed
     /// # Errors
-    ///   - Returns an `Error` if the document fails to delete from the collection
-    /// # Panics
-    ///   - If the document fails to delete from the collection
+    ///   - Returns a `RepoError` if the passkey fails to serialize or the document fails to update
     #[instrument(
-        name = "Delete cookie",
+        name = "Use passkey",
         level = "debug",
         target = "kid_data",
-        skip(self, cookie)
+        skip(self, object_id, passkey)
     )]
-    pub async fn delete_cookie(&self, cookie: Cookie<'_>) -> Result<DeleteResult, Error> {
-        info!("Delete cookie endpoint hit");
-        let filter = doc! { "cookie": cookie.value() };
-
-        let deleted_doc = match self.collection.delete_one(filter).await {
-            Ok(doc) => {
-                debug!("Cookie deleted");
-                doc
-            }
-            Err(err) => {
-                error!("Failed to delete document in collection: {err}");
-                return Err(Error::DeserializationError {
-                    message: "Failed to delete document in collection".to_string(),
-                });
+    pub async fn use_passkey(
+        &self,
+        object_id: ObjectId,
+        passkey_id: &str,
+        passkey: &Passkey,
+    ) -> Result<UpdateResult, RepoError> {
+        let passkey = mongodb::bson::to_bson(passkey)
+            .map_err(|err| db_error("Failed to serialize passkey", err))?;
+        self.repo
+            .update_one(
+                doc! { "_id": object_id, "passkeys.id": passkey_id },
+                doc! {
+                    "$set": {
+                        "passkeys.$.passkey": passkey,
+                        "passkeys.$.last_used_at": DateTime::now(),
+                    },
+                },
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` that modified nothing if the user has no
+    ///     such passkey
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Remove passkey",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id)
+    )]
+    pub async fn remove_passkey(
+        &self,
+        object_id: ObjectId,
+        passkey_id: &str,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Remove passkey called");
+        self.repo
+            .update_by_id(
+                object_id,
+                doc! { "$pull": { "passkeys": { "id": passkey_id } } },
+            )
+            .await
+    }
+
+    /// # Errors
+    ///   - Returns a `RepoError` if the document fails to update
+    #[instrument(
+        name = "Update activity",
+        level = "debug",
+        target = "kid_data",
+        skip(self, object_id)
+    )]
+    pub async fn toggle_activity(
+        &self,
+        object_id: ObjectId,
+        activiy: bool,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Toggle activity endpoint hit");
+        let new_doc = doc! {
+            "$set": {
+            "is_active": activiy,
             }
         };
 
-        Ok(deleted_doc)
+        self.repo.update_by_id(object_id, new_doc).await
     }
 
     /// # Results
-    ///   - Returns an ObjectId of the saved value
+    ///   - Returns a `DeleteResult` if the document is successfully deleted from the collection
     /// # Errors
-    ///   - Returns an error if the document fails to save in the collection
-    /// # Panics
-    ///   - If the Database connection fails
+    ///   - Returns `RepoError::Conflict` if it would delete the last admin
+    ///   - Returns a `RepoError` if the ID is malformed or the document fails to delete
     #[instrument(
-        name = "Add doctor appt form",
-        level = "info",
+        name = "Delete user",
+        level = "debug",
         target = "kid_data",
-        skip(self)
+        skip(self, id)
     )]
-    pub async fn insert_appointment(&self, appt: Appointment) -> Result<InsertOneResult, Error> {
-        info!("Adding a doctor's appointment");
-
-        let doc = doc! {
-            "date": appt.date,
-            "notes": appt.notes,
-            "purpose": appt.purpose,
-        };
+    pub async fn delete_user(&self, id: String) -> Result<DeleteResult, RepoError> {
+        info!("Delete user endpoint hit");
+        let obj_id = ObjectId::parse_str(id).map_err(|err| {
+            warn!("Failed to parse ObjectId: {err}");
+            RepoError::Validation(String::from("The ID passed in is not valid"))
+        })?;
 
-        let return_result = self.collection.insert_one(doc).await;
+        self.keep_an_admin(obj_id).await?;
+        self.repo.delete_by_id(obj_id).await
+    }
 
-        Ok(return_result.expect(""))
+    /// # Results
+    ///   - Returns a `Vec<User>` if the documents are successfully found in the collection
+    /// # Errors
+    ///   - Returns a `RepoError` if the documents fail to find in the collection
+    #[instrument(
+        name = "Get all users",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_all_users(&self) -> Result<Vec<User>, RepoError> {
+        info!("Get all users endpoint hit");
+        self.repo
+            .find_many(doc! {}, doc! { "last_name": 1 }, None)
+            .await
     }
 }
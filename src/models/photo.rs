@@ -0,0 +1,302 @@
+use std::io::Cursor;
+
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{In, Reader, Tag, Value};
+use image::{imageops::FilterType, ImageFormat};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    results::DeleteResult,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::repository::{db_error, Entity, MongoRepository, Page, RepoError, Repository};
+
+/// Photos shown per gallery page
+pub const PAGE_SIZE: u64 = 24;
+
+/// Longest edge, in pixels, of a generated thumbnail
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+/// Album used when none is given at upload
+pub const DEFAULT_ALBUM: &str = "General";
+
+/// A picture uploaded to a child's library
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Photo {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    pub album: String,
+    pub caption: Option<String>,
+    pub uploaded_by: Option<ObjectId>,
+    pub uploaded_at: NaiveDateTime,
+    /// From the EXIF data when present, otherwise the upload time
+    pub taken_at: NaiveDateTime,
+    /// Name of the original on disk; the thumbnail shares the stem with a `.jpg` extension
+    pub file_name: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Photo {
+    /// # Result
+    ///   - File name of the thumbnail written next to the original
+    #[must_use]
+    pub fn thumbnail_name(&self) -> String {
+        thumbnail_name(&self.file_name)
+    }
+}
+
+/// # Result
+///   - The thumbnail file name for an original's file name
+#[must_use]
+pub fn thumbnail_name(file_name: &str) -> String {
+    let stem = file_name
+        .rsplit_once('.')
+        .map_or(file_name, |(stem, _)| stem);
+    format!("{stem}.thumb.jpg")
+}
+
+/// What the server learned from an uploaded image
+#[derive(Debug)]
+pub struct ProcessedImage {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub taken_at: Option<NaiveDateTime>,
+    /// JPEG encoded thumbnail
+    pub thumbnail: Vec<u8>,
+}
+
+/// # Result
+///   - The capture date from the EXIF `DateTimeOriginal` (or `DateTime`) tag
+///   - `None` when the image carries no usable EXIF date
+#[must_use]
+pub fn exif_capture_date(bytes: &[u8]) -> Option<NaiveDateTime> {
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+
+    [Tag::DateTimeOriginal, Tag::DateTime]
+        .into_iter()
+        .filter_map(|tag| exif.get_field(tag, In::PRIMARY))
+        .find_map(|field| match &field.value {
+            Value::Ascii(values) => values.first().and_then(|ascii| {
+                let stamp = exif::DateTime::from_ascii(ascii).ok()?;
+                NaiveDate::from_ymd_opt(
+                    i32::from(stamp.year),
+                    u32::from(stamp.month),
+                    u32::from(stamp.day),
+                )?
+                .and_hms_opt(
+                    u32::from(stamp.hour),
+                    u32::from(stamp.minute),
+                    u32::from(stamp.second),
+                )
+            }),
+            _ => None,
+        })
+}
+
+/// # Result
+///   - The format, size, capture date, and a thumbnail of the uploaded bytes
+/// # Errors
+///   - `image::ImageError` when the bytes are not a supported image
+pub fn process_image(bytes: &[u8]) -> Result<ProcessedImage, image::ImageError> {
+    let format = image::guess_format(bytes)?;
+    let decoded = image::load_from_memory_with_format(bytes, format)?;
+
+    let mut thumbnail = Vec::new();
+    decoded
+        .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .into_rgb8()
+        .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Jpeg)?;
+
+    Ok(ProcessedImage {
+        format,
+        width: decoded.width(),
+        height: decoded.height(),
+        taken_at: exif_capture_date(bytes),
+        thumbnail,
+    })
+}
+
+impl Entity for Photo {
+    const COLLECTION: &'static str = "photos";
+    const NAME: &'static str = "Photo";
+}
+
+pub struct PhotoRepo {
+    repo: MongoRepository<Photo>,
+}
+
+impl PhotoRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new PhotoRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted photo
+    /// # Errors
+    ///   - Returns a `RepoError` if the photo fails to insert into the collection
+    #[instrument(
+        name = "Create photo",
+        level = "debug",
+        target = "kid_data",
+        skip(self, photo)
+    )]
+    pub async fn create_photo(&self, photo: &Photo) -> Result<ObjectId, RepoError> {
+        info!("Adding a photo");
+        self.repo.insert(photo).await
+    }
+
+    /// # Results
+    ///   - Returns the `Photo` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the photo fails to be found in the collection
+    #[instrument(name = "Get photo", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_photo(&self, id: ObjectId) -> Result<Photo, RepoError> {
+        info!("Getting photo");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns one page of the child's photos, newest capture date first,
+    ///     limited to `album` when given
+    /// # Errors
+    ///   - Returns a `RepoError` if the photos fail to be read from the collection
+    #[instrument(
+        name = "Get child photos",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_photos_for_child(
+        &self,
+        child_id: ObjectId,
+        album: Option<&str>,
+        page: u64,
+    ) -> Result<Vec<Photo>, RepoError> {
+        info!("Getting photos for child");
+        let mut filter = doc! { "child_id": child_id };
+        if let Some(album) = album {
+            filter.insert("album", album);
+        }
+
+        self.repo
+            .find_many(
+                filter,
+                doc! { "taken_at": -1, "_id": -1 },
+                Some(Page::new(page, PAGE_SIZE)),
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the names of the child's albums in alphabetical order
+    /// # Errors
+    ///   - Returns a `RepoError` if the albums fail to be read from the collection
+    #[instrument(name = "Get albums", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_albums(&self, child_id: ObjectId) -> Result<Vec<String>, RepoError> {
+        info!("Getting photo albums");
+        let values = self
+            .repo
+            .collection()
+            .distinct("album", doc! { "child_id": child_id })
+            .await
+            .map_err(|err| db_error("Failed to read albums from collection", err))?;
+
+        let mut albums: Vec<String> = values
+            .into_iter()
+            .filter_map(|value| value.as_str().map(String::from))
+            .collect();
+        albums.sort();
+        Ok(albums)
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the photo is successfully deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the photo fails to delete from the collection
+    #[instrument(
+        name = "Delete photo",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_photo(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting photo");
+        self.repo.delete_by_id(id).await
+    }
+}
+
+#[cfg(test)]
+mod test_photo {
+    use exif::{experimental::Writer, Field};
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(width, height, Rgb([200, 100, 50]));
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .expect("encode png");
+        bytes
+    }
+
+    #[test]
+    fn test_thumbnail_name() {
+        assert_eq!(thumbnail_name("abc.jpeg"), "abc.thumb.jpg");
+        assert_eq!(thumbnail_name("abc"), "abc.thumb.jpg");
+    }
+
+    #[test]
+    fn test_process_image_makes_bounded_thumbnail() {
+        let processed = process_image(&png(1000, 500)).expect("valid image");
+
+        assert_eq!(processed.format, ImageFormat::Png);
+        assert_eq!((processed.width, processed.height), (1000, 500));
+        assert_eq!(processed.taken_at, None);
+
+        let thumb = image::load_from_memory(&processed.thumbnail).expect("valid thumbnail");
+        assert_eq!(thumb.width(), THUMBNAIL_SIZE);
+        assert_eq!(thumb.height(), THUMBNAIL_SIZE / 2);
+    }
+
+    #[test]
+    fn test_process_image_rejects_non_images() {
+        assert!(process_image(b"definitely not a picture").is_err());
+    }
+
+    #[test]
+    fn test_exif_capture_date() {
+        let field = Field {
+            tag: Tag::DateTimeOriginal,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"2021:07:04 18:30:05".to_vec()]),
+        };
+        let mut writer = Writer::new();
+        writer.push_field(&field);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).expect("write exif");
+
+        assert_eq!(
+            exif_capture_date(tiff.get_ref()),
+            NaiveDate::from_ymd_opt(2021, 7, 4).and_then(|date| date.and_hms_opt(18, 30, 5))
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::{self, Display, Formatter};
+
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    results::UpdateResult,
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use crate::models::repository::{Entity, MongoRepository, RepoError, Repository};
+
+/// The kind of care a provider gives
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    Pediatrician,
+    Dentist,
+    Orthodontist,
+    Specialist,
+}
+
+impl ProviderKind {
+    pub const ALL: [Self; 4] = [
+        Self::Pediatrician,
+        Self::Dentist,
+        Self::Orthodontist,
+        Self::Specialist,
+    ];
+
+    /// Providers that can be picked for a doctor's appointment
+    pub const MEDICAL: [Self; 2] = [Self::Pediatrician, Self::Specialist];
+
+    /// Providers that can be picked for a dental visit
+    pub const DENTAL: [Self; 2] = [Self::Dentist, Self::Orthodontist];
+
+    /// # Result
+    ///   - The stored (and form field) name of the kind
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pediatrician => "pediatrician",
+            Self::Dentist => "dentist",
+            Self::Orthodontist => "orthodontist",
+            Self::Specialist => "specialist",
+        }
+    }
+}
+
+impl Display for ProviderKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Pediatrician => write!(f, "Pediatrician"),
+            Self::Dentist => write!(f, "Dentist"),
+            Self::Orthodontist => write!(f, "Orthodontist"),
+            Self::Specialist => write!(f, "Specialist"),
+        }
+    }
+}
+
+/// A doctor, dentist, or other practitioner that sees the children
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Provider {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub kind: ProviderKind,
+    pub name: String,
+    /// Name of the practice or office
+    pub practice: String,
+    pub email: String,
+    pub phone: String,
+    pub address: String,
+    pub photo: Option<String>,
+    /// Retired providers stay on old visits but are not offered for new ones
+    pub active: bool,
+}
+
+impl Provider {
+    /// # Result
+    ///   - The provider's photo, falling back to the stock image for the kind
+    #[must_use]
+    pub fn photo(&self) -> &str {
+        self.photo.as_deref().unwrap_or(match self.kind {
+            ProviderKind::Dentist | ProviderKind::Orthodontist => "/images/dental_image",
+            ProviderKind::Pediatrician | ProviderKind::Specialist => "/images/doctor_image",
+        })
+    }
+}
+
+impl Entity for Provider {
+    const COLLECTION: &'static str = "providers";
+    const NAME: &'static str = "Provider";
+}
+
+pub struct ProviderRepo {
+    repo: MongoRepository<Provider>,
+}
+
+impl ProviderRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new ProviderRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            repo: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted provider
+    /// # Errors
+    ///   - Returns a `RepoError` if the provider fails to insert into the collection
+    #[instrument(
+        name = "Create provider",
+        level = "debug",
+        target = "kid_data",
+        skip(self, provider)
+    )]
+    pub async fn create_provider(&self, provider: &Provider) -> Result<ObjectId, RepoError> {
+        info!("Adding a provider");
+        self.repo.insert(provider).await
+    }
+
+    /// # Results
+    ///   - Returns the `Provider` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the provider fails to be found in the collection
+    #[instrument(
+        name = "Get provider",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_provider(&self, id: ObjectId) -> Result<Provider, RepoError> {
+        info!("Getting provider");
+        self.repo.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns the providers of the given kinds ordered by name; every kind when empty
+    /// # Errors
+    ///   - Returns a `RepoError` if the providers fail to be read from the collection
+    #[instrument(
+        name = "Get providers",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_providers(
+        &self,
+        kinds: &[ProviderKind],
+        active_only: bool,
+    ) -> Result<Vec<Provider>, RepoError> {
+        info!("Getting providers");
+        let mut filter = Document::new();
+        if !kinds.is_empty() {
+            let kinds: Vec<&str> = kinds.iter().map(ProviderKind::as_str).collect();
+            filter.insert("kind", doc! { "$in": kinds });
+        }
+        if active_only {
+            filter.insert("active", true);
+        }
+
+        self.repo
+            .find_many(filter, doc! { "active": -1, "name": 1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the provider is successfully updated
+    /// # Errors
+    ///   - Returns a `RepoError` if the provider fails to update in the collection
+    #[instrument(
+        name = "Update provider",
+        level = "debug",
+        target = "kid_data",
+        skip(self, provider)
+    )]
+    pub async fn update_provider(
+        &self,
+        id: ObjectId,
+        provider: Provider,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Updating provider");
+        let update = doc! {
+            "$set": {
+                "kind": provider.kind.as_str(),
+                "name": provider.name,
+                "practice": provider.practice,
+                "email": provider.email,
+                "phone": provider.phone,
+                "address": provider.address,
+                "photo": provider.photo,
+                "active": provider.active,
+            }
+        };
+
+        self.repo.update_by_id(id, update).await
+    }
+}
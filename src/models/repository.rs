@@ -0,0 +1,390 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    future::Future,
+};
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    error::{ErrorKind, WriteFailure},
+    results::{DeleteResult, UpdateResult},
+    Collection, Database,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, error, instrument};
+
+/// A document type stored in its own collection
+pub trait Entity: Serialize + DeserializeOwned + Send + Sync + Unpin {
+    /// Name of the collection the entity lives in
+    const COLLECTION: &'static str;
+    /// What one entity is called in messages shown to the user
+    const NAME: &'static str;
+}
+
+/// Server error code for a unique index violation
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Why a repository call failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepoError {
+    /// Nothing matched the lookup
+    NotFound(String),
+    /// The write collides with an existing document
+    Conflict(String),
+    /// The input cannot be stored as given
+    Validation(String),
+    /// The database could not be reached or misbehaved
+    Backend(String),
+}
+
+impl Display for RepoError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::Validation(message)
+            | Self::Backend(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+/// One page of a listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    /// Zero based page number
+    pub number: u64,
+    pub size: u64,
+}
+
+impl Page {
+    #[must_use]
+    pub const fn new(number: u64, size: u64) -> Self {
+        Self { number, size }
+    }
+
+    /// # Result
+    ///   - Documents to skip to reach this page
+    #[must_use]
+    pub const fn skip(&self) -> u64 {
+        self.number.saturating_mul(self.size)
+    }
+
+    /// # Result
+    ///   - Documents to read for this page
+    #[must_use]
+    pub fn limit(&self) -> i64 {
+        i64::try_from(self.size).unwrap_or(i64::MAX)
+    }
+}
+
+/// # Result
+///   - Logs `err` and wraps `context` in a `RepoError::Backend`
+pub(crate) fn db_error(context: &str, err: impl Display) -> RepoError {
+    error!("{context}: {err}");
+    RepoError::Backend(context.to_string())
+}
+
+/// # Result
+///   - `RepoError::Conflict` for a unique index violation, otherwise a logged
+///     `RepoError::Backend`
+pub(crate) fn write_error(context: &str, err: &mongodb::error::Error) -> RepoError {
+    let duplicate = match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write)) => write.code == DUPLICATE_KEY,
+        ErrorKind::Command(command) => command.code == DUPLICATE_KEY,
+        _ => false,
+    };
+
+    if duplicate {
+        debug!("{context}: duplicate key");
+        RepoError::Conflict(String::from("A matching record already exists"))
+    } else {
+        db_error(context, err)
+    }
+}
+
+/// Typed CRUD over the collection of `T`
+pub trait Repository<T: Entity> {
+    /// # Results
+    ///   - Returns the database ID given to the inserted entity
+    /// # Errors
+    ///   - Returns a `RepoError` if the entity fails to insert into the collection
+    fn insert(&self, entity: &T) -> impl Future<Output = Result<ObjectId, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns the first entity matching `filter`
+    /// # Errors
+    ///   - Returns a `RepoError` if nothing matches or the collection fails to be read
+    fn find_one(&self, filter: Document) -> impl Future<Output = Result<T, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns the entities matching `filter` in `sort` order, limited to `page` when given
+    /// # Errors
+    ///   - Returns a `RepoError` if the entities fail to be read from the collection
+    fn find_many(
+        &self,
+        filter: Document,
+        sort: Document,
+        page: Option<Page>,
+    ) -> impl Future<Output = Result<Vec<T>, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns the number of entities matching `filter`
+    /// # Errors
+    ///   - Returns a `RepoError` if the collection fails to be counted
+    fn count(&self, filter: Document) -> impl Future<Output = Result<u64, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns an `UpdateResult` once `update` is applied to the first entity matching `filter`
+    /// # Errors
+    ///   - Returns a `RepoError` if the entity fails to update
+    fn update_one(
+        &self,
+        filter: Document,
+        update: Document,
+    ) -> impl Future<Output = Result<UpdateResult, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once the first entity matching `filter` is deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the entity fails to delete
+    fn delete_one(
+        &self,
+        filter: Document,
+    ) -> impl Future<Output = Result<DeleteResult, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once every entity matching `filter` is deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the entities fail to delete
+    fn delete_many(
+        &self,
+        filter: Document,
+    ) -> impl Future<Output = Result<DeleteResult, RepoError>> + Send;
+
+    /// # Results
+    ///   - Returns the entity with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if no entity has the ID or the collection fails to be read
+    fn find_by_id(&self, id: ObjectId) -> impl Future<Output = Result<T, RepoError>> + Send {
+        self.find_one(doc! { "_id": id })
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` once `update` is applied to the entity with the ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the entity fails to update
+    fn update_by_id(
+        &self,
+        id: ObjectId,
+        update: Document,
+    ) -> impl Future<Output = Result<UpdateResult, RepoError>> + Send {
+        self.update_one(doc! { "_id": id }, update)
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` once the entity with the ID is deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the entity fails to delete
+    fn delete_by_id(
+        &self,
+        id: ObjectId,
+    ) -> impl Future<Output = Result<DeleteResult, RepoError>> + Send {
+        self.delete_one(doc! { "_id": id })
+    }
+}
+
+/// `Repository` backed by the entity's Mongo collection
+#[allow(clippy::module_name_repetitions)]
+pub struct MongoRepository<T: Entity> {
+    collection: Collection<T>,
+}
+
+impl<T: Entity> MongoRepository<T> {
+    #[must_use]
+    #[instrument(
+        name = "Create new MongoRepository",
+        level = "debug",
+        target = "kid_data",
+        skip(db),
+        fields(collection = T::COLLECTION)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(T::COLLECTION),
+        }
+    }
+
+    /// # Result
+    ///   - The underlying collection, for queries the trait does not cover
+    #[must_use]
+    pub const fn collection(&self) -> &Collection<T> {
+        &self.collection
+    }
+}
+
+impl<T: Entity> Repository<T> for MongoRepository<T> {
+    #[instrument(
+        name = "Insert entity",
+        level = "debug",
+        target = "kid_data",
+        skip(self, entity),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn insert(&self, entity: &T) -> Result<ObjectId, RepoError> {
+        let result = self
+            .collection
+            .insert_one(entity)
+            .await
+            .map_err(|err| write_error("Failed to insert document into collection", &err))?;
+
+        debug!("Document inserted");
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or_else(|| db_error("Inserted document has no ObjectId", result.inserted_id))
+    }
+
+    #[instrument(
+        name = "Find entity",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn find_one(&self, filter: Document) -> Result<T, RepoError> {
+        match self.collection.find_one(filter).await {
+            Ok(Some(found)) => Ok(found),
+            Ok(None) => {
+                debug!("No document matched in {}", T::COLLECTION);
+                Err(RepoError::NotFound(format!("{} not found", T::NAME)))
+            }
+            Err(err) => Err(db_error("Failed to find document in collection", err)),
+        }
+    }
+
+    #[instrument(
+        name = "Find entities",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn find_many(
+        &self,
+        filter: Document,
+        sort: Document,
+        page: Option<Page>,
+    ) -> Result<Vec<T>, RepoError> {
+        let mut find = self.collection.find(filter).sort(sort);
+        if let Some(page) = page {
+            find = find.skip(page.skip()).limit(page.limit());
+        }
+
+        let cursor = find
+            .await
+            .map_err(|err| db_error("Failed to find documents in collection", err))?;
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|err| db_error("Failed to deserialize document in collection", err))
+    }
+
+    #[instrument(
+        name = "Count entities",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn count(&self, filter: Document) -> Result<u64, RepoError> {
+        self.collection
+            .count_documents(filter)
+            .await
+            .map_err(|err| db_error("Failed to count documents in collection", err))
+    }
+
+    #[instrument(
+        name = "Update entity",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn update_one(
+        &self,
+        filter: Document,
+        update: Document,
+    ) -> Result<UpdateResult, RepoError> {
+        let result = self
+            .collection
+            .update_one(filter, update)
+            .await
+            .map_err(|err| write_error("Failed to update document in collection", &err))?;
+
+        debug!("Document updated");
+        Ok(result)
+    }
+
+    #[instrument(
+        name = "Delete entity",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn delete_one(&self, filter: Document) -> Result<DeleteResult, RepoError> {
+        let result = self
+            .collection
+            .delete_one(filter)
+            .await
+            .map_err(|err| db_error("Failed to delete document in collection", err))?;
+
+        debug!("Document deleted");
+        Ok(result)
+    }
+
+    #[instrument(
+        name = "Delete entities",
+        level = "debug",
+        target = "kid_data",
+        skip(self),
+        fields(collection = T::COLLECTION)
+    )]
+    async fn delete_many(&self, filter: Document) -> Result<DeleteResult, RepoError> {
+        let result = self
+            .collection
+            .delete_many(filter)
+            .await
+            .map_err(|err| db_error("Failed to delete documents in collection", err))?;
+
+        debug!("Deleted {} document(s)", result.deleted_count);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test_repository {
+    use super::*;
+
+    #[test]
+    fn test_first_page_skips_nothing() {
+        let page = Page::new(0, 24);
+        assert_eq!(page.skip(), 0);
+        assert_eq!(page.limit(), 24);
+    }
+
+    #[test]
+    fn test_later_pages_skip_whole_pages() {
+        assert_eq!(Page::new(3, 24).skip(), 72);
+    }
+
+    #[test]
+    fn test_page_math_does_not_overflow() {
+        let page = Page::new(u64::MAX, u64::MAX);
+        assert_eq!(page.skip(), u64::MAX);
+        assert_eq!(page.limit(), i64::MAX);
+    }
+}
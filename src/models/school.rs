@@ -0,0 +1,491 @@
+use chrono::NaiveDate;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::UpdateOptions,
+    results::{DeleteResult, UpdateResult},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, instrument};
+
+use crate::models::repository::{write_error, Entity, MongoRepository, RepoError, Repository};
+
+/// Marking periods used when a school year is created without any
+pub const DEFAULT_MARKING_PERIODS: [&str; 4] = ["Q1", "Q2", "Q3", "Q4"];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct School {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub address: String,
+    pub phone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Teacher {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: Option<String>,
+    pub school_id: Option<ObjectId>,
+}
+
+impl Teacher {
+    #[must_use]
+    pub fn full_name(&self) -> String {
+        format!("{} {}", self.first_name, self.last_name)
+    }
+}
+
+/// A subject taken during a school year
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Class {
+    pub subject: String,
+    pub teacher_id: Option<ObjectId>,
+}
+
+/// One child's year at one school
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SchoolYear {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    pub school_id: ObjectId,
+    /// e.g. "2024-2025"
+    pub label: String,
+    /// e.g. "2nd Grade"
+    pub grade_level: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub homeroom_teacher_id: Option<ObjectId>,
+    pub marking_periods: Vec<String>,
+    pub classes: Vec<Class>,
+}
+
+/// The mark for one subject in one marking period
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Grade {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub child_id: ObjectId,
+    pub school_year_id: ObjectId,
+    pub marking_period: String,
+    pub subject: String,
+    pub mark: String,
+    pub comments: Option<String>,
+}
+
+/// A report card laid out as subjects by marking periods
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReportCard {
+    pub periods: Vec<String>,
+    /// Subject and its mark for every period; empty when not graded yet
+    pub rows: Vec<(String, Vec<String>)>,
+}
+
+/// # Result
+///   - A `ReportCard` with one row per class, then any graded subject without a class
+#[must_use]
+pub fn report_card(year: &SchoolYear, grades: &[Grade]) -> ReportCard {
+    let mut subjects: Vec<String> = year
+        .classes
+        .iter()
+        .map(|class| class.subject.clone())
+        .collect();
+
+    for grade in grades {
+        if !subjects.contains(&grade.subject) {
+            subjects.push(grade.subject.clone());
+        }
+    }
+
+    let rows = subjects
+        .into_iter()
+        .map(|subject| {
+            let marks = year
+                .marking_periods
+                .iter()
+                .map(|period| {
+                    grades
+                        .iter()
+                        .find(|grade| grade.subject == subject && &grade.marking_period == period)
+                        .map(|grade| grade.mark.clone())
+                        .unwrap_or_default()
+                })
+                .collect();
+            (subject, marks)
+        })
+        .collect();
+
+    ReportCard {
+        periods: year.marking_periods.clone(),
+        rows,
+    }
+}
+
+impl Entity for School {
+    const COLLECTION: &'static str = "schools";
+    const NAME: &'static str = "School";
+}
+
+impl Entity for Teacher {
+    const COLLECTION: &'static str = "teachers";
+    const NAME: &'static str = "Teacher";
+}
+
+impl Entity for SchoolYear {
+    const COLLECTION: &'static str = "school_years";
+    const NAME: &'static str = "School year";
+}
+
+impl Entity for Grade {
+    const COLLECTION: &'static str = "grades";
+    const NAME: &'static str = "Grade";
+}
+
+/// Schools, teachers, school years, and grades
+pub struct SchoolRepo {
+    schools: MongoRepository<School>,
+    teachers: MongoRepository<Teacher>,
+    years: MongoRepository<SchoolYear>,
+    grades: MongoRepository<Grade>,
+}
+
+impl SchoolRepo {
+    #[must_use]
+    #[instrument(
+        name = "Create new SchoolRepo",
+        level = "debug",
+        target = "kid_data",
+        skip(db)
+    )]
+    pub fn new(db: &Database) -> Self {
+        Self {
+            schools: MongoRepository::new(db),
+            teachers: MongoRepository::new(db),
+            years: MongoRepository::new(db),
+            grades: MongoRepository::new(db),
+        }
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted school
+    /// # Errors
+    ///   - Returns a `RepoError` if the school fails to insert into the collection
+    #[instrument(
+        name = "Create school",
+        level = "debug",
+        target = "kid_data",
+        skip(self, school)
+    )]
+    pub async fn create_school(&self, school: &School) -> Result<ObjectId, RepoError> {
+        info!("Adding a school");
+        self.schools.insert(school).await
+    }
+
+    /// # Results
+    ///   - Returns every `School` ordered by name
+    /// # Errors
+    ///   - Returns a `RepoError` if the schools fail to be read from the collection
+    #[instrument(name = "Get schools", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_schools(&self) -> Result<Vec<School>, RepoError> {
+        self.schools
+            .find_many(doc! {}, doc! { "name": 1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the `School` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the school fails to be found in the collection
+    #[instrument(name = "Get school", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_school(&self, id: ObjectId) -> Result<School, RepoError> {
+        self.schools.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted teacher
+    /// # Errors
+    ///   - Returns a `RepoError` if the teacher fails to insert into the collection
+    #[instrument(
+        name = "Create teacher",
+        level = "debug",
+        target = "kid_data",
+        skip(self, teacher)
+    )]
+    pub async fn create_teacher(&self, teacher: &Teacher) -> Result<ObjectId, RepoError> {
+        info!("Adding a teacher");
+        self.teachers.insert(teacher).await
+    }
+
+    /// # Results
+    ///   - Returns every `Teacher` ordered by last name
+    /// # Errors
+    ///   - Returns a `RepoError` if the teachers fail to be read from the collection
+    #[instrument(
+        name = "Get teachers",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_teachers(&self) -> Result<Vec<Teacher>, RepoError> {
+        self.teachers
+            .find_many(doc! {}, doc! { "last_name": 1, "first_name": 1 }, None)
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the database ID of the inserted school year
+    /// # Errors
+    ///   - Returns a `RepoError` if the school year fails to insert into the collection
+    #[instrument(
+        name = "Create school year",
+        level = "debug",
+        target = "kid_data",
+        skip(self, year)
+    )]
+    pub async fn create_year(&self, year: &SchoolYear) -> Result<ObjectId, RepoError> {
+        info!("Adding a school year");
+        self.years.insert(year).await
+    }
+
+    /// # Results
+    ///   - Returns the `SchoolYear` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the school year fails to be found in the collection
+    #[instrument(
+        name = "Get school year",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_year(&self, id: ObjectId) -> Result<SchoolYear, RepoError> {
+        self.years.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns every `SchoolYear` for the child, most recent first
+    /// # Errors
+    ///   - Returns a `RepoError` if the school years fail to be read from the collection
+    #[instrument(
+        name = "Get child school years",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn get_years_for_child(
+        &self,
+        child_id: ObjectId,
+    ) -> Result<Vec<SchoolYear>, RepoError> {
+        self.years
+            .find_many(
+                doc! { "child_id": child_id },
+                doc! { "start_date": -1 },
+                None,
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult` if the class is added to the school year
+    /// # Errors
+    ///   - Returns a `RepoError` if the school year fails to update
+    #[instrument(
+        name = "Add class",
+        level = "debug",
+        target = "kid_data",
+        skip(self, class)
+    )]
+    pub async fn add_class(
+        &self,
+        year_id: ObjectId,
+        class: Class,
+    ) -> Result<UpdateResult, RepoError> {
+        info!("Adding a class to a school year");
+        let update = doc! {
+            "$push": {
+                "classes": {
+                    "subject": class.subject,
+                    "teacher_id": class.teacher_id,
+                }
+            }
+        };
+
+        self.years.update_by_id(year_id, update).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the school year and its grades are deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if either delete fails
+    #[instrument(
+        name = "Delete school year",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_year(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting a school year and its grades");
+        self.grades
+            .delete_many(doc! { "school_year_id": id })
+            .await?;
+
+        self.years.delete_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns an `UpdateResult`; the grade replaces any previous mark for the
+    ///     same subject and marking period
+    /// # Errors
+    ///   - Returns a `RepoError` if the grade fails to save
+    #[instrument(
+        name = "Save grade",
+        level = "debug",
+        target = "kid_data",
+        skip(self, grade)
+    )]
+    pub async fn save_grade(&self, grade: Grade) -> Result<UpdateResult, RepoError> {
+        info!("Saving a grade");
+        let filter = doc! {
+            "school_year_id": grade.school_year_id,
+            "marking_period": &grade.marking_period,
+            "subject": &grade.subject,
+        };
+        let update = doc! {
+            "$set": {
+                "child_id": grade.child_id,
+                "mark": grade.mark,
+                "comments": grade.comments,
+            }
+        };
+
+        let result = self
+            .grades
+            .collection()
+            .update_one(filter, update)
+            .with_options(UpdateOptions::builder().upsert(true).build())
+            .await
+            .map_err(|err| write_error("Failed to save grade in collection", &err))?;
+
+        debug!("Grade saved");
+        Ok(result)
+    }
+
+    /// # Results
+    ///   - Returns every `Grade` recorded for the school year
+    /// # Errors
+    ///   - Returns a `RepoError` if the grades fail to be read from the collection
+    #[instrument(name = "Get grades", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_grades_for_year(&self, year_id: ObjectId) -> Result<Vec<Grade>, RepoError> {
+        self.grades
+            .find_many(
+                doc! { "school_year_id": year_id },
+                doc! { "subject": 1 },
+                None,
+            )
+            .await
+    }
+
+    /// # Results
+    ///   - Returns the `Grade` with the matching database ID
+    /// # Errors
+    ///   - Returns a `RepoError` if the grade fails to be found in the collection
+    #[instrument(name = "Get grade", level = "debug", target = "kid_data", skip(self))]
+    pub async fn get_grade(&self, id: ObjectId) -> Result<Grade, RepoError> {
+        self.grades.find_by_id(id).await
+    }
+
+    /// # Results
+    ///   - Returns a `DeleteResult` if the grade is deleted
+    /// # Errors
+    ///   - Returns a `RepoError` if the grade fails to delete from the collection
+    #[instrument(
+        name = "Delete grade",
+        level = "debug",
+        target = "kid_data",
+        skip(self)
+    )]
+    pub async fn delete_grade(&self, id: ObjectId) -> Result<DeleteResult, RepoError> {
+        info!("Deleting a grade");
+        self.grades.delete_by_id(id).await
+    }
+}
+
+#[cfg(test)]
+mod test_school {
+    use super::*;
+
+    fn year(subjects: &[&str]) -> SchoolYear {
+        SchoolYear {
+            id: Some(ObjectId::new()),
+            child_id: ObjectId::new(),
+            school_id: ObjectId::new(),
+            label: String::from("2024-2025"),
+            grade_level: String::from("2nd Grade"),
+            start_date: NaiveDate::from_ymd_opt(2024, 8, 12).expect("valid date"),
+            end_date: NaiveDate::from_ymd_opt(2025, 5, 30).expect("valid date"),
+            homeroom_teacher_id: None,
+            marking_periods: DEFAULT_MARKING_PERIODS.map(String::from).to_vec(),
+            classes: subjects
+                .iter()
+                .map(|subject| Class {
+                    subject: (*subject).to_string(),
+                    teacher_id: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn grade(year: &SchoolYear, period: &str, subject: &str, mark: &str) -> Grade {
+        Grade {
+            id: None,
+            child_id: year.child_id,
+            school_year_id: year.id.unwrap_or_default(),
+            marking_period: period.to_string(),
+            subject: subject.to_string(),
+            mark: mark.to_string(),
+            comments: None,
+        }
+    }
+
+    #[test]
+    fn test_report_card_lays_out_marks_by_period() {
+        let year = year(&["Math", "Reading"]);
+        let grades = vec![
+            grade(&year, "Q1", "Math", "A"),
+            grade(&year, "Q2", "Math", "B+"),
+            grade(&year, "Q1", "Reading", "A-"),
+        ];
+
+        let card = report_card(&year, &grades);
+
+        assert_eq!(card.periods, vec!["Q1", "Q2", "Q3", "Q4"]);
+        assert_eq!(
+            card.rows,
+            vec![
+                (
+                    String::from("Math"),
+                    vec!["A".into(), "B+".into(), String::new(), String::new()]
+                ),
+                (
+                    String::from("Reading"),
+                    vec!["A-".into(), String::new(), String::new(), String::new()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_card_keeps_subjects_without_a_class() {
+        let year = year(&["Math"]);
+        let grades = vec![grade(&year, "Q3", "Art", "S")];
+
+        let card = report_card(&year, &grades);
+
+        assert_eq!(card.rows.len(), 2);
+        assert_eq!(card.rows[1].0, "Art");
+        assert_eq!(card.rows[1].1[2], "S");
+    }
+}
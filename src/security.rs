@@ -1,8 +1,7 @@
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::LazyLock};
 
-lazy_static! {
-    pub static ref BAD_MAPS: HashMap<char, &'static str> = HashMap::from([
+pub static BAD_MAPS: LazyLock<HashMap<char, &'static str>> = LazyLock::new(|| {
+    HashMap::from([
         ('&', "&amp;"),
         ('<', "&lt;"),
         ('>', "&gt;"),
@@ -12,15 +11,15 @@ lazy_static! {
         ('`', "&grave;"),
         ('=', "&#x3D;"),
         (' ', "&nbsp;"),
-    // (' ', "&#x20;"),
+        // (' ', "&#x20;"),
         ('!', "&#x21;"),
         ('"', "&#x22;"),
         ('#', "&#x23;"),
         ('$', "&#x24;"),
         ('%', "&#x25;"),
         // ('&', "&#x26;"),
-    ]);
-}
+    ])
+});
 
 #[allow(dead_code)]
 #[must_use]
@@ -1,13 +1,13 @@
 use std::{
-    convert::Infallible,
     env,
     future::{ready, Ready},
+    net::IpAddr,
 };
 
-use actix_web::{web::Data, FromRequest, HttpRequest};
+use actix_web::{error::ErrorInternalServerError, web::Data, FromRequest, HttpRequest};
 use mongodb::options::ClientOptions;
 use serde::Deserialize;
-use tracing::{info, instrument, warn};
+use tracing::{error, info, instrument, warn};
 
 /// Global setting for exposing all preconfigured variables
 #[derive(Deserialize, Clone)]
@@ -19,11 +19,11 @@ pub struct Settings {
     pub secret: Secret,
     pub email: Email,
     pub frontend_url: String,
-    pub doctor: Doctor,
+    pub media: Media,
 }
 
 impl FromRequest for Settings {
-    type Error = Infallible;
+    type Error = actix_web::Error;
 
     type Future = Ready<Result<Self, Self::Error>>;
 
@@ -34,10 +34,11 @@ impl FromRequest for Settings {
         skip(req)
     )]
     fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
-        let app_data = req.app_data::<Data<Self>>();
-        let settings = app_data.expect("Settings not found in the request").clone();
-        let var_name = settings.into_inner().as_ref().clone();
-        ready(Ok(var_name))
+        let Some(settings) = req.app_data::<Data<Self>>() else {
+            error!("Settings not found in the request");
+            return ready(Err(ErrorInternalServerError("Settings are unavailable")));
+        };
+        ready(Ok(settings.as_ref().clone()))
     }
 
     fn extract(req: &actix_web::HttpRequest) -> Self::Future {
@@ -46,19 +47,57 @@ impl FromRequest for Settings {
 }
 
 #[derive(Deserialize, Clone)]
-pub struct Doctor {
-    pub name: String,
-    pub email: String,
-    pub phone: String,
-    pub address: String,
-    pub speciality: String,
+pub struct Secret {
+    /// Name of the key in `keys` that encrypts new tokens
+    pub signing_key: String,
+    pub keys: Vec<KeyConfig>,
+    pub token_expiration: i64,
+    /// Keys CSRF tokens and is bound to every PASETO token, read the same
+    /// way as the keys of the ring
+    pub hmac_secret: SecretSource,
+    #[serde(default)]
+    pub argon2: Argon2Cost,
 }
 
+/// Where a secret is read from: an environment variable or a file kept
+/// outside the repository
+#[derive(Deserialize, Clone, Default)]
+pub struct SecretSource {
+    /// Environment variable holding the secret
+    #[serde(default)]
+    pub env: Option<String>,
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+/// One key of the ring that encrypts tokens: 32 bytes written as hex
 #[derive(Deserialize, Clone)]
-pub struct Secret {
-    pub secret_key: String,
-    pub token_expiration: i64,
-    pub hmac_secret: String,
+pub struct KeyConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: SecretSource,
+    /// Tokens a retired key encrypted are refused
+    #[serde(default)]
+    pub retired: bool,
+}
+
+/// Cost of hashing passwords with argon2id. Hashes made at an older cost
+/// still verify, and are replaced at the owner's next login.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2Cost {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Cost {
+    fn default() -> Self {
+        Self {
+            memory_kib: 15_000,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -68,6 +107,18 @@ pub struct Email {
     pub host_user_password: String,
 }
 
+/// Where uploaded photos and their thumbnails are written
+#[derive(Deserialize, Clone, Debug)]
+pub struct Media {
+    pub path: String,
+    /// Largest file one upload field may carry
+    pub max_upload_bytes: usize,
+    /// Most bytes one upload may carry across all of its files
+    pub max_request_bytes: usize,
+    /// Most photos one upload may carry
+    pub max_upload_files: usize,
+}
+
 /// Redis setting for the entire application
 #[derive(Deserialize, Clone, Debug)]
 pub struct Redis {
@@ -99,6 +150,10 @@ pub struct Application {
     pub host: String,
     pub base_url: String,
     pub protocol: String,
+    /// Reverse proxies whose `X-Forwarded-For` is believed. Requests from
+    /// anywhere else are known by their peer address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl Mongo {
@@ -172,6 +227,16 @@ impl TryFrom<String> for Environment {
     }
 }
 
+/// # Result
+///   - The environment named by ``APP_ENVIRONMENT``, development if unset
+/// # Errors
+///   - Returns a `String` if it names neither environment
+pub fn environment() -> Result<Environment, String> {
+    env::var("APP_ENVIRONMENT")
+        .unwrap_or_else(|_| String::from("development"))
+        .try_into()
+}
+
 /// # Result
 ///   - Returns a `Result` of `Settings` if successful
 /// # Errors
@@ -196,20 +261,16 @@ impl TryFrom<String> for Environment {
 #[instrument(name = "Get settings", level = "info", target = "kid_data")]
 pub fn get() -> Result<Settings, config::ConfigError> {
     info!("Getting the system config settings");
-    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
+    let base_path = std::env::current_dir().map_err(|err| {
+        config::ConfigError::Message(format!("Failed to determine the current directory: {err}"))
+    })?;
     warn!(
         "the contents of the settings file: {:?}",
         base_path.join("settings")
     );
     let setting_directory = base_path.join("settings");
 
-    let environment: Environment = match env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| String::from("development"))
-        .try_into()
-    {
-        Ok(env) => env,
-        Err(err) => return Err(config::ConfigError::Message(err)),
-    };
+    let environment = environment().map_err(config::ConfigError::Message)?;
     let environment_filename = format!("{}.yaml", environment.as_str());
     warn!(
         "Building the settings for the {} environment",
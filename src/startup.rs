@@ -2,39 +2,231 @@ use std::net;
 
 use actix_cors::Cors;
 use actix_files::Files;
-use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_session::SessionMiddleware;
 use actix_web::dev;
 use actix_web::http::header;
 use actix_web::{
     http::KeepAlive,
-    middleware,
-    web::{scope, Data},
+    middleware, rt,
+    web::{scope, Data, ServiceConfig},
     App, HttpServer,
 };
 use mongodb::Database;
 use tracing::instrument;
 use tracing::{debug, error, info, warn};
 
-use crate::endpoints::adrian::doctor::{doctor_card, doctor_data};
-use crate::endpoints::adrian::landing::adrian;
-use crate::endpoints::corbin::landing::corbin;
-use crate::endpoints::images::{
-    aj_headshot, cj_headshot, dental_image, doctor_image, physician_headshot,
+use crate::endpoints::children::{
+    child_landing, create_child, delete_child, edit_child, new_child, update_child,
+};
+use crate::endpoints::dentist::{
+    add_dental_visit, delete_dental_visit, dental_card, dental_data, edit_dental_visit,
+    new_dental_visit, update_dental_visit,
+};
+use crate::endpoints::doctor::{
+    add_doctor_visit, delete_doctor_visit, doctor_card, doctor_data, edit_doctor_visit,
+    new_doctor_visit, update_doctor_visit,
+};
+use crate::endpoints::images::{aj_headshot, cj_headshot, dental_image, doctor_image};
+use crate::endpoints::immunizations::{
+    add_immunization, delete_immunization, edit_immunization, immunization_card, immunization_data,
+    new_immunization, update_immunization,
 };
 use crate::endpoints::index::index;
 use crate::endpoints::login::logout;
+use crate::endpoints::photos::{
+    delete_photo, photo_detail, photo_gallery, photo_original, photo_page, photo_thumbnail,
+    upload_photos,
+};
+use crate::endpoints::providers::{
+    add_provider, edit_provider, new_provider, provider_card, provider_directory, update_provider,
+};
+use crate::endpoints::school::{
+    add_class, add_school, add_school_year, add_teacher, delete_grade, delete_school_year,
+    new_class, new_grade, new_school_year, save_grade, school_data, school_directory, school_year,
+};
 use crate::{
+    auth::{
+        api_tokens::authenticate_api_tokens, csrf::reject_forged_requests, guard::require_login,
+        keys::KeyRing, policy::enforce_policy, session::sign_out_stale_sessions,
+        store::RedisSessionStore, throttle::LoginThrottle,
+    },
     endpoints::{
+        api_tokens::{create_api_token, revoke_api_token},
+        devices::{devices_page, logout_everywhere, revoke_device},
+        error::htmx_error_fragments,
         health::health_check,
         images::{english_image, math_image, science_image, social_studies_image},
-        login::{login, login_user},
-        register::{register, registration},
+        invitations::{
+            accept_invitation, accept_invitation_page, invitations_page, send_invitation,
+            withdraw_invitation,
+        },
+        login::{login, login_two_factor, login_user},
+        passkeys::{
+            finish_passkey_login, finish_passkey_registration, passkeys_page, remove_passkey,
+            start_passkey_login, start_passkey_registration,
+        },
+        password::{
+            change_password, change_password_page, password_reset_page, request_password_reset,
+        },
+        profile::{
+            avatar, change_email, change_profile_password, close_account, confirm_email_change,
+            keep_account, profile_page, purge_closed_accounts, update_name, upload_avatar,
+        },
+        register::{
+            confirm, register, registration, resend_verification, resend_verification_page,
+        },
         templates::{favicon, htmx, response_targets, source_map, stylesheet},
-        users::{create, delete_user, get_user, get_users, update_user},
+        two_factor::{activate_two_factor, disable_two_factor, setup_two_factor, two_factor_page},
+        users::{
+            create, delete_user, get_user, get_users, reset_two_factor, unlock_user, update_user,
+        },
     },
+    models::migrations,
     settings::{self, Settings},
 };
 
+/// Signing in and out, and everything users manage about their own account
+fn account_routes(cfg: &mut ServiceConfig) {
+    cfg.service(login)
+        .service(index)
+        .service(login_user)
+        .service(registration)
+        .service(register)
+        .service(confirm)
+        .service(resend_verification_page)
+        .service(resend_verification)
+        .service(logout)
+        .service(password_reset_page)
+        .service(request_password_reset)
+        .service(change_password_page)
+        .service(change_password)
+        .service(profile_page)
+        .service(update_name)
+        .service(upload_avatar)
+        .service(avatar)
+        .service(change_profile_password)
+        .service(change_email)
+        .service(confirm_email_change)
+        .service(close_account)
+        .service(keep_account)
+        .service(devices_page)
+        .service(logout_everywhere)
+        .service(revoke_device)
+        .service(login_two_factor)
+        .service(two_factor_page)
+        .service(setup_two_factor)
+        .service(activate_two_factor)
+        .service(disable_two_factor)
+        .service(start_passkey_login)
+        .service(finish_passkey_login)
+        .service(passkeys_page)
+        .service(start_passkey_registration)
+        .service(finish_passkey_registration)
+        .service(remove_passkey)
+        .service(create_api_token)
+        .service(revoke_api_token)
+        .service(accept_invitation_page)
+        .service(accept_invitation)
+        .service(invitations_page)
+        .service(send_invitation)
+        .service(withdraw_invitation);
+}
+
+/// Every route the application serves, so tests can mount exactly what
+/// production does
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(
+        Files::new("/static", "./static")
+            .show_files_listing()
+            .use_last_modified(true),
+    )
+    .service(favicon)
+    .service(stylesheet)
+    .service(source_map)
+    .service(htmx)
+    .service(response_targets)
+    .service(
+        scope("/images")
+            .service(english_image)
+            .service(science_image)
+            .service(math_image)
+            .service(dental_image)
+            .service(doctor_image)
+            .service(social_studies_image)
+            .service(aj_headshot)
+            .service(cj_headshot),
+    )
+    .configure(account_routes)
+    .service(
+        scope("/child")
+            .wrap(middleware::from_fn(require_login))
+            .service(new_child)
+            .service(create_child)
+            .service(edit_child)
+            .service(child_landing)
+            .service(update_child)
+            .service(delete_child),
+    )
+    .service(new_doctor_visit)
+    .service(doctor_data)
+    .service(add_doctor_visit)
+    .service(edit_doctor_visit)
+    .service(doctor_card)
+    .service(update_doctor_visit)
+    .service(delete_doctor_visit)
+    .service(new_dental_visit)
+    .service(dental_data)
+    .service(add_dental_visit)
+    .service(edit_dental_visit)
+    .service(dental_card)
+    .service(update_dental_visit)
+    .service(delete_dental_visit)
+    .service(new_immunization)
+    .service(immunization_data)
+    .service(add_immunization)
+    .service(edit_immunization)
+    .service(immunization_card)
+    .service(update_immunization)
+    .service(delete_immunization)
+    .service(photo_page)
+    .service(photo_gallery)
+    .service(upload_photos)
+    .service(photo_detail)
+    .service(delete_photo)
+    .service(photo_thumbnail)
+    .service(photo_original)
+    .service(provider_directory)
+    .service(new_provider)
+    .service(add_provider)
+    .service(edit_provider)
+    .service(provider_card)
+    .service(update_provider)
+    .service(new_school_year)
+    .service(school_data)
+    .service(add_school_year)
+    .service(school_year)
+    .service(delete_school_year)
+    .service(new_class)
+    .service(add_class)
+    .service(new_grade)
+    .service(save_grade)
+    .service(delete_grade)
+    .service(school_directory)
+    .service(add_school)
+    .service(add_teacher)
+    .service(
+        scope("/v1")
+            .service(create)
+            .service(get_user)
+            .service(update_user)
+            .service(delete_user)
+            .service(reset_two_factor)
+            .service(unlock_user)
+            .service(get_users),
+    )
+    .service(health_check);
+}
+
 #[instrument(
     name = "main runner",
     level = "info",
@@ -47,8 +239,15 @@ fn run(
     settings: Settings,
 ) -> Result<dev::Server, std::io::Error> {
     // For each session
-    let secret_key = actix_web::cookie::Key::from(settings.secret.hmac_secret.as_bytes());
-    info!("Obtaining the cookie secret");
+    let key_ring = KeyRing::new(&settings.secret).map_err(|err| {
+        error!("Failed to load the key ring: {err}\nExiting...");
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Failed to load the key ring",
+        )
+    })?;
+    let secret_key = key_ring.cookie_key();
+    info!("Signing with the {} key", key_ring.signing_key());
 
     // Connect to the MongoDB database
     let mongo_pool = Data::new(db_pool);
@@ -68,7 +267,10 @@ fn run(
     };
     info!("Established secondary cache db connection pool");
 
+    let session_store = RedisSessionStore::new(redis_pool.clone());
+    let login_throttle = LoginThrottle::new(redis_pool.clone());
     let redis_pool = Data::new(redis_pool);
+    let key_ring = Data::new(key_ring);
     let setters = Data::new(settings);
 
     let _cors_middleware = Cors::default()
@@ -84,64 +286,33 @@ fn run(
     let server = HttpServer::new(move || {
         App::new()
             // .wrap(cors_middleware)
-            .wrap(if setters.debug {
-                warn!("DEBUG MODE");
-                SessionMiddleware::builder(CookieSessionStore::default(), secret_key.clone())
+            .wrap(middleware::from_fn(enforce_policy))
+            .wrap(middleware::from_fn(authenticate_api_tokens))
+            .wrap(middleware::from_fn(sign_out_stale_sessions))
+            .wrap(middleware::from_fn(reject_forged_requests))
+            .wrap(middleware::from_fn(htmx_error_fragments))
+            .wrap({
+                if setters.debug {
+                    warn!("DEBUG MODE");
+                } else {
+                    warn!("PRODUCTION MODE");
+                }
+                SessionMiddleware::builder(session_store.clone(), secret_key.clone())
                     .cookie_http_only(true)
                     .cookie_same_site(actix_web::cookie::SameSite::Lax)
                     .cookie_secure(true)
                     .build()
-            } else {
-                // TODO: Check if the below implementation is a secure cookie
-                warn!("PRODUCTION MODE");
-                SessionMiddleware::new(CookieSessionStore::default(), secret_key.clone())
             })
             .wrap(middleware::Compress::default())
             .wrap(middleware::DefaultHeaders::new().add(("X-Version", env!("CARGO_PKG_VERSION"))))
             .wrap(middleware::Logger::default())
             .app_data(mongo_pool.clone())
             .app_data(redis_pool.clone())
+            .app_data(Data::new(session_store.clone()))
+            .app_data(Data::new(login_throttle.clone()))
+            .app_data(key_ring.clone())
             .app_data(setters.clone())
-            .service(
-                Files::new("/static", "./static")
-                    .show_files_listing()
-                    .use_last_modified(true),
-            )
-            .service(favicon)
-            .service(stylesheet)
-            .service(source_map)
-            .service(htmx)
-            .service(response_targets)
-            .service(
-                scope("/images")
-                    .service(english_image)
-                    .service(science_image)
-                    .service(math_image)
-                    .service(dental_image)
-                    .service(doctor_image)
-                    .service(social_studies_image)
-                    .service(physician_headshot)
-                    .service(aj_headshot)
-                    .service(cj_headshot),
-            )
-            .service(login)
-            .service(index)
-            .service(login_user)
-            .service(registration)
-            .service(register)
-            .service(logout)
-            .service(scope("/child").service(adrian).service(corbin))
-            .service(doctor_data)
-            .service(doctor_card)
-            .service(
-                scope("/v1")
-                    .service(create)
-                    .service(get_user)
-                    .service(update_user)
-                    .service(delete_user)
-                    .service(get_users),
-            )
-            .service(health_check)
+            .configure(routes)
     })
     .keep_alive(KeepAlive::Os) // Keep the connection alive; OS handled
     .disable_signals() // Disable the signals to allow the OS to handle the signals
@@ -161,7 +332,8 @@ impl Application {
     /// # Result
     ///  - `Ok(Application)` if the application was successfully built
     /// # Errors
-    ///  - `std::io::Error` if the application could not be built
+    ///  - `std::io::Error` if the application could not be built or a
+    ///    database migration fails
     /// # Panics
     ///  - If the application could not be built
     #[instrument(
@@ -181,6 +353,18 @@ impl Application {
             get_connection_pool(&settings.mongo).await
         };
 
+        let applied = migrations::run(&connection_pool).await.map_err(|err| {
+            error!("Database migration failed: {err}");
+            std::io::Error::other(format!("Database migration failed: {err}"))
+        })?;
+        if !applied.is_empty() {
+            info!("Applied database migrations: {applied:?}");
+        }
+        rt::spawn(purge_closed_accounts(
+            connection_pool.clone(),
+            settings.media.path.clone(),
+        ));
+
         let address = format!(
             "{}:{}",
             settings.application.host, settings.application.port
@@ -8,6 +8,12 @@ pub mod tokens;
 pub enum Types {
     UserIdKey,
     UserEmailKey,
+    SessionVersionKey,
+    DeviceKey,
+    PendingLoginKey,
+    PasskeyRegistrationKey,
+    PasskeyLoginKey,
+    CsrfKey,
 }
 
 impl Display for Types {
@@ -15,6 +21,12 @@ impl Display for Types {
         match self {
             Self::UserIdKey => write!(f, "user_id"),
             Self::UserEmailKey => write!(f, "email"),
+            Self::SessionVersionKey => write!(f, "session_version"),
+            Self::DeviceKey => write!(f, "device"),
+            Self::PendingLoginKey => write!(f, "pending_login"),
+            Self::PasskeyRegistrationKey => write!(f, "passkey_registration"),
+            Self::PasskeyLoginKey => write!(f, "passkey_login"),
+            Self::CsrfKey => write!(f, "csrf"),
         }
     }
 }
@@ -34,6 +46,12 @@ impl From<Types> for String {
         match t {
             Types::UserIdKey => "user_id".to_string(),
             Types::UserEmailKey => "email".to_string(),
+            Types::SessionVersionKey => "session_version".to_string(),
+            Types::DeviceKey => "device".to_string(),
+            Types::PendingLoginKey => "pending_login".to_string(),
+            Types::PasskeyRegistrationKey => "passkey_registration".to_string(),
+            Types::PasskeyLoginKey => "passkey_login".to_string(),
+            Types::CsrfKey => "csrf".to_string(),
         }
     }
 }
@@ -8,14 +8,26 @@ use lettre::{
 use mongodb::bson::oid::ObjectId;
 use tracing::{debug, error, info, instrument};
 
-use crate::{auth::tokens::issue_confirmation_token, endpoints::templates::EmailPage, settings};
+use crate::{
+    auth::{
+        keys::KeyRing,
+        tokens::{issue_confirmation_token, INVITATION_LIFETIME_DAYS},
+    },
+    endpoints::templates::{EmailPage, InvitationEmail, LockoutEmail, PasswordResetEmail},
+    settings::{self, Settings},
+};
+
+/// Template that turns a multipart email into a password reset
+pub const PASSWORD_RESET_TEMPLATE: &str = "password_reset_email.html";
+
+/// Minutes a password reset link stays valid
+const PASSWORD_RESET_MINUTES: i64 = 60;
 
 /// # Results
 ///   - Ok(()) if the email was sent successfully.
 /// # Errors
-///   - Err(String) if the email could not be sent.
-/// # Panics
-///   - If the settings could not be retrieved.
+///   - Err(String) if the settings cannot be loaded, an address does not
+///     parse, or the email could not be sent.
 #[instrument(
     name = "Send email",
     level = "info",
@@ -39,29 +51,27 @@ pub async fn send_email(
     text_content: impl Into<String> + Send + Sync,
 ) -> Result<(), String> {
     info!("Send email function called.");
-    let settings = settings::get().expect("Could not get settings.");
+    let settings = settings::get().map_err(|err| format!("Could not get settings: {err}"))?;
+
+    let sender = format!(
+        "{} <{}>",
+        "JohnWrites",
+        sender_email.unwrap_or_else(|| settings.email.host_user.clone())
+    )
+    .parse()
+    .map_err(|err| format!("Could not parse sender email: {err}"))?;
+
+    let recipient = format!(
+        "{} <{}>",
+        [recipient_first_name, recipient_last_name].join(" "),
+        recipient_email
+    )
+    .parse()
+    .map_err(|err| format!("Could not parse recipient email: {err}"))?;
 
     let email = Message::builder()
-        .from(
-            format!(
-                "{} <{}>",
-                "JohnWrites",
-                if sender_email.is_some() {
-                    sender_email.expect("Could not get sender email.")
-                } else {
-                    settings.email.host_user.clone()
-                }
-            )
-            .parse()
-            .expect("Could not parse sender email."),
-        )
-        .to(format!(
-            "{} <{}>",
-            [recipient_first_name, recipient_last_name].join(" "),
-            recipient_email
-        )
-        .parse()
-        .expect("Could not parse recipient email."))
+        .from(sender)
+        .to(recipient)
         .subject(subject)
         .multipart(
             MultiPart::alternative()
@@ -76,14 +86,14 @@ pub async fn send_email(
                         .body(html_content.into()),
                 ),
         )
-        .expect("Could not build email.");
+        .map_err(|err| format!("Could not build email: {err}"))?;
 
     let creds = Credentials::new(settings.email.host_user, settings.email.host_user_password);
 
     // Open a remote connection to the mail server
     let mailer: AsyncSmtpTransport<Tokio1Executor> =
         AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.email.host)
-            .expect("Could not create AsyncSmtpMailer mailer.")
+            .map_err(|err| format!("Could not create AsyncSmtpMailer mailer: {err}"))?
             .credentials(creds)
             .build();
 
@@ -103,9 +113,8 @@ pub async fn send_email(
 /// # Results
 ///   - Ok(()) if the multi-part email was sent successfully.
 /// # Errors
-///   - Err(String) if the email could not be sent.
-/// # Panics
-///   - If the settings could not be retrieved.
+///   - Err(String) if the settings cannot be loaded, the token cannot be
+///     issued, or the email could not be rendered or sent.
 #[instrument(
     name = "Send multipart email",
     level = "info",
@@ -116,9 +125,11 @@ pub async fn send_email(
         recipient_first_name,
         recipient_last_name,
         template_name,
-        redis_connection
+        redis_connection,
+        key_ring
     )
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn send_multipart_email(
     subject: String,
     user_id: ObjectId,
@@ -127,13 +138,23 @@ pub async fn send_multipart_email(
     recipient_last_name: String,
     template_name: &str,
     redis_connection: &mut aio::MultiplexedConnection,
+    key_ring: &KeyRing,
 ) -> Result<(), String> {
     info!("Send multipart email function called.");
-    let settings = settings::get().expect("Could not get settings.");
+    let settings = settings::get().map_err(|err| format!("Could not get settings: {err}"))?;
 
     let title = subject.clone();
 
-    let issued_token = match issue_confirmation_token(user_id, redis_connection, None).await {
+    let is_password_reset = template_name == PASSWORD_RESET_TEMPLATE;
+
+    let issued_token = match issue_confirmation_token(
+        user_id,
+        redis_connection,
+        key_ring,
+        is_password_reset.then_some(true),
+    )
+    .await
+    {
         Ok(token) => token,
         Err(err) => {
             error!("Could not issue confirmation token: {err}.");
@@ -141,40 +162,51 @@ pub async fn send_multipart_email(
         }
     };
 
-    let web_address = {
-        if settings.debug {
-            format!(
-                "{}:{}",
-                settings.application.base_url, settings.application.port
-            )
-        } else {
-            settings.application.base_url
-        }
-    };
+    let web_address = web_address(&settings);
 
     let confirmation_link = {
         debug!("Creating email confirmation link from multi-part template.");
-        if template_name == "password_reset_email.html" {
+        if is_password_reset {
             format!("{web_address}/users/password/confirm/change_password?token={issued_token}")
         } else {
-            format!("{web_address}/users/register/confirm/?token={issued_token}")
+            format!("{web_address}/register/confirm?token={issued_token}")
         }
     };
 
-    let current_date_time = chrono::Local::now();
-    let dt = current_date_time + chrono::Duration::minutes(settings.secret.token_expiration);
-
-    let email = EmailPage {
-        title,
-        confirmation_link: confirmation_link.clone(),
-        domain: settings.frontend_url,
-        expiration_time: settings.secret.token_expiration.to_string(),
-        exact_time: dt.format("%A %B %d, %Y at %r").to_string(),
+    let minutes = if is_password_reset {
+        PASSWORD_RESET_MINUTES
+    } else {
+        key_ring.token_expiration()
     };
+    let dt = chrono::Local::now() + chrono::Duration::minutes(minutes);
+    let exact_time = dt.format("%A %B %d, %Y at %r").to_string();
 
-    let template = email.render().expect("Could not render email template.");
+    let rendered = if is_password_reset {
+        PasswordResetEmail {
+            title,
+            confirmation_link: confirmation_link.clone(),
+            domain: settings.frontend_url,
+            expiration_time: minutes.to_string(),
+            exact_time,
+        }
+        .render()
+    } else {
+        EmailPage {
+            title,
+            confirmation_link: confirmation_link.clone(),
+            domain: settings.frontend_url,
+            expiration_time: minutes.to_string(),
+            exact_time,
+        }
+        .render()
+    };
+    let template = rendered.map_err(|err| format!("Could not render email template: {err}"))?;
 
-    let text = format!("Tap the link below to confirm your email address.{confirmation_link}");
+    let text = if is_password_reset {
+        format!("Tap the link below to reset your password.{confirmation_link}")
+    } else {
+        format!("Tap the link below to confirm your email address.{confirmation_link}")
+    };
 
     // actix_web::rt::spawn(send_email(
     //     None,
@@ -193,3 +225,177 @@ pub async fn send_multipart_email(
 
     Ok(())
 }
+
+/// # Results
+///   - Ok(()) if the lockout notice was sent successfully.
+/// # Errors
+///   - Err(String) if the settings cannot be loaded, or the email could not
+///     be rendered or sent.
+#[instrument(
+    name = "Send lockout email",
+    level = "info",
+    skip(recipient_email, recipient_first_name, recipient_last_name)
+)]
+pub async fn send_lockout_email(
+    recipient_email: String,
+    recipient_first_name: String,
+    recipient_last_name: String,
+    lockout_minutes: i64,
+) -> Result<(), String> {
+    info!("Send lockout email function called.");
+    let settings = settings::get().map_err(|err| format!("Could not get settings: {err}"))?;
+
+    let title = String::from("Child Data - Your account is locked");
+    let reset_link = format!("{}/users/password/reset", web_address(&settings));
+    let dt = chrono::Local::now() + chrono::Duration::minutes(lockout_minutes);
+    let exact_time = dt.format("%A %B %d, %Y at %r").to_string();
+
+    let template = LockoutEmail {
+        title: title.clone(),
+        reset_link: reset_link.clone(),
+        lockout_time: lockout_minutes.to_string(),
+        exact_time: exact_time.clone(),
+    }
+    .render()
+    .map_err(|err| format!("Could not render email template: {err}"))?;
+
+    let text = format!(
+        "Your account was locked after too many failed logins. It unlocks on {exact_time}. If this wasn't you, reset your password: {reset_link}"
+    );
+
+    send_email(
+        None,
+        recipient_email,
+        recipient_first_name,
+        recipient_last_name,
+        title,
+        template,
+        text,
+    )
+    .await
+}
+
+/// # Results
+///   - Ok(()) if the invitation was sent successfully.
+/// # Errors
+///   - Err(String) if the settings cannot be loaded, or the email could not
+///     be rendered or sent.
+#[instrument(
+    name = "Send invitation email",
+    level = "info",
+    skip(
+        recipient_email,
+        recipient_first_name,
+        recipient_last_name,
+        inviter,
+        token
+    )
+)]
+pub async fn send_invitation_email(
+    recipient_email: String,
+    recipient_first_name: String,
+    recipient_last_name: String,
+    inviter: String,
+    token: &str,
+) -> Result<(), String> {
+    info!("Send invitation email function called.");
+    let settings = settings::get().map_err(|err| format!("Could not get settings: {err}"))?;
+
+    let title = format!("Child Data - {inviter} has invited you");
+    let accept_link = format!(
+        "{}/invitations/accept?token={token}",
+        web_address(&settings)
+    );
+    let dt = chrono::Local::now() + chrono::Duration::days(INVITATION_LIFETIME_DAYS);
+    let exact_time = dt.format("%A %B %d, %Y at %r").to_string();
+
+    let template = InvitationEmail {
+        title: title.clone(),
+        inviter: inviter.clone(),
+        accept_link: accept_link.clone(),
+        expiration_days: INVITATION_LIFETIME_DAYS.to_string(),
+        exact_time,
+    }
+    .render()
+    .map_err(|err| format!("Could not render email template: {err}"))?;
+
+    let text = format!(
+        "{inviter} has invited you to see some of the children's records they keep. Tap the link below to join.{accept_link}"
+    );
+
+    send_email(
+        None,
+        recipient_email,
+        recipient_first_name,
+        recipient_last_name,
+        title,
+        template,
+        text,
+    )
+    .await
+}
+
+/// # Results
+///   - Ok(()) if the link confirming a new address was sent successfully.
+/// # Errors
+///   - Err(String) if the settings cannot be loaded, or the email could not
+///     be rendered or sent.
+#[instrument(
+    name = "Send email change email",
+    level = "info",
+    skip(recipient_email, recipient_first_name, recipient_last_name, token)
+)]
+pub async fn send_email_change_email(
+    recipient_email: String,
+    recipient_first_name: String,
+    recipient_last_name: String,
+    token: &str,
+) -> Result<(), String> {
+    info!("Send email change email function called.");
+    let settings = settings::get().map_err(|err| format!("Could not get settings: {err}"))?;
+
+    let title = String::from("Child Data - Confirm your new email address");
+    let confirmation_link = format!(
+        "{}/users/email/confirm?token={token}",
+        web_address(&settings)
+    );
+    let minutes = settings.secret.token_expiration;
+    let dt = chrono::Local::now() + chrono::Duration::minutes(minutes);
+
+    let template = EmailPage {
+        title: title.clone(),
+        confirmation_link: confirmation_link.clone(),
+        domain: settings.frontend_url.clone(),
+        expiration_time: minutes.to_string(),
+        exact_time: dt.format("%A %B %d, %Y at %r").to_string(),
+    }
+    .render()
+    .map_err(|err| format!("Could not render email template: {err}"))?;
+
+    let text =
+        format!("Tap the link below to make this your account's email address.{confirmation_link}");
+
+    send_email(
+        None,
+        recipient_email,
+        recipient_first_name,
+        recipient_last_name,
+        title,
+        template,
+        text,
+    )
+    .await
+}
+
+/// # Result
+///   - Where links in emails should point
+fn web_address(settings: &Settings) -> String {
+    if settings.debug {
+        format!(
+            "{}:{}",
+            settings.application.base_url, settings.application.port
+        )
+    } else {
+        settings.application.base_url.clone()
+    }
+}
@@ -0,0 +1,100 @@
+// Runs the browser's side of passkey sign-in and registration. The server
+// sends WebAuthn options as JSON with binary fields in base64url; the
+// browser API takes and returns ArrayBuffers.
+(function () {
+  const toBuffer = (value) =>
+    Uint8Array.from(atob(value.replace(/-/g, "+").replace(/_/g, "/")), (c) =>
+      c.charCodeAt(0),
+    ).buffer;
+
+  const toBase64Url = (buffer) =>
+    btoa(String.fromCharCode(...new Uint8Array(buffer)))
+      .replace(/\+/g, "-")
+      .replace(/\//g, "_")
+      .replace(/=+$/, "");
+
+  const showError = (html) => {
+    document.getElementById("error_block").innerHTML = html;
+  };
+
+  // Posts the form to `url` and returns the options it answers with
+  async function fetchOptions(url, form) {
+    const response = await fetch(url, {
+      method: "POST",
+      body: new URLSearchParams(new FormData(form)),
+      headers: { "X-CSRF-Token": csrfToken(), "HX-Request": "true" },
+    });
+    if (!response.ok) {
+      showError(await response.text());
+      return null;
+    }
+    return response.json();
+  }
+
+  // Hands the browser's answer to the form, which posts it through HTMX
+  function submit(form, credential) {
+    form.elements.credential.value = JSON.stringify(credential);
+    htmx.trigger(form, "passkey");
+  }
+
+  async function register(form) {
+    const options = await fetchOptions("/users/passkeys/register/start", form);
+    if (!options) return;
+    const key = options.publicKey;
+    key.challenge = toBuffer(key.challenge);
+    key.user.id = toBuffer(key.user.id);
+    (key.excludeCredentials || []).forEach((c) => (c.id = toBuffer(c.id)));
+
+    const credential = await navigator.credentials.create(options);
+    submit(form, {
+      id: credential.id,
+      rawId: toBase64Url(credential.rawId),
+      type: credential.type,
+      extensions: credential.getClientExtensionResults(),
+      response: {
+        attestationObject: toBase64Url(credential.response.attestationObject),
+        clientDataJSON: toBase64Url(credential.response.clientDataJSON),
+        transports: credential.response.getTransports
+          ? credential.response.getTransports()
+          : [],
+      },
+    });
+  }
+
+  async function logIn(form) {
+    const options = await fetchOptions("/login/passkey/start", form);
+    if (!options) return;
+    const key = options.publicKey;
+    key.challenge = toBuffer(key.challenge);
+    (key.allowCredentials || []).forEach((c) => (c.id = toBuffer(c.id)));
+
+    const credential = await navigator.credentials.get(options);
+    const response = credential.response;
+    submit(form, {
+      id: credential.id,
+      rawId: toBase64Url(credential.rawId),
+      type: credential.type,
+      extensions: credential.getClientExtensionResults(),
+      response: {
+        authenticatorData: toBase64Url(response.authenticatorData),
+        clientDataJSON: toBase64Url(response.clientDataJSON),
+        signature: toBase64Url(response.signature),
+        userHandle: response.userHandle ? toBase64Url(response.userHandle) : null,
+      },
+    });
+  }
+
+  document.addEventListener("click", (event) => {
+    const button = event.target.closest("[data-passkey]");
+    if (!button) return;
+    event.preventDefault();
+    if (!window.PublicKeyCredential) {
+      showError("<p>This browser does not support passkeys.</p>");
+      return;
+    }
+    const ceremony = button.dataset.passkey === "register" ? register : logIn;
+    ceremony(button.form).catch((err) => {
+      showError(`<p>The passkey was not used: ${err.message}</p>`);
+    });
+  });
+})();
@@ -0,0 +1,104 @@
+use actix_web::{
+    http::StatusCode,
+    test::{call_service, init_service, TestRequest},
+    web::Data,
+    App,
+};
+use kid_data::{
+    auth::{keys::KeyRing, tokens::issue_confirmation_token},
+    endpoints::register::{confirm, CreateNewUser},
+    models::mongo::UserRepo,
+    settings::{self, KeyConfig, SecretSource, Settings},
+};
+use mongodb::Client;
+
+fn test_settings() -> Settings {
+    let mut settings = settings::get().expect("settings load from ./settings");
+    settings.mongo.db = String::from("kid_data_email_verification_test");
+    std::env::set_var(
+        "KID_DATA_TEST_KEY",
+        "357685dcf1e83645e9b83695e9e5e7bd5a7fbcc84d5aa40e5ef5f15ac6fece0e",
+    );
+    settings.secret.signing_key = String::from("test");
+    settings.secret.keys = vec![KeyConfig {
+        name: String::from("test"),
+        source: SecretSource {
+            env: Some(String::from("KID_DATA_TEST_KEY")),
+            file: None,
+        },
+        retired: false,
+    }];
+    std::env::set_var("KID_DATA_TEST_HMAC_SECRET", "a secret for the tests");
+    settings.secret.hmac_secret = SecretSource {
+        env: Some(String::from("KID_DATA_TEST_HMAC_SECRET")),
+        file: None,
+    };
+    settings
+}
+
+/// Follows a verification link from issue to confirm against the `MongoDB`
+/// and Redis servers named in ./settings
+#[actix_web::test]
+#[ignore = "needs the MongoDB and Redis servers from ./settings"]
+async fn test_confirmation_link_verifies_the_email() {
+    let settings = test_settings();
+    let client = Client::with_options(settings.mongo.mongo_options().await)
+        .expect("client options are valid");
+    let db = client.database(&settings.mongo.db);
+    db.drop().await.expect("test database is dropped");
+
+    let users = UserRepo::new(&db);
+    let user_id = users
+        .create_user(
+            CreateNewUser {
+                email: String::from("parent@example.com"),
+                password: String::from("correct horse battery staple"),
+                password_2: String::from("correct horse battery staple"),
+                first_name: String::from("Test"),
+                last_name: String::from("Parent"),
+            },
+            settings.secret.argon2,
+        )
+        .await
+        .expect("user is created");
+    let user = users
+        .get_user(Some(user_id), None)
+        .await
+        .expect("user is stored");
+    assert!(user.email_verified_at.is_none());
+
+    let key_ring = KeyRing::new(&settings.secret).expect("test key ring loads");
+    let redis_pool = deadpool_redis::Config::from_url(settings.redis.url.clone())
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .expect("redis pool builds");
+    let token = {
+        let mut conn = redis_pool.get().await.expect("redis is reachable");
+        issue_confirmation_token(user_id, &mut conn, &key_ring, None)
+            .await
+            .expect("token is issued")
+    };
+
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(db.clone()))
+            .app_data(Data::new(redis_pool))
+            .app_data(Data::new(key_ring))
+            .service(confirm),
+    )
+    .await;
+    let confirm_uri = format!("/register/confirm?token={token}");
+
+    let response = call_service(&app, TestRequest::get().uri(&confirm_uri).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = users
+        .get_user(Some(user_id), None)
+        .await
+        .expect("user is stored");
+    assert!(user.email_verified_at.is_some());
+
+    // The link only works once
+    let response = call_service(&app, TestRequest::get().uri(&confirm_uri).to_request()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    db.drop().await.expect("test database is dropped");
+}
@@ -0,0 +1,457 @@
+use std::time::Duration;
+
+use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_web::{
+    body::MessageBody,
+    cookie::{Cookie, Key},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    middleware::{from_fn, Next},
+    test::{call_service, init_service, TestRequest},
+    web::Data,
+    App, HttpMessage,
+};
+use kid_data::{
+    auth::{
+        api_tokens::authenticate_api_tokens,
+        csrf::{reject_forged_requests, CSRF_COOKIE, CSRF_HEADER},
+        keys::KeyRing,
+        policy::{enforce_policy, Role},
+        throttle::LoginThrottle,
+    },
+    endpoints::error::htmx_error_fragments,
+    models::mongo::User,
+    settings::{self, KeyConfig, SecretSource, Settings},
+    startup::routes,
+};
+use mongodb::{
+    bson::{oid::ObjectId, DateTime},
+    options::{ClientOptions, ServerAddress},
+    Client, Database,
+};
+
+/// A well formed ID that no document has
+const MISSING_ID: &str = "0123456789abcdef01234567";
+
+/// A database handle that never connects; driver calls fail fast
+fn unreachable_database() -> Database {
+    let options = ClientOptions::builder()
+        .hosts(vec![ServerAddress::Tcp {
+            host: String::from("127.0.0.1"),
+            port: Some(1),
+        }])
+        .server_selection_timeout(Duration::from_millis(100))
+        .connect_timeout(Duration::from_millis(100))
+        .build();
+
+    Client::with_options(options)
+        .expect("client options are valid")
+        .database("kid_data_test")
+}
+
+fn test_settings() -> Settings {
+    let mut settings = settings::get().expect("settings load from ./settings");
+    settings.redis.url = String::from("redis://127.0.0.1:1");
+    std::env::set_var(
+        "KID_DATA_TEST_KEY",
+        "357685dcf1e83645e9b83695e9e5e7bd5a7fbcc84d5aa40e5ef5f15ac6fece0e",
+    );
+    settings.secret.signing_key = String::from("test");
+    settings.secret.keys = vec![KeyConfig {
+        name: String::from("test"),
+        source: SecretSource {
+            env: Some(String::from("KID_DATA_TEST_KEY")),
+            file: None,
+        },
+        retired: false,
+    }];
+    std::env::set_var("KID_DATA_TEST_HMAC_SECRET", "a secret for the tests");
+    settings.secret.hmac_secret = SecretSource {
+        env: Some(String::from("KID_DATA_TEST_HMAC_SECRET")),
+        file: None,
+    };
+    settings
+}
+
+fn test_key_ring() -> KeyRing {
+    KeyRing::new(&test_settings().secret).expect("test key ring loads")
+}
+
+/// Stands in for `sign_out_stale_sessions` so requests reach past the login
+/// guard as an admin
+#[allow(clippy::future_not_send)]
+async fn sign_in(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    req.extensions_mut().insert(User {
+        id: Some(ObjectId::new()),
+        role: Role::Admin,
+        email_verified_at: Some(DateTime::now()),
+        ..User::default()
+    });
+    next.call(req).await
+}
+
+fn redis_pool(settings: &Settings) -> deadpool_redis::Pool {
+    deadpool_redis::Config::from_url(settings.redis.url.clone())
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .expect("redis pool builds without connecting")
+}
+
+/// The application's routes behind the production middleware, signed in
+macro_rules! signed_in_app {
+    () => {{
+        let settings = test_settings();
+        init_service(
+            App::new()
+                .wrap(from_fn(sign_in))
+                .wrap(from_fn(htmx_error_fragments))
+                .wrap(
+                    SessionMiddleware::builder(CookieSessionStore::default(), Key::generate())
+                        .cookie_secure(false)
+                        .build(),
+                )
+                .app_data(Data::new(unreachable_database()))
+                .app_data(Data::new(redis_pool(&settings)))
+                .app_data(Data::new(LoginThrottle::new(redis_pool(&settings))))
+                .app_data(Data::new(test_key_ring()))
+                .app_data(Data::new(settings))
+                .configure(routes),
+        )
+        .await
+    }};
+}
+
+/// A request with a body that cannot be what the route expects
+struct Case {
+    method: Method,
+    uri: String,
+    content_type: &'static str,
+    body: &'static str,
+}
+
+impl Case {
+    fn new(method: Method, uri: &str) -> Self {
+        Self {
+            method,
+            uri: uri.to_string(),
+            content_type: "application/x-www-form-urlencoded",
+            body: "%%%=&&date=yesterday&score=-1",
+        }
+    }
+
+    const fn with(mut self, content_type: &'static str, body: &'static str) -> Self {
+        self.content_type = content_type;
+        self.body = body;
+        self
+    }
+
+    fn request(&self) -> TestRequest {
+        TestRequest::default()
+            .method(self.method.clone())
+            .uri(&self.uri)
+            .insert_header((header::CONTENT_TYPE, self.content_type))
+            .set_payload(self.body)
+    }
+}
+
+/// Every route that takes an ID, given one that is not an `ObjectId`
+fn bad_id_cases() -> Vec<Case> {
+    let mut cases = Vec::new();
+    for uri in [
+        "/doctor_card/not-an-id",
+        "/dental_card/not-an-id",
+        "/immunization_card/not-an-id",
+        "/providers/not-an-id",
+        "/school_year/not-an-id",
+        "/photo/not-an-id",
+    ] {
+        cases.push(Case::new(Method::GET, uri));
+        cases.push(Case::new(Method::DELETE, uri));
+    }
+    for uri in [
+        "/doctor_card/not-an-id/edit",
+        "/dental_card/not-an-id/edit",
+        "/immunization_card/not-an-id/edit",
+        "/providers/not-an-id/edit",
+        "/school_year/not-an-id/class",
+        "/school_year/not-an-id/grade",
+        "/media/not-an-id",
+        "/media/not-an-id/thumb",
+        "/v1/user/not-an-id",
+    ] {
+        cases.push(Case::new(Method::GET, uri));
+    }
+    cases.push(Case::new(Method::DELETE, "/grade/not-an-id"));
+    cases.push(Case::new(Method::DELETE, "/v1/user/not-an-id"));
+    cases.push(Case::new(Method::PUT, "/v1/user/not-an-id").with(
+        "application/json",
+        r#"{"first_name":"A","last_name":"B","email":"a@b.c","password":"x"}"#,
+    ));
+    cases
+}
+
+/// Every route that reads a body, given one it cannot parse
+fn bad_body_cases() -> Vec<Case> {
+    let mut cases: Vec<Case> = [
+        (Method::POST, "/login"),
+        (Method::POST, "/register"),
+        (Method::POST, "/child"),
+        (Method::PUT, "/child/some-child"),
+        (Method::POST, "/doctor_logs/some-child"),
+        (Method::PUT, &format!("/doctor_card/{MISSING_ID}")),
+        (Method::POST, "/dental_logs/some-child"),
+        (Method::PUT, &format!("/dental_card/{MISSING_ID}")),
+        (Method::POST, "/immunization_logs/some-child"),
+        (Method::PUT, &format!("/immunization_card/{MISSING_ID}")),
+        (Method::POST, "/providers"),
+        (Method::PUT, &format!("/providers/{MISSING_ID}")),
+        (Method::POST, "/school_logs/some-child"),
+        (Method::POST, &format!("/school_year/{MISSING_ID}/class")),
+        (Method::POST, &format!("/school_year/{MISSING_ID}/grade")),
+        (Method::POST, "/schools"),
+        (Method::POST, "/teachers"),
+        (Method::POST, "/users/profile/name"),
+        (Method::POST, "/users/profile/password"),
+        (Method::POST, "/users/profile/email"),
+        (Method::POST, "/users/profile/delete"),
+    ]
+    .into_iter()
+    .map(|(method, uri)| Case::new(method, uri))
+    .collect();
+
+    cases.push(Case::new(Method::POST, "/v1/user").with("application/json", "{\"email\":"));
+    cases.push(Case::new(Method::GET, "/register/confirm"));
+    cases.push(Case::new(Method::POST, "/register/resend"));
+    cases.push(Case::new(
+        Method::POST,
+        "/users/password/confirm/change_password",
+    ));
+    cases.push(Case::new(
+        Method::GET,
+        "/users/password/confirm/change_password",
+    ));
+    cases
+}
+
+/// Uploads that are not the multipart form the photo library expects
+fn bad_upload_cases() -> Vec<Case> {
+    vec![
+        Case::new(Method::POST, "/photo_logs/some-child")
+            .with("multipart/form-data; boundary=xyz", "not multipart at all"),
+        Case::new(Method::POST, "/photo_logs/some-child").with(
+            "multipart/form-data; boundary=xyz",
+            "--xyz\r\nContent-Disposition: form-data; name=\"photos\"; filename=\"a.jpg\"\r\n\r\nnot an image\r\n--xyz--\r\n",
+        ),
+        Case::new(Method::POST, "/photo_logs/some-child").with("multipart/form-data", ""),
+        Case::new(Method::POST, "/users/profile/avatar").with(
+            "multipart/form-data; boundary=xyz",
+            "--xyz\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"a.jpg\"\r\n\r\nnot an image\r\n--xyz--\r\n",
+        ),
+        Case::new(Method::POST, "/users/profile/avatar").with("multipart/form-data", ""),
+    ]
+}
+
+#[actix_web::test]
+async fn test_malformed_ids_are_rejected_as_client_errors() {
+    let app = signed_in_app!();
+
+    for case in bad_id_cases() {
+        let res = call_service(&app, case.request().to_request()).await;
+        assert!(
+            res.status().is_client_error(),
+            "{} {} answered {}",
+            case.method,
+            case.uri,
+            res.status()
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_unparsable_bodies_are_rejected_as_client_errors() {
+    let app = signed_in_app!();
+
+    for case in bad_body_cases() {
+        let res = call_service(&app, case.request().to_request()).await;
+        assert!(
+            res.status().is_client_error(),
+            "{} {} answered {}",
+            case.method,
+            case.uri,
+            res.status()
+        );
+    }
+}
+
+#[actix_web::test]
+async fn test_bad_uploads_get_a_response() {
+    let app = signed_in_app!();
+
+    for case in bad_upload_cases() {
+        let res = call_service(&app, case.request().to_request()).await;
+        assert_ne!(res.status(), StatusCode::OK, "{} {}", case.method, case.uri);
+    }
+}
+
+#[actix_web::test]
+async fn test_bad_confirmation_token_gets_a_response() {
+    let app = signed_in_app!();
+    let res = call_service(
+        &app,
+        TestRequest::get()
+            .uri("/register/confirm?token=v4.local.garbage")
+            .to_request(),
+    )
+    .await;
+    assert!(!res.status().is_success());
+
+    let res = call_service(
+        &app,
+        TestRequest::get()
+            .uri("/users/email/confirm?token=v4.local.garbage")
+            .to_request(),
+    )
+    .await;
+    assert!(!res.status().is_success());
+}
+
+#[actix_web::test]
+async fn test_signed_out_requests_are_sent_to_log_in() {
+    let app = init_service(
+        App::new()
+            .app_data(Data::new(unreachable_database()))
+            .configure(routes),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/child/new").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        res.headers()
+            .get(header::LOCATION)
+            .map(header::HeaderValue::as_bytes),
+        Some(b"/?next=/child/new".as_slice())
+    );
+
+    let res = call_service(
+        &app,
+        TestRequest::get()
+            .uri(&format!("/doctor_card/{MISSING_ID}"))
+            .insert_header(("HX-Request", "true"))
+            .insert_header(("HX-Current-URL", "http://localhost/doctor_logs/aj"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()
+            .get("HX-Redirect")
+            .map(header::HeaderValue::as_bytes),
+        Some(b"/?next=/doctor_logs/aj".as_slice())
+    );
+}
+
+#[actix_web::test]
+async fn test_bad_api_tokens_are_refused() {
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(enforce_policy))
+            .wrap(from_fn(authenticate_api_tokens))
+            .app_data(Data::new(unreachable_database()))
+            .app_data(Data::new(test_key_ring()))
+            .configure(routes),
+    )
+    .await;
+
+    for authorization in ["Bearer v4.local.garbage", "Bearer "] {
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/v1/users")
+                .insert_header((header::AUTHORIZATION, authorization))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{authorization}");
+    }
+
+    let res = call_service(
+        &app,
+        TestRequest::get()
+            .uri("/v1/users")
+            .insert_header((header::AUTHORIZATION, "Bearer v4.local.garbage"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(
+        res.headers()
+            .get(header::WWW_AUTHENTICATE)
+            .map(header::HeaderValue::as_bytes),
+        Some(b"Bearer".as_slice())
+    );
+}
+
+#[actix_web::test]
+async fn test_posts_without_a_csrf_token_are_refused() {
+    let settings = test_settings();
+    let app = init_service(
+        App::new()
+            .wrap(from_fn(reject_forged_requests))
+            .wrap(from_fn(htmx_error_fragments))
+            .wrap(
+                SessionMiddleware::builder(CookieSessionStore::default(), Key::generate())
+                    .cookie_secure(false)
+                    .build(),
+            )
+            .app_data(Data::new(unreachable_database()))
+            .app_data(Data::new(redis_pool(&settings)))
+            .app_data(Data::new(test_key_ring()))
+            .app_data(Data::new(settings))
+            .configure(routes),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::post().uri("/logout").to_request()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let token = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .expect("a CSRF cookie is handed out");
+
+    let forged = "0123.4567";
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/logout")
+            .cookie(Cookie::new(CSRF_COOKIE, forged))
+            .insert_header((CSRF_HEADER, forged))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/logout")
+            .cookie(Cookie::new(CSRF_COOKIE, token.clone()))
+            .insert_header((CSRF_HEADER, token))
+            .to_request(),
+    )
+    .await;
+    assert_ne!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = call_service(
+        &app,
+        TestRequest::post()
+            .uri("/v1/users")
+            .insert_header((header::AUTHORIZATION, "Bearer v4.local.garbage"))
+            .to_request(),
+    )
+    .await;
+    assert_ne!(res.status(), StatusCode::FORBIDDEN);
+}